@@ -0,0 +1,147 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    compile_protos();
+    #[cfg(feature = "capi")]
+    generate_header();
+}
+
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    // protoc is not required on the build machine
+    std::env::set_var(
+        "PROTOC",
+        protoc_bin_vendored::protoc_bin_path().expect("protoc is not vendored for this platform"),
+    );
+    // `connect` of the generated client relies on the prelude of edition 2021,
+    // but this crate is edition 2018. Clients are created from a Channel with `new` instead.
+    tonic_build::configure()
+        .build_transport(false)
+        .compile(&["proto/gateway.proto"], &["proto"])
+        .expect("failed to compile protos");
+}
+
+// Generate skyway_gateway.h in OUT_DIR from the items of src/capi.rs.
+// include/skyway_gateway.h is a copy of it, and a test checks that the copy is up to date.
+// Only the types used in the C ABI are supported.
+#[cfg(feature = "capi")]
+fn generate_header() {
+    use std::path::PathBuf;
+
+    const SOURCE: &str = "src/capi.rs";
+    println!("cargo:rerun-if-changed={}", SOURCE);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    fn c_type(ty: &syn::Type) -> String {
+        match ty {
+            syn::Type::Ptr(ptr) => {
+                let elem = c_type(&ptr.elem);
+                let elem = match ptr.const_token {
+                    Some(_) => format!("const {}", elem),
+                    None => elem,
+                };
+                let separator = if elem.ends_with('*') { "" } else { " " };
+                format!("{}{}*", elem, separator)
+            }
+            syn::Type::Path(path) if path.qself.is_none() => {
+                let ident = path.path.segments.last().unwrap().ident.to_string();
+                match ident.as_str() {
+                    "SkywayClient" => ident,
+                    "c_char" => "char".into(),
+                    "c_int" => "int".into(),
+                    "bool" => "bool".into(),
+                    "u64" => "uint64_t".into(),
+                    other => panic!("{} is not supported in the C header", other),
+                }
+            }
+            _ => panic!("the type is not supported in the C header"),
+        }
+    }
+
+    fn docs(attrs: &[syn::Attribute]) -> String {
+        attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("doc"))
+            .filter_map(|attr| match attr.meta {
+                syn::Meta::NameValue(syn::MetaNameValue {
+                    value:
+                        syn::Expr::Lit(syn::ExprLit {
+                            lit: syn::Lit::Str(ref doc),
+                            ..
+                        }),
+                    ..
+                }) => Some(format!("/*{} */\n", doc.value())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn is_pub(vis: &syn::Visibility) -> bool {
+        matches!(vis, syn::Visibility::Public(_))
+    }
+
+    let source = std::fs::read_to_string(SOURCE).expect("src/capi.rs is not found");
+    let file = syn::parse_file(&source).expect("src/capi.rs is not valid Rust");
+    let mut header = String::from(
+        "/* Generated from src/capi.rs by build.rs. Do not edit. */\n\
+         #ifndef SKYWAY_GATEWAY_H\n\
+         #define SKYWAY_GATEWAY_H\n\n\
+         #include <stdbool.h>\n\
+         #include <stdint.h>\n\n\
+         #ifdef __cplusplus\n\
+         extern \"C\" {\n\
+         #endif\n\n",
+    );
+    for item in file.items.iter() {
+        match item {
+            syn::Item::Const(item) if is_pub(&item.vis) => {
+                let value = match *item.expr {
+                    syn::Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Int(ref value),
+                        ..
+                    }) => value.base10_digits().to_string(),
+                    _ => panic!("{} is not an integer literal", item.ident),
+                };
+                header += &docs(&item.attrs);
+                header += &format!("#define {} {}\n", item.ident, value);
+            }
+            syn::Item::Struct(item) if is_pub(&item.vis) => {
+                header += "\n";
+                header += &docs(&item.attrs);
+                header += &format!("typedef struct {0} {0};\n", item.ident);
+            }
+            syn::Item::Fn(item) if is_pub(&item.vis) && item.sig.abi.is_some() => {
+                let args = item
+                    .sig
+                    .inputs
+                    .iter()
+                    .map(|arg| match arg {
+                        syn::FnArg::Typed(arg) => {
+                            let name = match *arg.pat {
+                                syn::Pat::Ident(ref pat) => pat.ident.to_string(),
+                                _ => panic!("arguments of {} must be named", item.sig.ident),
+                            };
+                            let arg_type = c_type(&arg.ty);
+                            let separator = if arg_type.ends_with('*') { "" } else { " " };
+                            format!("{}{}{}", arg_type, separator, name)
+                        }
+                        syn::FnArg::Receiver(_) => panic!("{} has a receiver", item.sig.ident),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let ret = match item.sig.output {
+                    syn::ReturnType::Type(_, ref ret_type) => c_type(ret_type),
+                    syn::ReturnType::Default => "void".into(),
+                };
+                let separator = if ret.ends_with('*') { "" } else { " " };
+                header += "\n";
+                header += &docs(&item.attrs);
+                header += &format!("{}{}{}({});\n", ret, separator, item.sig.ident, args);
+            }
+            _ => {}
+        }
+    }
+    header += "\n#ifdef __cplusplus\n}\n#endif\n\n#endif /* SKYWAY_GATEWAY_H */\n";
+
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("skyway_gateway.h");
+    std::fs::write(out, header).expect("failed to write the C header");
+}
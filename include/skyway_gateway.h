@@ -0,0 +1,92 @@
+/* Generated from src/capi.rs by build.rs. Do not edit. */
+#ifndef SKYWAY_GATEWAY_H
+#define SKYWAY_GATEWAY_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Succeeded */
+#define SKYWAY_OK 0
+/* I/O error or invalid UTF-8 string. The reason is "IoError" */
+#define SKYWAY_ERROR_IO 1
+/* Invalid JSON parameter. The reason is "JsonError" */
+#define SKYWAY_ERROR_JSON 2
+/* Failed to access WebRTC Gateway. The reason is "NetworkError" */
+#define SKYWAY_ERROR_NETWORK 3
+/* Invalid IP address. The reason is "InvalidAddressError" */
+#define SKYWAY_ERROR_INVALID_ADDRESS 4
+/* Other errors including invalid ids and NULL parameters. The reason is "InternalError" */
+#define SKYWAY_ERROR_INTERNAL 5
+
+/* Opaque handle of the client. */
+/* */
+/* It owns a runtime to run requests, and keeps track of resources created through it. */
+typedef struct SkywayClient SkywayClient;
+
+/* Create a client for the WebRTC Gateway at `base_url`, such as "http://127.0.0.1:8000". */
+/* */
+/* Returns NULL on failure. The base url of the first client is used by all clients in a process. */
+SkywayClient *skyway_client_new(const char *base_url);
+
+/* Release the client. Resources in the WebRTC Gateway are not released, call `skyway_client_shutdown` before it. */
+void skyway_client_free(SkywayClient *client);
+
+/* Release a string returned from this library. */
+void skyway_string_free(char *string);
+
+/* Release all PeerObjects, connections and sockets created through the client within `timeout_ms`. */
+/* */
+/* `out` receives the report of the shutdown. */
+int skyway_client_shutdown(const SkywayClient *client, uint64_t timeout_ms, char **out);
+
+/* Create a PeerObject. `out` receives PeerInfo such as `{"peer_id": "...", "token": "pt-..."}`. */
+int skyway_create_peer(const SkywayClient *client, const char *api_key, const char *domain, const char *peer_id, bool turn, char **out);
+
+/* Delete a PeerObject. `peer_info` is the JSON returned from `skyway_create_peer`. */
+int skyway_delete_peer(const SkywayClient *client, const char *peer_info, char **out);
+
+/* Wait for an event of a PeerObject. `out` receives the event, which may be `{"event": "TIMEOUT"}`. */
+int skyway_peer_event(const SkywayClient *client, const char *peer_info, char **out);
+
+/* Open a socket to feed data. `out` receives the socket such as `{"data_id": "da-...", "port": 10000, "ip_v4": "..."}`. */
+int skyway_open_data_socket(const SkywayClient *client, char **out);
+
+/* Establish a DataConnection with the JSON of `ConnectQuery`. `out` receives `{"data_connection_id": "dc-..."}`. */
+int skyway_connect(const SkywayClient *client, const char *query, char **out);
+
+/* Set sockets of a DataConnection with the JSON of `RedirectDataParams`. */
+int skyway_redirect_data(const SkywayClient *client, const char *data_connection_id, const char *params, char **out);
+
+/* Close a DataConnection. */
+int skyway_disconnect_data(const SkywayClient *client, const char *data_connection_id, char **out);
+
+/* Wait for an event of a DataConnection. `out` receives the event, which may be `{"event": "TIMEOUT"}`. */
+int skyway_data_event(const SkywayClient *client, const char *data_connection_id, char **out);
+
+/* Open a socket to feed video or audio. `out` receives the socket such as `{"media_id": "vi-...", "port": 10000, "ip_v4": "..."}`. */
+int skyway_open_media_socket(const SkywayClient *client, bool is_video, char **out);
+
+/* Open a socket to feed RTCP. `out` receives the socket such as `{"rtcp_id": "rc-...", "port": 10000, "ip_v4": "..."}`. */
+int skyway_open_rtcp_socket(const SkywayClient *client, char **out);
+
+/* Call a neighbour with the JSON of `CallQuery`. `out` receives the response including media_connection_id. */
+int skyway_call(const SkywayClient *client, const char *query, char **out);
+
+/* Answer a call with the JSON of `AnswerQuery`. */
+int skyway_answer(const SkywayClient *client, const char *media_connection_id, const char *query, char **out);
+
+/* Close a MediaConnection. */
+int skyway_disconnect_media(const SkywayClient *client, const char *media_connection_id, char **out);
+
+/* Wait for an event of a MediaConnection. `out` receives the event, which may be `{"event": "TIMEOUT"}`. */
+int skyway_media_event(const SkywayClient *client, const char *media_connection_id, char **out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SKYWAY_GATEWAY_H */
@@ -0,0 +1,495 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use futures::channel::mpsc;
+use futures::*;
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::data::formats::DataConnectionId;
+use crate::error;
+use crate::media::formats::MediaConnectionId;
+use crate::peer::PeerEventEnum;
+
+/// Condition to identify remote peers.
+///
+/// In TOML, it's written as `{ exact = "operator" }`, `{ prefix = "operator-" }` or `{ regex = "^op-[0-9]+$" }`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerMatcher {
+    /// Matches the PeerId itself
+    Exact(String),
+    /// Matches PeerIds starting with the string
+    Prefix(String),
+    /// Matches PeerIds which the regular expression matches
+    Regex(String),
+}
+
+/// Rules of access control.
+///
+/// A peer matching any of `deny` is rejected.
+/// Otherwise, it's accepted if `allow` is empty or the peer matches any of `allow`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AccessRules {
+    #[serde(default)]
+    pub allow: Vec<PeerMatcher>,
+    #[serde(default)]
+    pub deny: Vec<PeerMatcher>,
+}
+
+#[derive(Debug)]
+enum CompiledMatcher {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl CompiledMatcher {
+    fn compile(matcher: &PeerMatcher) -> Result<Self, error::Error> {
+        Ok(match matcher {
+            PeerMatcher::Exact(id) => CompiledMatcher::Exact(id.clone()),
+            PeerMatcher::Prefix(prefix) => CompiledMatcher::Prefix(prefix.clone()),
+            PeerMatcher::Regex(regex) => {
+                CompiledMatcher::Regex(Regex::new(regex).map_err(|e| {
+                    error::Error::create_local_error(&format!("invalid regex {}: {}", regex, e))
+                })?)
+            }
+        })
+    }
+
+    fn matches(&self, peer_id: &str) -> bool {
+        match self {
+            CompiledMatcher::Exact(id) => id == peer_id,
+            CompiledMatcher::Prefix(prefix) => peer_id.starts_with(prefix.as_str()),
+            CompiledMatcher::Regex(regex) => regex.is_match(peer_id),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CompiledRules {
+    allow: Vec<CompiledMatcher>,
+    deny: Vec<CompiledMatcher>,
+}
+
+impl CompiledRules {
+    fn compile(rules: &AccessRules) -> Result<Self, error::Error> {
+        Ok(Self {
+            allow: rules
+                .allow
+                .iter()
+                .map(CompiledMatcher::compile)
+                .collect::<Result<_, _>>()?,
+            deny: rules
+                .deny
+                .iter()
+                .map(CompiledMatcher::compile)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn is_allowed(&self, peer_id: &str) -> bool {
+        if self.deny.iter().any(|m| m.matches(peer_id)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|m| m.matches(peer_id))
+    }
+}
+
+/// Connection checked by `AccessControl`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum CheckedConnection {
+    Data(DataConnectionId),
+    Media(MediaConnectionId),
+}
+
+/// Audit log of a connection rejected by `AccessControl`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    /// When the connection is rejected
+    pub time: SystemTime,
+    /// Remote peer_id of the connection. It's None if the check failed before the peer was known.
+    pub remote_id: Option<String>,
+    /// Rejected connection
+    pub connection: CheckedConnection,
+    /// Error message if the connection is rejected because the check failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub check_error: Option<String>,
+    /// Error message if WebRTC Gateway failed to disconnect the connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disconnect_error: Option<String>,
+}
+
+/// Access control for DataConnections and MediaConnections from neighbours.
+///
+/// It checks remote peer_id of CONNECTION and CALL events and disconnects unauthorized ones.
+/// Rules can be replaced with `reload` while it's running.
+/// It is cheap to clone. All clones share the same rules.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    rules: Arc<RwLock<CompiledRules>>,
+    // Shared by all clones. A cloned Sender has its own slot, so the channel would never be full.
+    audit_notifier: Option<Arc<Mutex<mpsc::Sender<AuditRecord>>>>,
+}
+
+impl AccessControl {
+    /// Create AccessControl.
+    ///
+    /// If audit_notifier is set, AuditRecords of rejected connections are sent to it.
+    /// AuditRecords are dropped while the channel is full.
+    ///
+    /// # Failures
+    /// It returns error if rules contain invalid regular expressions.
+    pub fn new(
+        rules: AccessRules,
+        audit_notifier: Option<mpsc::Sender<AuditRecord>>,
+    ) -> Result<Self, error::Error> {
+        Ok(Self {
+            rules: Arc::new(RwLock::new(CompiledRules::compile(&rules)?)),
+            audit_notifier: audit_notifier.map(|notifier| Arc::new(Mutex::new(notifier))),
+        })
+    }
+
+    /// Replace rules. Current rules are kept if new rules are invalid.
+    pub fn reload(&self, rules: AccessRules) -> Result<(), error::Error> {
+        let compiled = CompiledRules::compile(&rules)?;
+        *self.rules.write().expect("access rules lock poisoned") = compiled;
+        Ok(())
+    }
+
+    /// Returns true if the peer is allowed to connect.
+    pub fn is_allowed(&self, peer_id: &str) -> bool {
+        self.rules
+            .read()
+            .expect("access rules lock poisoned")
+            .is_allowed(peer_id)
+    }
+
+    /// Check CONNECTION and CALL events.
+    ///
+    /// It returns the event if it's allowed or it's other kind of events.
+    /// If the remote peer is not allowed, the connection is disconnected and it returns None.
+    ///
+    /// # Failures
+    /// If the remote peer can't be checked, the connection is disconnected too, and it returns the error.
+    pub async fn filter(
+        &self,
+        event: PeerEventEnum,
+    ) -> Result<Option<PeerEventEnum>, error::Error> {
+        let connection = match event {
+            PeerEventEnum::CONNECTION(ref connection) => {
+                CheckedConnection::Data(connection.data_params.data_connection_id.clone())
+            }
+            PeerEventEnum::CALL(ref call) => {
+                CheckedConnection::Media(call.call_params.media_connection_id.clone())
+            }
+            _ => return Ok(Some(event)),
+        };
+        match remote_id(&connection).await {
+            Ok(remote_id) if self.is_allowed(&remote_id) => Ok(Some(event)),
+            Ok(remote_id) => {
+                self.reject(connection, Some(remote_id), None).await;
+                Ok(None)
+            }
+            Err(e) => {
+                let check_error = serde_json::to_string(&e).unwrap_or_default();
+                self.reject(connection, None, Some(check_error)).await;
+                Err(e)
+            }
+        }
+    }
+
+    // Disconnect the connection and send an AuditRecord without waiting for the audit observer.
+    async fn reject(
+        &self,
+        connection: CheckedConnection,
+        remote_id: Option<String>,
+        check_error: Option<String>,
+    ) {
+        let result = match connection {
+            CheckedConnection::Data(ref id) => crate::data::disconnect(id).await,
+            CheckedConnection::Media(ref id) => crate::media::disconnect(id).await,
+        };
+        warn!(
+            "reject {:?} from peer {:?}. check error: {:?}, disconnect result: {:?}",
+            connection, remote_id, check_error, result
+        );
+        let record = AuditRecord {
+            time: SystemTime::now(),
+            remote_id,
+            connection,
+            check_error,
+            disconnect_error: result
+                .err()
+                .map(|e| serde_json::to_string(&e).unwrap_or_default()),
+        };
+        if let Some(ref notifier) = self.audit_notifier {
+            let result = notifier
+                .lock()
+                .expect("audit notifier lock poisoned")
+                .try_send(record);
+            if let Err(e) = result {
+                warn!("audit observer doesn't receive an AuditRecord: {:?}", e);
+            }
+        }
+    }
+
+    /// Filter events from `peer::listen_events` and returns only authorized events.
+    ///
+    /// Connections failed to be checked are disconnected and dropped, and the errors are logged.
+    pub fn guard(
+        &self,
+        mut observer: mpsc::Receiver<PeerEventEnum>,
+    ) -> mpsc::Receiver<PeerEventEnum> {
+        let (mut notifier, guarded_observer) = mpsc::channel::<PeerEventEnum>(10);
+        let access_control = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = observer.next().await {
+                match access_control.filter(event).await {
+                    Ok(Some(event)) => {
+                        if notifier.send(event).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("fail to check access of an event: {:?}", e),
+                }
+            }
+        });
+        guarded_observer
+    }
+}
+
+async fn remote_id(connection: &CheckedConnection) -> Result<String, error::Error> {
+    match connection {
+        CheckedConnection::Data(id) => Ok(crate::data::status(id).await?.remote_id),
+        CheckedConnection::Media(id) => Ok(crate::media::status(id)
+            .await?
+            .remote_id
+            .as_str()
+            .to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test_access_control {
+    use mockito::mock;
+
+    use super::*;
+    use crate::data::formats::DataConnectionIdWrapper;
+    use crate::media::formats::MediaConnectionIdWrapper;
+    use crate::peer::formats::{PeerCallEvent, PeerConnectionEvent, PeerInfo};
+
+    fn rules() -> AccessRules {
+        toml::from_str(
+            r#"
+            allow = [{ exact = "admin" }, { prefix = "operator-" }, { regex = "^robot-[0-9]+$" }]
+            deny = [{ exact = "operator-banned" }]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let access_control = AccessControl::new(rules(), None).unwrap();
+        assert!(access_control.is_allowed("admin"));
+        assert!(!access_control.is_allowed("admin2"));
+        assert!(access_control.is_allowed("operator-1"));
+        assert!(!access_control.is_allowed("operator-banned"));
+        assert!(access_control.is_allowed("robot-12"));
+        assert!(!access_control.is_allowed("robot-x"));
+    }
+
+    #[test]
+    fn empty_allow_accepts_everyone() {
+        let access_control = AccessControl::new(AccessRules::default(), None).unwrap();
+        assert!(access_control.is_allowed("anyone"));
+    }
+
+    #[test]
+    fn reload() {
+        let access_control = AccessControl::new(rules(), None).unwrap();
+        let cloned = access_control.clone();
+        cloned
+            .reload(AccessRules {
+                allow: vec![PeerMatcher::Exact("operator-banned".into())],
+                deny: vec![],
+            })
+            .unwrap();
+        assert!(access_control.is_allowed("operator-banned"));
+        assert!(!access_control.is_allowed("admin"));
+    }
+
+    #[test]
+    fn reload_invalid_regex() {
+        let access_control = AccessControl::new(rules(), None).unwrap();
+        let result = access_control.reload(AccessRules {
+            allow: vec![PeerMatcher::Regex("(".into())],
+            deny: vec![],
+        });
+        assert!(result.is_err());
+        // old rules are kept
+        assert!(access_control.is_allowed("admin"));
+    }
+
+    #[tokio::test]
+    async fn disconnect_unauthorized_connection() {
+        crate::initialize(mockito::server_url());
+        let data_connection_id = "dc-6995f372-fb6a-4196-b30a-ce11e5c7f56c";
+        let status_mock = mock(
+            "GET",
+            format!("/data/connections/{}/status", data_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{
+                "buffersize": 0,
+                "label": "",
+                "metadata": "",
+                "open": true,
+                "reliable": true,
+                "remote_id": "stranger",
+                "serialization": "BINARY",
+                "type": "DATA"
+            }"#,
+        )
+        .create();
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/data/connections/{}", data_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+
+        let (audit_notifier, mut audit_observer) = mpsc::channel::<AuditRecord>(1);
+        let access_control = AccessControl::new(rules(), Some(audit_notifier)).unwrap();
+        let event = PeerEventEnum::CONNECTION(PeerConnectionEvent {
+            params: PeerInfo::try_create("peer_id", "pt-9749250e-d157-4f80-9ee2-359ce8524308")
+                .unwrap(),
+            data_params: DataConnectionIdWrapper {
+                data_connection_id: DataConnectionId::try_create(data_connection_id).unwrap(),
+            },
+        });
+        let result = access_control.filter(event).await.unwrap();
+
+        assert!(result.is_none());
+        let record = audit_observer.next().await.unwrap();
+        assert_eq!(record.remote_id.as_deref(), Some("stranger"));
+        assert!(record.check_error.is_none());
+        assert_eq!(
+            record.connection,
+            CheckedConnection::Data(DataConnectionId::try_create(data_connection_id).unwrap())
+        );
+        assert!(record.disconnect_error.is_none());
+        status_mock.assert();
+        disconnect_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn disconnect_connection_failed_to_check() {
+        crate::initialize(mockito::server_url());
+        let media_connection_id = "mc-7a9c1e5d-2b7f-4d8e-a6c4-1f0e9d8c7b6a";
+        let status_mock = mock(
+            "GET",
+            format!("/media/connections/{}/status", media_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NOT_FOUND.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"command_type": "MEDIA_CONNECTION_STATUS", "params": {"errors": [{"field": "media_connection_id", "message": "not found"}]}}"#)
+        .create();
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/media/connections/{}", media_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+
+        let (audit_notifier, mut audit_observer) = mpsc::channel::<AuditRecord>(1);
+        let access_control = AccessControl::new(rules(), Some(audit_notifier)).unwrap();
+        let event = PeerEventEnum::CALL(PeerCallEvent {
+            params: PeerInfo::try_create("peer_id", "pt-9749250e-d157-4f80-9ee2-359ce8524308")
+                .unwrap(),
+            call_params: MediaConnectionIdWrapper {
+                media_connection_id: MediaConnectionId::try_create(media_connection_id).unwrap(),
+            },
+        });
+        assert!(access_control.filter(event).await.is_err());
+
+        let record = audit_observer.next().await.unwrap();
+        assert!(record.remote_id.is_none());
+        assert!(record.check_error.is_some());
+        assert_eq!(
+            record.connection,
+            CheckedConnection::Media(MediaConnectionId::try_create(media_connection_id).unwrap())
+        );
+        status_mock.assert();
+        disconnect_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn audit_does_not_block() {
+        crate::initialize(mockito::server_url());
+        let data_connection_id = "dc-7995f372-fb6a-4196-b30a-ce11e5c7f56c";
+        let status_mock = mock(
+            "GET",
+            format!("/data/connections/{}/status", data_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{
+                "buffersize": 0,
+                "label": "",
+                "metadata": "",
+                "open": true,
+                "reliable": true,
+                "remote_id": "stranger",
+                "serialization": "BINARY",
+                "type": "DATA"
+            }"#,
+        )
+        .expect(3)
+        .create();
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/data/connections/{}", data_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .expect(3)
+        .create();
+
+        // nobody reads AuditRecords
+        let (audit_notifier, mut audit_observer) = mpsc::channel::<AuditRecord>(0);
+        let access_control = AccessControl::new(rules(), Some(audit_notifier)).unwrap();
+        let event = PeerEventEnum::CONNECTION(PeerConnectionEvent {
+            params: PeerInfo::try_create("peer_id", "pt-9749250e-d157-4f80-9ee2-359ce8524308")
+                .unwrap(),
+            data_params: DataConnectionIdWrapper {
+                data_connection_id: DataConnectionId::try_create(data_connection_id).unwrap(),
+            },
+        });
+        for _ in 0..3 {
+            assert!(access_control
+                .filter(event.clone())
+                .await
+                .unwrap()
+                .is_none());
+        }
+        // only the first AuditRecord fits in the channel, and the others are dropped
+        assert!(audit_observer.try_recv().is_ok());
+        assert!(audit_observer.try_recv().is_err());
+        status_mock.assert();
+        disconnect_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn pass_other_events() {
+        let access_control = AccessControl::new(rules(), None).unwrap();
+        let event = PeerEventEnum::TIMEOUT;
+        let result = access_control.filter(event.clone()).await.unwrap();
+        assert_eq!(result, Some(event));
+    }
+}
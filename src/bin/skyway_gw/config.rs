@@ -0,0 +1,118 @@
+use std::path::Path;
+
+use serde::Deserialize;
+use skyway_webrtc_gateway_api::error;
+use skyway_webrtc_gateway_api::prelude::*;
+
+const DEFAULT_BASE_URL: &str = "http://localhost:8000";
+const DEFAULT_DOMAIN: &str = "localhost";
+
+/// Settings written in the file given with `--config`
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ConfigFile {
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    pub domain: Option<String>,
+    pub peer_id: Option<String>,
+    pub token: Option<String>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, error::Error> {
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            error::Error::create_local_error(&format!("fail to read {}: {}", path.display(), e))
+        })?;
+        toml::from_str(&text).map_err(|e| {
+            error::Error::create_local_error(&format!("fail to parse {}: {}", path.display(), e))
+        })
+    }
+}
+
+/// Settings after merging flags, environment variables and the config file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub domain: String,
+    pub peer_id: Option<String>,
+    pub token: Option<String>,
+}
+
+impl Settings {
+    /// Values from flags or environment variables take precedence over the file.
+    pub fn merge(
+        file: ConfigFile,
+        base_url: Option<String>,
+        api_key: Option<String>,
+        domain: Option<String>,
+        peer_id: Option<String>,
+        token: Option<String>,
+    ) -> Self {
+        Self {
+            base_url: base_url
+                .or(file.base_url)
+                .unwrap_or_else(|| DEFAULT_BASE_URL.into()),
+            api_key: api_key.or(file.api_key),
+            domain: domain
+                .or(file.domain)
+                .unwrap_or_else(|| DEFAULT_DOMAIN.into()),
+            peer_id: peer_id.or(file.peer_id),
+            token: token.or(file.token),
+        }
+    }
+
+    pub fn api_key(&self) -> Result<String, error::Error> {
+        self.api_key.clone().ok_or_else(|| {
+            error::Error::create_local_error(
+                "api_key is not set. Use --api-key, API_KEY or the config file",
+            )
+        })
+    }
+
+    pub fn peer_id(&self) -> Result<PeerId, error::Error> {
+        self.peer_id.as_ref().map(PeerId::new).ok_or_else(|| {
+            error::Error::create_local_error(
+                "peer_id is not set. Use --peer-id, PEER_ID or the config file",
+            )
+        })
+    }
+
+    pub fn peer_info(&self) -> Result<PeerInfo, error::Error> {
+        let token = self.token.as_ref().ok_or_else(|| {
+            error::Error::create_local_error(
+                "token is not set. Use --token, PEER_TOKEN or the config file",
+            )
+        })?;
+        PeerInfo::try_create(self.peer_id()?.as_str(), token)
+    }
+}
+
+#[cfg(test)]
+mod test_settings {
+    use super::*;
+
+    #[test]
+    fn flags_override_file() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            base_url = "http://192.168.0.2:8000"
+            domain = "example.com"
+            peer_id = "file_peer"
+            "#,
+        )
+        .unwrap();
+        let settings = Settings::merge(file, None, None, None, Some("flag_peer".into()), None);
+        assert_eq!(settings.base_url, "http://192.168.0.2:8000");
+        assert_eq!(settings.domain, "example.com");
+        assert_eq!(settings.peer_id.as_deref(), Some("flag_peer"));
+        assert!(settings.api_key().is_err());
+        assert!(settings.peer_info().is_err());
+    }
+
+    #[test]
+    fn defaults() {
+        let settings = Settings::merge(ConfigFile::default(), None, None, None, None, None);
+        assert_eq!(settings.base_url, DEFAULT_BASE_URL);
+        assert_eq!(settings.domain, DEFAULT_DOMAIN);
+    }
+}
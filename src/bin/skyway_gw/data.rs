@@ -0,0 +1,147 @@
+use clap::Subcommand;
+use skyway_webrtc_gateway_api::data::{self, ConnectQuery, ConnectQueryOption, DataIdWrapper};
+use skyway_webrtc_gateway_api::data::{DataConnectionIdWrapper, RedirectDataParams};
+use skyway_webrtc_gateway_api::error;
+use skyway_webrtc_gateway_api::prelude::*;
+use skyway_webrtc_gateway_api::registry::Registry;
+
+use crate::config::Settings;
+use crate::output::Output;
+use crate::parse;
+
+#[derive(Subcommand, Debug)]
+pub enum DataCommand {
+    /// Open a data socket to feed data to neighbours
+    Open,
+    /// Close a data socket
+    Close {
+        #[arg(value_parser = parse::id::<DataId>)]
+        data_id: DataId,
+    },
+    /// Establish a DataConnection with --peer-id to the target peer
+    Connect {
+        /// Peer to connect
+        target_id: String,
+        /// Data socket to feed data
+        #[arg(long, value_parser = parse::id::<DataId>)]
+        data_id: Option<DataId>,
+        /// ip:port to which received data is redirected
+        #[arg(long, value_parser = parse::socket)]
+        redirect: Option<SocketInfo<PhantomId>>,
+        #[arg(long)]
+        metadata: Option<String>,
+        #[arg(long)]
+        serialization: Option<String>,
+    },
+    /// Redirect a DataConnection
+    Redirect {
+        #[arg(value_parser = parse::data_connection_id)]
+        data_connection_id: DataConnectionId,
+        /// Data socket to feed data
+        #[arg(long, value_parser = parse::id::<DataId>)]
+        data_id: Option<DataId>,
+        /// ip:port to which received data is redirected
+        #[arg(long, value_parser = parse::socket)]
+        redirect: Option<SocketInfo<PhantomId>>,
+    },
+    /// Disconnect a DataConnection
+    Disconnect {
+        #[arg(value_parser = parse::data_connection_id)]
+        data_connection_id: DataConnectionId,
+    },
+    /// Print status of a DataConnection
+    Status {
+        #[arg(value_parser = parse::data_connection_id)]
+        data_connection_id: DataConnectionId,
+    },
+    /// Print events of a DataConnection
+    Events {
+        #[arg(value_parser = parse::data_connection_id)]
+        data_connection_id: DataConnectionId,
+        /// Keep printing events until CLOSE
+        #[arg(long, short)]
+        follow: bool,
+    },
+}
+
+pub async fn run(
+    command: DataCommand,
+    settings: &Settings,
+    registry: &Registry,
+    output: &Output,
+) -> Result<(), error::Error> {
+    match command {
+        DataCommand::Open => {
+            let socket = data::open_data_socket().await?;
+            if let Some(data_id) = socket.get_id() {
+                registry.register_data_socket(data_id);
+            }
+            output.print(&socket)
+        }
+        DataCommand::Close { data_id } => {
+            data::close_data_socket(&data_id).await?;
+            registry.unregister_data_socket(&data_id);
+            Ok(())
+        }
+        DataCommand::Connect {
+            target_id,
+            data_id,
+            redirect,
+            metadata,
+            serialization,
+        } => {
+            let peer_info = settings.peer_info()?;
+            let options = if metadata.is_some() || serialization.is_some() {
+                Some(ConnectQueryOption {
+                    metadata,
+                    serialization,
+                    dcInit: None,
+                })
+            } else {
+                None
+            };
+            let query = ConnectQuery {
+                peer_id: peer_info.peer_id(),
+                token: peer_info.token(),
+                options,
+                target_id: PeerId::new(target_id),
+                params: data_id.map(|data_id| DataIdWrapper { data_id }),
+                redirect_params: redirect,
+            };
+            let data_connection_id = data::connect(query).await?;
+            registry.register_data_connection(data_connection_id.clone());
+            output.print(&DataConnectionIdWrapper { data_connection_id })
+        }
+        DataCommand::Redirect {
+            data_connection_id,
+            data_id,
+            redirect,
+        } => {
+            let params = RedirectDataParams {
+                feed_params: data_id.map(|data_id| DataIdWrapper { data_id }),
+                redirect_params: redirect,
+            };
+            output.print(&data::redirect(&data_connection_id, &params).await?)
+        }
+        DataCommand::Disconnect { data_connection_id } => {
+            data::disconnect(&data_connection_id).await?;
+            registry.unregister_data_connection(&data_connection_id);
+            Ok(())
+        }
+        DataCommand::Status { data_connection_id } => {
+            output.print(&data::status(&data_connection_id).await?)
+        }
+        DataCommand::Events {
+            data_connection_id,
+            follow: false,
+        } => output.print(&data::event(&data_connection_id).await?),
+        DataCommand::Events {
+            data_connection_id,
+            follow: true,
+        } => {
+            output
+                .follow(|event_notifier| data::listen_events(data_connection_id, event_notifier))
+                .await
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! `skyway-gw` drives a SkyWay WebRTC Gateway from the command line.
+//!
+//! Settings are read from flags, environment variables or a TOML file in this order.
+//! ```toml
+//! base_url = "http://localhost:8000"
+//! api_key = "your-api-key"
+//! domain = "localhost"
+//! peer_id = "peer_id"
+//! token = "pt-9749250e-d157-4f80-9ee2-359ce8524308"
+//! ```
+mod config;
+mod data;
+mod media;
+mod output;
+mod parse;
+mod peer;
+mod shell;
+
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use skyway_webrtc_gateway_api::error;
+use skyway_webrtc_gateway_api::registry::Registry;
+
+use config::{ConfigFile, Settings};
+use output::Output;
+
+#[derive(Parser, Debug)]
+#[command(name = "skyway-gw", version, about = "Drive a SkyWay WebRTC Gateway")]
+struct Cli {
+    /// TOML file containing default settings
+    #[arg(long, short, global = true, env = "SKYWAY_GW_CONFIG")]
+    config: Option<PathBuf>,
+    /// URL of WebRTC Gateway
+    #[arg(long, global = true, env = "BASE_URL")]
+    base_url: Option<String>,
+    /// SkyWay Service API Key
+    #[arg(long, global = true, env = "API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+    /// Domain registered with SkyWay
+    #[arg(long, global = true, env = "DOMAIN")]
+    domain: Option<String>,
+    /// Identifier of your peer
+    #[arg(long, global = true, env = "PEER_ID")]
+    peer_id: Option<String>,
+    /// Token of your peer returned by `peer create`
+    #[arg(long, global = true, env = "PEER_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Print results and events as JSON lines
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Control PeerObjects
+    #[command(subcommand)]
+    Peer(peer::PeerCommand),
+    /// Control data sockets and DataConnections
+    #[command(subcommand)]
+    Data(data::DataCommand),
+    /// Control media sockets and MediaConnections
+    #[command(subcommand)]
+    Media(media::MediaCommand),
+    /// Start an interactive shell watching the PeerObject given with --peer-id and --token
+    Shell,
+}
+
+impl Cli {
+    fn settings(&self) -> Result<Settings, error::Error> {
+        let file = match self.config {
+            Some(ref path) => ConfigFile::load(path)?,
+            None => ConfigFile::default(),
+        };
+        Ok(Settings::merge(
+            file,
+            self.base_url.clone(),
+            self.api_key.clone(),
+            self.domain.clone(),
+            self.peer_id.clone(),
+            self.token.clone(),
+        ))
+    }
+}
+
+async fn run(cli: Cli, output: &Output) -> Result<(), error::Error> {
+    let settings = cli.settings()?;
+    skyway_webrtc_gateway_api::initialize(settings.base_url.clone());
+    // one-shot commands don't keep resources, but they share the code with the shell
+    let registry = Registry::new();
+    match cli.command {
+        Command::Peer(command) => peer::run(command, &settings, &registry, output).await,
+        Command::Data(command) => data::run(command, &settings, &registry, output).await,
+        Command::Media(command) => media::run(command, &settings, &registry, output).await,
+        Command::Shell => shell::run(&settings, output).await,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let cli = Cli::parse();
+    let output = Output::new(cli.json);
+    if let Err(e) = run(cli, &output).await {
+        output.error(&e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test_cli {
+    use super::*;
+
+    #[test]
+    fn parse_global_flags_after_subcommand() {
+        let cli = Cli::try_parse_from([
+            "skyway-gw",
+            "peer",
+            "status",
+            "--peer-id",
+            "peer_id",
+            "--token",
+            "pt-9749250e-d157-4f80-9ee2-359ce8524308",
+            "--json",
+        ])
+        .unwrap();
+        assert!(cli.json);
+        let settings = cli.settings().unwrap();
+        assert_eq!(settings.peer_id.as_deref(), Some("peer_id"));
+        assert!(settings.peer_info().is_ok());
+    }
+
+    #[test]
+    fn reject_unknown_subcommand() {
+        assert!(Cli::try_parse_from(["skyway-gw", "peer", "open"]).is_err());
+    }
+}
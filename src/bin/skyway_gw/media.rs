@@ -0,0 +1,286 @@
+use clap::{Args, Subcommand};
+use skyway_webrtc_gateway_api::error;
+use skyway_webrtc_gateway_api::media::{self, AnswerQuery, CallQuery, Constraints, MediaParams};
+use skyway_webrtc_gateway_api::media::{MediaConnectionIdWrapper, RedirectParameters};
+use skyway_webrtc_gateway_api::prelude::*;
+use skyway_webrtc_gateway_api::registry::Registry;
+
+use crate::config::Settings;
+use crate::output::Output;
+use crate::parse;
+
+#[derive(Subcommand, Debug)]
+pub enum MediaCommand {
+    /// Open a media socket to send video or audio to neighbours
+    Open {
+        /// Open an audio socket instead of a video socket
+        #[arg(long)]
+        audio: bool,
+    },
+    /// Open a socket to send RTCP to neighbours
+    Rtcp,
+    /// Close a media socket or an RTCP socket
+    Close {
+        /// vi-, au- or rc- id
+        id: String,
+    },
+    /// Call the target peer with --peer-id
+    Call {
+        /// Peer to call
+        target_id: String,
+        #[command(flatten)]
+        media: Box<MediaArgs>,
+    },
+    /// Answer a call
+    Answer {
+        #[arg(value_parser = parse::media_connection_id)]
+        media_connection_id: MediaConnectionId,
+        #[command(flatten)]
+        media: Box<MediaArgs>,
+    },
+    /// Request the neighbour to send a key frame
+    Pli {
+        #[arg(value_parser = parse::media_connection_id)]
+        media_connection_id: MediaConnectionId,
+        /// ip:port of the RTCP socket receiving the video
+        #[arg(long, value_parser = parse::socket)]
+        target: SocketInfo<PhantomId>,
+    },
+    /// Disconnect a MediaConnection
+    Disconnect {
+        #[arg(value_parser = parse::media_connection_id)]
+        media_connection_id: MediaConnectionId,
+    },
+    /// Print status of a MediaConnection
+    Status {
+        #[arg(value_parser = parse::media_connection_id)]
+        media_connection_id: MediaConnectionId,
+    },
+    /// Print events of a MediaConnection
+    Events {
+        #[arg(value_parser = parse::media_connection_id)]
+        media_connection_id: MediaConnectionId,
+        /// Keep printing events until CLOSE
+        #[arg(long, short)]
+        follow: bool,
+    },
+}
+
+/// Media to send and redirect in call and answer
+#[derive(Args, Debug)]
+pub struct MediaArgs {
+    /// Media socket to send video
+    #[arg(long, value_parser = parse::id::<MediaId>)]
+    video: Option<MediaId>,
+    /// RTCP socket for the video
+    #[arg(long, value_parser = parse::id::<RtcpId>)]
+    video_rtcp: Option<RtcpId>,
+    #[arg(long, default_value = "H264")]
+    video_codec: String,
+    #[arg(long, default_value_t = 100)]
+    video_payload_type: u16,
+    #[arg(long, default_value_t = 90000)]
+    video_sampling_rate: usize,
+    /// Media socket to send audio
+    #[arg(long, value_parser = parse::id::<MediaId>)]
+    audio: Option<MediaId>,
+    /// RTCP socket for the audio
+    #[arg(long, value_parser = parse::id::<RtcpId>)]
+    audio_rtcp: Option<RtcpId>,
+    #[arg(long, default_value = "OPUS")]
+    audio_codec: String,
+    #[arg(long, default_value_t = 111)]
+    audio_payload_type: u16,
+    #[arg(long, default_value_t = 48000)]
+    audio_sampling_rate: usize,
+    /// Band width of both media in kbps
+    #[arg(long, default_value_t = 1500)]
+    band_width: usize,
+    /// ip:port to which received video is redirected
+    #[arg(long, value_parser = parse::socket)]
+    redirect_video: Option<SocketInfo<PhantomId>>,
+    /// ip:port to which received RTCP of the video is redirected
+    #[arg(long, value_parser = parse::socket)]
+    redirect_video_rtcp: Option<SocketInfo<PhantomId>>,
+    /// ip:port to which received audio is redirected
+    #[arg(long, value_parser = parse::socket)]
+    redirect_audio: Option<SocketInfo<PhantomId>>,
+    /// ip:port to which received RTCP of the audio is redirected
+    #[arg(long, value_parser = parse::socket)]
+    redirect_audio_rtcp: Option<SocketInfo<PhantomId>>,
+    #[arg(long)]
+    metadata: Option<String>,
+}
+
+impl MediaArgs {
+    fn constraints(&self) -> Constraints {
+        Constraints {
+            video: self.video.is_some(),
+            videoReceiveEnabled: Some(self.redirect_video.is_some()),
+            audio: self.audio.is_some(),
+            audioReceiveEnabled: Some(self.redirect_audio.is_some()),
+            video_params: self.video.clone().map(|media_id| MediaParams {
+                band_width: self.band_width,
+                codec: self.video_codec.clone(),
+                media_id,
+                rtcp_id: self.video_rtcp.clone(),
+                payload_type: Some(self.video_payload_type),
+                sampling_rate: Some(self.video_sampling_rate),
+            }),
+            audio_params: self.audio.clone().map(|media_id| MediaParams {
+                band_width: self.band_width,
+                codec: self.audio_codec.clone(),
+                media_id,
+                rtcp_id: self.audio_rtcp.clone(),
+                payload_type: Some(self.audio_payload_type),
+                sampling_rate: Some(self.audio_sampling_rate),
+            }),
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    fn redirect_params(&self) -> Option<RedirectParameters> {
+        let params = RedirectParameters {
+            video: self.redirect_video.clone(),
+            video_rtcp: self.redirect_video_rtcp.clone(),
+            audio: self.redirect_audio.clone(),
+            audio_rtcp: self.redirect_audio_rtcp.clone(),
+        };
+        if params.video.is_none()
+            && params.video_rtcp.is_none()
+            && params.audio.is_none()
+            && params.audio_rtcp.is_none()
+        {
+            None
+        } else {
+            Some(params)
+        }
+    }
+}
+
+pub async fn run(
+    command: MediaCommand,
+    settings: &Settings,
+    registry: &Registry,
+    output: &Output,
+) -> Result<(), error::Error> {
+    match command {
+        MediaCommand::Open { audio } => {
+            let socket = media::open_media_socket(!audio).await?;
+            if let Some(media_id) = socket.get_id() {
+                registry.register_media_socket(media_id);
+            }
+            output.print(&socket)
+        }
+        MediaCommand::Rtcp => {
+            let socket = media::open_rtcp_socket().await?;
+            if let Some(rtcp_id) = socket.get_id() {
+                registry.register_rtcp_socket(rtcp_id);
+            }
+            output.print(&socket)
+        }
+        MediaCommand::Close { id } if id.starts_with("rc-") => {
+            let rtcp_id = RtcpId::try_create(id)?;
+            media::delete_rtcp(&rtcp_id).await?;
+            registry.unregister_rtcp_socket(&rtcp_id);
+            Ok(())
+        }
+        MediaCommand::Close { id } => {
+            let media_id = MediaId::try_create(id)?;
+            media::delete_media(&media_id).await?;
+            registry.unregister_media_socket(&media_id);
+            Ok(())
+        }
+        MediaCommand::Call { target_id, media } => {
+            let peer_info = settings.peer_info()?;
+            let query = CallQuery {
+                peer_id: peer_info.peer_id(),
+                token: peer_info.token(),
+                target_id: PeerId::new(target_id),
+                constraints: Some(media.constraints()),
+                redirect_params: media.redirect_params(),
+            };
+            let response = media::call(&query).await?;
+            registry.register_media_connection(response.params.media_connection_id.clone());
+            output.print(&MediaConnectionIdWrapper {
+                media_connection_id: response.params.media_connection_id,
+            })
+        }
+        MediaCommand::Answer {
+            media_connection_id,
+            media,
+        } => {
+            let query = AnswerQuery {
+                constraints: media.constraints(),
+                redirect_params: media.redirect_params(),
+            };
+            output.print(&media::answer(&media_connection_id, &query).await?)
+        }
+        MediaCommand::Pli {
+            media_connection_id,
+            target,
+        } => media::send_pli(&media_connection_id, &target).await,
+        MediaCommand::Disconnect {
+            media_connection_id,
+        } => {
+            media::disconnect(&media_connection_id).await?;
+            registry.unregister_media_connection(&media_connection_id);
+            Ok(())
+        }
+        MediaCommand::Status {
+            media_connection_id,
+        } => output.print(&media::status(&media_connection_id).await?),
+        MediaCommand::Events {
+            media_connection_id,
+            follow: false,
+        } => output.print(&media::event(&media_connection_id).await?),
+        MediaCommand::Events {
+            media_connection_id,
+            follow: true,
+        } => {
+            output
+                .follow(|event_notifier| media::listen_events(media_connection_id, event_notifier))
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_media_args {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Wrapper {
+        #[command(flatten)]
+        media: Box<MediaArgs>,
+    }
+
+    #[test]
+    fn send_video_and_receive_audio() {
+        let wrapper = Wrapper::try_parse_from([
+            "media",
+            "--video",
+            "vi-4d053831-5dc2-461b-a358-d062d6115216",
+            "--redirect-audio",
+            "127.0.0.1:10010",
+        ])
+        .unwrap();
+        let constraints = wrapper.media.constraints();
+        assert!(constraints.video);
+        assert_eq!(constraints.videoReceiveEnabled, Some(false));
+        assert!(!constraints.audio);
+        assert_eq!(constraints.audioReceiveEnabled, Some(true));
+        assert_eq!(constraints.video_params.unwrap().codec, "H264");
+        let redirect_params = wrapper.media.redirect_params().unwrap();
+        assert_eq!(redirect_params.audio.unwrap().port(), 10010);
+        assert!(redirect_params.video.is_none());
+    }
+
+    #[test]
+    fn no_redirect() {
+        let wrapper = Wrapper::try_parse_from(["media"]).unwrap();
+        assert!(wrapper.media.redirect_params().is_none());
+    }
+}
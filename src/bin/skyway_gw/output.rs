@@ -0,0 +1,65 @@
+use std::fmt::Debug;
+use std::future::Future;
+
+use futures::channel::mpsc;
+use futures::*;
+use serde::Serialize;
+use skyway_webrtc_gateway_api::error;
+
+/// Prints results for humans, or as JSON lines for scripts.
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    json: bool,
+}
+
+impl Output {
+    pub fn new(json: bool) -> Self {
+        Self { json }
+    }
+
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    pub fn print<T: Serialize + Debug>(&self, value: &T) -> Result<(), error::Error> {
+        if self.json {
+            let text =
+                serde_json::to_string(value).map_err(|error| error::Error::SerdeError { error })?;
+            println!("{}", text);
+        } else {
+            println!("{:#?}", value);
+        }
+        Ok(())
+    }
+
+    pub fn error(&self, error: &error::Error) {
+        if self.json {
+            eprintln!("{}", serde_json::to_string(error).unwrap_or_default());
+        } else {
+            eprintln!("error: {:?}", error);
+        }
+    }
+
+    /// Print events from a `listen_events` function until it finishes or Ctrl-C is pressed.
+    pub async fn follow<T, F, Fut>(&self, listen: F) -> Result<(), error::Error>
+    where
+        T: Serialize + Debug,
+        F: FnOnce(mpsc::Sender<T>) -> Fut,
+        Fut: Future<Output = Result<(), error::Error>>,
+    {
+        let (event_notifier, mut event_observer) = mpsc::channel::<T>(10);
+        let listen_fut = listen(event_notifier);
+        let print_fut = async {
+            while let Some(event) = event_observer.next().await {
+                self.print(&event)?;
+            }
+            Ok::<(), error::Error>(())
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => Ok(()),
+            (listen_result, print_result) = future::join(listen_fut, print_fut) => {
+                print_result.and(listen_result)
+            }
+        }
+    }
+}
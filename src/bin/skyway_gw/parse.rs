@@ -0,0 +1,45 @@
+//! Parsers for clap arguments. Errors are returned as strings so that clap prints them.
+use std::net::SocketAddr;
+
+use skyway_webrtc_gateway_api::prelude::*;
+
+/// Parse `ip:port` into a socket to which WebRTC Gateway sends data
+pub fn socket(text: &str) -> Result<SocketInfo<PhantomId>, String> {
+    let addr: SocketAddr = text
+        .parse()
+        .map_err(|e| format!("{} is not ip:port: {}", text, e))?;
+    SocketInfo::try_create(None, &addr.ip().to_string(), addr.port())
+        .map_err(|e| format!("{:?}", e))
+}
+
+/// Parse ids like `da-...`, `vi-...`, `au-...` and `rc-...`
+pub fn id<T: SerializableId>(text: &str) -> Result<T, String> {
+    T::try_create(text).map_err(|_| format!("{} is not a valid id", text))
+}
+
+pub fn data_connection_id(text: &str) -> Result<DataConnectionId, String> {
+    DataConnectionId::try_create(text).map_err(|_| format!("{} is not a valid id", text))
+}
+
+pub fn media_connection_id(text: &str) -> Result<MediaConnectionId, String> {
+    MediaConnectionId::try_create(text).map_err(|_| format!("{} is not a valid id", text))
+}
+
+#[cfg(test)]
+mod test_parse {
+    use super::*;
+
+    #[test]
+    fn parse_socket() {
+        assert_eq!(socket("127.0.0.1:10000").unwrap().port(), 10000);
+        assert!(socket("127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn parse_id() {
+        assert!(id::<DataId>("da-50a32bab-b3d9-4913-8e20-f79c90a6a211").is_ok());
+        assert!(id::<DataId>("vi-50a32bab-b3d9-4913-8e20-f79c90a6a211").is_err());
+        assert!(media_connection_id("mc-102127d9-30de-413b-93f7-41a33e39d82b").is_ok());
+        assert!(data_connection_id("mc-102127d9-30de-413b-93f7-41a33e39d82b").is_err());
+    }
+}
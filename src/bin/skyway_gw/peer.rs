@@ -0,0 +1,64 @@
+use clap::Subcommand;
+use skyway_webrtc_gateway_api::error;
+use skyway_webrtc_gateway_api::peer;
+use skyway_webrtc_gateway_api::registry::Registry;
+
+use crate::config::Settings;
+use crate::output::Output;
+
+#[derive(Subcommand, Debug)]
+pub enum PeerCommand {
+    /// Create a PeerObject with --peer-id and print its token
+    Create {
+        /// Use TURN server
+        #[arg(long)]
+        turn: bool,
+    },
+    /// Delete the PeerObject
+    Delete,
+    /// Print whether the PeerObject is connected to SkyWay server
+    Status,
+    /// Print events of the PeerObject
+    Events {
+        /// Keep printing events until CLOSE
+        #[arg(long, short)]
+        follow: bool,
+    },
+}
+
+pub async fn run(
+    command: PeerCommand,
+    settings: &Settings,
+    registry: &Registry,
+    output: &Output,
+) -> Result<(), error::Error> {
+    match command {
+        PeerCommand::Create { turn } => {
+            let peer_info = peer::create(
+                settings.api_key()?,
+                settings.domain.clone(),
+                settings.peer_id()?,
+                turn,
+            )
+            .await?;
+            registry.register_peer(peer_info.clone());
+            output.print(&peer_info)
+        }
+        PeerCommand::Delete => {
+            let peer_info = settings.peer_info()?;
+            peer::delete(&peer_info).await?;
+            registry.unregister_peer(&peer_info);
+            Ok(())
+        }
+        PeerCommand::Status => output.print(&peer::status(&settings.peer_info()?).await?),
+        PeerCommand::Events { follow: false } => {
+            output.print(&peer::event(settings.peer_info()?).await?)
+        }
+        PeerCommand::Events { follow: true } => {
+            let peer_info = settings.peer_info()?;
+            output
+                .follow(|event_notifier| peer::listen_events(peer_info, event_notifier))
+                .await
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! Interactive shell keeping a live view of a PeerObject and its connections.
+//!
+//! Events are printed as they arrive, and ids of live resources are completed with Tab.
+//! Arguments are split on whitespace, so they can't contain spaces.
+use std::sync::mpsc as std_mpsc;
+
+use clap::{CommandFactory, Parser, Subcommand};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, ExternalPrinter, Helper};
+use skyway_webrtc_gateway_api::data::DataConnectionEventEnum;
+use skyway_webrtc_gateway_api::error;
+use skyway_webrtc_gateway_api::event_bus::{EventBus, GatewayEvent};
+use skyway_webrtc_gateway_api::media::MediaConnectionEventEnum;
+use skyway_webrtc_gateway_api::peer::PeerEventEnum;
+use skyway_webrtc_gateway_api::prelude::SerializableId;
+use skyway_webrtc_gateway_api::registry::{Registry, Resources};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc;
+
+use crate::config::Settings;
+use crate::data::{self, DataCommand};
+use crate::media::{self, MediaCommand};
+use crate::output::Output;
+use crate::peer::{self, PeerCommand};
+
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true, disable_version_flag = true)]
+struct ShellLine {
+    #[command(subcommand)]
+    command: ShellCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ShellCommand {
+    /// Control the PeerObject
+    #[command(subcommand)]
+    Peer(PeerCommand),
+    /// Control data sockets and DataConnections
+    #[command(subcommand)]
+    Data(DataCommand),
+    /// Control media sockets and MediaConnections
+    #[command(subcommand)]
+    Media(MediaCommand),
+    /// List live resources
+    Ls,
+    /// Leave the shell
+    #[command(alias = "quit")]
+    Exit,
+}
+
+struct ShellHelper {
+    registry: Registry,
+}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(candidates(&self.registry.snapshot(), line, pos))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+// Complete subcommands for the first two words, and ids of live resources for the others.
+fn candidates(resources: &Resources, line: &str, pos: usize) -> (usize, Vec<String>) {
+    let start = line[..pos].rfind(' ').map(|i| i + 1).unwrap_or(0);
+    let word = &line[start..pos];
+    let preceding: Vec<&str> = line[..start].split_whitespace().collect();
+
+    let names: Vec<String> = match preceding.as_slice() {
+        [] => ShellLine::command()
+            .get_subcommands()
+            .map(|c| c.get_name().to_string())
+            .collect(),
+        [group] => ShellLine::command()
+            .find_subcommand(group)
+            .map(|c| {
+                c.get_subcommands()
+                    .map(|c| c.get_name().to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        _ => resource_ids(resources),
+    };
+    let mut names: Vec<String> = names
+        .into_iter()
+        .filter(|name| name.starts_with(word))
+        .collect();
+    names.sort();
+    (start, names)
+}
+
+fn resource_ids(resources: &Resources) -> Vec<String> {
+    let data_connections = resources.data_connections.iter().map(|id| id.as_str());
+    let media_connections = resources.media_connections.iter().map(|id| id.as_str());
+    let data_sockets = resources.data_sockets.iter().map(|id| id.as_str());
+    let media_sockets = resources.media_sockets.iter().map(|id| id.as_str());
+    let rtcp_sockets = resources.rtcp_sockets.iter().map(|id| id.as_str());
+    data_connections
+        .chain(media_connections)
+        .chain(data_sockets)
+        .chain(media_sockets)
+        .chain(rtcp_sockets)
+        .map(String::from)
+        .collect()
+}
+
+// keep the registry in sync with events from the gateway
+fn track(registry: &Registry, event: &GatewayEvent) {
+    match event {
+        GatewayEvent::Peer {
+            event: PeerEventEnum::CONNECTION(connection),
+            ..
+        } => registry.register_data_connection(connection.data_params.data_connection_id.clone()),
+        GatewayEvent::Peer {
+            event: PeerEventEnum::CALL(call),
+            ..
+        } => registry.register_media_connection(call.call_params.media_connection_id.clone()),
+        GatewayEvent::Peer {
+            event: PeerEventEnum::CLOSE(close),
+            ..
+        } => registry.unregister_peer(&close.params),
+        GatewayEvent::Data {
+            data_connection_id,
+            event: DataConnectionEventEnum::CLOSE(_),
+            ..
+        } => registry.unregister_data_connection(data_connection_id),
+        GatewayEvent::Media {
+            media_connection_id,
+            event: MediaConnectionEventEnum::CLOSE(_),
+            ..
+        } => registry.unregister_media_connection(media_connection_id),
+        _ => {}
+    }
+}
+
+fn prompt(registry: &Registry, peer_id: &str) -> String {
+    let resources = registry.snapshot();
+    format!(
+        "{} dc:{} mc:{}> ",
+        peer_id,
+        resources.data_connections.len(),
+        resources.media_connections.len()
+    )
+}
+
+// Returns false if the shell should exit.
+async fn execute(
+    line: &str,
+    settings: &Settings,
+    registry: &Registry,
+    bus: &EventBus,
+    output: &Output,
+) -> Result<bool, error::Error> {
+    let words = line.split_whitespace();
+    let command = match ShellLine::try_parse_from(words) {
+        Ok(parsed) => parsed.command,
+        Err(e) => {
+            let _ = e.print();
+            return Ok(true);
+        }
+    };
+
+    let before = registry.snapshot();
+    match command {
+        ShellCommand::Peer(command) => peer::run(command, settings, registry, output).await?,
+        ShellCommand::Data(command) => data::run(command, settings, registry, output).await?,
+        ShellCommand::Media(command) => media::run(command, settings, registry, output).await?,
+        ShellCommand::Ls => output.print(&registry.snapshot())?,
+        ShellCommand::Exit => return Ok(false),
+    }
+
+    // start listening events of connections established from this side
+    let peer_id = settings.peer_id()?;
+    let after = registry.snapshot();
+    for data_connection_id in after.data_connections.difference(&before.data_connections) {
+        bus.watch_data_connection(peer_id.clone(), data_connection_id.clone());
+    }
+    for media_connection_id in after
+        .media_connections
+        .difference(&before.media_connections)
+    {
+        bus.watch_media_connection(peer_id.clone(), media_connection_id.clone());
+    }
+    Ok(true)
+}
+
+pub async fn run(settings: &Settings, output: &Output) -> Result<(), error::Error> {
+    let peer_info = settings.peer_info()?;
+    let registry = Registry::new();
+    registry.register_peer(peer_info.clone());
+
+    let mut editor = Editor::<ShellHelper, DefaultHistory>::new()
+        .map_err(|e| error::Error::create_local_error(&format!("{}", e)))?;
+    editor.set_helper(Some(ShellHelper {
+        registry: registry.clone(),
+    }));
+    // events are printed above the prompt. Without a terminal, they are simply printed.
+    let mut print: Box<dyn FnMut(String) -> bool + Send> = match editor.create_external_printer() {
+        Ok(mut printer) => Box::new(move |message| printer.print(message).is_ok()),
+        Err(_) => Box::new(|message| {
+            println!("{}", message);
+            true
+        }),
+    };
+
+    let bus = EventBus::new(64);
+    let mut events = bus.subscribe();
+    bus.watch_peer(peer_info.clone());
+    let event_registry = registry.clone();
+    let json = output.is_json();
+    let event_task = tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            track(&event_registry, &event);
+            let message = if json {
+                serde_json::to_string(&event).unwrap_or_default()
+            } else {
+                format!("event: {:?}", event)
+            };
+            if !print(message) {
+                break;
+            }
+        }
+    });
+
+    // rustyline blocks, so lines are read in another thread and executed one by one
+    let (line_notifier, mut line_observer) = mpsc::channel::<String>(1);
+    let (done_notifier, done_observer) = std_mpsc::channel::<()>();
+    let prompt_registry = registry.clone();
+    let peer_id = peer_info.peer_id().as_str().to_string();
+    let read_task = tokio::task::spawn_blocking(move || loop {
+        match editor.readline(&prompt(&prompt_registry, &peer_id)) {
+            Ok(line) if line.trim().is_empty() => {}
+            Ok(line) => {
+                let _ = editor.add_history_entry(line.as_str());
+                if line_notifier.blocking_send(line).is_err() || done_observer.recv().is_err() {
+                    break;
+                }
+            }
+            // Ctrl-C clears the current line
+            Err(ReadlineError::Interrupted) => {}
+            Err(_) => break,
+        }
+    });
+
+    while let Some(line) = line_observer.recv().await {
+        match execute(&line, settings, &registry, &bus, output).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => output.error(&e),
+        }
+        if done_notifier.send(()).is_err() {
+            break;
+        }
+    }
+    drop(done_notifier);
+    drop(line_observer);
+    bus.stop();
+    event_task.abort();
+    let _ = read_task.await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_shell {
+    use skyway_webrtc_gateway_api::prelude::*;
+
+    use super::*;
+
+    fn resources() -> Resources {
+        let mut resources = Resources::default();
+        resources.data_connections.insert(
+            DataConnectionId::try_create("dc-102127d9-30de-413b-93f7-41a33e39d82b").unwrap(),
+        );
+        resources.media_connections.insert(
+            MediaConnectionId::try_create("mc-102127d9-30de-413b-93f7-41a33e39d82b").unwrap(),
+        );
+        resources
+            .media_sockets
+            .insert(MediaId::try_create("vi-4d053831-5dc2-461b-a358-d062d6115216").unwrap());
+        resources
+    }
+
+    #[test]
+    fn complete_commands() {
+        let (start, names) = candidates(&resources(), "me", 2);
+        assert_eq!(start, 0);
+        assert_eq!(names, vec!["media"]);
+        let (start, names) = candidates(&resources(), "media ca", 8);
+        assert_eq!(start, 6);
+        assert_eq!(names, vec!["call"]);
+    }
+
+    #[test]
+    fn complete_ids() {
+        let line = "media answer mc-";
+        let (start, names) = candidates(&resources(), line, line.len());
+        assert_eq!(start, 13);
+        assert_eq!(names, vec!["mc-102127d9-30de-413b-93f7-41a33e39d82b"]);
+        let line = "media answer mc-102127d9-30de-413b-93f7-41a33e39d82b --video v";
+        let (_, names) = candidates(&resources(), line, line.len());
+        assert_eq!(names, vec!["vi-4d053831-5dc2-461b-a358-d062d6115216"]);
+    }
+
+    #[test]
+    fn parse_line() {
+        let parsed = ShellLine::try_parse_from(
+            "data status dc-102127d9-30de-413b-93f7-41a33e39d82b".split_whitespace(),
+        )
+        .unwrap();
+        assert!(matches!(
+            parsed.command,
+            ShellCommand::Data(DataCommand::Status { .. })
+        ));
+        let parsed = ShellLine::try_parse_from("quit".split_whitespace()).unwrap();
+        assert!(matches!(parsed.command, ShellCommand::Exit));
+    }
+}
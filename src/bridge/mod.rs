@@ -0,0 +1,609 @@
+/// Schema of messages sent over DataConnections
+pub mod schema;
+/// Pub/sub middleware to which topics are bridged
+pub mod transport;
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::common::formats::{PhantomId, SerializableSocket, SocketInfo};
+use crate::data::formats::{ConnectQuery, ConnectQueryOption, DataId, DataIdWrapper};
+use crate::data::policy::{DataRedirector, RedirectDecision};
+use crate::data::{DataConnectionEventEnum, DataConnectionStatus};
+use crate::error;
+use crate::helper::default_bind_ip;
+use crate::peer::formats::{PeerId, PeerInfo};
+use crate::peer::{PeerConnectionEvent, PeerEventEnum};
+use crate::registry::Registry;
+use crate::shutdown;
+use crate::stats::{DataMeter, DataStats};
+pub use schema::{Field, FieldType, MessageSchema};
+pub use transport::{LoopbackTransport, Message, Transport};
+
+/// Direction in which messages of a topic flow
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Messages subscribed from the transport are sent to the remote side.
+    ToRemote,
+    /// Messages received from the remote side are published to the transport.
+    FromRemote,
+}
+
+/// A topic bridged over a DataConnection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TopicConfig {
+    /// Name of the DataConnection. It's sent as the metadata of the DataConnection,
+    /// so that the remote side can find the topic.
+    pub name: String,
+    /// Topic in the transport
+    pub topic: String,
+    pub schema: MessageSchema,
+    pub direction: Direction,
+}
+
+/// Config of a `Bridge`.
+///
+/// ```toml
+/// target_id = "operator"
+///
+/// [[topics]]
+/// name = "cmd_vel"
+/// topic = "/cmd_vel"
+/// direction = "from_remote"
+/// schema = { name = "std_msgs/Float64", fields = [{ name = "data", type = "float64" }] }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BridgeConfig {
+    /// Peer to which DataConnections are established.
+    /// If it's None, the bridge waits for DataConnections from neighbours.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_id: Option<String>,
+    #[serde(default)]
+    pub topics: Vec<TopicConfig>,
+    /// Wait before establishing a DataConnection again after it's closed.
+    #[serde(default = "default_reconnect_interval_ms")]
+    pub reconnect_interval_ms: u64,
+    /// Address on which the bridge receives data redirected from WebRTC Gateway
+    #[serde(default = "default_bind_ip")]
+    pub bind_ip: IpAddr,
+}
+
+fn default_reconnect_interval_ms() -> u64 {
+    3000
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            target_id: None,
+            topics: vec![],
+            reconnect_interval_ms: default_reconnect_interval_ms(),
+            bind_ip: default_bind_ip(),
+        }
+    }
+}
+
+// Data plane of a topic.
+// Received data is redirected to `socket`, and data is sent to `feed` while a DataConnection is open.
+struct TopicLink {
+    config: TopicConfig,
+    socket: Arc<UdpSocket>,
+    redirect: SocketInfo<PhantomId>,
+    feed: watch::Sender<Option<SocketAddr>>,
+    meter: DataMeter,
+}
+
+impl TopicLink {
+    async fn bind(
+        config: TopicConfig,
+        bind_ip: IpAddr,
+        meter: DataMeter,
+    ) -> Result<Self, error::Error> {
+        let socket = UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await?;
+        let addr = socket.local_addr()?;
+        let redirect =
+            SocketInfo::<PhantomId>::try_create(None, &addr.ip().to_string(), addr.port())?;
+        Ok(Self {
+            config,
+            socket: Arc::new(socket),
+            redirect,
+            feed: watch::channel(None).0,
+            meter,
+        })
+    }
+
+    fn decision(&self) -> RedirectDecision {
+        RedirectDecision {
+            feed: self.config.direction == Direction::ToRemote,
+            redirect: match self.config.direction {
+                Direction::FromRemote => Some(self.redirect.clone()),
+                Direction::ToRemote => None,
+            },
+        }
+    }
+
+    // Relay messages between the transport and the DataConnection until the transport stops.
+    // Topics are subscribed before the future is polled, so that no message is missed.
+    fn relay(self: Arc<Self>, transport: Arc<dyn Transport>) -> BoxFuture<'static, ()> {
+        let topic = self.config.topic.clone();
+        match self.config.direction {
+            Direction::FromRemote => async move {
+                let schema = &self.config.schema;
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    let len = match self.socket.recv_from(&mut buf).await {
+                        Ok((len, _)) => len,
+                        Err(e) => {
+                            warn!("fail to receive data of {}: {:?}", topic, e);
+                            return;
+                        }
+                    };
+                    self.meter.redirect.record(len);
+                    let message = match schema.decode(&buf[..len]) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            warn!("drop a message of {}: {:?}", topic, e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = transport.publish(&topic, message).await {
+                        warn!("fail to publish a message to {}: {:?}", topic, e);
+                    }
+                }
+            }
+            .boxed(),
+            Direction::ToRemote => {
+                let mut messages = transport.subscribe(&topic);
+                async move {
+                    let schema = &self.config.schema;
+                    while let Some(message) = messages.next().await {
+                        let feed = *self.feed.borrow();
+                        let feed = match feed {
+                            Some(feed) => feed,
+                            None => continue,
+                        };
+                        match schema.encode(&message) {
+                            Ok(data) => match self.socket.send_to(&data, feed).await {
+                                Ok(len) => self.meter.feed.record(len),
+                                Err(e) => {
+                                    warn!("fail to send a message of {}: {:?}", topic, e)
+                                }
+                            },
+                            Err(e) => warn!("drop a message of {}: {:?}", topic, e),
+                        }
+                    }
+                }
+                .boxed()
+            }
+        }
+    }
+}
+
+/// Bridges topics of a pub/sub transport and DataConnections of a PeerObject.
+///
+/// Each topic is carried by a DataConnection named by `TopicConfig::name`.
+/// If `BridgeConfig::target_id` is set, the bridge establishes the DataConnections
+/// and establishes them again after they are closed.
+/// In any case, DataConnections from neighbours are accepted if their metadata matches a topic name,
+/// so a bridge without `target_id` picks up the DataConnections established again by the remote bridge.
+///
+/// DataConnections and data sockets are registered to the registry, so that `shutdown` can release them.
+/// Traffic of each topic is counted by a `DataMeter`.
+#[derive(Clone)]
+pub struct Bridge {
+    peer_info: PeerInfo,
+    config: BridgeConfig,
+    transport: Arc<dyn Transport>,
+    registry: Registry,
+    meters: BTreeMap<String, DataMeter>,
+}
+
+impl Bridge {
+    pub fn new(
+        peer_info: PeerInfo,
+        config: BridgeConfig,
+        transport: impl Transport + 'static,
+        registry: Registry,
+    ) -> Self {
+        let meters = config
+            .topics
+            .iter()
+            .map(|topic| (topic.name.clone(), DataMeter::new()))
+            .collect();
+        Self {
+            peer_info,
+            config,
+            transport: Arc::new(transport),
+            registry,
+            meters,
+        }
+    }
+
+    pub fn config(&self) -> &BridgeConfig {
+        &self.config
+    }
+
+    /// Traffic of the topics keyed by `TopicConfig::name`.
+    /// Clones share the traffic, so it can be called on a clone while running.
+    pub fn stats(&self) -> BTreeMap<String, DataStats> {
+        self.meters
+            .iter()
+            .map(|(name, meter)| (name.clone(), meter.stats()))
+            .collect()
+    }
+
+    /// Bridge the topics until the token is cancelled or the PeerObject is closed.
+    pub async fn run(self, token: CancellationToken) -> Result<(), error::Error> {
+        let mut links = vec![];
+        for topic in &self.config.topics {
+            let meter = self.meters.get(&topic.name).cloned().unwrap_or_default();
+            let link = Arc::new(TopicLink::bind(topic.clone(), self.config.bind_ip, meter).await?);
+            shutdown::spawn_listener(&token, link.clone().relay(self.transport.clone()));
+            links.push(link);
+        }
+        let links = Arc::new(links);
+
+        if let Some(ref target_id) = self.config.target_id {
+            let interval = Duration::from_millis(self.config.reconnect_interval_ms);
+            for link in links.iter() {
+                shutdown::spawn_listener(
+                    &token,
+                    keep_connected(
+                        self.peer_info.clone(),
+                        PeerId::new(target_id.clone()),
+                        link.clone(),
+                        self.registry.clone(),
+                        token.clone(),
+                        interval,
+                    ),
+                );
+            }
+        }
+
+        let (event_notifier, mut event_observer) = mpsc::channel::<PeerEventEnum>(10);
+        shutdown::spawn_listener(
+            &token,
+            crate::peer::listen_events(self.peer_info.clone(), event_notifier),
+        );
+        let hook_links = links.clone();
+        let redirector = DataRedirector::new(
+            move |status: &DataConnectionStatus| {
+                find_link(&hook_links, status).map(|link| link.decision())
+            },
+            self.registry.clone(),
+        );
+        let registry = self.registry.clone();
+        let listener_token = token.clone();
+        let peer_closed = async move {
+            while let Some(event) = event_observer.next().await {
+                match event {
+                    PeerEventEnum::CONNECTION(event) => {
+                        shutdown::spawn_listener(
+                            &listener_token,
+                            accept(event, redirector.clone(), links.clone(), registry.clone()),
+                        );
+                    }
+                    PeerEventEnum::CLOSE(_) => return,
+                    _ => {}
+                }
+            }
+            // events can't be received anymore, but the DataConnections may be established again
+            future::pending::<()>().await
+        };
+
+        tokio::select! {
+            _ = token.cancelled() => {}
+            _ = peer_closed => token.cancel(),
+        }
+        Ok(())
+    }
+}
+
+fn find_link(links: &[Arc<TopicLink>], status: &DataConnectionStatus) -> Option<Arc<TopicLink>> {
+    links
+        .iter()
+        .find(|link| link.config.name == status.metadata)
+        .cloned()
+}
+
+// Redirect a DataConnection from a neighbour to the link of the topic until it's closed.
+async fn accept(
+    event: PeerConnectionEvent,
+    redirector: DataRedirector,
+    links: Arc<Vec<Arc<TopicLink>>>,
+    registry: Registry,
+) {
+    let mut channel = match redirector.handle_connection(&event).await {
+        Ok(Some(channel)) => channel,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("fail to accept a DataConnection: {:?}", e);
+            return;
+        }
+    };
+    if let Some(link) = find_link(&links, &channel.status) {
+        link.feed
+            .send_replace(channel.feed.as_ref().map(|socket| *socket.addr()));
+        while channel.events.next().await.is_some() {}
+        link.feed.send_replace(None);
+    }
+    registry.unregister_data_connection(&channel.data_connection_id);
+    if let Some(data_id) = channel.feed.and_then(|socket| socket.get_id()) {
+        release_data_socket(&registry, &data_id).await;
+    }
+}
+
+// Establish a DataConnection of the topic again and again until the token is cancelled.
+async fn keep_connected(
+    peer_info: PeerInfo,
+    target_id: PeerId,
+    link: Arc<TopicLink>,
+    registry: Registry,
+    token: CancellationToken,
+    interval: Duration,
+) {
+    loop {
+        if let Err(e) = connect_once(&peer_info, &target_id, &link, &registry, &token).await {
+            warn!("DataConnection of {} is lost: {:?}", link.config.name, e);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn connect_once(
+    peer_info: &PeerInfo,
+    target_id: &PeerId,
+    link: &TopicLink,
+    registry: &Registry,
+    token: &CancellationToken,
+) -> Result<(), error::Error> {
+    let feed = if link.config.direction == Direction::ToRemote {
+        let socket = crate::data::open_data_socket().await?;
+        if let Some(data_id) = socket.get_id() {
+            registry.register_data_socket(data_id);
+        }
+        Some(socket)
+    } else {
+        None
+    };
+    let result = connect_and_wait(peer_info, target_id, link, feed.as_ref(), registry, token).await;
+    link.feed.send_replace(None);
+    if let Some(data_id) = feed.and_then(|socket| socket.get_id()) {
+        release_data_socket(registry, &data_id).await;
+    }
+    result
+}
+
+// Establish a DataConnection and wait until it's closed.
+async fn connect_and_wait(
+    peer_info: &PeerInfo,
+    target_id: &PeerId,
+    link: &TopicLink,
+    feed: Option<&SocketInfo<DataId>>,
+    registry: &Registry,
+    token: &CancellationToken,
+) -> Result<(), error::Error> {
+    let decision = link.decision();
+    let query = ConnectQuery {
+        peer_id: peer_info.peer_id(),
+        token: peer_info.token(),
+        options: Some(ConnectQueryOption {
+            metadata: Some(link.config.name.clone()),
+            serialization: Some("BINARY".into()),
+            dcInit: None,
+        }),
+        target_id: target_id.clone(),
+        params: feed
+            .and_then(|socket| socket.get_id())
+            .map(|data_id| DataIdWrapper { data_id }),
+        redirect_params: decision.redirect,
+    };
+    let data_connection_id = crate::data::connect(query).await?;
+    registry.register_data_connection(data_connection_id.clone());
+
+    let (event_notifier, mut event_observer) = mpsc::channel::<DataConnectionEventEnum>(10);
+    shutdown::spawn_listener(
+        token,
+        crate::data::listen_events(data_connection_id.clone(), event_notifier),
+    );
+    let mut opened = false;
+    while let Some(event) = event_observer.next().await {
+        match event {
+            DataConnectionEventEnum::OPEN(_) => {
+                opened = true;
+                link.feed.send_replace(feed.map(|socket| *socket.addr()));
+            }
+            DataConnectionEventEnum::CLOSE(_) => break,
+            _ => {}
+        }
+    }
+    registry.unregister_data_connection(&data_connection_id);
+    if opened {
+        Ok(())
+    } else {
+        Err(error::Error::create_local_error(
+            "DataConnection has been closed before OPEN",
+        ))
+    }
+}
+
+async fn release_data_socket(registry: &Registry, data_id: &DataId) {
+    match crate::data::close_data_socket(data_id).await {
+        Ok(_) => registry.unregister_data_socket(data_id),
+        Err(e) => warn!("fail to close a data socket: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod test_bridge {
+    use mockito::mock;
+    use serde_json::json;
+
+    use super::*;
+
+    const TOKEN: &str = "pt-9749250e-d157-4f80-9ee2-359ce8524308";
+    const DATA_CONNECTION_ID: &str = "dc-1b2d7c3e-9f4a-4e6b-8c1d-2a3b4c5d6e7f";
+
+    fn topic(name: &str, direction: Direction) -> TopicConfig {
+        TopicConfig {
+            name: name.into(),
+            topic: format!("/{}", name),
+            schema: toml::from_str(
+                r#"
+                name = "std_msgs/Float64"
+                fields = [{ name = "data", type = "float64" }]
+                "#,
+            )
+            .unwrap(),
+            direction,
+        }
+    }
+
+    #[test]
+    fn parse_config() {
+        let config: BridgeConfig = toml::from_str(
+            r#"
+            target_id = "operator"
+
+            [[topics]]
+            name = "cmd_vel"
+            topic = "/cmd_vel"
+            direction = "from_remote"
+            schema = { name = "std_msgs/Float64", fields = [{ name = "data", type = "float64" }] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.target_id, Some("operator".into()));
+        assert_eq!(config.topics, vec![topic("cmd_vel", Direction::FromRemote)]);
+        assert_eq!(config.reconnect_interval_ms, 3000);
+        assert_eq!(config.bind_ip, default_bind_ip());
+    }
+
+    #[tokio::test]
+    async fn relay_messages() {
+        let transport: Arc<dyn Transport> = Arc::new(LoopbackTransport::default());
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let schema = topic("odom", Direction::ToRemote).schema;
+
+        // messages published to the transport are sent to the feed socket
+        let to_remote = Arc::new(
+            TopicLink::bind(
+                topic("odom", Direction::ToRemote),
+                default_bind_ip(),
+                DataMeter::new(),
+            )
+            .await
+            .unwrap(),
+        );
+        to_remote
+            .feed
+            .send_replace(Some(gateway.local_addr().unwrap()));
+        tokio::spawn(to_remote.clone().relay(transport.clone()));
+        transport
+            .publish("/odom", json!({"data": 1.5}))
+            .await
+            .unwrap();
+        let mut buf = vec![0u8; 1024];
+        let (len, _) = gateway.recv_from(&mut buf).await.unwrap();
+        assert_eq!(schema.decode(&buf[..len]).unwrap(), json!({"data": 1.5}));
+        let stats = to_remote.meter.stats();
+        assert_eq!((stats.feed.packets, stats.feed.bytes), (1, len as u64));
+        assert_eq!(stats.redirect.packets, 0);
+
+        // data redirected to the socket is published to the transport
+        let from_remote = Arc::new(
+            TopicLink::bind(
+                topic("cmd_vel", Direction::FromRemote),
+                default_bind_ip(),
+                DataMeter::new(),
+            )
+            .await
+            .unwrap(),
+        );
+        assert_eq!(
+            from_remote.decision().redirect,
+            Some(from_remote.redirect.clone())
+        );
+        let mut messages = transport.subscribe("/cmd_vel");
+        tokio::spawn(from_remote.clone().relay(transport.clone()));
+        let data = schema.encode(&json!({"data": -0.5})).unwrap();
+        gateway
+            .send_to(b"broken", from_remote.redirect.addr())
+            .await
+            .unwrap();
+        gateway
+            .send_to(&data, from_remote.redirect.addr())
+            .await
+            .unwrap();
+        assert_eq!(messages.next().await, Some(json!({"data": -0.5})));
+        // broken messages are counted too, since they have arrived
+        let stats = from_remote.meter.stats();
+        assert_eq!(stats.redirect.packets, 2);
+        assert_eq!(stats.redirect.bytes, 6 + data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_close() {
+        crate::initialize(mockito::server_url());
+        let connect_mock = mock("POST", "/data/connections")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"peer_id": "bridge_peer", "target_id": "operator", "options": {"metadata": "cmd_vel"}}"#.into(),
+            ))
+            .with_status(reqwest::StatusCode::ACCEPTED.as_u16() as usize)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"command_type": "PEERS_CONNECT", "params": {{"data_connection_id": "{}"}}}}"#,
+                DATA_CONNECTION_ID
+            ))
+            .expect_at_least(2)
+            .create();
+        let event_mock = mock(
+            "GET",
+            format!("/data/connections/{}/events", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"event": "CLOSE"}"#)
+        .expect_at_least(2)
+        .create();
+
+        let peer_info = PeerInfo::try_create("bridge_peer", TOKEN).unwrap();
+        let config = BridgeConfig {
+            target_id: Some("operator".into()),
+            topics: vec![topic("cmd_vel", Direction::FromRemote)],
+            reconnect_interval_ms: 10,
+            ..Default::default()
+        };
+        let registry = Registry::new();
+        let bridge = Bridge::new(
+            peer_info,
+            config,
+            LoopbackTransport::default(),
+            registry.clone(),
+        );
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(bridge.run(token.clone()));
+        for _ in 0..200 {
+            if connect_mock.matched() && event_mock.matched() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        token.cancel();
+        assert!(handle.await.unwrap().is_ok());
+        connect_mock.assert();
+        event_mock.assert();
+    }
+}
@@ -0,0 +1,371 @@
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+
+use crate::error;
+use crate::helper::fnv1a;
+
+/// Type of a field. Primitive types follow the ones of ROS 2 messages.
+///
+/// In TOML, primitive types are written as strings like `"float64"`,
+/// and others are written as tables like `{ sequence = "float64" }`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    Bool,
+    Int8,
+    Uint8,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Int64,
+    Uint64,
+    Float32,
+    Float64,
+    String,
+    /// Variable-length array of the type
+    Sequence(Box<FieldType>),
+    /// Nested message
+    Message(MessageSchema),
+}
+
+/// Field of a message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+}
+
+/// Definition of a message like a `.msg` file of ROS 2.
+///
+/// Messages are JSON objects on the transport side, and binary on the DataConnection side.
+/// The binary is a fingerprint of the schema followed by the fields in order.
+/// Numbers are little-endian, and strings and sequences are prefixed with their lengths as uint32.
+///
+/// ```toml
+/// name = "geometry_msgs/Vector3"
+/// fields = [
+///     { name = "x", type = "float64" },
+///     { name = "y", type = "float64" },
+///     { name = "z", type = "float64" },
+/// ]
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageSchema {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+fn mismatch(path: &str, expected: &FieldType) -> error::Error {
+    error::Error::create_local_error(&format!("{} is not {:?}", path, expected))
+}
+
+fn truncated(path: &str) -> error::Error {
+    error::Error::create_local_error(&format!("message is truncated at {}", path))
+}
+
+impl MessageSchema {
+    /// FNV-1a hash of the schema. Both sides of a topic must have the same schema.
+    pub fn fingerprint(&self) -> u32 {
+        let json = serde_json::to_string(self).unwrap_or_default();
+        fnv1a(json.as_bytes())
+    }
+
+    /// Serialize a message to send it over a DataConnection.
+    pub fn encode(&self, message: &Value) -> Result<Vec<u8>, error::Error> {
+        let mut buf = self.fingerprint().to_le_bytes().to_vec();
+        encode_message(self, message, &self.name, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Deserialize a message received from a DataConnection.
+    pub fn decode(&self, data: &[u8]) -> Result<Value, error::Error> {
+        let mut reader = Reader { data, pos: 0 };
+        let fingerprint = u32::from_le_bytes(reader.array(&self.name)?);
+        if fingerprint != self.fingerprint() {
+            return Err(error::Error::create_local_error(&format!(
+                "message is not {}",
+                self.name
+            )));
+        }
+        let message = decode_message(self, &mut reader, &self.name)?;
+        if reader.pos != data.len() {
+            return Err(error::Error::create_local_error(&format!(
+                "message is longer than {}",
+                self.name
+            )));
+        }
+        Ok(message)
+    }
+}
+
+fn encode_message(
+    schema: &MessageSchema,
+    message: &Value,
+    path: &str,
+    buf: &mut Vec<u8>,
+) -> Result<(), error::Error> {
+    let object = message.as_object().ok_or_else(|| {
+        error::Error::create_local_error(&format!("{} is not {}", path, schema.name))
+    })?;
+    for field in &schema.fields {
+        let path = format!("{}.{}", path, field.name);
+        let value = object
+            .get(&field.name)
+            .ok_or_else(|| error::Error::create_local_error(&format!("{} is missing", path)))?;
+        encode_value(&field.field_type, value, &path, buf)?;
+    }
+    Ok(())
+}
+
+fn integer<T: TryFrom<i64>>(
+    value: &Value,
+    path: &str,
+    field_type: &FieldType,
+) -> Result<T, error::Error> {
+    value
+        .as_i64()
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or_else(|| mismatch(path, field_type))
+}
+
+fn encode_value(
+    field_type: &FieldType,
+    value: &Value,
+    path: &str,
+    buf: &mut Vec<u8>,
+) -> Result<(), error::Error> {
+    match field_type {
+        FieldType::Bool => {
+            let value = value.as_bool().ok_or_else(|| mismatch(path, field_type))?;
+            buf.push(value as u8);
+        }
+        FieldType::Int8 => buf.extend(integer::<i8>(value, path, field_type)?.to_le_bytes()),
+        FieldType::Uint8 => buf.extend(integer::<u8>(value, path, field_type)?.to_le_bytes()),
+        FieldType::Int16 => buf.extend(integer::<i16>(value, path, field_type)?.to_le_bytes()),
+        FieldType::Uint16 => buf.extend(integer::<u16>(value, path, field_type)?.to_le_bytes()),
+        FieldType::Int32 => buf.extend(integer::<i32>(value, path, field_type)?.to_le_bytes()),
+        FieldType::Uint32 => buf.extend(integer::<u32>(value, path, field_type)?.to_le_bytes()),
+        FieldType::Int64 => buf.extend(integer::<i64>(value, path, field_type)?.to_le_bytes()),
+        FieldType::Uint64 => {
+            let value = value.as_u64().ok_or_else(|| mismatch(path, field_type))?;
+            buf.extend(value.to_le_bytes());
+        }
+        FieldType::Float32 => {
+            let value = value.as_f64().ok_or_else(|| mismatch(path, field_type))?;
+            buf.extend((value as f32).to_le_bytes());
+        }
+        FieldType::Float64 => {
+            let value = value.as_f64().ok_or_else(|| mismatch(path, field_type))?;
+            buf.extend(value.to_le_bytes());
+        }
+        FieldType::String => {
+            let value = value.as_str().ok_or_else(|| mismatch(path, field_type))?;
+            buf.extend((value.len() as u32).to_le_bytes());
+            buf.extend(value.as_bytes());
+        }
+        FieldType::Sequence(item_type) => {
+            let items = value.as_array().ok_or_else(|| mismatch(path, field_type))?;
+            buf.extend((items.len() as u32).to_le_bytes());
+            for (i, item) in items.iter().enumerate() {
+                encode_value(item_type, item, &format!("{}[{}]", path, i), buf)?;
+            }
+        }
+        FieldType::Message(schema) => encode_message(schema, value, path, buf)?,
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize, path: &str) -> Result<&'a [u8], error::Error> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| truncated(path))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self, path: &str) -> Result<[u8; N], error::Error> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.bytes(N, path)?);
+        Ok(array)
+    }
+
+    fn len(&mut self, path: &str) -> Result<usize, error::Error> {
+        Ok(u32::from_le_bytes(self.array(path)?) as usize)
+    }
+}
+
+fn float(value: f64, path: &str) -> Result<Value, error::Error> {
+    Number::from_f64(value)
+        .map(Value::Number)
+        .ok_or_else(|| error::Error::create_local_error(&format!("{} is not finite", path)))
+}
+
+fn decode_message(
+    schema: &MessageSchema,
+    reader: &mut Reader,
+    path: &str,
+) -> Result<Value, error::Error> {
+    let mut object = Map::new();
+    for field in &schema.fields {
+        let path = format!("{}.{}", path, field.name);
+        let value = decode_value(&field.field_type, reader, &path)?;
+        object.insert(field.name.clone(), value);
+    }
+    Ok(Value::Object(object))
+}
+
+fn decode_value(
+    field_type: &FieldType,
+    reader: &mut Reader,
+    path: &str,
+) -> Result<Value, error::Error> {
+    let value = match field_type {
+        FieldType::Bool => Value::Bool(reader.array::<1>(path)?[0] != 0),
+        FieldType::Int8 => i8::from_le_bytes(reader.array(path)?).into(),
+        FieldType::Uint8 => u8::from_le_bytes(reader.array(path)?).into(),
+        FieldType::Int16 => i16::from_le_bytes(reader.array(path)?).into(),
+        FieldType::Uint16 => u16::from_le_bytes(reader.array(path)?).into(),
+        FieldType::Int32 => i32::from_le_bytes(reader.array(path)?).into(),
+        FieldType::Uint32 => u32::from_le_bytes(reader.array(path)?).into(),
+        FieldType::Int64 => i64::from_le_bytes(reader.array(path)?).into(),
+        FieldType::Uint64 => u64::from_le_bytes(reader.array(path)?).into(),
+        FieldType::Float32 => float(f32::from_le_bytes(reader.array(path)?) as f64, path)?,
+        FieldType::Float64 => float(f64::from_le_bytes(reader.array(path)?), path)?,
+        FieldType::String => {
+            let len = reader.len(path)?;
+            let bytes = reader.bytes(len, path)?;
+            let string =
+                std::str::from_utf8(bytes).map_err(|error| error::Error::Utf8Error { error })?;
+            Value::String(string.into())
+        }
+        FieldType::Sequence(item_type) => {
+            let len = reader.len(path)?;
+            let items = (0..len)
+                .map(|i| decode_value(item_type, reader, &format!("{}[{}]", path, i)))
+                .collect::<Result<Vec<_>, _>>()?;
+            Value::Array(items)
+        }
+        FieldType::Message(schema) => decode_message(schema, reader, path)?,
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test_schema {
+    use serde_json::json;
+
+    use super::*;
+
+    fn twist() -> MessageSchema {
+        toml::from_str(
+            r#"
+            name = "geometry_msgs/Twist"
+            [[fields]]
+            name = "linear"
+            type = { message = { name = "geometry_msgs/Vector3", fields = [
+                { name = "x", type = "float64" },
+                { name = "y", type = "float64" },
+                { name = "z", type = "float64" },
+            ] } }
+            [[fields]]
+            name = "frame_id"
+            type = "string"
+            [[fields]]
+            name = "covariance"
+            type = { sequence = "float32" }
+            [[fields]]
+            name = "seq"
+            type = "uint32"
+            [[fields]]
+            name = "valid"
+            type = "bool"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let schema = twist();
+        let message = json!({
+            "linear": {"x": 1.5, "y": 0.0, "z": -2.0},
+            "frame_id": "base_link",
+            "covariance": [0.5, 0.25],
+            "seq": 7,
+            "valid": true,
+        });
+        let data = schema.encode(&message).unwrap();
+        // fingerprint + 3 float64 + string + sequence of 2 float32 + uint32 + bool
+        assert_eq!(data.len(), 4 + 24 + 4 + 9 + 4 + 8 + 4 + 1);
+        assert_eq!(schema.decode(&data).unwrap(), message);
+    }
+
+    #[test]
+    fn reject_invalid_messages() {
+        let schema = twist();
+        let message = json!({
+            "linear": {"x": 1.5, "y": 0.0, "z": "fast"},
+            "frame_id": "base_link",
+            "covariance": [],
+            "seq": 7,
+            "valid": true,
+        });
+        match schema.encode(&message) {
+            Err(error::Error::LocalError(message)) => {
+                assert_eq!(message, "geometry_msgs/Twist.linear.z is not Float64")
+            }
+            result => unreachable!("{:?}", result),
+        }
+        let message = json!({"linear": {"x": 1.5, "y": 0.0, "z": 0.0}});
+        assert!(schema.encode(&message).is_err());
+
+        let message = json!({
+            "linear": {"x": 1.5, "y": 0.0, "z": 0.0},
+            "frame_id": "",
+            "covariance": [],
+            "seq": -1,
+            "valid": true,
+        });
+        assert!(schema.encode(&message).is_err());
+    }
+
+    #[test]
+    fn reject_invalid_data() {
+        let schema = twist();
+        let mut other = twist();
+        other.fields.pop();
+        let message = json!({
+            "linear": {"x": 1.5, "y": 0.0, "z": 0.0},
+            "frame_id": "",
+            "covariance": [],
+            "seq": 1,
+        });
+        let data = other.encode(&message).unwrap();
+        assert!(schema.decode(&data).is_err());
+
+        let mut data = schema
+            .encode(&json!({
+                "linear": {"x": 1.5, "y": 0.0, "z": 0.0},
+                "frame_id": "",
+                "covariance": [],
+                "seq": 1,
+                "valid": false,
+            }))
+            .unwrap();
+        data.pop();
+        assert!(schema.decode(&data).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::*;
+use tokio::sync::broadcast;
+
+use crate::error;
+
+/// Message on a topic in the same form as JSON
+pub type Message = serde_json::Value;
+
+/// Pub/sub middleware, such as ROS 2, to which topics are bridged.
+pub trait Transport: Send + Sync {
+    /// Publish a message received from the remote side.
+    fn publish(
+        &self,
+        topic: &str,
+        message: Message,
+    ) -> BoxFuture<'static, Result<(), error::Error>>;
+
+    /// Subscribe messages to send them to the remote side.
+    fn subscribe(&self, topic: &str) -> BoxStream<'static, Message>;
+}
+
+/// In-process transport for tests.
+///
+/// Messages are delivered to the subscribers of the same topic, including subscribers in other bridges.
+/// Messages are dropped if there is no subscriber, and lagged subscribers miss old messages.
+#[derive(Debug, Clone)]
+pub struct LoopbackTransport {
+    topics: Arc<Mutex<HashMap<String, broadcast::Sender<Message>>>>,
+    capacity: usize,
+}
+
+impl Default for LoopbackTransport {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+impl LoopbackTransport {
+    /// `capacity` is the number of messages kept for each subscriber.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            topics: Arc::new(Mutex::new(HashMap::new())),
+            capacity,
+        }
+    }
+
+    fn sender(&self, topic: &str) -> broadcast::Sender<Message> {
+        let mut topics = self.topics.lock().unwrap();
+        topics
+            .entry(topic.to_string())
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .clone()
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn publish(
+        &self,
+        topic: &str,
+        message: Message,
+    ) -> BoxFuture<'static, Result<(), error::Error>> {
+        let _ = self.sender(topic).send(message);
+        future::ok(()).boxed()
+    }
+
+    fn subscribe(&self, topic: &str) -> BoxStream<'static, Message> {
+        let receiver = self.sender(topic).subscribe();
+        stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => return Some((message, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test_loopback {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn deliver_to_subscribers_of_the_topic() {
+        let transport = LoopbackTransport::default();
+        let mut odom = transport.subscribe("/odom");
+        let mut other = transport.subscribe("/other");
+        transport.publish("/odom", json!({"x": 1})).await.unwrap();
+        assert_eq!(odom.next().await, Some(json!({"x": 1})));
+
+        transport.publish("/other", json!({"x": 2})).await.unwrap();
+        assert_eq!(other.next().await, Some(json!({"x": 2})));
+    }
+}
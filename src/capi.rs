@@ -0,0 +1,527 @@
+//! C ABI of this crate.
+//!
+//! `build.rs` generates the C header from this file when the `capi` feature is enabled.
+//! `include/skyway_gateway.h` is a copy of it. Copy it again from `OUT_DIR` after changing this file,
+//! otherwise `header_is_up_to_date` fails.
+//! Build a shared library with `cargo rustc --release --features capi --crate-type cdylib`.
+//!
+//! Every function returns `SKYWAY_OK` or one of the error codes below.
+//! Parameters and results which are structs in this crate are passed as JSON strings.
+//! If `out` is not NULL, it receives the JSON of the result, or the JSON of `error::Error` on failure.
+//! Strings returned through `out` must be released with `skyway_string_free`.
+//!
+//! # Safety
+//! String parameters must be NULL or NUL-terminated strings, `client` must be NULL or a handle
+//! returned from `skyway_client_new` and not freed yet, and `out` must be NULL or writable.
+
+// Safety requirements are common to all functions, so they are described in the module docs.
+#![allow(clippy::missing_safety_doc)]
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::common::formats::SerializableSocket;
+use crate::data::{ConnectQuery, DataConnectionId, DataConnectionIdWrapper, RedirectDataParams};
+use crate::error;
+use crate::media::{AnswerQuery, CallQuery, MediaConnectionId};
+use crate::peer::{PeerId, PeerInfo};
+use crate::registry::Registry;
+
+/// Succeeded
+pub const SKYWAY_OK: c_int = 0;
+/// I/O error or invalid UTF-8 string. The reason is "IoError"
+pub const SKYWAY_ERROR_IO: c_int = 1;
+/// Invalid JSON parameter. The reason is "JsonError"
+pub const SKYWAY_ERROR_JSON: c_int = 2;
+/// Failed to access WebRTC Gateway. The reason is "NetworkError"
+pub const SKYWAY_ERROR_NETWORK: c_int = 3;
+/// Invalid IP address. The reason is "InvalidAddressError"
+pub const SKYWAY_ERROR_INVALID_ADDRESS: c_int = 4;
+/// Other errors including invalid ids and NULL parameters. The reason is "InternalError"
+pub const SKYWAY_ERROR_INTERNAL: c_int = 5;
+
+/// Opaque handle of the client.
+///
+/// It owns a runtime to run requests, and keeps track of resources created through it.
+pub struct SkywayClient {
+    runtime: tokio::runtime::Runtime,
+    registry: Registry,
+}
+
+fn error_code(error: &error::Error) -> c_int {
+    match error {
+        error::Error::IOError { .. } | error::Error::Utf8Error { .. } => SKYWAY_ERROR_IO,
+        error::Error::SerdeError { .. } => SKYWAY_ERROR_JSON,
+        error::Error::ReqwestError(_) => SKYWAY_ERROR_NETWORK,
+        error::Error::AddrParseError(_) => SKYWAY_ERROR_INVALID_ADDRESS,
+        error::Error::LocalError(_) => SKYWAY_ERROR_INTERNAL,
+    }
+}
+
+unsafe fn to_str<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, error::Error> {
+    if ptr.is_null() {
+        return Err(error::Error::create_local_error(&format!(
+            "{} is NULL",
+            name
+        )));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|error| error::Error::Utf8Error { error })
+}
+
+unsafe fn from_json<T: DeserializeOwned>(
+    ptr: *const c_char,
+    name: &str,
+) -> Result<T, error::Error> {
+    serde_json::from_str(to_str(ptr, name)?).map_err(|error| error::Error::SerdeError { error })
+}
+
+unsafe fn to_client<'a>(client: *const SkywayClient) -> Result<&'a SkywayClient, error::Error> {
+    client
+        .as_ref()
+        .ok_or_else(|| error::Error::create_local_error("client is NULL"))
+}
+
+unsafe fn write_out(out: *mut *mut c_char, json: String) {
+    if !out.is_null() {
+        // serde_json never emits NUL
+        *out = CString::new(json).unwrap_or_default().into_raw();
+    }
+}
+
+// Runs the body, writes the result to `out` and returns the code. Panics don't cross the FFI boundary.
+unsafe fn run<T: Serialize>(
+    out: *mut *mut c_char,
+    body: impl FnOnce() -> Result<T, error::Error>,
+) -> c_int {
+    if !out.is_null() {
+        *out = ptr::null_mut();
+    }
+    let result = panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|_| {
+        Err(error::Error::create_local_error(
+            "panicked in skyway-webrtc-gateway-api",
+        ))
+    });
+    let result = result.and_then(|value| {
+        serde_json::to_string(&value).map_err(|error| error::Error::SerdeError { error })
+    });
+    match result {
+        Ok(json) => {
+            write_out(out, json);
+            SKYWAY_OK
+        }
+        Err(error) => {
+            write_out(out, serde_json::to_string(&error).unwrap_or_default());
+            error_code(&error)
+        }
+    }
+}
+
+/// Create a client for the WebRTC Gateway at `base_url`, such as "http://127.0.0.1:8000".
+///
+/// Returns NULL on failure. The base url of the first client is used by all clients in a process.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_client_new(base_url: *const c_char) -> *mut SkywayClient {
+    let base_url = match to_str(base_url, "base_url") {
+        Ok(base_url) => base_url,
+        Err(_) => return ptr::null_mut(),
+    };
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(_) => return ptr::null_mut(),
+    };
+    crate::initialize(base_url);
+    Box::into_raw(Box::new(SkywayClient {
+        runtime,
+        registry: Registry::new(),
+    }))
+}
+
+/// Release the client. Resources in the WebRTC Gateway are not released, call `skyway_client_shutdown` before it.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_client_free(client: *mut SkywayClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Release a string returned from this library.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Release all PeerObjects, connections and sockets created through the client within `timeout_ms`.
+///
+/// `out` receives the report of the shutdown.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_client_shutdown(
+    client: *const SkywayClient,
+    timeout_ms: u64,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let deadline = Duration::from_millis(timeout_ms);
+        Ok(client
+            .runtime
+            .block_on(crate::shutdown::shutdown(&client.registry, deadline)))
+    })
+}
+
+/// Create a PeerObject. `out` receives PeerInfo such as `{"peer_id": "...", "token": "pt-..."}`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_create_peer(
+    client: *const SkywayClient,
+    api_key: *const c_char,
+    domain: *const c_char,
+    peer_id: *const c_char,
+    turn: bool,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let api_key = to_str(api_key, "api_key")?;
+        let domain = to_str(domain, "domain")?;
+        let peer_id = PeerId::new(to_str(peer_id, "peer_id")?);
+        let peer_info = client
+            .runtime
+            .block_on(crate::peer::create(api_key, domain, peer_id, turn))?;
+        client.registry.register_peer(peer_info.clone());
+        Ok(peer_info)
+    })
+}
+
+/// Delete a PeerObject. `peer_info` is the JSON returned from `skyway_create_peer`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_delete_peer(
+    client: *const SkywayClient,
+    peer_info: *const c_char,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let peer_info: PeerInfo = from_json(peer_info, "peer_info")?;
+        client.runtime.block_on(crate::peer::delete(&peer_info))?;
+        client.registry.unregister_peer(&peer_info);
+        Ok(())
+    })
+}
+
+/// Wait for an event of a PeerObject. `out` receives the event, which may be `{"event": "TIMEOUT"}`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_peer_event(
+    client: *const SkywayClient,
+    peer_info: *const c_char,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let peer_info: PeerInfo = from_json(peer_info, "peer_info")?;
+        client.runtime.block_on(crate::peer::event(peer_info))
+    })
+}
+
+/// Open a socket to feed data. `out` receives the socket such as `{"data_id": "da-...", "port": 10000, "ip_v4": "..."}`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_open_data_socket(
+    client: *const SkywayClient,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let socket = client.runtime.block_on(crate::data::open_data_socket())?;
+        if let Some(data_id) = socket.get_id() {
+            client.registry.register_data_socket(data_id);
+        }
+        Ok(socket)
+    })
+}
+
+/// Establish a DataConnection with the JSON of `ConnectQuery`. `out` receives `{"data_connection_id": "dc-..."}`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_connect(
+    client: *const SkywayClient,
+    query: *const c_char,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let query: ConnectQuery = from_json(query, "query")?;
+        let data_connection_id = client.runtime.block_on(crate::data::connect(query))?;
+        client
+            .registry
+            .register_data_connection(data_connection_id.clone());
+        Ok(DataConnectionIdWrapper { data_connection_id })
+    })
+}
+
+/// Set sockets of a DataConnection with the JSON of `RedirectDataParams`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_redirect_data(
+    client: *const SkywayClient,
+    data_connection_id: *const c_char,
+    params: *const c_char,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let data_connection_id =
+            DataConnectionId::try_create(to_str(data_connection_id, "data_connection_id")?)?;
+        let params: RedirectDataParams = from_json(params, "params")?;
+        client
+            .runtime
+            .block_on(crate::data::redirect(&data_connection_id, &params))
+    })
+}
+
+/// Close a DataConnection.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_disconnect_data(
+    client: *const SkywayClient,
+    data_connection_id: *const c_char,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let data_connection_id =
+            DataConnectionId::try_create(to_str(data_connection_id, "data_connection_id")?)?;
+        client
+            .runtime
+            .block_on(crate::data::disconnect(&data_connection_id))?;
+        client
+            .registry
+            .unregister_data_connection(&data_connection_id);
+        Ok(())
+    })
+}
+
+/// Wait for an event of a DataConnection. `out` receives the event, which may be `{"event": "TIMEOUT"}`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_data_event(
+    client: *const SkywayClient,
+    data_connection_id: *const c_char,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let data_connection_id =
+            DataConnectionId::try_create(to_str(data_connection_id, "data_connection_id")?)?;
+        client
+            .runtime
+            .block_on(crate::data::event(&data_connection_id))
+    })
+}
+
+/// Open a socket to feed video or audio. `out` receives the socket such as `{"media_id": "vi-...", "port": 10000, "ip_v4": "..."}`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_open_media_socket(
+    client: *const SkywayClient,
+    is_video: bool,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let socket = client
+            .runtime
+            .block_on(crate::media::open_media_socket(is_video))?;
+        if let Some(media_id) = socket.get_id() {
+            client.registry.register_media_socket(media_id);
+        }
+        Ok(socket)
+    })
+}
+
+/// Open a socket to feed RTCP. `out` receives the socket such as `{"rtcp_id": "rc-...", "port": 10000, "ip_v4": "..."}`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_open_rtcp_socket(
+    client: *const SkywayClient,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let socket = client.runtime.block_on(crate::media::open_rtcp_socket())?;
+        if let Some(rtcp_id) = socket.get_id() {
+            client.registry.register_rtcp_socket(rtcp_id);
+        }
+        Ok(socket)
+    })
+}
+
+/// Call a neighbour with the JSON of `CallQuery`. `out` receives the response including media_connection_id.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_call(
+    client: *const SkywayClient,
+    query: *const c_char,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let query: CallQuery = from_json(query, "query")?;
+        let response = client.runtime.block_on(crate::media::call(&query))?;
+        client
+            .registry
+            .register_media_connection(response.params.media_connection_id.clone());
+        Ok(response)
+    })
+}
+
+/// Answer a call with the JSON of `AnswerQuery`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_answer(
+    client: *const SkywayClient,
+    media_connection_id: *const c_char,
+    query: *const c_char,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let media_connection_id =
+            MediaConnectionId::try_create(to_str(media_connection_id, "media_connection_id")?)?;
+        let query: AnswerQuery = from_json(query, "query")?;
+        let response = client
+            .runtime
+            .block_on(crate::media::answer(&media_connection_id, &query))?;
+        client
+            .registry
+            .register_media_connection(media_connection_id);
+        Ok(response)
+    })
+}
+
+/// Close a MediaConnection.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_disconnect_media(
+    client: *const SkywayClient,
+    media_connection_id: *const c_char,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let media_connection_id =
+            MediaConnectionId::try_create(to_str(media_connection_id, "media_connection_id")?)?;
+        client
+            .runtime
+            .block_on(crate::media::disconnect(&media_connection_id))?;
+        client
+            .registry
+            .unregister_media_connection(&media_connection_id);
+        Ok(())
+    })
+}
+
+/// Wait for an event of a MediaConnection. `out` receives the event, which may be `{"event": "TIMEOUT"}`.
+#[no_mangle]
+pub unsafe extern "C" fn skyway_media_event(
+    client: *const SkywayClient,
+    media_connection_id: *const c_char,
+    out: *mut *mut c_char,
+) -> c_int {
+    run(out, || {
+        let client = to_client(client)?;
+        let media_connection_id =
+            MediaConnectionId::try_create(to_str(media_connection_id, "media_connection_id")?)?;
+        client
+            .runtime
+            .block_on(crate::media::event(&media_connection_id))
+    })
+}
+
+#[cfg(test)]
+mod test_capi {
+    use mockito::mock;
+    use serde_json::Value;
+
+    use super::*;
+
+    const DATA_CONNECTION_ID: &str = "dc-102127d9-30de-413b-93f7-41a33e39d82b";
+
+    unsafe fn take(out: *mut c_char) -> Value {
+        let value = serde_json::from_str(CStr::from_ptr(out).to_str().unwrap()).unwrap();
+        skyway_string_free(out);
+        value
+    }
+
+    #[test]
+    fn disconnect_and_errors() {
+        let base_url = CString::new(mockito::server_url()).unwrap();
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/data/connections/{}", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+
+        unsafe {
+            let client = skyway_client_new(base_url.as_ptr());
+            assert!(!client.is_null());
+            let data_connection_id = DataConnectionId::try_create(DATA_CONNECTION_ID).unwrap();
+            (*client)
+                .registry
+                .register_data_connection(data_connection_id);
+
+            let mut out = ptr::null_mut();
+            let id = CString::new(DATA_CONNECTION_ID).unwrap();
+            assert_eq!(
+                skyway_disconnect_data(client, id.as_ptr(), &mut out),
+                SKYWAY_OK
+            );
+            assert_eq!(take(out), Value::Null);
+            assert!((*client).registry.snapshot().data_connections.is_empty());
+            disconnect_mock.assert();
+
+            // errors are returned as codes and the JSON of error::Error
+            let id = CString::new("invalid").unwrap();
+            assert_eq!(
+                skyway_disconnect_data(client, id.as_ptr(), &mut out),
+                SKYWAY_ERROR_INTERNAL
+            );
+            assert_eq!(take(out)["reason"], "InternalError");
+
+            let query = CString::new("{").unwrap();
+            assert_eq!(
+                skyway_connect(client, query.as_ptr(), &mut out),
+                SKYWAY_ERROR_JSON
+            );
+            assert_eq!(take(out)["reason"], "JsonError");
+
+            assert_eq!(
+                skyway_open_rtcp_socket(ptr::null(), ptr::null_mut()),
+                SKYWAY_ERROR_INTERNAL
+            );
+
+            skyway_client_free(client);
+        }
+    }
+
+    #[test]
+    fn header_is_up_to_date() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/skyway_gateway.h"));
+        let header = include_str!("../include/skyway_gateway.h");
+        assert_eq!(header, generated);
+    }
+
+    #[test]
+    fn header_declares_all_functions() {
+        let header = include_str!("../include/skyway_gateway.h");
+        let source = include_str!("capi.rs");
+        for name in source
+            .split("pub unsafe extern \"C\" fn ")
+            .skip(1)
+            .filter_map(|rest| rest.split('(').next())
+        {
+            assert!(header.contains(&format!("{}(", name)), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Commands shared by the WebSocket and MQTT APIs.
+//!
+//! Commands are JSON objects like below.
+//! ```json
+//! {"command": "disconnect", "params": {"data_connection_id": "dc-..."}}
+//! ```
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::common::formats::{PhantomId, SocketInfo};
+use crate::data::formats::{
+    ConnectQuery, DataConnectionId, DataConnectionIdWrapper, RedirectDataParams,
+};
+use crate::error;
+use crate::event_bus::EventBus;
+use crate::media::formats::{AnswerQuery, CallQuery, MediaConnectionId, MediaConnectionIdWrapper};
+use crate::registry::Registry;
+
+/// Commands mapped to functions of this crate
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command", content = "params", rename_all = "snake_case")]
+pub enum Command {
+    /// `data::connect`
+    Connect(ConnectQuery),
+    /// `media::call`
+    Call(CallQuery),
+    /// `media::answer`
+    Answer {
+        media_connection_id: MediaConnectionId,
+        #[serde(flatten)]
+        query: AnswerQuery,
+    },
+    /// `data::redirect`
+    Redirect {
+        data_connection_id: DataConnectionId,
+        #[serde(flatten)]
+        params: RedirectDataParams,
+    },
+    /// `data::disconnect` or `media::disconnect`
+    Disconnect(ConnectionIdWrapper),
+    /// `media::send_pli`
+    SendPli {
+        media_connection_id: MediaConnectionId,
+        target: SocketInfo<PhantomId>,
+    },
+}
+
+/// Id of a DataConnection or a MediaConnection
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ConnectionIdWrapper {
+    Data(DataConnectionIdWrapper),
+    Media(MediaConnectionIdWrapper),
+}
+
+fn to_value(result: Result<impl Serialize, error::Error>) -> Result<Value, error::Error> {
+    let result = result?;
+    serde_json::to_value(result).map_err(|error| error::Error::SerdeError { error })
+}
+
+/// Execute a command.
+///
+/// Connections established with commands are registered, and their events are published to the bus.
+pub async fn execute(
+    registry: &Registry,
+    bus: &EventBus,
+    command: Command,
+) -> Result<Value, error::Error> {
+    match command {
+        Command::Connect(query) => {
+            let peer_id = query.peer_id.clone();
+            let data_connection_id = crate::data::connect(query).await?;
+            registry.register_data_connection(data_connection_id.clone());
+            bus.watch_data_connection(peer_id, data_connection_id.clone());
+            to_value(Ok(DataConnectionIdWrapper { data_connection_id }))
+        }
+        Command::Call(query) => {
+            let response = crate::media::call(&query).await?;
+            let media_connection_id = response.params.media_connection_id.clone();
+            registry.register_media_connection(media_connection_id.clone());
+            bus.watch_media_connection(query.peer_id, media_connection_id);
+            to_value(Ok(response))
+        }
+        Command::Answer {
+            media_connection_id,
+            query,
+        } => to_value(crate::media::answer(&media_connection_id, &query).await),
+        Command::Redirect {
+            data_connection_id,
+            params,
+        } => to_value(crate::data::redirect(&data_connection_id, &params).await),
+        Command::Disconnect(ConnectionIdWrapper::Data(DataConnectionIdWrapper {
+            data_connection_id,
+        })) => {
+            crate::data::disconnect(&data_connection_id).await?;
+            registry.unregister_data_connection(&data_connection_id);
+            Ok(Value::Null)
+        }
+        Command::Disconnect(ConnectionIdWrapper::Media(MediaConnectionIdWrapper {
+            media_connection_id,
+        })) => {
+            crate::media::disconnect(&media_connection_id).await?;
+            registry.unregister_media_connection(&media_connection_id);
+            Ok(Value::Null)
+        }
+        Command::SendPli {
+            media_connection_id,
+            target,
+        } => {
+            crate::media::send_pli(&media_connection_id, &target).await?;
+            Ok(Value::Null)
+        }
+    }
+}
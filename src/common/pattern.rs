@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+
+/// Glob pattern to match PeerIds and metadata.
+///
+/// `*` matches any sequence of characters and `?` matches any single character.
+/// Other characters match themselves.
+///
+/// # Examples
+/// ```
+/// use skyway_webrtc_gateway_api::prelude::Pattern;
+///
+/// let pattern = Pattern::new("operator-*");
+/// assert!(pattern.matches("operator-01"));
+/// assert!(!pattern.matches("robot-01"));
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(transparent)]
+pub struct Pattern(String);
+
+impl Pattern {
+    pub fn new(pattern: impl Into<String>) -> Self {
+        Pattern(pattern.into())
+    }
+
+    /// Pattern which matches any string.
+    pub fn any() -> Self {
+        Pattern("*".into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// Returns true if the whole `value` matches this pattern.
+    pub fn matches(&self, value: &str) -> bool {
+        let pattern: Vec<char> = self.0.chars().collect();
+        let value: Vec<char> = value.chars().collect();
+
+        // position of the last `*` in pattern and the position in value it has consumed up to
+        let mut star: Option<(usize, usize)> = None;
+        let (mut p, mut v) = (0, 0);
+        while v < value.len() {
+            if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+                p += 1;
+                v += 1;
+            } else if p < pattern.len() && pattern[p] == '*' {
+                star = Some((p, v));
+                p += 1;
+            } else if let Some((star_p, star_v)) = star {
+                // let the last `*` consume one more character and retry
+                p = star_p + 1;
+                v = star_v + 1;
+                star = Some((star_p, star_v + 1));
+            } else {
+                return false;
+            }
+        }
+        pattern[p..].iter().all(|c| *c == '*')
+    }
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Pattern::any()
+    }
+}
+
+#[cfg(test)]
+mod test_pattern {
+    use super::*;
+
+    #[test]
+    fn exact() {
+        let pattern = Pattern::new("operator");
+        assert!(pattern.matches("operator"));
+        assert!(!pattern.matches("operator2"));
+        assert!(!pattern.matches("operato"));
+    }
+
+    #[test]
+    fn wildcard() {
+        assert!(Pattern::any().matches(""));
+        assert!(Pattern::any().matches("anything"));
+        assert!(Pattern::new("op-*-cam").matches("op-1-cam"));
+        assert!(Pattern::new("op-*-cam").matches("op--cam"));
+        assert!(Pattern::new("op-*-cam").matches("op-a-b-cam"));
+        assert!(!Pattern::new("op-*-cam").matches("op-1-mic"));
+        assert!(Pattern::new("*a*b").matches("xaab"));
+    }
+
+    #[test]
+    fn single_char() {
+        let pattern = Pattern::new("robot-??");
+        assert!(pattern.matches("robot-01"));
+        assert!(!pattern.matches("robot-1"));
+        assert!(!pattern.matches("robot-001"));
+    }
+
+    #[test]
+    fn deserialize_from_str() {
+        let pattern: Pattern = serde_json::from_str(r#""operator-*""#).unwrap();
+        assert_eq!(pattern, Pattern::new("operator-*"));
+    }
+}
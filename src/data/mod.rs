@@ -1,5 +1,7 @@
 mod api;
 pub(crate) mod formats;
+/// Redirect DataConnections established by neighbours according to policies
+pub mod policy;
 
 use futures::channel::mpsc;
 use futures::*;
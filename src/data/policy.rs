@@ -0,0 +1,456 @@
+use std::sync::Arc;
+
+use futures::channel::mpsc;
+use futures::*;
+use serde::{Deserialize, Serialize};
+
+use crate::common::formats::{PhantomId, SerializableSocket, SocketInfo};
+use crate::common::pattern::Pattern;
+use crate::data::formats::{
+    DataConnectionId, DataConnectionStatus, DataId, DataIdWrapper, RedirectDataParams,
+};
+use crate::data::DataConnectionEventEnum;
+use crate::error;
+use crate::peer::formats::PeerConnectionEvent;
+use crate::registry::Registry;
+
+/// Declarative rule to redirect DataConnections established by neighbours.
+///
+/// A policy matches a DataConnection with its remote peer_id, label, metadata and serialization.
+///
+/// It can be deserialized from a TOML or JSON like below.
+/// ```toml
+/// peer_id = "operator-*"
+/// label = "control"
+/// feed = true
+/// redirect = { ip = "127.0.0.1", port = 10000 }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RedirectPolicy {
+    /// Pattern for remote peer_id. Matches any peer if not set.
+    #[serde(default)]
+    pub peer_id: Pattern,
+    /// Pattern for label of the DataConnection. Matches any label if not set.
+    #[serde(default)]
+    pub label: Pattern,
+    /// Pattern for metadata of the DataConnection. Matches any metadata if not set.
+    #[serde(default)]
+    pub metadata: Pattern,
+    /// Pattern for serialization of the DataConnection. Matches any serialization if not set.
+    #[serde(default)]
+    pub serialization: Pattern,
+    /// If true, a data socket is opened to feed data to the neighbour.
+    #[serde(default)]
+    pub feed: bool,
+    /// Received data is redirected to this socket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<RedirectSocketConfig>,
+}
+
+/// Socket to which received data is redirected
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RedirectSocketConfig {
+    pub ip: String,
+    pub port: u16,
+}
+
+impl RedirectSocketConfig {
+    pub fn socket_info(&self) -> Result<SocketInfo<PhantomId>, error::Error> {
+        SocketInfo::<PhantomId>::try_create(None, &self.ip, self.port)
+    }
+}
+
+impl RedirectPolicy {
+    /// Returns true if this policy should be applied to the DataConnection.
+    pub fn matches(&self, status: &DataConnectionStatus) -> bool {
+        self.peer_id.matches(&status.remote_id)
+            && self.label.matches(&status.label)
+            && self.metadata.matches(&status.metadata)
+            && self.serialization.matches(&status.serialization)
+    }
+
+    pub fn decision(&self) -> Result<RedirectDecision, error::Error> {
+        let redirect = match self.redirect {
+            Some(ref config) => Some(config.socket_info()?),
+            None => None,
+        };
+        Ok(RedirectDecision {
+            feed: self.feed,
+            redirect,
+        })
+    }
+}
+
+/// Shows how a DataConnection should be redirected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedirectDecision {
+    /// If true, a data socket is opened to feed data to the neighbour.
+    pub feed: bool,
+    /// Received data is redirected to this socket
+    pub redirect: Option<SocketInfo<PhantomId>>,
+}
+
+/// Hook to decide how a DataConnection should be redirected.
+///
+/// It returns None if the DataConnection should be left as it is,
+/// and error if the decision can't be made, e.g. the redirect address of the matched policy is invalid.
+///
+/// It's implemented for a list of `RedirectPolicy` and closures.
+/// A list of policies is evaluated in order and the first matched one is applied.
+pub trait RedirectHook: Send + Sync {
+    fn decide(
+        &self,
+        status: &DataConnectionStatus,
+    ) -> Result<Option<RedirectDecision>, error::Error>;
+}
+
+impl RedirectHook for Vec<RedirectPolicy> {
+    fn decide(
+        &self,
+        status: &DataConnectionStatus,
+    ) -> Result<Option<RedirectDecision>, error::Error> {
+        match self.iter().find(|policy| policy.matches(status)) {
+            Some(policy) => policy.decision().map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<F> RedirectHook for F
+where
+    F: Fn(&DataConnectionStatus) -> Option<RedirectDecision> + Send + Sync,
+{
+    fn decide(
+        &self,
+        status: &DataConnectionStatus,
+    ) -> Result<Option<RedirectDecision>, error::Error> {
+        Ok(self(status))
+    }
+}
+
+/// DataConnection which has been redirected by `DataRedirector`.
+#[derive(Debug)]
+pub struct DataChannel {
+    pub data_connection_id: DataConnectionId,
+    /// Status of the DataConnection when it was opened.
+    pub status: DataConnectionStatus,
+    /// Socket to feed data. Users send data to this socket.
+    pub feed: Option<SocketInfo<DataId>>,
+    /// Socket to which received data is redirected.
+    pub redirect: Option<SocketInfo<PhantomId>>,
+    /// Events of the DataConnection after OPEN. It's closed after CLOSE event.
+    pub events: mpsc::Receiver<DataConnectionEventEnum>,
+}
+
+/// Redirects DataConnections established by neighbours with a `RedirectHook`.
+///
+/// DataConnections and data sockets are registered to the registry, so that `shutdown` can release them.
+#[derive(Clone)]
+pub struct DataRedirector {
+    hook: Arc<dyn RedirectHook>,
+    registry: Registry,
+}
+
+impl DataRedirector {
+    pub fn new(hook: impl RedirectHook + 'static, registry: Registry) -> Self {
+        Self {
+            hook: Arc::new(hook),
+            registry,
+        }
+    }
+
+    /// Handle a CONNECTION event of a PeerObject.
+    ///
+    /// It waits for the OPEN event of the DataConnection, and redirects it as the hook decides.
+    /// It returns None if the hook doesn't pick up the DataConnection, and error if the hook fails.
+    /// The DataConnection is registered to the registry only after the hook picks it up.
+    pub async fn handle_connection(
+        &self,
+        event: &PeerConnectionEvent,
+    ) -> Result<Option<DataChannel>, error::Error> {
+        let data_connection_id = event.data_params.data_connection_id.clone();
+        let (event_notifier, mut event_observer) = mpsc::channel::<DataConnectionEventEnum>(10);
+        tokio::spawn(super::listen_events(
+            data_connection_id.clone(),
+            event_notifier,
+        ));
+        loop {
+            match event_observer.next().await {
+                Some(DataConnectionEventEnum::OPEN(_)) => break,
+                Some(DataConnectionEventEnum::CLOSE(_)) | None => {
+                    return Err(error::Error::create_local_error(
+                        "DataConnection has been closed before OPEN",
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+
+        let status = super::status(&data_connection_id).await?;
+        let decision = match self.hook.decide(&status)? {
+            Some(decision) => decision,
+            None => return Ok(None),
+        };
+        self.registry
+            .register_data_connection(data_connection_id.clone());
+
+        let feed = if decision.feed {
+            let socket = super::open_data_socket().await?;
+            if let Some(data_id) = socket.get_id() {
+                self.registry.register_data_socket(data_id);
+            }
+            Some(socket)
+        } else {
+            None
+        };
+        let params = RedirectDataParams {
+            feed_params: match feed {
+                Some(ref socket) => socket.get_id().map(|data_id| DataIdWrapper { data_id }),
+                None => None,
+            },
+            redirect_params: decision.redirect.clone(),
+        };
+        if params.feed_params.is_some() || params.redirect_params.is_some() {
+            let _ = super::redirect(&data_connection_id, &params).await?;
+        }
+
+        Ok(Some(DataChannel {
+            data_connection_id,
+            status,
+            feed,
+            redirect: decision.redirect,
+            events: event_observer,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test_data_redirector {
+    use mockito::mock;
+
+    use super::*;
+    use crate::data::formats::DataConnectionIdWrapper;
+    use crate::peer::formats::PeerInfo;
+
+    fn status(remote_id: &str, label: &str) -> DataConnectionStatus {
+        DataConnectionStatus {
+            remote_id: remote_id.into(),
+            buffersize: 0,
+            label: label.into(),
+            metadata: "".into(),
+            open: true,
+            reliable: true,
+            serialization: "BINARY".into(),
+            r#type: "DATA".into(),
+        }
+    }
+
+    fn policies() -> Vec<RedirectPolicy> {
+        vec![
+            RedirectPolicy {
+                peer_id: Pattern::new("operator-*"),
+                label: Pattern::new("control"),
+                metadata: Pattern::any(),
+                serialization: Pattern::any(),
+                feed: false,
+                redirect: Some(RedirectSocketConfig {
+                    ip: "127.0.0.1".into(),
+                    port: 10000,
+                }),
+            },
+            RedirectPolicy {
+                peer_id: Pattern::new("operator-*"),
+                label: Pattern::any(),
+                metadata: Pattern::any(),
+                serialization: Pattern::any(),
+                feed: true,
+                redirect: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn decide_with_policies() {
+        let policies = policies();
+        let decision = policies
+            .decide(&status("operator-1", "control"))
+            .unwrap()
+            .unwrap();
+        assert!(!decision.feed);
+        assert_eq!(decision.redirect.unwrap().port(), 10000);
+
+        let decision = policies
+            .decide(&status("operator-1", "log"))
+            .unwrap()
+            .unwrap();
+        assert!(decision.feed);
+        assert!(decision.redirect.is_none());
+
+        assert!(policies
+            .decide(&status("stranger", "control"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn fail_with_invalid_redirect() {
+        let mut policies = policies();
+        policies[0].redirect = Some(RedirectSocketConfig {
+            ip: "not an ip".into(),
+            port: 10000,
+        });
+        assert!(policies.decide(&status("operator-1", "control")).is_err());
+        // the invalid policy doesn't affect connections it doesn't match
+        assert!(policies.decide(&status("operator-1", "log")).is_ok());
+    }
+
+    #[test]
+    fn decide_with_closure() {
+        let hook = |status: &DataConnectionStatus| {
+            if status.label == "control" {
+                Some(RedirectDecision {
+                    feed: true,
+                    redirect: None,
+                })
+            } else {
+                None
+            }
+        };
+        assert!(hook.decide(&status("anyone", "control")).unwrap().is_some());
+        assert!(hook.decide(&status("anyone", "log")).unwrap().is_none());
+    }
+
+    #[test]
+    fn deserialize_policy() {
+        let policy: RedirectPolicy = toml::from_str(
+            "peer_id = \"operator-*\"\nredirect = { ip = \"127.0.0.1\", port = 10000 }",
+        )
+        .unwrap();
+        assert_eq!(policy.label, Pattern::any());
+        assert!(!policy.feed);
+        assert_eq!(policy.redirect.unwrap().port, 10000);
+    }
+
+    #[tokio::test]
+    async fn redirect_after_open() {
+        crate::initialize(mockito::server_url());
+        let data_connection_id = "dc-5995f372-fb6a-4196-b30a-ce11e5c7f56c";
+        let event_mock = mock(
+            "GET",
+            format!("/data/connections/{}/events", data_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"event": "OPEN"}"#)
+        .expect_at_least(1)
+        .create();
+        let status_mock = mock(
+            "GET",
+            format!("/data/connections/{}/status", data_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{
+                "buffersize": 0,
+                "label": "control",
+                "metadata": "",
+                "open": true,
+                "reliable": true,
+                "remote_id": "operator-1",
+                "serialization": "BINARY",
+                "type": "DATA"
+            }"#,
+        )
+        .create();
+        let redirect_mock = mock(
+            "PUT",
+            format!("/data/connections/{}", data_connection_id).as_str(),
+        )
+        .match_body(mockito::Matcher::Json(serde_json::json!({
+            "redirect_params": {"ip_v4": "127.0.0.1", "port": 10000}
+        })))
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{
+                "command_type": "DATA_CONNECTION_PUT",
+                "data_id": "da-50a32bab-b3d9-4913-8e20-f79c90a6a211"
+            }"#,
+        )
+        .create();
+
+        let registry = Registry::new();
+        let redirector = DataRedirector::new(policies(), registry.clone());
+        let event = PeerConnectionEvent {
+            params: PeerInfo::try_create("peer_id", "pt-9749250e-d157-4f80-9ee2-359ce8524308")
+                .unwrap(),
+            data_params: DataConnectionIdWrapper {
+                data_connection_id: DataConnectionId::try_create(data_connection_id).unwrap(),
+            },
+        };
+        let channel = redirector
+            .handle_connection(&event)
+            .await
+            .unwrap()
+            .expect("policy should be applied");
+
+        assert_eq!(channel.status.label, "control");
+        assert!(channel.feed.is_none());
+        assert_eq!(channel.redirect.unwrap().port(), 10000);
+        assert_eq!(registry.snapshot().data_connections.len(), 1);
+        event_mock.assert();
+        status_mock.assert();
+        redirect_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn not_registered_when_declined() {
+        crate::initialize(mockito::server_url());
+        let data_connection_id = "dc-6a0f3c2e-1b7d-4e9a-8c5f-2d4b6e8a0c13";
+        let event_mock = mock(
+            "GET",
+            format!("/data/connections/{}/events", data_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"event": "OPEN"}"#)
+        .expect_at_least(1)
+        .create();
+        let status_mock = mock(
+            "GET",
+            format!("/data/connections/{}/status", data_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{
+                "buffersize": 0,
+                "label": "log",
+                "metadata": "",
+                "open": true,
+                "reliable": true,
+                "remote_id": "anyone",
+                "serialization": "BINARY",
+                "type": "DATA"
+            }"#,
+        )
+        .create();
+
+        let registry = Registry::new();
+        let redirector = DataRedirector::new(policies(), registry.clone());
+        let event = PeerConnectionEvent {
+            params: PeerInfo::try_create("peer_id", "pt-9749250e-d157-4f80-9ee2-359ce8524308")
+                .unwrap(),
+            data_params: DataConnectionIdWrapper {
+                data_connection_id: DataConnectionId::try_create(data_connection_id).unwrap(),
+            },
+        };
+        let channel = redirector.handle_connection(&event).await.unwrap();
+
+        assert!(channel.is_none());
+        assert!(registry.snapshot().data_connections.is_empty());
+        event_mock.assert();
+        status_mock.assert();
+    }
+}
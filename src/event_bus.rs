@@ -0,0 +1,412 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc;
+use futures::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::data::formats::DataConnectionId;
+use crate::data::DataConnectionEventEnum;
+use crate::error;
+use crate::media::formats::MediaConnectionId;
+use crate::media::MediaConnectionEventEnum;
+use crate::peer::formats::{PeerId, PeerInfo};
+use crate::peer::PeerEventEnum;
+
+/// Events of PeerObjects, DataConnections and MediaConnections merged into one enum.
+///
+/// Every event is tagged with the PeerId and the connection id it belongs to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum GatewayEvent {
+    Peer {
+        peer_id: PeerId,
+        event: PeerEventEnum,
+    },
+    Data {
+        peer_id: PeerId,
+        data_connection_id: DataConnectionId,
+        event: DataConnectionEventEnum,
+    },
+    Media {
+        peer_id: PeerId,
+        media_connection_id: MediaConnectionId,
+        event: MediaConnectionEventEnum,
+    },
+}
+
+impl GatewayEvent {
+    /// PeerId of the PeerObject which the event belongs to.
+    pub fn peer_id(&self) -> &PeerId {
+        match self {
+            GatewayEvent::Peer { peer_id, .. } => peer_id,
+            GatewayEvent::Data { peer_id, .. } => peer_id,
+            GatewayEvent::Media { peer_id, .. } => peer_id,
+        }
+    }
+
+    /// Id of the DataConnection or MediaConnection which the event belongs to.
+    pub fn connection_id(&self) -> Option<&str> {
+        match self {
+            GatewayEvent::Peer { .. } => None,
+            GatewayEvent::Data {
+                data_connection_id, ..
+            } => Some(data_connection_id.as_str()),
+            GatewayEvent::Media {
+                media_connection_id,
+                ..
+            } => Some(media_connection_id.as_str()),
+        }
+    }
+
+    /// Returns true if it's CLOSE event.
+    pub fn is_close(&self) -> bool {
+        matches!(
+            self,
+            GatewayEvent::Peer {
+                event: PeerEventEnum::CLOSE(_),
+                ..
+            } | GatewayEvent::Data {
+                event: DataConnectionEventEnum::CLOSE(_),
+                ..
+            } | GatewayEvent::Media {
+                event: MediaConnectionEventEnum::CLOSE(_),
+                ..
+            }
+        )
+    }
+}
+
+/// Merges events of PeerObjects, DataConnections and MediaConnections into one broadcast stream.
+///
+/// When a watched PeerObject receives CONNECTION or CALL events,
+/// the bus automatically starts listening events of the DataConnection or MediaConnection.
+/// Listeners of connections stop on their CLOSE events or the CLOSE event of the PeerObject.
+///
+/// It is cheap to clone. All clones share the same stream.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<GatewayEvent>,
+    token: CancellationToken,
+    // tokens of PeerObjects being watched, to stop them with `unwatch_peer`
+    peers: Arc<Mutex<BTreeMap<PeerId, CancellationToken>>>,
+}
+
+impl EventBus {
+    /// Create an EventBus. Subscribers lagging behind more than `capacity` events lose old events.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            sender,
+            token: CancellationToken::new(),
+            peers: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Receive all events published after this call.
+    pub fn subscribe(&self) -> broadcast::Receiver<GatewayEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Stop all listeners started by this bus.
+    pub fn stop(&self) {
+        self.token.cancel();
+    }
+
+    pub(crate) fn publish(&self, event: GatewayEvent) {
+        // it fails only when there are no subscribers
+        let _ = self.sender.send(event);
+    }
+
+    /// Start listening events of the PeerObject.
+    ///
+    /// The returned handle finishes after the CLOSE event of the PeerObject.
+    pub fn watch_peer(&self, peer_info: PeerInfo) -> JoinHandle<Result<(), error::Error>> {
+        let bus = self.clone();
+        let peer_token = self.token.child_token();
+        let old = self
+            .peers
+            .lock()
+            .expect("event bus lock poisoned")
+            .insert(peer_info.peer_id(), peer_token.clone());
+        if let Some(old) = old {
+            old.cancel();
+        }
+        tokio::spawn(async move {
+            let (event_notifier, mut event_observer) = mpsc::channel::<PeerEventEnum>(10);
+            let listen_fut = crate::peer::listen_events(peer_info.clone(), event_notifier);
+            let forward_fut = async {
+                while let Some(event) = event_observer.next().await {
+                    bus.on_peer_event(peer_info.peer_id(), &peer_token, event);
+                }
+            };
+            let result = tokio::select! {
+                _ = peer_token.cancelled() => Ok(()),
+                (result, _) = future::join(listen_fut, forward_fut) => result,
+            };
+            // connections of the PeerObject are closed with it
+            peer_token.cancel();
+            bus.peers
+                .lock()
+                .expect("event bus lock poisoned")
+                .retain(|_, token| !token.is_cancelled());
+            result
+        })
+    }
+
+    /// Stop listening events of the PeerObject and its connections,
+    /// typically after it's deleted with `peer::delete`.
+    pub fn unwatch_peer(&self, peer_id: &PeerId) {
+        let token = self
+            .peers
+            .lock()
+            .expect("event bus lock poisoned")
+            .remove(peer_id);
+        if let Some(token) = token {
+            token.cancel();
+        }
+    }
+
+    fn on_peer_event(&self, peer_id: PeerId, peer_token: &CancellationToken, event: PeerEventEnum) {
+        match event {
+            PeerEventEnum::CONNECTION(ref connection) => {
+                self.spawn_data_listener(
+                    peer_id.clone(),
+                    connection.data_params.data_connection_id.clone(),
+                    peer_token.child_token(),
+                );
+            }
+            PeerEventEnum::CALL(ref call) => {
+                self.spawn_media_listener(
+                    peer_id.clone(),
+                    call.call_params.media_connection_id.clone(),
+                    peer_token.child_token(),
+                );
+            }
+            _ => {}
+        }
+        self.publish(GatewayEvent::Peer { peer_id, event });
+    }
+
+    /// Start listening events of a DataConnection, typically the one created with `data::connect`.
+    pub fn watch_data_connection(
+        &self,
+        peer_id: PeerId,
+        data_connection_id: DataConnectionId,
+    ) -> JoinHandle<Result<(), error::Error>> {
+        self.spawn_data_listener(peer_id, data_connection_id, self.token.child_token())
+    }
+
+    /// Start listening events of a MediaConnection, typically the one created with `media::call`.
+    pub fn watch_media_connection(
+        &self,
+        peer_id: PeerId,
+        media_connection_id: MediaConnectionId,
+    ) -> JoinHandle<Result<(), error::Error>> {
+        self.spawn_media_listener(peer_id, media_connection_id, self.token.child_token())
+    }
+
+    fn spawn_data_listener(
+        &self,
+        peer_id: PeerId,
+        data_connection_id: DataConnectionId,
+        token: CancellationToken,
+    ) -> JoinHandle<Result<(), error::Error>> {
+        let bus = self.clone();
+        tokio::spawn(async move {
+            let (event_notifier, mut event_observer) = mpsc::channel::<DataConnectionEventEnum>(10);
+            let listen_fut = crate::data::listen_events(data_connection_id.clone(), event_notifier);
+            let forward_fut = async {
+                while let Some(event) = event_observer.next().await {
+                    bus.publish(GatewayEvent::Data {
+                        peer_id: peer_id.clone(),
+                        data_connection_id: data_connection_id.clone(),
+                        event,
+                    });
+                }
+            };
+            let result = tokio::select! {
+                _ = token.cancelled() => Ok(()),
+                (result, _) = future::join(listen_fut, forward_fut) => result,
+            };
+            if let Err(ref e) = result {
+                warn!(
+                    "stop listening events of {}: {:?}",
+                    data_connection_id.as_str(),
+                    e
+                );
+            }
+            result
+        })
+    }
+
+    fn spawn_media_listener(
+        &self,
+        peer_id: PeerId,
+        media_connection_id: MediaConnectionId,
+        token: CancellationToken,
+    ) -> JoinHandle<Result<(), error::Error>> {
+        let bus = self.clone();
+        tokio::spawn(async move {
+            let (event_notifier, mut event_observer) =
+                mpsc::channel::<MediaConnectionEventEnum>(10);
+            let listen_fut =
+                crate::media::listen_events(media_connection_id.clone(), event_notifier);
+            let forward_fut = async {
+                while let Some(event) = event_observer.next().await {
+                    bus.publish(GatewayEvent::Media {
+                        peer_id: peer_id.clone(),
+                        media_connection_id: media_connection_id.clone(),
+                        event,
+                    });
+                }
+            };
+            let result = tokio::select! {
+                _ = token.cancelled() => Ok(()),
+                (result, _) = future::join(listen_fut, forward_fut) => result,
+            };
+            if let Err(ref e) = result {
+                warn!(
+                    "stop listening events of {}: {:?}",
+                    media_connection_id.as_str(),
+                    e
+                );
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_event_bus {
+    use mockito::mock;
+
+    use super::*;
+    use crate::data::formats::DataConnectionIdWrapper;
+    use crate::peer::formats::PeerConnectionEvent;
+
+    const TOKEN: &str = "pt-9749250e-d157-4f80-9ee2-359ce8524308";
+
+    #[tokio::test]
+    async fn peer_close() {
+        crate::initialize(mockito::server_url());
+        let peer_info = PeerInfo::try_create("bus_peer", TOKEN).unwrap();
+        let event_mock = mock(
+            "GET",
+            format!("/peers/bus_peer/events?token={}", TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(format!(
+            r#"{{"event": "CLOSE", "params": {{"peer_id": "bus_peer", "token": "{}"}}}}"#,
+            TOKEN
+        ))
+        .create();
+
+        let bus = EventBus::new(10);
+        let mut first = bus.subscribe();
+        let mut second = bus.subscribe();
+        bus.watch_peer(peer_info.clone()).await.unwrap().unwrap();
+
+        for observer in [&mut first, &mut second].iter_mut() {
+            let event = observer.recv().await.unwrap();
+            assert_eq!(event.peer_id(), &peer_info.peer_id());
+            assert!(event.connection_id().is_none());
+            assert!(event.is_close());
+        }
+        event_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn connection_starts_data_listener() {
+        crate::initialize(mockito::server_url());
+        let data_connection_id = "dc-7995f372-fb6a-4196-b30a-ce11e5c7f56c";
+        let event_mock = mock(
+            "GET",
+            format!("/data/connections/{}/events", data_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"event": "CLOSE"}"#)
+        .create();
+
+        let bus = EventBus::new(10);
+        let mut observer = bus.subscribe();
+        let peer_info = PeerInfo::try_create("bus_peer", TOKEN).unwrap();
+        let event = PeerEventEnum::CONNECTION(PeerConnectionEvent {
+            params: peer_info.clone(),
+            data_params: DataConnectionIdWrapper {
+                data_connection_id: DataConnectionId::try_create(data_connection_id).unwrap(),
+            },
+        });
+        bus.on_peer_event(
+            peer_info.peer_id(),
+            &CancellationToken::new(),
+            event.clone(),
+        );
+
+        let received = observer.recv().await.unwrap();
+        assert_eq!(
+            received,
+            GatewayEvent::Peer {
+                peer_id: peer_info.peer_id(),
+                event
+            }
+        );
+        let received = observer.recv().await.unwrap();
+        assert_eq!(received.connection_id(), Some(data_connection_id));
+        assert!(received.is_close());
+        event_mock.assert();
+    }
+
+    #[test]
+    fn serialize_event() {
+        let event = GatewayEvent::Media {
+            peer_id: PeerId::new("peer_id"),
+            media_connection_id: MediaConnectionId::try_create(
+                "mc-102127d9-30de-413b-93f7-41a33e39d82b",
+            )
+            .unwrap(),
+            event: MediaConnectionEventEnum::TIMEOUT,
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "Media",
+                "peer_id": "peer_id",
+                "media_connection_id": "mc-102127d9-30de-413b-93f7-41a33e39d82b",
+                "event": {"event": "TIMEOUT"}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn unwatch_peer() {
+        crate::initialize(mockito::server_url());
+        let peer_info = PeerInfo::try_create("unwatched_peer", TOKEN).unwrap();
+        let event_mock = mock(
+            "GET",
+            format!("/peers/unwatched_peer/events?token={}", TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::REQUEST_TIMEOUT.as_u16() as usize)
+        .expect_at_least(1)
+        .create();
+
+        let bus = EventBus::new(10);
+        let handle = bus.watch_peer(peer_info.clone());
+        // the listener keeps polling while the PeerObject is watched
+        while !event_mock.matched() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!handle.is_finished());
+        bus.unwatch_peer(&peer_info.peer_id());
+        handle.await.unwrap().unwrap();
+        assert!(bus.peers.lock().unwrap().is_empty());
+        event_mock.assert();
+    }
+}
@@ -0,0 +1,783 @@
+// Conversions between the structs of this crate and the generated proto messages.
+//
+// Structs of this crate are converted with `From`, and proto messages are converted with `TryFrom`
+// because ids and sockets inside them are validated in the same way as JSON.
+use std::convert::TryFrom;
+
+use tonic::Status;
+
+use super::proto;
+use crate::common::formats::{PhantomId, SerializableId, SerializableSocket, SocketInfo};
+use crate::data::{
+    ConnectQuery, ConnectQueryOption, DataConnectionEventEnum, DataConnectionId,
+    DataConnectionIdWrapper, DataConnectionStatus, DataId, DataIdWrapper, DcInit,
+    RedirectDataParams, RedirectDataResponse,
+};
+use crate::error;
+use crate::media::{
+    AnswerQuery, AnswerResponse, AnswerResponseParams, CallQuery, CallResponse, Constraints,
+    MediaConnectionEventEnum, MediaConnectionId, MediaConnectionIdWrapper, MediaConnectionStatus,
+    MediaId, MediaParams, RedirectParameters, RtcpId, SsrcPair,
+};
+use crate::peer::{PeerEventEnum, PeerId, PeerInfo, PeerStatusMessage};
+
+impl From<error::Error> for Status {
+    fn from(error: error::Error) -> Self {
+        let message = serde_json::to_string(&error).unwrap_or_default();
+        match error {
+            error::Error::ReqwestError(_) => Status::unavailable(message),
+            error::Error::AddrParseError(_) | error::Error::SerdeError { .. } => {
+                Status::invalid_argument(message)
+            }
+            _ => Status::internal(message),
+        }
+    }
+}
+
+// Errors in proto messages are caused by clients
+fn invalid(error: error::Error) -> Status {
+    Status::invalid_argument(serde_json::to_string(&error).unwrap_or_default())
+}
+
+pub(crate) fn try_id<T: SerializableId>(id: impl Into<String>) -> Result<T, Status> {
+    T::try_create(id).map_err(invalid)
+}
+
+pub(crate) fn try_data_connection_id(id: String) -> Result<DataConnectionId, Status> {
+    DataConnectionId::try_create(id).map_err(invalid)
+}
+
+pub(crate) fn try_media_connection_id(id: String) -> Result<MediaConnectionId, Status> {
+    MediaConnectionId::try_create(id).map_err(invalid)
+}
+
+fn required<T>(field: Option<T>, name: &str) -> Result<T, Status> {
+    field.ok_or_else(|| Status::invalid_argument(format!("{} is required", name)))
+}
+
+impl<T: SerializableId> From<SocketInfo<T>> for proto::Socket {
+    fn from(socket: SocketInfo<T>) -> Self {
+        proto::Socket {
+            id: socket.get_id().map(|id| id.id()).unwrap_or_default(),
+            ip: socket.ip().to_string(),
+            port: socket.port() as u32,
+        }
+    }
+}
+
+// Empty id means a socket without id, such as destinations of redirection
+impl<T: SerializableId> TryFrom<proto::Socket> for SocketInfo<T> {
+    type Error = Status;
+
+    fn try_from(socket: proto::Socket) -> Result<Self, Status> {
+        let port = u16::try_from(socket.port)
+            .map_err(|_| Status::invalid_argument(format!("invalid port {}", socket.port)))?;
+        let id = Some(socket.id).filter(|id| !id.is_empty());
+        SocketInfo::try_create(id, &socket.ip, port).map_err(invalid)
+    }
+}
+
+fn try_socket(socket: Option<proto::Socket>) -> Result<Option<SocketInfo<PhantomId>>, Status> {
+    socket.map(SocketInfo::try_from).transpose()
+}
+
+impl From<PeerInfo> for proto::PeerInfo {
+    fn from(peer_info: PeerInfo) -> Self {
+        proto::PeerInfo {
+            peer_id: peer_info.peer_id().as_str().into(),
+            token: peer_info.token().as_str().into(),
+        }
+    }
+}
+
+impl TryFrom<proto::PeerInfo> for PeerInfo {
+    type Error = Status;
+
+    fn try_from(peer_info: proto::PeerInfo) -> Result<Self, Status> {
+        PeerInfo::try_create(peer_info.peer_id, peer_info.token).map_err(invalid)
+    }
+}
+
+impl From<PeerStatusMessage> for proto::PeerStatusMessage {
+    fn from(status: PeerStatusMessage) -> Self {
+        proto::PeerStatusMessage {
+            peer_id: status.peer_id.0,
+            disconnected: status.disconnected,
+        }
+    }
+}
+
+impl From<proto::PeerStatusMessage> for PeerStatusMessage {
+    fn from(status: proto::PeerStatusMessage) -> Self {
+        PeerStatusMessage {
+            peer_id: PeerId::new(status.peer_id),
+            disconnected: status.disconnected,
+        }
+    }
+}
+
+// TIMEOUT is not delivered to clients, so it is mapped to a message without event.
+impl From<PeerEventEnum> for proto::PeerEvent {
+    fn from(event: PeerEventEnum) -> Self {
+        use proto::peer_event::Event;
+
+        let (params, event) = match event {
+            PeerEventEnum::OPEN(event) => (Some(event.params), Some(Event::Open(proto::Empty {}))),
+            PeerEventEnum::CLOSE(event) => {
+                (Some(event.params), Some(Event::Close(proto::Empty {})))
+            }
+            PeerEventEnum::CONNECTION(event) => (
+                Some(event.params),
+                Some(Event::Connection(event.data_params.into())),
+            ),
+            PeerEventEnum::CALL(event) => (
+                Some(event.params),
+                Some(Event::Call(event.call_params.into())),
+            ),
+            PeerEventEnum::ERROR(event) => (
+                Some(event.params),
+                Some(Event::ErrorMessage(event.error_message)),
+            ),
+            PeerEventEnum::TIMEOUT => (None, None),
+        };
+        proto::PeerEvent {
+            params: params.map(Into::into),
+            event,
+        }
+    }
+}
+
+impl From<DataIdWrapper> for proto::DataIdWrapper {
+    fn from(wrapper: DataIdWrapper) -> Self {
+        proto::DataIdWrapper {
+            data_id: wrapper.data_id.id(),
+        }
+    }
+}
+
+impl TryFrom<proto::DataIdWrapper> for DataIdWrapper {
+    type Error = Status;
+
+    fn try_from(wrapper: proto::DataIdWrapper) -> Result<Self, Status> {
+        Ok(DataIdWrapper {
+            data_id: try_id::<DataId>(wrapper.data_id)?,
+        })
+    }
+}
+
+impl From<DataConnectionIdWrapper> for proto::DataConnectionIdWrapper {
+    fn from(wrapper: DataConnectionIdWrapper) -> Self {
+        proto::DataConnectionIdWrapper {
+            data_connection_id: wrapper.data_connection_id.as_str().into(),
+        }
+    }
+}
+
+impl TryFrom<proto::DataConnectionIdWrapper> for DataConnectionIdWrapper {
+    type Error = Status;
+
+    fn try_from(wrapper: proto::DataConnectionIdWrapper) -> Result<Self, Status> {
+        Ok(DataConnectionIdWrapper {
+            data_connection_id: try_data_connection_id(wrapper.data_connection_id)?,
+        })
+    }
+}
+
+impl From<DcInit> for proto::DcInit {
+    fn from(dc_init: DcInit) -> Self {
+        proto::DcInit {
+            ordered: dc_init.ordered,
+            max_packet_life_time: dc_init.maxPacketLifeTime.map(|value| value as u64),
+            max_retransmits: dc_init.maxRetransmits.map(|value| value as u64),
+            protocol: dc_init.protocol,
+            negotiated: dc_init.negotiated,
+            id: dc_init.id.map(|value| value as u64),
+            priority: dc_init.priority,
+        }
+    }
+}
+
+impl From<proto::DcInit> for DcInit {
+    fn from(dc_init: proto::DcInit) -> Self {
+        DcInit {
+            ordered: dc_init.ordered,
+            maxPacketLifeTime: dc_init.max_packet_life_time.map(|value| value as usize),
+            maxRetransmits: dc_init.max_retransmits.map(|value| value as usize),
+            protocol: dc_init.protocol,
+            negotiated: dc_init.negotiated,
+            id: dc_init.id.map(|value| value as usize),
+            priority: dc_init.priority,
+        }
+    }
+}
+
+impl From<ConnectQueryOption> for proto::ConnectQueryOption {
+    fn from(option: ConnectQueryOption) -> Self {
+        proto::ConnectQueryOption {
+            metadata: option.metadata,
+            serialization: option.serialization,
+            dc_init: option.dcInit.map(Into::into),
+        }
+    }
+}
+
+impl From<proto::ConnectQueryOption> for ConnectQueryOption {
+    fn from(option: proto::ConnectQueryOption) -> Self {
+        ConnectQueryOption {
+            metadata: option.metadata,
+            serialization: option.serialization,
+            dcInit: option.dc_init.map(Into::into),
+        }
+    }
+}
+
+impl From<ConnectQuery> for proto::ConnectQuery {
+    fn from(query: ConnectQuery) -> Self {
+        proto::ConnectQuery {
+            peer_id: query.peer_id.0,
+            token: query.token.as_str().into(),
+            options: query.options.map(Into::into),
+            target_id: query.target_id.0,
+            params: query.params.map(Into::into),
+            redirect_params: query.redirect_params.map(Into::into),
+        }
+    }
+}
+
+impl TryFrom<proto::ConnectQuery> for ConnectQuery {
+    type Error = Status;
+
+    fn try_from(query: proto::ConnectQuery) -> Result<Self, Status> {
+        let peer_info = PeerInfo::try_create(query.peer_id, query.token).map_err(invalid)?;
+        Ok(ConnectQuery {
+            peer_id: peer_info.peer_id(),
+            token: peer_info.token(),
+            options: query.options.map(Into::into),
+            target_id: PeerId::new(query.target_id),
+            params: query.params.map(DataIdWrapper::try_from).transpose()?,
+            redirect_params: try_socket(query.redirect_params)?,
+        })
+    }
+}
+
+impl From<RedirectDataParams> for proto::RedirectDataParams {
+    fn from(params: RedirectDataParams) -> Self {
+        proto::RedirectDataParams {
+            feed_params: params.feed_params.map(Into::into),
+            redirect_params: params.redirect_params.map(Into::into),
+        }
+    }
+}
+
+impl TryFrom<proto::RedirectDataParams> for RedirectDataParams {
+    type Error = Status;
+
+    fn try_from(params: proto::RedirectDataParams) -> Result<Self, Status> {
+        Ok(RedirectDataParams {
+            feed_params: params
+                .feed_params
+                .map(DataIdWrapper::try_from)
+                .transpose()?,
+            redirect_params: try_socket(params.redirect_params)?,
+        })
+    }
+}
+
+impl From<RedirectDataResponse> for proto::RedirectDataResponse {
+    fn from(response: RedirectDataResponse) -> Self {
+        proto::RedirectDataResponse {
+            command_type: response.command_type,
+            data_id: response.data_id.id(),
+        }
+    }
+}
+
+impl From<DataConnectionStatus> for proto::DataConnectionStatus {
+    fn from(status: DataConnectionStatus) -> Self {
+        proto::DataConnectionStatus {
+            remote_id: status.remote_id,
+            buffersize: status.buffersize as u64,
+            label: status.label,
+            metadata: status.metadata,
+            open: status.open,
+            reliable: status.reliable,
+            serialization: status.serialization,
+            r#type: status.r#type,
+        }
+    }
+}
+
+impl From<proto::DataConnectionStatus> for DataConnectionStatus {
+    fn from(status: proto::DataConnectionStatus) -> Self {
+        DataConnectionStatus {
+            remote_id: status.remote_id,
+            buffersize: status.buffersize as usize,
+            label: status.label,
+            metadata: status.metadata,
+            open: status.open,
+            reliable: status.reliable,
+            serialization: status.serialization,
+            r#type: status.r#type,
+        }
+    }
+}
+
+impl From<DataConnectionEventEnum> for proto::DataConnectionEvent {
+    fn from(event: DataConnectionEventEnum) -> Self {
+        use proto::data_connection_event::Event;
+
+        let (data_connection_id, event) = match event {
+            DataConnectionEventEnum::OPEN(wrapper) => (
+                wrapper.data_connection_id.as_str().into(),
+                Some(Event::Open(proto::Empty {})),
+            ),
+            DataConnectionEventEnum::CLOSE(wrapper) => (
+                wrapper.data_connection_id.as_str().into(),
+                Some(Event::Close(proto::Empty {})),
+            ),
+            DataConnectionEventEnum::ERROR((data_connection_id, message)) => (
+                data_connection_id.as_str().into(),
+                Some(Event::ErrorMessage(message)),
+            ),
+            DataConnectionEventEnum::TIMEOUT => (String::new(), None),
+        };
+        proto::DataConnectionEvent {
+            data_connection_id,
+            event,
+        }
+    }
+}
+
+impl From<MediaConnectionIdWrapper> for proto::MediaConnectionIdWrapper {
+    fn from(wrapper: MediaConnectionIdWrapper) -> Self {
+        proto::MediaConnectionIdWrapper {
+            media_connection_id: wrapper.media_connection_id.as_str().into(),
+        }
+    }
+}
+
+impl TryFrom<proto::MediaConnectionIdWrapper> for MediaConnectionIdWrapper {
+    type Error = Status;
+
+    fn try_from(wrapper: proto::MediaConnectionIdWrapper) -> Result<Self, Status> {
+        Ok(MediaConnectionIdWrapper {
+            media_connection_id: try_media_connection_id(wrapper.media_connection_id)?,
+        })
+    }
+}
+
+impl From<MediaParams> for proto::MediaParams {
+    fn from(params: MediaParams) -> Self {
+        proto::MediaParams {
+            band_width: params.band_width as u64,
+            codec: params.codec,
+            media_id: params.media_id.id(),
+            rtcp_id: params.rtcp_id.map(|rtcp_id| rtcp_id.id()),
+            payload_type: params.payload_type.map(Into::into),
+            sampling_rate: params.sampling_rate.map(|value| value as u64),
+        }
+    }
+}
+
+impl TryFrom<proto::MediaParams> for MediaParams {
+    type Error = Status;
+
+    fn try_from(params: proto::MediaParams) -> Result<Self, Status> {
+        let payload_type = params
+            .payload_type
+            .map(|payload_type| {
+                u16::try_from(payload_type).map_err(|_| {
+                    Status::invalid_argument(format!("invalid payload_type {}", payload_type))
+                })
+            })
+            .transpose()?;
+        Ok(MediaParams {
+            band_width: params.band_width as usize,
+            codec: params.codec,
+            media_id: try_id::<MediaId>(params.media_id)?,
+            rtcp_id: params.rtcp_id.map(try_id::<RtcpId>).transpose()?,
+            payload_type,
+            sampling_rate: params.sampling_rate.map(|value| value as usize),
+        })
+    }
+}
+
+impl From<Constraints> for proto::Constraints {
+    fn from(constraints: Constraints) -> Self {
+        proto::Constraints {
+            video: constraints.video,
+            video_receive_enabled: constraints.videoReceiveEnabled,
+            audio: constraints.audio,
+            audio_receive_enabled: constraints.audioReceiveEnabled,
+            video_params: constraints.video_params.map(Into::into),
+            audio_params: constraints.audio_params.map(Into::into),
+            metadata: constraints.metadata,
+        }
+    }
+}
+
+impl TryFrom<proto::Constraints> for Constraints {
+    type Error = Status;
+
+    fn try_from(constraints: proto::Constraints) -> Result<Self, Status> {
+        Ok(Constraints {
+            video: constraints.video,
+            videoReceiveEnabled: constraints.video_receive_enabled,
+            audio: constraints.audio,
+            audioReceiveEnabled: constraints.audio_receive_enabled,
+            video_params: constraints
+                .video_params
+                .map(MediaParams::try_from)
+                .transpose()?,
+            audio_params: constraints
+                .audio_params
+                .map(MediaParams::try_from)
+                .transpose()?,
+            metadata: constraints.metadata,
+        })
+    }
+}
+
+impl From<RedirectParameters> for proto::RedirectParameters {
+    fn from(params: RedirectParameters) -> Self {
+        proto::RedirectParameters {
+            video: params.video.map(Into::into),
+            video_rtcp: params.video_rtcp.map(Into::into),
+            audio: params.audio.map(Into::into),
+            audio_rtcp: params.audio_rtcp.map(Into::into),
+        }
+    }
+}
+
+impl TryFrom<proto::RedirectParameters> for RedirectParameters {
+    type Error = Status;
+
+    fn try_from(params: proto::RedirectParameters) -> Result<Self, Status> {
+        Ok(RedirectParameters {
+            video: try_socket(params.video)?,
+            video_rtcp: try_socket(params.video_rtcp)?,
+            audio: try_socket(params.audio)?,
+            audio_rtcp: try_socket(params.audio_rtcp)?,
+        })
+    }
+}
+
+impl From<CallQuery> for proto::CallQuery {
+    fn from(query: CallQuery) -> Self {
+        proto::CallQuery {
+            peer_id: query.peer_id.0,
+            token: query.token.as_str().into(),
+            target_id: query.target_id.0,
+            constraints: query.constraints.map(Into::into),
+            redirect_params: query.redirect_params.map(Into::into),
+        }
+    }
+}
+
+impl TryFrom<proto::CallQuery> for CallQuery {
+    type Error = Status;
+
+    fn try_from(query: proto::CallQuery) -> Result<Self, Status> {
+        let peer_info = PeerInfo::try_create(query.peer_id, query.token).map_err(invalid)?;
+        Ok(CallQuery {
+            peer_id: peer_info.peer_id(),
+            token: peer_info.token(),
+            target_id: PeerId::new(query.target_id),
+            constraints: query.constraints.map(Constraints::try_from).transpose()?,
+            redirect_params: query
+                .redirect_params
+                .map(RedirectParameters::try_from)
+                .transpose()?,
+        })
+    }
+}
+
+impl From<CallResponse> for proto::CallResponse {
+    fn from(response: CallResponse) -> Self {
+        proto::CallResponse {
+            command_type: response.command_type,
+            params: Some(response.params.into()),
+        }
+    }
+}
+
+impl From<AnswerQuery> for proto::AnswerQuery {
+    fn from(query: AnswerQuery) -> Self {
+        proto::AnswerQuery {
+            constraints: Some(query.constraints.into()),
+            redirect_params: query.redirect_params.map(Into::into),
+        }
+    }
+}
+
+impl TryFrom<proto::AnswerQuery> for AnswerQuery {
+    type Error = Status;
+
+    fn try_from(query: proto::AnswerQuery) -> Result<Self, Status> {
+        Ok(AnswerQuery {
+            constraints: Constraints::try_from(required(query.constraints, "constraints")?)?,
+            redirect_params: query
+                .redirect_params
+                .map(RedirectParameters::try_from)
+                .transpose()?,
+        })
+    }
+}
+
+impl From<AnswerResponse> for proto::AnswerResponse {
+    fn from(response: AnswerResponse) -> Self {
+        proto::AnswerResponse {
+            command_type: response.command_type,
+            params: Some(proto::AnswerResponseParams {
+                video_id: response.params.video_id.map(|media_id| media_id.id()),
+                audio_id: response.params.audio_id.map(|media_id| media_id.id()),
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::AnswerResponse> for AnswerResponse {
+    type Error = Status;
+
+    fn try_from(response: proto::AnswerResponse) -> Result<Self, Status> {
+        let params = required(response.params, "params")?;
+        Ok(AnswerResponse {
+            command_type: response.command_type,
+            params: AnswerResponseParams {
+                video_id: params.video_id.map(try_id::<MediaId>).transpose()?,
+                audio_id: params.audio_id.map(try_id::<MediaId>).transpose()?,
+            },
+        })
+    }
+}
+
+// An empty list of SSRCs is same as a status without SSRC information.
+impl From<MediaConnectionStatus> for proto::MediaConnectionStatus {
+    fn from(status: MediaConnectionStatus) -> Self {
+        proto::MediaConnectionStatus {
+            metadata: status.metadata,
+            open: status.open,
+            remote_id: status.remote_id.0,
+            ssrc: status
+                .ssrc
+                .unwrap_or_default()
+                .into_iter()
+                .map(|pair| proto::SsrcPair {
+                    media_id: pair.media_id.id(),
+                    ssrc: pair.ssrc as u64,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<proto::MediaConnectionStatus> for MediaConnectionStatus {
+    type Error = Status;
+
+    fn try_from(status: proto::MediaConnectionStatus) -> Result<Self, Status> {
+        let ssrc = status
+            .ssrc
+            .into_iter()
+            .map(|pair| {
+                Ok(SsrcPair {
+                    media_id: try_id::<MediaId>(pair.media_id)?,
+                    ssrc: pair.ssrc as usize,
+                })
+            })
+            .collect::<Result<Vec<_>, Status>>()?;
+        Ok(MediaConnectionStatus {
+            metadata: status.metadata,
+            open: status.open,
+            remote_id: PeerId::new(status.remote_id),
+            ssrc: Some(ssrc).filter(|ssrc| !ssrc.is_empty()),
+        })
+    }
+}
+
+impl From<MediaConnectionEventEnum> for proto::MediaConnectionEvent {
+    fn from(event: MediaConnectionEventEnum) -> Self {
+        use proto::media_connection_event::Event;
+
+        let (media_connection_id, event) = match event {
+            MediaConnectionEventEnum::READY(wrapper) => (
+                wrapper.media_connection_id.as_str().into(),
+                Some(Event::Ready(proto::Empty {})),
+            ),
+            MediaConnectionEventEnum::STREAM(wrapper) => (
+                wrapper.media_connection_id.as_str().into(),
+                Some(Event::Stream(proto::Empty {})),
+            ),
+            MediaConnectionEventEnum::CLOSE(wrapper) => (
+                wrapper.media_connection_id.as_str().into(),
+                Some(Event::Close(proto::Empty {})),
+            ),
+            MediaConnectionEventEnum::ERROR((media_connection_id, message)) => (
+                media_connection_id.as_str().into(),
+                Some(Event::ErrorMessage(message)),
+            ),
+            MediaConnectionEventEnum::TIMEOUT => (String::new(), None),
+        };
+        proto::MediaConnectionEvent {
+            media_connection_id,
+            event,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_convert {
+    use super::*;
+    use crate::peer::Token;
+
+    const TOKEN: &str = "pt-9749250e-d157-4f80-9ee2-359ce8524308";
+
+    #[test]
+    fn connect_query_round_trip() {
+        let query = ConnectQuery {
+            peer_id: PeerId::new("robot"),
+            token: Token::try_create(TOKEN).unwrap(),
+            options: Some(ConnectQueryOption {
+                metadata: Some("meta".into()),
+                serialization: Some("BINARY".into()),
+                dcInit: Some(DcInit {
+                    ordered: Some(true),
+                    maxPacketLifeTime: None,
+                    maxRetransmits: Some(3),
+                    protocol: None,
+                    negotiated: None,
+                    id: None,
+                    priority: Some("high".into()),
+                }),
+            }),
+            target_id: PeerId::new("operator"),
+            params: Some(DataIdWrapper {
+                data_id: DataId::try_create("da-50a32bab-b3d9-4913-8e20-f79c90a6a211").unwrap(),
+            }),
+            redirect_params: Some(
+                SocketInfo::<PhantomId>::try_create(None, "127.0.0.1", 10001).unwrap(),
+            ),
+        };
+        let message = proto::ConnectQuery::from(query.clone());
+        assert_eq!(message.redirect_params.as_ref().unwrap().id, "");
+        assert_eq!(ConnectQuery::try_from(message).unwrap(), query);
+    }
+
+    #[test]
+    fn call_and_answer_query_round_trip() {
+        let constraints = Constraints {
+            video: true,
+            videoReceiveEnabled: Some(false),
+            audio: false,
+            audioReceiveEnabled: None,
+            video_params: Some(MediaParams {
+                band_width: 1500,
+                codec: "H264".into(),
+                media_id: MediaId::try_create("vi-4d053831-5dc2-461b-a358-d062d6115216").unwrap(),
+                rtcp_id: Some(
+                    RtcpId::try_create("rc-970f2e4d-4a67-4e4d-8b22-81e2c9b7d2b0").unwrap(),
+                ),
+                payload_type: Some(100),
+                sampling_rate: Some(90000),
+            }),
+            audio_params: None,
+            metadata: None,
+        };
+        let redirect_params = RedirectParameters {
+            video: Some(SocketInfo::try_create(None, "127.0.0.1", 20000).unwrap()),
+            video_rtcp: None,
+            audio: None,
+            audio_rtcp: Some(SocketInfo::try_create(None, "::1", 20003).unwrap()),
+        };
+        let query = CallQuery {
+            peer_id: PeerId::new("robot"),
+            token: Token::try_create(TOKEN).unwrap(),
+            target_id: PeerId::new("operator"),
+            constraints: Some(constraints.clone()),
+            redirect_params: Some(redirect_params.clone()),
+        };
+        assert_eq!(
+            CallQuery::try_from(proto::CallQuery::from(query.clone())).unwrap(),
+            query
+        );
+
+        let query = AnswerQuery {
+            constraints,
+            redirect_params: Some(redirect_params),
+        };
+        assert_eq!(
+            AnswerQuery::try_from(proto::AnswerQuery::from(query.clone())).unwrap(),
+            query
+        );
+    }
+
+    #[test]
+    fn status_round_trip() {
+        let status = MediaConnectionStatus {
+            metadata: "".into(),
+            open: true,
+            remote_id: PeerId::new("operator"),
+            ssrc: Some(vec![SsrcPair {
+                media_id: MediaId::try_create("au-4d053831-5dc2-461b-a358-d062d6115216").unwrap(),
+                ssrc: 42,
+            }]),
+        };
+        assert_eq!(
+            MediaConnectionStatus::try_from(proto::MediaConnectionStatus::from(status.clone()))
+                .unwrap(),
+            status
+        );
+
+        let status = DataConnectionStatus {
+            remote_id: "operator".into(),
+            buffersize: 0,
+            label: "c_1".into(),
+            metadata: "".into(),
+            open: true,
+            reliable: true,
+            serialization: "BINARY_UTF8".into(),
+            r#type: "DATA".into(),
+        };
+        assert_eq!(
+            DataConnectionStatus::from(proto::DataConnectionStatus::from(status.clone())),
+            status
+        );
+    }
+
+    #[test]
+    fn invalid_messages() {
+        let mut message = proto::ConnectQuery::from(ConnectQuery {
+            peer_id: PeerId::new("robot"),
+            token: Token::try_create(TOKEN).unwrap(),
+            options: None,
+            target_id: PeerId::new("operator"),
+            params: None,
+            redirect_params: None,
+        });
+        message.token = "invalid".into();
+        assert_eq!(
+            ConnectQuery::try_from(message).unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+
+        let socket = proto::Socket {
+            id: "".into(),
+            ip: "127.0.0.1".into(),
+            port: 70000,
+        };
+        assert_eq!(
+            SocketInfo::<PhantomId>::try_from(socket)
+                .unwrap_err()
+                .code(),
+            tonic::Code::InvalidArgument
+        );
+
+        let query = proto::AnswerQuery {
+            constraints: None,
+            redirect_params: None,
+        };
+        assert_eq!(
+            AnswerQuery::try_from(query).unwrap_err().code(),
+            tonic::Code::InvalidArgument
+        );
+    }
+}
@@ -0,0 +1,405 @@
+//! gRPC service mirroring the functions in `peer`, `data` and `media`.
+//!
+//! Messages are defined in `proto/gateway.proto`, and each of them maps to a struct of this crate.
+//! Events of a PeerObject or a connection are delivered by server-streaming RPCs,
+//! which keep long-polling the WebRTC Gateway while the client is reading the stream.
+
+// tonic::Status is the error type of every RPC
+#![allow(clippy::result_large_err)]
+mod convert;
+
+/// Messages and service generated from `proto/gateway.proto`
+///
+/// The generated client has no `connect`. Create it with `GatewayControllerClient::new`
+/// from a `tonic::transport::Channel`.
+pub mod proto {
+    tonic::include_proto!("skyway.gateway.v1");
+}
+
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use futures::channel::mpsc;
+use futures::*;
+use tokio_util::sync::CancellationToken;
+use tonic::{Request, Response, Status};
+
+use crate::common::formats::{PhantomId, SerializableSocket, SocketInfo};
+use crate::data::{ConnectQuery, DataConnectionIdWrapper, DataId, RedirectDataParams};
+use crate::error;
+use crate::media::{AnswerQuery, CallQuery, MediaConnectionIdWrapper, MediaId, RtcpId};
+use crate::peer::{PeerId, PeerInfo};
+use crate::registry::Registry;
+use convert::{try_data_connection_id, try_id, try_media_connection_id};
+use proto::gateway_controller_server::{GatewayController, GatewayControllerServer};
+
+/// Stream of events returned from server-streaming RPCs
+pub type EventStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// Implementation of `GatewayController`.
+///
+/// PeerObjects, connections and sockets created through the service are registered to the registry,
+/// so that they can be released with `shutdown::shutdown`.
+#[derive(Debug, Clone)]
+pub struct GatewayService {
+    registry: Registry,
+}
+
+impl GatewayService {
+    pub fn new(registry: Registry) -> Self {
+        Self { registry }
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+}
+
+// The listener is driven by the returned stream, so it stops when the client drops the stream.
+// An error of the listener is sent to the client as the last item.
+fn event_stream<E, T>(
+    listener: impl Future<Output = Result<(), error::Error>> + Send + 'static,
+    receiver: mpsc::Receiver<E>,
+) -> EventStream<T>
+where
+    E: Send + 'static,
+    T: From<E> + Send + 'static,
+{
+    let events = receiver.map(|event| Ok(T::from(event)));
+    let result = listener
+        .into_stream()
+        .filter_map(|result| future::ready(result.err().map(|e| Err(Status::from(e)))));
+    Box::pin(stream::select(events, result))
+}
+
+#[tonic::async_trait]
+impl GatewayController for GatewayService {
+    async fn create_peer(
+        &self,
+        request: Request<proto::CreatePeerRequest>,
+    ) -> Result<Response<proto::PeerInfo>, Status> {
+        let request = request.into_inner();
+        let peer_info = crate::peer::create(
+            request.api_key,
+            request.domain,
+            PeerId::new(request.peer_id),
+            request.turn,
+        )
+        .await?;
+        self.registry.register_peer(peer_info.clone());
+        Ok(Response::new(peer_info.into()))
+    }
+
+    async fn delete_peer(
+        &self,
+        request: Request<proto::PeerInfo>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let peer_info = PeerInfo::try_from(request.into_inner())?;
+        crate::peer::delete(&peer_info).await?;
+        self.registry.unregister_peer(&peer_info);
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn peer_status(
+        &self,
+        request: Request<proto::PeerInfo>,
+    ) -> Result<Response<proto::PeerStatusMessage>, Status> {
+        let peer_info = PeerInfo::try_from(request.into_inner())?;
+        let status = crate::peer::status(&peer_info).await?;
+        Ok(Response::new(status.into()))
+    }
+
+    type PeerEventsStream = EventStream<proto::PeerEvent>;
+
+    async fn peer_events(
+        &self,
+        request: Request<proto::PeerInfo>,
+    ) -> Result<Response<Self::PeerEventsStream>, Status> {
+        let peer_info = PeerInfo::try_from(request.into_inner())?;
+        let (sender, receiver) = mpsc::channel(0);
+        let listener = crate::peer::listen_events(peer_info, sender);
+        Ok(Response::new(event_stream(listener, receiver)))
+    }
+
+    async fn open_data_socket(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::Socket>, Status> {
+        let socket = crate::data::open_data_socket().await?;
+        if let Some(data_id) = socket.get_id() {
+            self.registry.register_data_socket(data_id);
+        }
+        Ok(Response::new(socket.into()))
+    }
+
+    async fn close_data_socket(
+        &self,
+        request: Request<proto::DataIdWrapper>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let data_id = try_id::<DataId>(request.into_inner().data_id)?;
+        crate::data::close_data_socket(&data_id).await?;
+        self.registry.unregister_data_socket(&data_id);
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn connect_data(
+        &self,
+        request: Request<proto::ConnectQuery>,
+    ) -> Result<Response<proto::DataConnectionIdWrapper>, Status> {
+        let query = ConnectQuery::try_from(request.into_inner())?;
+        let data_connection_id = crate::data::connect(query).await?;
+        self.registry
+            .register_data_connection(data_connection_id.clone());
+        Ok(Response::new(
+            DataConnectionIdWrapper { data_connection_id }.into(),
+        ))
+    }
+
+    async fn redirect(
+        &self,
+        request: Request<proto::RedirectDataRequest>,
+    ) -> Result<Response<proto::RedirectDataResponse>, Status> {
+        let request = request.into_inner();
+        let data_connection_id = try_data_connection_id(request.data_connection_id)?;
+        let params = RedirectDataParams::try_from(request.params.unwrap_or_default())?;
+        let response = crate::data::redirect(&data_connection_id, &params).await?;
+        Ok(Response::new(response.into()))
+    }
+
+    async fn disconnect_data(
+        &self,
+        request: Request<proto::DataConnectionIdWrapper>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let wrapper = DataConnectionIdWrapper::try_from(request.into_inner())?;
+        crate::data::disconnect(&wrapper.data_connection_id).await?;
+        self.registry
+            .unregister_data_connection(&wrapper.data_connection_id);
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn data_status(
+        &self,
+        request: Request<proto::DataConnectionIdWrapper>,
+    ) -> Result<Response<proto::DataConnectionStatus>, Status> {
+        let wrapper = DataConnectionIdWrapper::try_from(request.into_inner())?;
+        let status = crate::data::status(&wrapper.data_connection_id).await?;
+        Ok(Response::new(status.into()))
+    }
+
+    type DataEventsStream = EventStream<proto::DataConnectionEvent>;
+
+    async fn data_events(
+        &self,
+        request: Request<proto::DataConnectionIdWrapper>,
+    ) -> Result<Response<Self::DataEventsStream>, Status> {
+        let wrapper = DataConnectionIdWrapper::try_from(request.into_inner())?;
+        let (sender, receiver) = mpsc::channel(0);
+        let listener = crate::data::listen_events(wrapper.data_connection_id, sender);
+        Ok(Response::new(event_stream(listener, receiver)))
+    }
+
+    async fn open_media_socket(
+        &self,
+        request: Request<proto::OpenMediaSocketRequest>,
+    ) -> Result<Response<proto::Socket>, Status> {
+        let socket = crate::media::open_media_socket(request.into_inner().is_video).await?;
+        if let Some(media_id) = socket.get_id() {
+            self.registry.register_media_socket(media_id);
+        }
+        Ok(Response::new(socket.into()))
+    }
+
+    async fn delete_media_socket(
+        &self,
+        request: Request<proto::MediaIdWrapper>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let media_id = try_id::<MediaId>(request.into_inner().media_id)?;
+        crate::media::delete_media(&media_id).await?;
+        self.registry.unregister_media_socket(&media_id);
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn open_rtcp_socket(
+        &self,
+        _request: Request<proto::Empty>,
+    ) -> Result<Response<proto::Socket>, Status> {
+        let socket = crate::media::open_rtcp_socket().await?;
+        if let Some(rtcp_id) = socket.get_id() {
+            self.registry.register_rtcp_socket(rtcp_id);
+        }
+        Ok(Response::new(socket.into()))
+    }
+
+    async fn delete_rtcp_socket(
+        &self,
+        request: Request<proto::RtcpIdWrapper>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let rtcp_id = try_id::<RtcpId>(request.into_inner().rtcp_id)?;
+        crate::media::delete_rtcp(&rtcp_id).await?;
+        self.registry.unregister_rtcp_socket(&rtcp_id);
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn call(
+        &self,
+        request: Request<proto::CallQuery>,
+    ) -> Result<Response<proto::CallResponse>, Status> {
+        let query = CallQuery::try_from(request.into_inner())?;
+        let response = crate::media::call(&query).await?;
+        self.registry
+            .register_media_connection(response.params.media_connection_id.clone());
+        Ok(Response::new(response.into()))
+    }
+
+    async fn answer(
+        &self,
+        request: Request<proto::AnswerRequest>,
+    ) -> Result<Response<proto::AnswerResponse>, Status> {
+        let request = request.into_inner();
+        let media_connection_id = try_media_connection_id(request.media_connection_id)?;
+        let query = AnswerQuery::try_from(request.query.unwrap_or_default())?;
+        let response = crate::media::answer(&media_connection_id, &query).await?;
+        Ok(Response::new(response.into()))
+    }
+
+    async fn disconnect_media(
+        &self,
+        request: Request<proto::MediaConnectionIdWrapper>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let wrapper = MediaConnectionIdWrapper::try_from(request.into_inner())?;
+        crate::media::disconnect(&wrapper.media_connection_id).await?;
+        self.registry
+            .unregister_media_connection(&wrapper.media_connection_id);
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn send_pli(
+        &self,
+        request: Request<proto::SendPliRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let request = request.into_inner();
+        let media_connection_id = try_media_connection_id(request.media_connection_id)?;
+        let target = SocketInfo::<PhantomId>::try_from(request.params.unwrap_or_default())?;
+        crate::media::send_pli(&media_connection_id, &target).await?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn media_status(
+        &self,
+        request: Request<proto::MediaConnectionIdWrapper>,
+    ) -> Result<Response<proto::MediaConnectionStatus>, Status> {
+        let wrapper = MediaConnectionIdWrapper::try_from(request.into_inner())?;
+        let status = crate::media::status(&wrapper.media_connection_id).await?;
+        Ok(Response::new(status.into()))
+    }
+
+    type MediaEventsStream = EventStream<proto::MediaConnectionEvent>;
+
+    async fn media_events(
+        &self,
+        request: Request<proto::MediaConnectionIdWrapper>,
+    ) -> Result<Response<Self::MediaEventsStream>, Status> {
+        let wrapper = MediaConnectionIdWrapper::try_from(request.into_inner())?;
+        let (sender, receiver) = mpsc::channel(0);
+        let listener = crate::media::listen_events(wrapper.media_connection_id, sender);
+        Ok(Response::new(event_stream(listener, receiver)))
+    }
+}
+
+/// Serve the gRPC service until the token is cancelled.
+pub async fn serve(
+    addr: SocketAddr,
+    service: GatewayService,
+    token: CancellationToken,
+) -> Result<(), error::Error> {
+    tonic::transport::Server::builder()
+        .add_service(GatewayControllerServer::new(service))
+        .serve_with_shutdown(addr, async move { token.cancelled().await })
+        .await
+        .map_err(|e| error::Error::create_local_error(&format!("server error: {:?}", e)))
+}
+
+#[cfg(test)]
+mod test_grpc {
+    use mockito::mock;
+    use tokio::net::TcpListener;
+
+    use super::proto::gateway_controller_client::GatewayControllerClient;
+    use super::*;
+    use crate::data::DataConnectionId;
+
+    const DATA_CONNECTION_ID: &str = "dc-8bdef7a1-65c8-46be-a82e-37d51c776309";
+
+    async fn start(service: GatewayService) -> GatewayControllerClient<tonic::transport::Channel> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(GatewayControllerServer::new(service))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+        let channel = tonic::transport::Endpoint::new(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        GatewayControllerClient::new(channel)
+    }
+
+    #[tokio::test]
+    async fn disconnect_and_stream_events() {
+        crate::initialize(mockito::server_url());
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/data/connections/{}", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+        let events_mock = mock(
+            "GET",
+            format!("/data/connections/{}/events", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"event": "CLOSE"}"#)
+        .create();
+
+        let registry = Registry::new();
+        let data_connection_id = DataConnectionId::try_create(DATA_CONNECTION_ID).unwrap();
+        registry.register_data_connection(data_connection_id.clone());
+        let mut client = start(GatewayService::new(registry.clone())).await;
+        let wrapper = proto::DataConnectionIdWrapper {
+            data_connection_id: DATA_CONNECTION_ID.into(),
+        };
+
+        let mut events = client
+            .data_events(wrapper.clone())
+            .await
+            .unwrap()
+            .into_inner();
+        let event = events.message().await.unwrap().unwrap();
+        assert_eq!(
+            event,
+            proto::DataConnectionEvent {
+                data_connection_id: DATA_CONNECTION_ID.into(),
+                event: Some(proto::data_connection_event::Event::Close(proto::Empty {})),
+            }
+        );
+        assert!(events.message().await.unwrap().is_none());
+        events_mock.assert();
+
+        client.disconnect_data(wrapper).await.unwrap();
+        assert!(registry.snapshot().data_connections.is_empty());
+        disconnect_mock.assert();
+
+        // invalid ids are rejected without accessing the gateway
+        let status = client
+            .disconnect_data(proto::DataConnectionIdWrapper {
+                data_connection_id: "invalid".into(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
@@ -1,4 +1,6 @@
 // This code is from https://stackoverflow.com/questions/56384447/how-do-i-transform-special-values-into-optionnone-when-using-serde-to-deserial
+use std::net::{IpAddr, Ipv4Addr};
+
 use serde::de::Deserializer;
 use serde::Deserialize;
 
@@ -37,3 +39,15 @@ where
         }
     }
 }
+
+// Data sockets of bridges are bound to localhost unless configured.
+pub fn default_bind_ip() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+// 32-bit FNV-1a hash
+pub fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5u32, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
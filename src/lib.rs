@@ -1,17 +1,51 @@
+/// Access control for connections from neighbours
+pub mod access;
+/// Bridge pub/sub topics over named DataConnections
+pub mod bridge;
+/// C ABI of this crate
+#[cfg(feature = "capi")]
+pub mod capi;
+/// Commands shared by the WebSocket and MQTT APIs
+pub mod command;
 /// common fields
 pub mod common;
 /// /data api bindings
 pub mod data;
 /// Definition of errors occur in this crate
 pub mod error;
+/// Merges events of peers, data and media into one stream
+pub mod event_bus;
+/// gRPC service to control WebRTC Gateway
+#[cfg(feature = "grpc")]
+pub mod grpc;
 /// helper to load yaml
 pub(crate) mod helper;
 /// /media api bindings
 pub mod media;
+/// MQTT bridge of events, data and commands
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
 /// /peers api bindings
 pub mod peer;
 /// A "prelude" for users of this crate.
 pub mod prelude;
+/// Python extension module built with maturin
+#[cfg(feature = "python")]
+pub mod python;
+/// Keeps track of resources allocated in a WebRTC Gateway
+pub mod registry;
+/// Local REST API to control WebRTC Gateway
+#[cfg(feature = "server")]
+pub mod server;
+/// Declarative description of peers and connections brought up together
+#[cfg(feature = "session")]
+pub mod session;
+/// Graceful shutdown of resources in a WebRTC Gateway
+pub mod shutdown;
+/// State machines of PeerObjects and connections driven by events
+pub mod state;
+/// Traffic statistics of UDP bridges for data and media
+pub mod stats;
 
 use std::sync::Once;
 
@@ -0,0 +1,481 @@
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Format of capture files
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureFormat {
+    /// libpcap format with raw IP packets. Wireshark shows them as UDP, and decodes RTP with "Decode As".
+    Pcap,
+    /// rtpdump format of rtptools. A file holds packets sent to a socket.
+    Rtpdump,
+}
+
+impl CaptureFormat {
+    /// Extension of capture files
+    pub fn extension(&self) -> &'static str {
+        match self {
+            CaptureFormat::Pcap => "pcap",
+            CaptureFormat::Rtpdump => "rtpdump",
+        }
+    }
+}
+
+/// UDP datagram in a capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedPacket {
+    /// Time when the packet is received
+    pub time: SystemTime,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const RTPDUMP_SHEBANG: &str = "#!rtpplay1.0";
+
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+fn ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+// checksum of IPv4 header
+fn checksum(header: &[u8]) -> u16 {
+    let sum = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+    let sum = (sum & 0xffff) + (sum >> 16);
+    !((sum & 0xffff) + (sum >> 16)) as u16
+}
+
+// IP and UDP headers in front of the payload.
+// UDP checksum is left 0, which means it's not computed.
+fn ip_udp_headers(packet: &CapturedPacket) -> Vec<u8> {
+    let udp_len = (8 + packet.payload.len()) as u16;
+    let mut headers = match (packet.source.ip(), packet.destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let mut header = vec![0x45, 0];
+            header.extend((20 + udp_len).to_be_bytes());
+            // identification, don't fragment, ttl and udp
+            header.extend([0, 0, 0x40, 0, 64, 17, 0, 0]);
+            header.extend(source.octets());
+            header.extend(destination.octets());
+            let sum = checksum(&header);
+            header[10..12].copy_from_slice(&sum.to_be_bytes());
+            header
+        }
+        (source, destination) => {
+            let mut header = vec![0x60, 0, 0, 0];
+            header.extend(udp_len.to_be_bytes());
+            // udp and hop limit
+            header.extend([17, 64]);
+            header.extend(ipv6(source));
+            header.extend(ipv6(destination));
+            header
+        }
+    };
+    headers.extend(packet.source.port().to_be_bytes());
+    headers.extend(packet.destination.port().to_be_bytes());
+    headers.extend(udp_len.to_be_bytes());
+    headers.extend([0, 0]);
+    headers
+}
+
+/// Writes packets in libpcap format.
+#[derive(Debug)]
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the global header.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        // version 2.4
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        // timezone and accuracy of timestamps
+        writer.write_all(&[0; 8])?;
+        // snapshot length
+        writer.write_all(&65535u32.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn write_packet(&mut self, packet: &CapturedPacket) -> io::Result<()> {
+        let headers = ip_udp_headers(packet);
+        let len = (headers.len() + packet.payload.len()) as u32;
+        let time = since_epoch(packet.time);
+        self.writer
+            .write_all(&(time.as_secs() as u32).to_le_bytes())?;
+        self.writer.write_all(&time.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(&headers)?;
+        self.writer.write_all(&packet.payload)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes packets sent to a socket in rtpdump format.
+#[derive(Debug)]
+pub struct RtpdumpWriter<W: Write> {
+    writer: W,
+    start: SystemTime,
+    rtcp: bool,
+}
+
+impl<W: Write> RtpdumpWriter<W> {
+    /// Write the file header.
+    ///
+    /// `destination` is the socket to which packets are sent.
+    /// Offsets of packets are recorded from `start`.
+    /// If `rtcp` is true, packets are recorded as RTCP.
+    pub fn new(
+        mut writer: W,
+        start: SystemTime,
+        destination: SocketAddr,
+        rtcp: bool,
+    ) -> io::Result<Self> {
+        writeln!(
+            writer,
+            "{} {}/{}",
+            RTPDUMP_SHEBANG,
+            destination.ip(),
+            destination.port()
+        )?;
+        let time = since_epoch(start);
+        writer.write_all(&(time.as_secs() as u32).to_be_bytes())?;
+        writer.write_all(&time.subsec_micros().to_be_bytes())?;
+        let source = match destination.ip() {
+            IpAddr::V4(ip) => u32::from(ip),
+            IpAddr::V6(_) => 0,
+        };
+        writer.write_all(&source.to_be_bytes())?;
+        writer.write_all(&destination.port().to_be_bytes())?;
+        writer.write_all(&[0, 0])?;
+        Ok(Self {
+            writer,
+            start,
+            rtcp,
+        })
+    }
+
+    pub fn write_packet(&mut self, packet: &CapturedPacket) -> io::Result<()> {
+        let len = packet.payload.len() as u16;
+        let offset = packet
+            .time
+            .duration_since(self.start)
+            .unwrap_or_default()
+            .as_millis() as u32;
+        self.writer.write_all(&(len + 8).to_be_bytes())?;
+        // length of RTP packet, or 0 for RTCP
+        let plen = if self.rtcp { 0 } else { len };
+        self.writer.write_all(&plen.to_be_bytes())?;
+        self.writer.write_all(&offset.to_be_bytes())?;
+        self.writer.write_all(&packet.payload)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Read packets from a capture file written by `PcapWriter`, `RtpdumpWriter`, tcpdump or Wireshark.
+///
+/// The format is detected from the content. In pcap files, frames other than UDP over IPv4 or IPv6 are skipped.
+/// Packets in rtpdump files have no source address, so it's set to `0.0.0.0:0`.
+pub fn read_capture(mut reader: impl Read) -> io::Result<Vec<CapturedPacket>> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    if data.starts_with(RTPDUMP_SHEBANG.as_bytes()) {
+        read_rtpdump(&data)
+    } else {
+        read_pcap(&data)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn truncated() -> io::Error {
+    invalid("capture is truncated")
+}
+
+fn read_pcap(data: &[u8]) -> io::Result<Vec<CapturedPacket>> {
+    let header = data.get(..24).ok_or_else(truncated)?;
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let (little_endian, nanos) = match magic {
+        PCAP_MAGIC => (true, false),
+        PCAP_MAGIC_NANOS => (true, true),
+        _ if magic.swap_bytes() == PCAP_MAGIC => (false, false),
+        _ if magic.swap_bytes() == PCAP_MAGIC_NANOS => (false, true),
+        _ => return Err(invalid("unknown capture format")),
+    };
+    let u32_at = |bytes: &[u8], offset: usize| {
+        let bytes = [
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ];
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    };
+    let link_type = u32_at(header, 20);
+
+    let mut packets = vec![];
+    let mut rest = &data[24..];
+    while !rest.is_empty() {
+        let record = rest.get(..16).ok_or_else(truncated)?;
+        let len = u32_at(record, 8) as usize;
+        let frame = rest.get(16..16 + len).ok_or_else(truncated)?;
+        rest = &rest[16 + len..];
+
+        let fraction = u32_at(record, 4) as u64;
+        let time = UNIX_EPOCH
+            + Duration::from_secs(u32_at(record, 0) as u64)
+            + if nanos {
+                Duration::from_nanos(fraction)
+            } else {
+                Duration::from_micros(fraction)
+            };
+        let ip = match link_type {
+            LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(frame),
+            LINKTYPE_ETHERNET => ethernet_payload(frame),
+            LINKTYPE_LINUX_SLL => frame.get(16..),
+            _ => return Err(invalid("unsupported link type")),
+        };
+        if let Some((source, destination, payload)) = ip.and_then(parse_ip_udp) {
+            packets.push(CapturedPacket {
+                time,
+                source,
+                destination,
+                payload: payload.to_vec(),
+            });
+        }
+    }
+    Ok(packets)
+}
+
+fn ethernet_payload(frame: &[u8]) -> Option<&[u8]> {
+    match frame.get(12..14)? {
+        // VLAN tag
+        [0x81, 0x00] => frame.get(18..),
+        _ => frame.get(14..),
+    }
+}
+
+// Returns source, destination and payload of a UDP datagram in an IP packet.
+fn parse_ip_udp(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (source, destination, udp): (IpAddr, IpAddr, &[u8]) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = ((packet[0] & 0x0f) * 4) as usize;
+            if *packet.get(9)? != 17 {
+                return None;
+            }
+            let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                Ipv4Addr::from(source).into(),
+                Ipv4Addr::from(destination).into(),
+                packet.get(header_len..)?,
+            )
+        }
+        6 => {
+            if *packet.get(6)? != 17 {
+                return None;
+            }
+            let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                Ipv6Addr::from(source).into(),
+                Ipv6Addr::from(destination).into(),
+                packet.get(40..)?,
+            )
+        }
+        _ => return None,
+    };
+    let source_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+    let destination_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+    let len = u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize;
+    Some((
+        SocketAddr::new(source, source_port),
+        SocketAddr::new(destination, destination_port),
+        udp.get(8..len.max(8))?,
+    ))
+}
+
+fn read_rtpdump(data: &[u8]) -> io::Result<Vec<CapturedPacket>> {
+    let line_end = data
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or_else(truncated)?;
+    let line =
+        std::str::from_utf8(&data[..line_end]).map_err(|_| invalid("invalid rtpdump header"))?;
+    let destination = line
+        .trim_start_matches(RTPDUMP_SHEBANG)
+        .trim()
+        .rsplit_once('/')
+        .and_then(|(ip, port)| Some(SocketAddr::new(ip.parse().ok()?, port.parse().ok()?)))
+        .ok_or_else(|| invalid("invalid rtpdump header"))?;
+    let header = data
+        .get(line_end + 1..line_end + 17)
+        .ok_or_else(truncated)?;
+    let start = UNIX_EPOCH
+        + Duration::from_secs(
+            u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64,
+        )
+        + Duration::from_micros(
+            u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as u64,
+        );
+
+    let mut packets = vec![];
+    let mut rest = &data[line_end + 17..];
+    while !rest.is_empty() {
+        let record = rest.get(..8).ok_or_else(truncated)?;
+        let len = u16::from_be_bytes([record[0], record[1]]) as usize;
+        let offset = u32::from_be_bytes([record[4], record[5], record[6], record[7]]);
+        let payload = rest.get(8..len.max(8)).ok_or_else(truncated)?;
+        rest = &rest[len.max(8)..];
+        packets.push(CapturedPacket {
+            time: start + Duration::from_millis(offset as u64),
+            source: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            destination,
+            payload: payload.to_vec(),
+        });
+    }
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod test_capture {
+    use super::*;
+
+    fn packet(payload: &[u8]) -> CapturedPacket {
+        CapturedPacket {
+            time: UNIX_EPOCH + Duration::from_micros(1_600_000_000_250_000),
+            source: "10.0.0.1:50000".parse().unwrap(),
+            destination: "127.0.0.1:20000".parse().unwrap(),
+            payload: payload.to_vec(),
+        }
+    }
+
+    #[test]
+    fn write_pcap() {
+        let mut buf = vec![];
+        let mut writer = PcapWriter::new(&mut buf).unwrap();
+        writer.write_packet(&packet(&[0x80, 0x60, 0, 1])).unwrap();
+        assert_eq!(buf.len(), 24 + 16 + 28 + 4);
+        assert_eq!(&buf[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(&buf[20..24], &LINKTYPE_RAW.to_le_bytes());
+        // record header
+        assert_eq!(&buf[24..28], &1_600_000_000u32.to_le_bytes());
+        assert_eq!(&buf[28..32], &250_000u32.to_le_bytes());
+        assert_eq!(&buf[32..36], &32u32.to_le_bytes());
+        // IPv4 header with a valid checksum
+        let ip = &buf[40..60];
+        assert_eq!(ip[0], 0x45);
+        assert_eq!(checksum(ip), 0);
+        assert_eq!(&ip[12..16], &[10, 0, 0, 1]);
+        // UDP header
+        assert_eq!(&buf[60..68], &[0xc3, 0x50, 0x4e, 0x20, 0, 12, 0, 0]);
+        assert_eq!(&buf[68..], &[0x80, 0x60, 0, 1]);
+
+        let mut packet = packet(&[]);
+        packet.destination = "[::1]:20000".parse().unwrap();
+        assert_eq!(ip_udp_headers(&packet).len(), 48);
+    }
+
+    #[test]
+    fn write_rtpdump() {
+        let mut buf = vec![];
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let destination = "127.0.0.1:20000".parse().unwrap();
+        let mut writer = RtpdumpWriter::new(&mut buf, start, destination, false).unwrap();
+        writer.write_packet(&packet(&[0x80, 0x60, 0, 1])).unwrap();
+        let header = b"#!rtpplay1.0 127.0.0.1/20000\n";
+        assert_eq!(&buf[..header.len()], header);
+        let buf = &buf[header.len()..];
+        assert_eq!(&buf[..4], &1_600_000_000u32.to_be_bytes());
+        assert_eq!(&buf[8..12], &[127, 0, 0, 1]);
+        assert_eq!(&buf[16..24], &[0, 12, 0, 4, 0, 0, 0, 250]);
+        assert_eq!(&buf[24..], &[0x80, 0x60, 0, 1]);
+    }
+
+    #[test]
+    fn read_written_captures() {
+        let packets = vec![packet(&[0x80, 0x60, 0, 1]), {
+            let mut packet = packet(&[0x80, 0x60, 0, 2]);
+            packet.time += Duration::from_millis(20);
+            packet.source = "[fe80::1]:50000".parse().unwrap();
+            packet.destination = "[::1]:20000".parse().unwrap();
+            packet
+        }];
+        let mut buf = vec![];
+        let mut writer = PcapWriter::new(&mut buf).unwrap();
+        for packet in packets.iter() {
+            writer.write_packet(packet).unwrap();
+        }
+        assert_eq!(read_capture(buf.as_slice()).unwrap(), packets);
+
+        let mut buf = vec![];
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let destination = "127.0.0.1:20000".parse().unwrap();
+        let mut writer = RtpdumpWriter::new(&mut buf, start, destination, false).unwrap();
+        for packet in packets.iter() {
+            writer.write_packet(packet).unwrap();
+        }
+        let read = read_capture(buf.as_slice()).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[1].time, packets[1].time);
+        assert_eq!(read[1].destination, destination);
+        assert_eq!(read[1].payload, packets[1].payload);
+
+        buf.pop();
+        assert!(read_capture(buf.as_slice()).is_err());
+        assert!(read_capture(&b"not a capture file"[..]).is_err());
+    }
+
+    #[test]
+    fn read_ethernet_frames() {
+        let mut buf = vec![];
+        PcapWriter::new(&mut buf).unwrap();
+        buf[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        let packet = packet(&[0x80, 0x60, 0, 1]);
+        let mut frame = vec![0; 12];
+        frame.extend([0x08, 0x00]);
+        frame.extend(ip_udp_headers(&packet));
+        frame.extend(&packet.payload);
+        buf.extend(1_600_000_000u32.to_le_bytes());
+        buf.extend(250_000u32.to_le_bytes());
+        buf.extend((frame.len() as u32).to_le_bytes());
+        buf.extend((frame.len() as u32).to_le_bytes());
+        buf.extend(frame);
+        assert_eq!(read_capture(buf.as_slice()).unwrap(), vec![packet]);
+    }
+}
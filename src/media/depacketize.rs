@@ -0,0 +1,415 @@
+use serde::{Deserialize, Serialize};
+
+use super::rtp::RtpPacket;
+
+/// Codec of RTP payloads
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    H264,
+    Vp8,
+    Opus,
+    /// G.711 μ-law
+    Pcmu,
+    /// G.711 A-law
+    Pcma,
+}
+
+impl Codec {
+    pub fn is_video(&self) -> bool {
+        matches!(self, Codec::H264 | Codec::Vp8)
+    }
+
+    /// RTP clock rate
+    pub fn clock_rate(&self) -> u32 {
+        match self {
+            Codec::H264 | Codec::Vp8 => 90000,
+            Codec::Opus => 48000,
+            Codec::Pcmu | Codec::Pcma => 8000,
+        }
+    }
+
+    /// Parse a codec name of `MediaParams::codec`. `"G711"` is regarded as μ-law.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "H264" => Some(Codec::H264),
+            "VP8" => Some(Codec::Vp8),
+            "OPUS" => Some(Codec::Opus),
+            "G711" | "PCMU" => Some(Codec::Pcmu),
+            "PCMA" => Some(Codec::Pcma),
+            _ => None,
+        }
+    }
+
+    /// Encoding name in SDP
+    pub fn encoding_name(&self) -> &'static str {
+        match self {
+            Codec::H264 => "H264",
+            Codec::Vp8 => "VP8",
+            Codec::Opus => "OPUS",
+            Codec::Pcmu => "PCMU",
+            Codec::Pcma => "PCMA",
+        }
+    }
+
+    /// Static payload type of RFC 3551
+    pub fn static_payload_type(&self) -> Option<u8> {
+        match self {
+            Codec::Pcmu => Some(0),
+            Codec::Pcma => Some(8),
+            _ => None,
+        }
+    }
+}
+
+/// Encoded frame assembled from RTP payloads
+///
+/// H264 frames are sequences of NAL units with 4-byte length prefixes (AVC format).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// RTP timestamp shared by the packets of the frame
+    pub timestamp: u32,
+    pub keyframe: bool,
+    pub data: Vec<u8>,
+}
+
+/// Assembles frames from RTP packets of a stream.
+///
+/// Packets are expected in order. Late packets are dropped, and a lost packet discards the frame.
+/// Video frames are dropped after a loss until the next keyframe arrives,
+/// since they can't be decoded without the lost reference.
+#[derive(Debug, Clone)]
+pub struct Depacketizer {
+    codec: Codec,
+    next_sequence: Option<u16>,
+    timestamp: Option<u32>,
+    data: Vec<u8>,
+    keyframe: bool,
+    // a packet of the frame being assembled is lost
+    broken: bool,
+    wait_keyframe: bool,
+    // H264 NAL unit being reassembled from FU-A
+    fragment: Option<Vec<u8>>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl Depacketizer {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            next_sequence: None,
+            timestamp: None,
+            data: vec![],
+            keyframe: false,
+            broken: false,
+            wait_keyframe: codec.is_video(),
+            fragment: None,
+            sps: None,
+            pps: None,
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Latest SPS and PPS of an H264 stream
+    pub fn parameter_sets(&self) -> Option<(&[u8], &[u8])> {
+        match (&self.sps, &self.pps) {
+            (Some(sps), Some(pps)) => Some((sps, pps)),
+            _ => None,
+        }
+    }
+
+    /// Feed an RTP packet, and returns frames completed by it.
+    pub fn push(&mut self, packet: &[u8]) -> Vec<Frame> {
+        let packet = match RtpPacket::parse(packet) {
+            Some(packet) => packet,
+            None => return vec![],
+        };
+        let mut lost = false;
+        if let Some(expected) = self.next_sequence {
+            match packet.sequence.wrapping_sub(expected) as i16 {
+                diff if diff < 0 => return vec![],
+                0 => {}
+                _ => lost = true,
+            }
+        }
+        self.next_sequence = Some(packet.sequence.wrapping_add(1));
+
+        if !self.codec.is_video() {
+            return vec![Frame {
+                timestamp: packet.timestamp,
+                keyframe: true,
+                data: packet.payload.to_vec(),
+            }];
+        }
+
+        let mut frames = vec![];
+        if self.timestamp != Some(packet.timestamp) {
+            // the lost packet may belong to either frame
+            self.broken |= lost;
+            frames.extend(self.finish());
+            self.timestamp = Some(packet.timestamp);
+            self.broken = lost;
+        } else {
+            self.broken |= lost;
+        }
+        if lost {
+            self.fragment = None;
+        }
+        match self.codec {
+            Codec::Vp8 => self.push_vp8(packet.payload),
+            _ => self.push_h264(packet.payload),
+        }
+        if packet.marker {
+            frames.extend(self.finish());
+        }
+        frames
+    }
+
+    fn finish(&mut self) -> Option<Frame> {
+        let data = std::mem::take(&mut self.data);
+        let keyframe = std::mem::replace(&mut self.keyframe, false);
+        let broken = std::mem::replace(&mut self.broken, false);
+        self.fragment = None;
+        if broken {
+            self.wait_keyframe = true;
+            return None;
+        }
+        if data.is_empty() {
+            return None;
+        }
+        if self.wait_keyframe && !keyframe {
+            return None;
+        }
+        self.wait_keyframe = false;
+        Some(Frame {
+            timestamp: self.timestamp.unwrap_or_default(),
+            keyframe,
+            data,
+        })
+    }
+
+    // RFC 7741
+    fn push_vp8(&mut self, payload: &[u8]) {
+        let (start, offset) = match vp8_descriptor(payload) {
+            Some(descriptor) => descriptor,
+            None => {
+                self.broken = true;
+                return;
+            }
+        };
+        let body = &payload[offset..];
+        if start {
+            self.data.clear();
+            self.keyframe = body.first().map(|byte| byte & 0x01 == 0).unwrap_or(false);
+        } else if self.data.is_empty() {
+            // the first packet of the frame is missing
+            self.broken = true;
+            return;
+        }
+        self.data.extend_from_slice(body);
+    }
+
+    // RFC 6184 with single NAL unit packets, STAP-A and FU-A
+    fn push_h264(&mut self, payload: &[u8]) {
+        let nal_type = match payload.first() {
+            Some(header) => header & 0x1f,
+            None => return,
+        };
+        match nal_type {
+            1..=23 => self.push_nal(payload),
+            24 => {
+                let mut rest = &payload[1..];
+                while rest.len() > 2 {
+                    let size = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    if size == 0 || rest.len() < 2 + size {
+                        self.broken = true;
+                        return;
+                    }
+                    self.push_nal(&rest[2..2 + size]);
+                    rest = &rest[2 + size..];
+                }
+            }
+            28 if payload.len() > 2 => {
+                let header = payload[1];
+                if header & 0x80 != 0 {
+                    let mut nal = vec![(payload[0] & 0xe0) | (header & 0x1f)];
+                    nal.extend_from_slice(&payload[2..]);
+                    self.fragment = Some(nal);
+                } else if let Some(ref mut nal) = self.fragment {
+                    nal.extend_from_slice(&payload[2..]);
+                } else {
+                    self.broken = true;
+                    return;
+                }
+                if header & 0x40 != 0 {
+                    if let Some(nal) = self.fragment.take() {
+                        self.push_nal(&nal);
+                    }
+                }
+            }
+            _ => self.broken = true,
+        }
+    }
+
+    fn push_nal(&mut self, nal: &[u8]) {
+        match nal[0] & 0x1f {
+            5 => self.keyframe = true,
+            7 => self.sps = Some(nal.to_vec()),
+            8 => self.pps = Some(nal.to_vec()),
+            _ => {}
+        }
+        self.data.extend((nal.len() as u32).to_be_bytes());
+        self.data.extend_from_slice(nal);
+    }
+}
+
+// Returns whether the packet starts a frame and the size of the payload descriptor.
+fn vp8_descriptor(payload: &[u8]) -> Option<(bool, usize)> {
+    let first = *payload.first()?;
+    let start = first & 0x10 != 0 && first & 0x07 == 0;
+    let mut offset = 1;
+    if first & 0x80 != 0 {
+        let extension = *payload.get(1)?;
+        offset += 1;
+        if extension & 0x80 != 0 {
+            // 15 bits picture id if M bit is set
+            offset += if payload.get(offset)? & 0x80 != 0 {
+                2
+            } else {
+                1
+            };
+        }
+        if extension & 0x40 != 0 {
+            offset += 1;
+        }
+        if extension & 0x30 != 0 {
+            offset += 1;
+        }
+    }
+    if offset >= payload.len() {
+        return None;
+    }
+    Some((start, offset))
+}
+
+/// Width and height in a VP8 keyframe header
+pub fn vp8_dimensions(frame: &[u8]) -> Option<(u16, u16)> {
+    if frame.len() < 10 || frame[0] & 0x01 != 0 || frame[3..6] != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+    let width = u16::from_le_bytes([frame[6], frame[7]]) & 0x3fff;
+    let height = u16::from_le_bytes([frame[8], frame[9]]) & 0x3fff;
+    Some((width, height))
+}
+
+#[cfg(test)]
+mod test_depacketizer {
+    use super::*;
+    use crate::media::rtp::test_rtp::rtp;
+
+    #[test]
+    fn h264_stap_a_and_fu_a() {
+        let mut depacketizer = Depacketizer::new(Codec::H264);
+        let sps = [0x67, 0x42, 0xc0, 0x1f];
+        let pps = [0x68, 0xce, 0x3c];
+        let mut stap_a = vec![0x78, 0, 4];
+        stap_a.extend(sps);
+        stap_a.extend([0, 3]);
+        stap_a.extend(pps);
+        assert!(depacketizer
+            .push(&rtp(1, 3000, 7, false, &stap_a))
+            .is_empty());
+        // IDR slice split into 2 fragments
+        assert!(depacketizer
+            .push(&rtp(2, 3000, 7, false, &[0x7c, 0x85, 1, 2]))
+            .is_empty());
+        let frames = depacketizer.push(&rtp(3, 3000, 7, true, &[0x7c, 0x45, 3]));
+
+        let mut data = vec![0, 0, 0, 4];
+        data.extend(sps);
+        data.extend([0, 0, 0, 3]);
+        data.extend(pps);
+        data.extend([0, 0, 0, 4, 0x65, 1, 2, 3]);
+        assert_eq!(
+            frames,
+            vec![Frame {
+                timestamp: 3000,
+                keyframe: true,
+                data
+            }]
+        );
+        assert_eq!(depacketizer.parameter_sets(), Some((&sps[..], &pps[..])));
+
+        // a lost fragment discards the frame, and following delta frames until a keyframe
+        assert!(depacketizer
+            .push(&rtp(4, 6000, 7, false, &[0x5c, 0x81, 1]))
+            .is_empty());
+        assert!(depacketizer
+            .push(&rtp(6, 6000, 7, true, &[0x5c, 0x41, 2]))
+            .is_empty());
+        assert!(depacketizer
+            .push(&rtp(7, 9000, 7, true, &[0x41, 9]))
+            .is_empty());
+        let frames = depacketizer.push(&rtp(8, 12000, 7, true, &[0x65, 8]));
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].keyframe);
+        assert_eq!(frames[0].timestamp, 12000);
+        // late packet
+        assert!(depacketizer
+            .push(&rtp(5, 6000, 7, true, &[0x41, 9]))
+            .is_empty());
+    }
+
+    #[test]
+    fn vp8_frames() {
+        let mut depacketizer = Depacketizer::new(Codec::Vp8);
+        // delta frame before the first keyframe is dropped
+        assert!(depacketizer
+            .push(&rtp(10, 0, 7, true, &[0x10, 0x01]))
+            .is_empty());
+        // keyframe with an extended descriptor carrying a 15 bits picture id
+        let keyframe = [0x9d, 0x01, 0x2a, 0x80, 0x02, 0xe0, 0x01];
+        let mut first = vec![0x90, 0x80, 0x80, 0x01, 0x00, 0x00, 0x00];
+        first.extend(keyframe);
+        assert!(depacketizer
+            .push(&rtp(11, 3000, 7, false, &first))
+            .is_empty());
+        let frames = depacketizer.push(&rtp(12, 3000, 7, true, &[0x00, 0xaa]));
+        let mut data = vec![0x00, 0x00, 0x00];
+        data.extend(keyframe);
+        data.push(0xaa);
+        assert_eq!(
+            frames,
+            vec![Frame {
+                timestamp: 3000,
+                keyframe: true,
+                data: data.clone()
+            }]
+        );
+        assert_eq!(vp8_dimensions(&data), Some((640, 480)));
+
+        let frames = depacketizer.push(&rtp(13, 6000, 7, true, &[0x10, 0x01, 0xbb]));
+        assert_eq!(frames.len(), 1);
+        assert!(!frames[0].keyframe);
+    }
+
+    #[test]
+    fn audio_frames() {
+        let mut depacketizer = Depacketizer::new(Codec::Opus);
+        let frames = depacketizer.push(&rtp(1, 960, 7, false, &[0xfc, 0xff, 0xfe]));
+        assert_eq!(
+            frames,
+            vec![Frame {
+                timestamp: 960,
+                keyframe: true,
+                data: vec![0xfc, 0xff, 0xfe]
+            }]
+        );
+        // audio survives losses
+        assert_eq!(depacketizer.push(&rtp(5, 4800, 7, false, &[0xfc])).len(), 1);
+    }
+}
@@ -0,0 +1,632 @@
+use std::f64::consts::PI;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+use super::depacketize::Codec;
+use crate::common::formats::{SerializableId, SerializableSocket, SocketInfo};
+use crate::error;
+use crate::helper::fnv1a;
+use crate::media::formats::{MediaId, MediaParams};
+
+// maximum size of RTP payloads, which keeps packets below common MTUs
+const MAX_PAYLOAD: usize = 1200;
+const MAX_FRAME_RATE: usize = 30;
+// audio packets carry 20ms
+const AUDIO_PACKET_MS: u64 = 20;
+
+const VP8_WIDTH: u16 = 160;
+const VP8_HEIGHT: u16 = 120;
+// enough for the headers and modes of all macroblocks of the frame
+const VP8_FIRST_PARTITION: usize = 2048;
+const VP8_DCT_PARTITION: usize = 512;
+
+const H264_WIDTH_MBS: usize = 4;
+const H264_HEIGHT_MBS: usize = 3;
+
+/// Parameters for Generator
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GeneratorConfig {
+    pub codec: Codec,
+    pub payload_type: u8,
+    pub ssrc: u32,
+    /// Target bitrate in kbps as `MediaParams::band_width`
+    pub band_width: usize,
+    /// Frequency of the G.711 tone in Hz
+    #[serde(default = "default_tone_frequency")]
+    pub tone_frequency: f64,
+}
+
+fn default_tone_frequency() -> f64 {
+    440.0
+}
+
+impl GeneratorConfig {
+    /// Generate the media described by MediaParams given to `media::call` or `media::answer`.
+    ///
+    /// Dynamic payload types default to 96 for video and 111 for Opus if it's not given.
+    /// The SSRC is derived from the MediaId, so that each socket has its own stream.
+    pub fn from_params(params: &MediaParams) -> Result<Self, error::Error> {
+        let codec = Codec::from_name(&params.codec).ok_or_else(|| {
+            error::Error::create_local_error(&format!("unsupported codec {}", params.codec))
+        })?;
+        let payload_type = match params.payload_type {
+            Some(payload_type) if payload_type < 128 => payload_type as u8,
+            Some(payload_type) => {
+                return Err(error::Error::create_local_error(&format!(
+                    "invalid payload type {}",
+                    payload_type
+                )))
+            }
+            None => match codec.static_payload_type() {
+                Some(payload_type) => payload_type,
+                None if codec.is_video() => 96,
+                None => 111,
+            },
+        };
+        let ssrc = fnv1a(params.media_id.as_str().as_bytes());
+        Ok(Self {
+            codec,
+            payload_type,
+            ssrc,
+            band_width: params.band_width,
+            tone_frequency: default_tone_frequency(),
+        })
+    }
+}
+
+/// Result of Generator::run
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct GeneratorReport {
+    pub frames: u64,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// Produces RTP streams of synthetic media without cameras or encoders.
+///
+/// - VP8: mid-gray keyframes of 160x120
+/// - H264: keyframes of 64x48 with a moving gradient made of I_PCM macroblocks
+/// - Opus: silence
+/// - G.711: a sine tone
+///
+/// Every video frame is a keyframe, so receivers can decode from any point.
+/// Video frames are padded and the frame rate is lowered so that the bitrate follows `band_width`.
+/// Opus packets are padded likewise, while G.711 is always 64kbps.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    config: GeneratorConfig,
+    frame_rate: usize,
+    // bytes added to each frame to reach the bitrate
+    padding: usize,
+    sequence: u16,
+    timestamp: u32,
+    frames: u64,
+}
+
+impl Generator {
+    pub fn new(config: GeneratorConfig) -> Self {
+        let bytes_per_sec = config.band_width * 1000 / 8;
+        let (frame_rate, padding) = match config.codec {
+            Codec::Vp8 | Codec::H264 => {
+                let size = match config.codec {
+                    Codec::Vp8 => vp8_keyframe(0).len(),
+                    _ => h264_keyframe(0, 0).iter().map(Vec::len).sum(),
+                };
+                let frame_rate = (bytes_per_sec / size).clamp(1, MAX_FRAME_RATE);
+                (
+                    frame_rate,
+                    (bytes_per_sec / frame_rate).saturating_sub(size),
+                )
+            }
+            _ => (
+                (1000 / AUDIO_PACKET_MS) as usize,
+                bytes_per_sec * AUDIO_PACKET_MS as usize / 1000,
+            ),
+        };
+        Self {
+            config,
+            frame_rate,
+            padding,
+            sequence: 0,
+            timestamp: 0,
+            frames: 0,
+        }
+    }
+
+    /// Frames or audio packets per second
+    pub fn frame_rate(&self) -> usize {
+        self.frame_rate
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.frame_rate as u32
+    }
+
+    /// RTP packets of the next frame
+    pub fn next_packets(&mut self) -> Vec<Vec<u8>> {
+        let index = self.frames;
+        let payloads = match self.config.codec {
+            Codec::Vp8 => packetize_vp8(&vp8_keyframe(self.padding)),
+            Codec::H264 => packetize_h264(&h264_keyframe(index, self.padding)),
+            Codec::Opus => vec![opus_silence(self.padding)],
+            Codec::Pcmu | Codec::Pcma => vec![self.g711_tone(index)],
+        };
+        let count = payloads.len();
+        let packets = payloads
+            .into_iter()
+            .enumerate()
+            .map(|(i, payload)| {
+                let mut packet = vec![0x80, self.config.payload_type];
+                if i + 1 == count && self.config.codec.is_video() {
+                    packet[1] |= 0x80;
+                }
+                packet.extend(self.sequence.to_be_bytes());
+                packet.extend(self.timestamp.to_be_bytes());
+                packet.extend(self.config.ssrc.to_be_bytes());
+                packet.extend(payload);
+                self.sequence = self.sequence.wrapping_add(1);
+                packet
+            })
+            .collect();
+        self.frames += 1;
+        self.timestamp = self
+            .timestamp
+            .wrapping_add(self.config.codec.clock_rate() / self.frame_rate as u32);
+        packets
+    }
+
+    fn g711_tone(&self, index: u64) -> Vec<u8> {
+        let samples = 8000 * AUDIO_PACKET_MS / 1000;
+        (0..samples)
+            .map(|i| {
+                let t = (index * samples + i) as f64 / 8000.0;
+                let sample = ((2.0 * PI * self.config.tone_frequency * t).sin() * 8000.0) as i16;
+                match self.config.codec {
+                    Codec::Pcma => linear_to_alaw(sample),
+                    _ => linear_to_ulaw(sample),
+                }
+            })
+            .collect()
+    }
+
+    /// Send the stream to a media socket until the token is cancelled.
+    pub async fn run(
+        mut self,
+        destination: &SocketInfo<MediaId>,
+        token: CancellationToken,
+    ) -> Result<GeneratorReport, error::Error> {
+        let bind_addr = match destination.addr() {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        let mut interval = tokio::time::interval(self.interval());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut report = GeneratorReport::default();
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => return Ok(report),
+                _ = interval.tick() => {}
+            }
+            for packet in self.next_packets() {
+                socket.send_to(&packet, destination.addr()).await?;
+                report.packets += 1;
+                report.bytes += packet.len() as u64;
+            }
+            report.frames += 1;
+        }
+    }
+}
+
+// VP8 keyframe whose partitions are all zero. Zero bytes decode to zero bits with any probabilities
+// of the boolean decoder, which select no updates, B_PRED with B_DC_PRED subblocks and EOB tokens.
+fn vp8_keyframe(padding: usize) -> Vec<u8> {
+    // keyframe, version 0, shown
+    let tag = 0x10 | ((VP8_FIRST_PARTITION as u32) << 5);
+    let mut frame = tag.to_le_bytes()[..3].to_vec();
+    frame.extend([0x9d, 0x01, 0x2a]);
+    frame.extend(VP8_WIDTH.to_le_bytes());
+    frame.extend(VP8_HEIGHT.to_le_bytes());
+    frame.resize(
+        frame.len() + VP8_FIRST_PARTITION + VP8_DCT_PARTITION + padding,
+        0,
+    );
+    frame
+}
+
+// RFC 7741
+fn packetize_vp8(frame: &[u8]) -> Vec<Vec<u8>> {
+    frame
+        .chunks(MAX_PAYLOAD - 1)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut payload = vec![if i == 0 { 0x10 } else { 0x00 }];
+            payload.extend_from_slice(chunk);
+            payload
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn bit(&mut self, bit: bool) {
+        if self.bits == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.bits;
+        }
+        self.bits = (self.bits + 1) % 8;
+    }
+
+    fn u(&mut self, length: u32, value: u32) {
+        for i in (0..length).rev() {
+            self.bit(value >> i & 1 == 1);
+        }
+    }
+
+    // Exp-Golomb
+    fn ue(&mut self, value: u32) {
+        let length = 32 - (value + 1).leading_zeros();
+        self.u(length - 1, 0);
+        self.u(length, value + 1);
+    }
+
+    fn align_zero(&mut self) {
+        while self.bits != 0 {
+            self.bit(false);
+        }
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.u(8, byte as u32);
+    }
+
+    fn trailing(mut self) -> Vec<u8> {
+        self.bit(true);
+        self.align_zero();
+        self.bytes
+    }
+}
+
+// NAL unit with emulation prevention bytes
+fn nal_unit(header: u8, rbsp: &[u8]) -> Vec<u8> {
+    let mut nal = vec![header];
+    let mut zeros = 0;
+    for byte in rbsp {
+        if zeros >= 2 && *byte <= 3 {
+            nal.push(3);
+            zeros = 0;
+        }
+        nal.push(*byte);
+        zeros = if *byte == 0 { zeros + 1 } else { 0 };
+    }
+    nal
+}
+
+// SPS, PPS, IDR slice of I_PCM macroblocks, and filler data of constrained baseline profile
+fn h264_keyframe(index: u64, padding: usize) -> Vec<Vec<u8>> {
+    let mut sps = BitWriter::default();
+    sps.byte(66);
+    // constraint_set0_flag and constraint_set1_flag
+    sps.byte(0xc0);
+    // level 3.1
+    sps.byte(31);
+    sps.ue(0);
+    // log2_max_frame_num_minus4
+    sps.ue(0);
+    // pic_order_cnt_type
+    sps.ue(2);
+    // max_num_ref_frames
+    sps.ue(1);
+    sps.bit(false);
+    sps.ue(H264_WIDTH_MBS as u32 - 1);
+    sps.ue(H264_HEIGHT_MBS as u32 - 1);
+    // frame_mbs_only_flag, direct_8x8_inference_flag, frame_cropping_flag, vui_parameters_present_flag
+    sps.u(4, 0b1100);
+
+    let mut pps = BitWriter::default();
+    pps.ue(0);
+    pps.ue(0);
+    // entropy_coding_mode_flag, bottom_field_pic_order_in_frame_present_flag
+    pps.u(2, 0);
+    // num_slice_groups_minus1, num_ref_idx_l0/l1_default_active_minus1
+    pps.ue(0);
+    pps.ue(0);
+    pps.ue(0);
+    // weighted_pred_flag, weighted_bipred_idc
+    pps.u(3, 0);
+    // pic_init_qp_minus26, pic_init_qs_minus26, chroma_qp_index_offset as se(0)
+    pps.ue(0);
+    pps.ue(0);
+    pps.ue(0);
+    // deblocking_filter_control_present_flag, constrained_intra_pred_flag, redundant_pic_cnt_present_flag
+    pps.u(3, 0);
+
+    let mut slice = BitWriter::default();
+    // first_mb_in_slice, slice_type I, pic_parameter_set_id
+    slice.ue(0);
+    slice.ue(7);
+    slice.ue(0);
+    // frame_num
+    slice.u(4, 0);
+    // consecutive IDR pictures need different idr_pic_id
+    slice.ue((index % 2) as u32);
+    // no_output_of_prior_pics_flag, long_term_reference_flag
+    slice.u(2, 0);
+    // slice_qp_delta
+    slice.ue(0);
+    for mb in 0..H264_WIDTH_MBS * H264_HEIGHT_MBS {
+        // I_PCM
+        slice.ue(25);
+        slice.align_zero();
+        let (mb_x, mb_y) = (mb % H264_WIDTH_MBS, mb / H264_WIDTH_MBS);
+        for y in 0..16 {
+            for x in 0..16 {
+                let position = mb_x * 16 + x + mb_y * 16 + y + index as usize * 4;
+                slice.byte(16 + (position % 220) as u8);
+            }
+        }
+        for _ in 0..128 {
+            slice.byte(128);
+        }
+    }
+
+    let mut nals = vec![
+        nal_unit(0x67, &sps.trailing()),
+        nal_unit(0x68, &pps.trailing()),
+        nal_unit(0x65, &slice.trailing()),
+    ];
+    if padding > 2 {
+        let mut filler = vec![0xff; padding - 2];
+        filler.push(0x80);
+        nals.push(nal_unit(0x0c, &filler));
+    }
+    nals
+}
+
+// RFC 6184 with single NAL unit packets and FU-A
+fn packetize_h264(nals: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut payloads = vec![];
+    for nal in nals {
+        if nal.len() <= MAX_PAYLOAD {
+            payloads.push(nal.clone());
+            continue;
+        }
+        let chunks: Vec<&[u8]> = nal[1..].chunks(MAX_PAYLOAD - 2).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut header = nal[0] & 0x1f;
+            if i == 0 {
+                header |= 0x80;
+            }
+            if i + 1 == chunks.len() {
+                header |= 0x40;
+            }
+            let mut payload = vec![(nal[0] & 0xe0) | 28, header];
+            payload.extend_from_slice(chunk);
+            payloads.push(payload);
+        }
+    }
+    payloads
+}
+
+// 20ms CELT frame of silence, padded to `size` bytes with a code 3 packet of RFC 6716
+fn opus_silence(size: usize) -> Vec<u8> {
+    const FRAME: [u8; 2] = [0xff, 0xfe];
+    let size = size.min(1275);
+    if size <= 4 + FRAME.len() {
+        return vec![0xf8, FRAME[0], FRAME[1]];
+    }
+    // TOC, frame count byte, padding length bytes and the frame
+    for length_bytes in 1.. {
+        let padding = size - 2 - length_bytes - FRAME.len();
+        match padding / 254 + 1 {
+            required if required == length_bytes => {
+                let mut packet = vec![0xfb, 0x41];
+                packet.resize(2 + padding / 254, 255);
+                packet.push((padding % 254) as u8);
+                packet.extend(FRAME);
+                packet.resize(size, 0);
+                return packet;
+            }
+            // the size can't be expressed since a length byte carries 254 bytes at most
+            required if required < length_bytes => break,
+            _ => {}
+        }
+    }
+    opus_silence(size - 1)
+}
+
+fn linear_to_ulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+    let (sign, magnitude) = match sample as i32 {
+        sample if sample < 0 => (0x80, -sample),
+        sample => (0x00, sample),
+    };
+    let magnitude = magnitude.min(CLIP) + BIAS;
+    let exponent = (0..8)
+        .rev()
+        .find(|exponent| magnitude & (0x80 << exponent) != 0)
+        .unwrap_or(0);
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0f;
+    !(sign | (exponent << 4) | mantissa) as u8
+}
+
+fn linear_to_alaw(sample: i16) -> u8 {
+    let sample = sample as i32 >> 3;
+    let (mask, magnitude) = if sample >= 0 {
+        (0xd5, sample)
+    } else {
+        (0x55, -sample - 1)
+    };
+    let segment = (0..8)
+        .find(|segment| magnitude <= (0x1f << segment) | ((1 << segment) - 1))
+        .unwrap_or(8);
+    if segment >= 8 {
+        return (0x7f ^ mask) as u8;
+    }
+    let shift = if segment < 2 { 1 } else { segment };
+    (((segment << 4) | ((magnitude >> shift) & 0x0f)) ^ mask) as u8
+}
+
+#[cfg(test)]
+mod test_generator {
+    use super::*;
+    use crate::media::depacketize::{self, Depacketizer};
+    use crate::media::rtp::RtpPacket;
+
+    fn config(codec: Codec, band_width: usize) -> GeneratorConfig {
+        GeneratorConfig {
+            codec,
+            payload_type: 100,
+            ssrc: 42,
+            band_width,
+            tone_frequency: 440.0,
+        }
+    }
+
+    // bytes of payloads sent in a second
+    fn bytes_per_sec(generator: &mut Generator) -> usize {
+        (0..generator.frame_rate())
+            .flat_map(|_| generator.next_packets())
+            .map(|packet| packet.len() - 12)
+            .sum()
+    }
+
+    #[test]
+    fn vp8_keyframes() {
+        let mut generator = Generator::new(config(Codec::Vp8, 1500));
+        assert_eq!(generator.frame_rate(), 30);
+        let mut depacketizer = Depacketizer::new(Codec::Vp8);
+        for i in 0..3u32 {
+            let frames: Vec<_> = generator
+                .next_packets()
+                .iter()
+                .flat_map(|packet| depacketizer.push(packet))
+                .collect();
+            assert_eq!(frames.len(), 1);
+            assert!(frames[0].keyframe);
+            assert_eq!(frames[0].timestamp, i * 3000);
+            assert_eq!(
+                depacketize::vp8_dimensions(&frames[0].data),
+                Some((160, 120))
+            );
+        }
+        let bytes = bytes_per_sec(&mut generator);
+        // descriptors are added to the bitrate
+        assert!((187_500..190_000).contains(&bytes), "{}", bytes);
+
+        // frame rate is lowered for narrow bands
+        let generator = Generator::new(config(Codec::Vp8, 100));
+        assert_eq!(generator.frame_rate(), 4);
+    }
+
+    #[test]
+    fn h264_keyframes() {
+        let mut generator = Generator::new(config(Codec::H264, 2000));
+        let mut depacketizer = Depacketizer::new(Codec::H264);
+        let packets = generator.next_packets();
+        assert!(packets
+            .iter()
+            .all(|packet| packet.len() <= 12 + MAX_PAYLOAD));
+        let rtp = RtpPacket::parse(packets.last().unwrap()).unwrap();
+        assert!(rtp.marker);
+        assert_eq!(rtp.payload_type, 100);
+        let frames: Vec<_> = packets
+            .iter()
+            .flat_map(|packet| depacketizer.push(packet))
+            .collect();
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].keyframe);
+        let (sps, pps) = depacketizer.parameter_sets().unwrap();
+        assert_eq!(sps, &[0x67, 66, 0xc0, 31, 0xda, 0x11, 0xe4]);
+        assert_eq!(pps, &[0x68, 0xce, 0x38, 0x80]);
+        let bytes = bytes_per_sec(&mut generator);
+        assert!((249_000..256_000).contains(&bytes), "{}", bytes);
+    }
+
+    #[test]
+    fn audio_packets() {
+        let mut generator = Generator::new(config(Codec::Opus, 64));
+        assert_eq!(generator.frame_rate(), 50);
+        let packets = generator.next_packets();
+        assert_eq!(packets[0].len(), 12 + 160);
+        assert_eq!(packets[0][12..14], [0xfb, 0x41]);
+        assert_eq!(opus_silence(0), vec![0xf8, 0xff, 0xfe]);
+        for (size, expected) in [(7, 7), (258, 258), (259, 258), (600, 600), (2000, 1275)] {
+            let packet = opus_silence(size);
+            let size = packet.len();
+            assert_eq!(size, expected);
+            let padding_bytes = packet[2..].iter().take_while(|byte| **byte == 255).count();
+            let padding = 254 * padding_bytes + packet[2 + padding_bytes] as usize;
+            assert_eq!(2 + padding_bytes + 1 + 2 + padding, size);
+        }
+        assert_eq!(generator.next_packets()[0][4..8], 960u32.to_be_bytes());
+
+        assert_eq!(linear_to_ulaw(0), 0xff);
+        assert_eq!(linear_to_ulaw(8159), 0x9f);
+        assert_eq!(linear_to_ulaw(32767), 0x80);
+        assert_eq!(linear_to_ulaw(-32768), 0x00);
+        assert_eq!(linear_to_alaw(0), 0xd5);
+        assert_eq!(linear_to_alaw(32767), 0xaa);
+        assert_eq!(linear_to_alaw(-32768), 0x2a);
+        let mut generator = Generator::new(config(Codec::Pcma, 64));
+        let packet = &generator.next_packets()[0];
+        assert_eq!(packet.len(), 12 + 160);
+        assert!(packet[12..].iter().any(|byte| *byte != packet[12]));
+    }
+
+    #[tokio::test]
+    async fn send_to_media_socket() {
+        let media_id = MediaId::try_create("au-4d053831-5dc2-461b-a358-d062d6115216").unwrap();
+        let params = MediaParams {
+            band_width: 64,
+            codec: "G711".into(),
+            media_id: media_id.clone(),
+            rtcp_id: None,
+            payload_type: None,
+            sampling_rate: None,
+        };
+        let config = GeneratorConfig::from_params(&params).unwrap();
+        assert_eq!(config.codec, Codec::Pcmu);
+        assert_eq!(config.payload_type, 0);
+
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let destination = SocketInfo::<MediaId>::try_create(
+            Some(media_id.as_str().into()),
+            "127.0.0.1",
+            gateway.local_addr().unwrap().port(),
+        )
+        .unwrap();
+        let token = CancellationToken::new();
+        let handle = tokio::spawn({
+            let token = token.clone();
+            async move { Generator::new(config).run(&destination, token).await }
+        });
+        let mut buf = vec![0u8; 2048];
+        let started = tokio::time::Instant::now();
+        for i in 0..5u16 {
+            let len = gateway.recv(&mut buf).await.unwrap();
+            let packet = RtpPacket::parse(&buf[..len]).unwrap();
+            assert_eq!(packet.sequence, i);
+            assert_eq!(packet.timestamp, i as u32 * 160);
+            assert_eq!(packet.payload.len(), 160);
+        }
+        // the first packet is sent immediately, and the following ones every 20ms
+        assert!(started.elapsed() >= Duration::from_millis(80));
+        token.cancel();
+        let report = handle.await.unwrap().unwrap();
+        assert!(report.packets >= 5);
+        assert_eq!(report.frames, report.packets);
+    }
+}
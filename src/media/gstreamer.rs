@@ -0,0 +1,448 @@
+use std::io;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+
+use futures::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::process::{Child, Command};
+use tokio_util::sync::CancellationToken;
+
+use super::depacketize::Codec;
+use super::MediaConnectionEventEnum;
+use crate::common::formats::{PhantomId, SerializableId, SerializableSocket, SocketInfo};
+use crate::error;
+use crate::media::formats::{MediaConnectionId, MediaId, MediaParams, RedirectParameters};
+
+/// Video source used if none is given to `send_description`
+pub const DEFAULT_VIDEO_SOURCE: &str = "videotestsrc is-live=true";
+/// Audio source used if none is given to `send_description`
+pub const DEFAULT_AUDIO_SOURCE: &str = "audiotestsrc is-live=true";
+/// Video sink used if none is given to `receive_description`
+pub const DEFAULT_VIDEO_SINK: &str = "autovideosink";
+/// Audio sink used if none is given to `receive_description`
+pub const DEFAULT_AUDIO_SINK: &str = "autoaudiosink";
+
+fn codec_of(params: &MediaParams) -> Result<Codec, error::Error> {
+    let codec = Codec::from_name(&params.codec).ok_or_else(|| {
+        error::Error::create_local_error(&format!("unsupported codec {}", params.codec))
+    })?;
+    let is_video = params.media_id.as_str().starts_with("vi-");
+    if codec.is_video() != is_video {
+        return Err(error::Error::create_local_error(&format!(
+            "{} doesn't match {}",
+            params.codec,
+            params.media_id.as_str()
+        )));
+    }
+    Ok(codec)
+}
+
+fn payload_type(params: &MediaParams, codec: Codec) -> Option<u16> {
+    params
+        .payload_type
+        .or_else(|| codec.static_payload_type().map(u16::from))
+}
+
+/// Pipeline description to feed a media socket opened by `media::open_media_socket`.
+///
+/// The payload type and bitrate follow `MediaParams`, which is given to `media::call` or `media::answer`
+/// with the socket. `band_width` is regarded as kbps.
+/// `source` is a chain of elements producing raw video or audio.
+///
+/// # Examples
+/// ```
+/// use skyway_webrtc_gateway_api::media::gstreamer::send_description;
+/// use skyway_webrtc_gateway_api::media::MediaParams;
+/// use skyway_webrtc_gateway_api::prelude::*;
+///
+/// let media_id = MediaId::try_create("vi-4d053831-5dc2-461b-a358-d062d6115216").unwrap();
+/// let socket = SocketInfo::<MediaId>::try_create(Some(media_id.as_str().to_string()), "127.0.0.1", 10000).unwrap();
+/// let params = MediaParams {
+///     band_width: 1500,
+///     codec: "H264".into(),
+///     media_id,
+///     rtcp_id: None,
+///     payload_type: Some(100),
+///     sampling_rate: Some(90000),
+/// };
+/// let description = send_description(&params, &socket, None).unwrap();
+/// assert!(description.ends_with("rtph264pay config-interval=-1 pt=100 ! udpsink host=127.0.0.1 port=10000"));
+/// ```
+pub fn send_description(
+    params: &MediaParams,
+    socket: &SocketInfo<MediaId>,
+    source: Option<&str>,
+) -> Result<String, error::Error> {
+    if let Some(media_id) = socket.get_id() {
+        if media_id != params.media_id {
+            return Err(error::Error::create_local_error(
+                "MediaParams and the socket refer to different media",
+            ));
+        }
+    }
+    let codec = codec_of(params)?;
+    let pt = payload_type(params, codec)
+        .map(|pt| format!(" pt={}", pt))
+        .unwrap_or_default();
+    let kbps = params.band_width;
+    let chain = match codec {
+        Codec::H264 => format!(
+            "videoconvert ! x264enc tune=zerolatency speed-preset=ultrafast bitrate={} key-int-max=60 ! video/x-h264,profile=constrained-baseline ! rtph264pay config-interval=-1{}",
+            kbps, pt
+        ),
+        Codec::Vp8 => format!(
+            "videoconvert ! vp8enc deadline=1 target-bitrate={} keyframe-max-dist=60 ! rtpvp8pay{}",
+            kbps * 1000,
+            pt
+        ),
+        Codec::Opus => format!(
+            "audioconvert ! audioresample ! audio/x-raw,rate=48000 ! opusenc bitrate={} ! rtpopuspay{}",
+            (kbps * 1000).clamp(4000, 650_000),
+            pt
+        ),
+        Codec::Pcmu => format!(
+            "audioconvert ! audioresample ! audio/x-raw,rate=8000,channels=1 ! mulawenc ! rtppcmupay{}",
+            pt
+        ),
+        Codec::Pcma => format!(
+            "audioconvert ! audioresample ! audio/x-raw,rate=8000,channels=1 ! alawenc ! rtppcmapay{}",
+            pt
+        ),
+    };
+    let source = source.unwrap_or(if codec.is_video() {
+        DEFAULT_VIDEO_SOURCE
+    } else {
+        DEFAULT_AUDIO_SOURCE
+    });
+    Ok(format!(
+        "{} ! {} ! udpsink host={} port={}",
+        source,
+        chain,
+        socket.ip(),
+        socket.port()
+    ))
+}
+
+/// Pipeline description to consume media redirected to `RedirectParameters`.
+///
+/// `params` describes the received media, typically the same as the one given to `media::call` or `media::answer`.
+/// If the RTCP port is redirected too, RTCP is received with rtpbin.
+/// `sink` is a chain of elements consuming raw video or audio.
+pub fn receive_description(
+    params: &MediaParams,
+    redirect: &RedirectParameters,
+    sink: Option<&str>,
+) -> Result<String, error::Error> {
+    let codec = codec_of(params)?;
+    let (rtp, rtcp) = if codec.is_video() {
+        (&redirect.video, &redirect.video_rtcp)
+    } else {
+        (&redirect.audio, &redirect.audio_rtcp)
+    };
+    let rtp: &SocketInfo<PhantomId> = rtp.as_ref().ok_or_else(|| {
+        error::Error::create_local_error("the media is not redirected in RedirectParameters")
+    })?;
+
+    let mut caps = format!(
+        "application/x-rtp,media=(string){},clock-rate=(int){},encoding-name=(string){}",
+        if codec.is_video() { "video" } else { "audio" },
+        params.sampling_rate.unwrap_or(codec.clock_rate() as usize),
+        codec.encoding_name()
+    );
+    if let Some(pt) = payload_type(params, codec) {
+        caps.push_str(&format!(",payload=(int){}", pt));
+    }
+    let chain = match codec {
+        Codec::H264 => "rtph264depay ! h264parse ! avdec_h264 ! videoconvert",
+        Codec::Vp8 => "rtpvp8depay ! vp8dec ! videoconvert",
+        Codec::Opus => "rtpopusdepay ! opusdec ! audioconvert ! audioresample",
+        Codec::Pcmu => "rtppcmudepay ! mulawdec ! audioconvert ! audioresample",
+        Codec::Pcma => "rtppcmadepay ! alawdec ! audioconvert ! audioresample",
+    };
+    let sink = sink.unwrap_or(if codec.is_video() {
+        DEFAULT_VIDEO_SINK
+    } else {
+        DEFAULT_AUDIO_SINK
+    });
+    let source = format!(
+        "udpsrc address={} port={} caps=\"{}\"",
+        rtp.ip(),
+        rtp.port(),
+        caps
+    );
+    Ok(match rtcp {
+        Some(rtcp) => format!(
+            "rtpbin name=rtpbin {} ! rtpbin.recv_rtp_sink_0 rtpbin. ! {} ! {} udpsrc address={} port={} ! rtpbin.recv_rtcp_sink_0",
+            source,
+            chain,
+            sink,
+            rtcp.ip(),
+            rtcp.port()
+        ),
+        None => format!("{} ! rtpjitterbuffer ! {} ! {}", source, chain, sink),
+    })
+}
+
+/// Process running a pipeline
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PipelineProcess {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Wait before restarting a process which exited
+    pub restart_delay_ms: u64,
+}
+
+impl PipelineProcess {
+    /// Run the description with `gst-launch-1.0`.
+    pub fn gst_launch(description: &str) -> Self {
+        Self {
+            program: "gst-launch-1.0".into(),
+            args: vec!["-e".into(), description.into()],
+            restart_delay_ms: 1000,
+        }
+    }
+
+    fn spawn(&self) -> Result<Child, error::Error> {
+        info!("launch {} {:?}", self.program, self.args);
+        Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| {
+                error::Error::create_local_error(&format!("fail to launch {}: {}", self.program, e))
+            })
+    }
+
+    /// Keep the process running for the MediaConnection.
+    ///
+    /// The process is restarted when it exits, and when a STREAM event of the MediaConnection arrives,
+    /// since the remote media may be renegotiated.
+    /// It's killed when a CLOSE event arrives, events end, or the token is cancelled.
+    /// It returns how many times the process is launched.
+    pub async fn supervise<S>(
+        &self,
+        media_connection_id: &MediaConnectionId,
+        mut events: S,
+        token: CancellationToken,
+    ) -> Result<u64, error::Error>
+    where
+        S: Stream<Item = MediaConnectionEventEnum> + Unpin,
+    {
+        enum Next {
+            Cancelled,
+            Exited(io::Result<ExitStatus>),
+            Event(Option<MediaConnectionEventEnum>),
+        }
+
+        let mut child = self.spawn()?;
+        let mut launches = 1;
+        loop {
+            let next = tokio::select! {
+                _ = token.cancelled() => Next::Cancelled,
+                status = child.wait() => Next::Exited(status),
+                event = events.next() => Next::Event(event),
+            };
+            match next {
+                Next::Cancelled => break,
+                Next::Exited(status) => {
+                    warn!("{} exited: {:?}", self.program, status);
+                    tokio::select! {
+                        _ = token.cancelled() => return Ok(launches),
+                        _ = tokio::time::sleep(Duration::from_millis(self.restart_delay_ms)) => {}
+                    }
+                }
+                Next::Event(Some(MediaConnectionEventEnum::STREAM(wrapper)))
+                    if &wrapper.media_connection_id == media_connection_id =>
+                {
+                    let _ = child.kill().await;
+                }
+                Next::Event(Some(MediaConnectionEventEnum::CLOSE(wrapper)))
+                    if &wrapper.media_connection_id == media_connection_id =>
+                {
+                    break
+                }
+                Next::Event(None) => break,
+                Next::Event(_) => continue,
+            }
+            child = self.spawn()?;
+            launches += 1;
+        }
+        let _ = child.kill().await;
+        Ok(launches)
+    }
+}
+
+#[cfg(test)]
+mod test_gstreamer {
+    use futures::channel::mpsc;
+
+    use super::*;
+    use crate::media::formats::MediaConnectionIdWrapper;
+
+    const MEDIA_CONNECTION_ID: &str = "mc-3a9c1e5d-2b7f-4d8e-a6c4-1f0e9d8c7b6a";
+
+    fn params(codec: &str, media_id: &str, payload_type: Option<u16>) -> MediaParams {
+        MediaParams {
+            band_width: 500,
+            codec: codec.into(),
+            media_id: MediaId::try_create(media_id).unwrap(),
+            rtcp_id: None,
+            payload_type,
+            sampling_rate: None,
+        }
+    }
+
+    fn socket(port: u16) -> Option<SocketInfo<PhantomId>> {
+        Some(SocketInfo::try_create(None, "127.0.0.1", port).unwrap())
+    }
+
+    #[test]
+    fn send_descriptions() {
+        let media_id = "au-4d053831-5dc2-461b-a358-d062d6115216";
+        let socket =
+            SocketInfo::<MediaId>::try_create(Some(media_id.into()), "127.0.0.1", 10002).unwrap();
+        assert_eq!(
+            send_description(&params("OPUS", media_id, Some(111)), &socket, None).unwrap(),
+            "audiotestsrc is-live=true ! audioconvert ! audioresample ! audio/x-raw,rate=48000 ! opusenc bitrate=500000 ! rtpopuspay pt=111 ! udpsink host=127.0.0.1 port=10002"
+        );
+        assert_eq!(
+            send_description(&params("G711", media_id, None), &socket, Some("alsasrc")).unwrap(),
+            "alsasrc ! audioconvert ! audioresample ! audio/x-raw,rate=8000,channels=1 ! mulawenc ! rtppcmupay pt=0 ! udpsink host=127.0.0.1 port=10002"
+        );
+        // video codec for an audio socket
+        assert!(send_description(&params("VP8", media_id, None), &socket, None).is_err());
+        // params of another media
+        let other = "au-00000000-5dc2-461b-a358-d062d6115216";
+        assert!(send_description(&params("OPUS", other, None), &socket, None).is_err());
+    }
+
+    #[test]
+    fn receive_descriptions() {
+        let redirect = RedirectParameters {
+            video: socket(20000),
+            video_rtcp: socket(20001),
+            audio: socket(20002),
+            audio_rtcp: None,
+        };
+        let video = params("VP8", "vi-4d053831-5dc2-461b-a358-d062d6115216", Some(96));
+        assert_eq!(
+            receive_description(&video, &redirect, None).unwrap(),
+            "rtpbin name=rtpbin udpsrc address=127.0.0.1 port=20000 caps=\"application/x-rtp,media=(string)video,clock-rate=(int)90000,encoding-name=(string)VP8,payload=(int)96\" ! rtpbin.recv_rtp_sink_0 rtpbin. ! rtpvp8depay ! vp8dec ! videoconvert ! autovideosink udpsrc address=127.0.0.1 port=20001 ! rtpbin.recv_rtcp_sink_0"
+        );
+        let audio = params("OPUS", "au-4d053831-5dc2-461b-a358-d062d6115216", None);
+        assert_eq!(
+            receive_description(&audio, &redirect, Some("fakesink")).unwrap(),
+            "udpsrc address=127.0.0.1 port=20002 caps=\"application/x-rtp,media=(string)audio,clock-rate=(int)48000,encoding-name=(string)OPUS\" ! rtpjitterbuffer ! rtpopusdepay ! opusdec ! audioconvert ! audioresample ! fakesink"
+        );
+        let redirect = RedirectParameters {
+            audio: None,
+            ..redirect
+        };
+        assert!(receive_description(&audio, &redirect, None).is_err());
+    }
+
+    fn launch_log(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("skyway_gstreamer_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn count_lines(path: &std::path::Path) -> usize {
+        std::fs::read_to_string(path)
+            .map(|log| log.lines().count())
+            .unwrap_or(0)
+    }
+
+    // wait until the processes have written the lines
+    async fn logged(path: &std::path::Path, lines: usize) {
+        while count_lines(path) < lines {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn restart_on_stream_and_stop_on_close() {
+        let log = launch_log("events");
+        let process = PipelineProcess {
+            program: "sh".into(),
+            args: vec![
+                "-c".into(),
+                format!("echo launched >> {}; sleep 10", log.display()),
+            ],
+            restart_delay_ms: 10,
+        };
+        let media_connection_id = MediaConnectionId::try_create(MEDIA_CONNECTION_ID).unwrap();
+        let (mut event_notifier, event_observer) = mpsc::channel(10);
+        let handle = tokio::spawn({
+            let media_connection_id = media_connection_id.clone();
+            async move {
+                process
+                    .supervise(
+                        &media_connection_id,
+                        event_observer,
+                        CancellationToken::new(),
+                    )
+                    .await
+            }
+        });
+        let wrapper = MediaConnectionIdWrapper {
+            media_connection_id,
+        };
+        logged(&log, 1).await;
+        event_notifier
+            .send(MediaConnectionEventEnum::STREAM(wrapper.clone()))
+            .await
+            .unwrap();
+        logged(&log, 2).await;
+        event_notifier
+            .send(MediaConnectionEventEnum::CLOSE(wrapper))
+            .await
+            .unwrap();
+        assert_eq!(handle.await.unwrap().unwrap(), 2);
+        assert_eq!(count_lines(&log), 2);
+        std::fs::remove_file(log).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restart_exited_process() {
+        let log = launch_log("exit");
+        let process = PipelineProcess {
+            program: "sh".into(),
+            args: vec!["-c".into(), format!("echo launched >> {}", log.display())],
+            restart_delay_ms: 20,
+        };
+        let media_connection_id = MediaConnectionId::try_create(MEDIA_CONNECTION_ID).unwrap();
+        let token = CancellationToken::new();
+        let handle = tokio::spawn({
+            let token = token.clone();
+            async move {
+                process
+                    .supervise(&media_connection_id, stream::pending(), token)
+                    .await
+            }
+        });
+        logged(&log, 3).await;
+        token.cancel();
+        let launches = handle.await.unwrap().unwrap();
+        assert!(launches >= 3);
+        std::fs::remove_file(log).unwrap();
+
+        let missing = PipelineProcess::gst_launch("fakesrc ! fakesink");
+        let missing = PipelineProcess {
+            program: "skyway-no-such-program".into(),
+            ..missing
+        };
+        let media_connection_id = MediaConnectionId::try_create(MEDIA_CONNECTION_ID).unwrap();
+        assert!(missing
+            .supervise(
+                &media_connection_id,
+                stream::pending(),
+                CancellationToken::new()
+            )
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,707 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use futures::channel::mpsc;
+use futures::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+use super::rtp::ReportBlock;
+use crate::common::formats::SerializableId;
+use crate::error;
+use crate::media::formats::{MediaConnectionId, MediaConnectionStatus, MediaId};
+use crate::stats::{self, MediaMeter, SsrcStats};
+
+/// Kind of media carried by a redirect port
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    Video,
+    Audio,
+}
+
+impl fmt::Display for MediaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaKind::Video => write!(f, "video"),
+            MediaKind::Audio => write!(f, "audio"),
+        }
+    }
+}
+
+/// Redirect port watched for RTP arrival
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtpWatch {
+    pub kind: MediaKind,
+    /// Address WebRTC Gateway redirects RTP to. The monitor binds it.
+    pub addr: SocketAddr,
+    /// Received packets are relayed to this address, so that the actual receiver keeps working.
+    pub forward: Option<SocketAddr>,
+    /// Raise an alarm if no packet arrives for this duration
+    pub silence: Duration,
+}
+
+/// Redirect port of RTCP watched for receiver reports
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtcpWatch {
+    pub kind: MediaKind,
+    /// Address WebRTC Gateway redirects RTCP to. The monitor binds it.
+    pub addr: SocketAddr,
+    /// Received packets are relayed to this address, so that the actual receiver keeps working.
+    pub forward: Option<SocketAddr>,
+}
+
+/// Parameters for HealthMonitor
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthConfig {
+    /// Interval of calling media::status
+    pub interval: Duration,
+    pub watches: Vec<RtpWatch>,
+    pub rtcp_watches: Vec<RtcpWatch>,
+    /// Raise an alarm if a report block shows a larger fraction of lost packets (0.0-1.0)
+    pub loss_threshold: f64,
+}
+
+/// Problems and recoveries found by HealthMonitor
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "alarm", rename_all = "snake_case")]
+pub enum AlarmKind {
+    /// media::status reports the MediaConnection is not open.
+    Closed,
+    /// SSRC of a media changed. It usually means the remote encoder restarted.
+    SsrcChanged {
+        media_id: MediaId,
+        old: usize,
+        new: usize,
+    },
+    /// No RTP packet arrived on the redirect port.
+    Silence { kind: MediaKind, duration: Duration },
+    /// RTP packets arrive again after Silence.
+    Resumed { kind: MediaKind },
+    /// A receiver report shows that packets of the stream are lost more than `loss_threshold`.
+    HighLoss {
+        kind: MediaKind,
+        ssrc: u32,
+        loss: f64,
+    },
+    /// Loss of the stream falls to `loss_threshold` or less after HighLoss.
+    LossRecovered { kind: MediaKind, ssrc: u32 },
+    /// Failed to get status of the MediaConnection.
+    /// It's raised once until status is fetched successfully again.
+    StatusFailed { message: String },
+}
+
+impl fmt::Display for AlarmKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AlarmKind::Closed => write!(f, "connection closed"),
+            AlarmKind::SsrcChanged { media_id, old, new } => {
+                write!(f, "ssrc changed: {} {} -> {}", media_id.as_str(), old, new)
+            }
+            AlarmKind::Silence { kind, duration } => {
+                write!(f, "no {} for {}s", kind, duration.as_secs_f32())
+            }
+            AlarmKind::Resumed { kind } => write!(f, "{} resumed", kind),
+            AlarmKind::HighLoss { kind, ssrc, loss } => {
+                write!(
+                    f,
+                    "{}% of {} lost (ssrc {})",
+                    (loss * 100.0).round(),
+                    kind,
+                    ssrc
+                )
+            }
+            AlarmKind::LossRecovered { kind, ssrc } => {
+                write!(f, "loss of {} recovered (ssrc {})", kind, ssrc)
+            }
+            AlarmKind::StatusFailed { message } => write!(f, "status failed: {}", message),
+        }
+    }
+}
+
+/// Alarm raised by HealthMonitor
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HealthAlarm {
+    pub media_connection_id: MediaConnectionId,
+    pub time: SystemTime,
+    #[serde(flatten)]
+    pub kind: AlarmKind,
+}
+
+/// Watches health of a MediaConnection.
+///
+/// It periodically fetches media::status to find closed connections and SSRC changes,
+/// watches RTP arrival on redirect ports to find silence,
+/// and watches receiver reports on RTCP redirect ports to find packet loss.
+#[derive(Debug, Clone)]
+pub struct HealthMonitor {
+    media_connection_id: MediaConnectionId,
+    config: HealthConfig,
+    meter: MediaMeter,
+}
+
+impl HealthMonitor {
+    pub fn new(media_connection_id: MediaConnectionId, config: HealthConfig) -> Self {
+        Self {
+            media_connection_id,
+            config,
+            meter: MediaMeter::new(),
+        }
+    }
+
+    /// Traffic per SSRC of the watched ports.
+    /// Clones share the traffic, so it can be called on a clone while running.
+    /// SSRCs are related to MediaIds whenever media::status is fetched.
+    pub fn stats(&self) -> Vec<SsrcStats> {
+        self.meter.stats()
+    }
+
+    /// Keep watching the MediaConnection until the token is cancelled.
+    ///
+    /// # Failures
+    /// It returns error if it fails to bind the redirect ports, or the alarm observer is dropped.
+    pub async fn run(
+        self,
+        mut alarm_notifier: mpsc::Sender<HealthAlarm>,
+        token: CancellationToken,
+    ) -> Result<(), error::Error> {
+        let token = token.child_token();
+        let mut watch_futs = vec![];
+        for watch in self.config.watches.iter() {
+            let socket = UdpSocket::bind(watch.addr).await.map_err(|e| {
+                error::Error::create_local_error(&format!("fail to bind {}: {}", watch.addr, e))
+            })?;
+            watch_futs.push(
+                watch_rtp(
+                    socket,
+                    watch.clone(),
+                    self.meter.clone(),
+                    self.media_connection_id.clone(),
+                    alarm_notifier.clone(),
+                    token.clone(),
+                )
+                .boxed(),
+            );
+        }
+        for watch in self.config.rtcp_watches.iter() {
+            let socket = UdpSocket::bind(watch.addr).await.map_err(|e| {
+                error::Error::create_local_error(&format!("fail to bind {}: {}", watch.addr, e))
+            })?;
+            watch_futs.push(
+                watch_rtcp(
+                    socket,
+                    watch.clone(),
+                    self.config.loss_threshold,
+                    self.meter.clone(),
+                    self.media_connection_id.clone(),
+                    alarm_notifier.clone(),
+                    token.clone(),
+                )
+                .boxed(),
+            );
+        }
+
+        let status_fut = async {
+            let mut interval = tokio::time::interval(self.config.interval);
+            // Closed is raised when an open connection is closed, not before it's opened.
+            let mut open = false;
+            let mut status_failed = false;
+            let mut ssrc = BTreeMap::new();
+            loop {
+                tokio::select! {
+                    _ = token.cancelled() => return Ok(()),
+                    _ = interval.tick() => {}
+                }
+                let status = super::status(&self.media_connection_id).await;
+                let was_failed = std::mem::replace(&mut status_failed, status.is_err());
+                let alarms = match status {
+                    Ok(status) => {
+                        self.meter.label(status.ssrc.as_deref().unwrap_or_default());
+                        let mut alarms = ssrc_changes(&mut ssrc, &status);
+                        if open && !status.open {
+                            alarms.push(AlarmKind::Closed);
+                        }
+                        open = status.open;
+                        alarms
+                    }
+                    Err(_) if was_failed => vec![],
+                    Err(e) => vec![AlarmKind::StatusFailed {
+                        message: serde_json::to_string(&e).unwrap_or_default(),
+                    }],
+                };
+                for kind in alarms {
+                    notify(&mut alarm_notifier, &self.media_connection_id, kind).await?;
+                }
+            }
+        };
+
+        // the others stop as soon as one of them fails
+        let watch_futs = watch_futs
+            .into_iter()
+            .map(|fut| cancel_on_error(fut, &token));
+        let (status_result, watch_results) = future::join(
+            cancel_on_error(status_fut, &token),
+            future::join_all(watch_futs),
+        )
+        .await;
+        watch_results
+            .into_iter()
+            .fold(status_result, |acc, result| acc.and(result))
+    }
+}
+
+async fn cancel_on_error<F>(fut: F, token: &CancellationToken) -> Result<(), error::Error>
+where
+    F: Future<Output = Result<(), error::Error>>,
+{
+    let result = fut.await;
+    if result.is_err() {
+        token.cancel();
+    }
+    result
+}
+
+async fn notify(
+    alarm_notifier: &mut mpsc::Sender<HealthAlarm>,
+    media_connection_id: &MediaConnectionId,
+    kind: AlarmKind,
+) -> Result<(), error::Error> {
+    warn!("{}: {}", media_connection_id.as_str(), kind);
+    alarm_notifier
+        .send(HealthAlarm {
+            media_connection_id: media_connection_id.clone(),
+            time: SystemTime::now(),
+            kind,
+        })
+        .await
+        .map_err(|_| error::Error::create_local_error("fail to notify an alarm"))
+}
+
+// update known SSRCs and return alarms for changed ones
+fn ssrc_changes(
+    known: &mut BTreeMap<MediaId, usize>,
+    status: &MediaConnectionStatus,
+) -> Vec<AlarmKind> {
+    let mut alarms = vec![];
+    for pair in status.ssrc.iter().flatten() {
+        if let Some(old) = known.insert(pair.media_id.clone(), pair.ssrc) {
+            if old != pair.ssrc {
+                alarms.push(AlarmKind::SsrcChanged {
+                    media_id: pair.media_id.clone(),
+                    old,
+                    new: pair.ssrc,
+                });
+            }
+        }
+    }
+    alarms
+}
+
+async fn watch_rtp(
+    socket: UdpSocket,
+    watch: RtpWatch,
+    meter: MediaMeter,
+    media_connection_id: MediaConnectionId,
+    mut alarm_notifier: mpsc::Sender<HealthAlarm>,
+    token: CancellationToken,
+) -> Result<(), error::Error> {
+    let mut buf = vec![0u8; 65536];
+    let mut silent = false;
+    loop {
+        let received = tokio::select! {
+            _ = token.cancelled() => return Ok(()),
+            received = tokio::time::timeout(watch.silence, socket.recv(&mut buf)) => received,
+        };
+        match received {
+            Ok(Ok(len)) => {
+                let clock_rate = stats::clock_rate(
+                    watch.kind == MediaKind::Video,
+                    stats::payload_type(&buf[..len]),
+                );
+                meter.record(&buf[..len], clock_rate);
+                if let Some(forward) = watch.forward {
+                    let _ = socket.send_to(&buf[..len], forward).await;
+                }
+                if silent {
+                    silent = false;
+                    let kind = AlarmKind::Resumed { kind: watch.kind };
+                    notify(&mut alarm_notifier, &media_connection_id, kind).await?;
+                }
+            }
+            Ok(Err(e)) => warn!("fail to receive RTP on {}: {:?}", watch.addr, e),
+            Err(_) if !silent => {
+                silent = true;
+                let kind = AlarmKind::Silence {
+                    kind: watch.kind,
+                    duration: watch.silence,
+                };
+                notify(&mut alarm_notifier, &media_connection_id, kind).await?;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+async fn watch_rtcp(
+    socket: UdpSocket,
+    watch: RtcpWatch,
+    loss_threshold: f64,
+    meter: MediaMeter,
+    media_connection_id: MediaConnectionId,
+    mut alarm_notifier: mpsc::Sender<HealthAlarm>,
+    token: CancellationToken,
+) -> Result<(), error::Error> {
+    let mut buf = vec![0u8; 65536];
+    // SSRCs whose loss exceeds the threshold
+    let mut lossy = BTreeSet::new();
+    loop {
+        let received = tokio::select! {
+            _ = token.cancelled() => return Ok(()),
+            received = socket.recv(&mut buf) => received,
+        };
+        let len = match received {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("fail to receive RTCP on {}: {:?}", watch.addr, e);
+                continue;
+            }
+        };
+        meter.record(&buf[..len], 0);
+        if let Some(forward) = watch.forward {
+            let _ = socket.send_to(&buf[..len], forward).await;
+        }
+        for block in ReportBlock::parse_compound(&buf[..len]) {
+            let loss = block.loss();
+            let kind = if loss > loss_threshold && lossy.insert(block.ssrc) {
+                AlarmKind::HighLoss {
+                    kind: watch.kind,
+                    ssrc: block.ssrc,
+                    loss,
+                }
+            } else if loss <= loss_threshold && lossy.remove(&block.ssrc) {
+                AlarmKind::LossRecovered {
+                    kind: watch.kind,
+                    ssrc: block.ssrc,
+                }
+            } else {
+                continue;
+            };
+            notify(&mut alarm_notifier, &media_connection_id, kind).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_health_monitor {
+    use mockito::mock;
+
+    use super::*;
+    use crate::media::formats::SsrcPair;
+    use crate::media::rtp::test_rtp::rtp;
+    use crate::peer::formats::PeerId;
+
+    const MEDIA_ID: &str = "vi-4d053831-5dc2-461b-a358-d062d6115216";
+
+    fn media_connection_id() -> MediaConnectionId {
+        MediaConnectionId::try_create("mc-102127d9-30de-413b-93f7-41a33e39d82b").unwrap()
+    }
+
+    fn status(ssrc: usize) -> MediaConnectionStatus {
+        MediaConnectionStatus {
+            metadata: "".into(),
+            open: true,
+            remote_id: PeerId::new("remote"),
+            ssrc: Some(vec![SsrcPair {
+                media_id: MediaId::try_create(MEDIA_ID).unwrap(),
+                ssrc,
+            }]),
+        }
+    }
+
+    #[test]
+    fn detect_ssrc_change() {
+        let mut known = BTreeMap::new();
+        assert!(ssrc_changes(&mut known, &status(1)).is_empty());
+        assert!(ssrc_changes(&mut known, &status(1)).is_empty());
+        assert_eq!(
+            ssrc_changes(&mut known, &status(2)),
+            vec![AlarmKind::SsrcChanged {
+                media_id: MediaId::try_create(MEDIA_ID).unwrap(),
+                old: 1,
+                new: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn display_alarm() {
+        let kind = AlarmKind::Silence {
+            kind: MediaKind::Video,
+            duration: Duration::from_secs(3),
+        };
+        assert_eq!(format!("{}", kind), "no video for 3s");
+    }
+
+    #[tokio::test]
+    async fn silence_and_resume() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let watch = RtpWatch {
+            kind: MediaKind::Audio,
+            addr,
+            forward: None,
+            silence: Duration::from_millis(50),
+        };
+        let (alarm_notifier, mut alarm_observer) = mpsc::channel::<HealthAlarm>(10);
+        let token = CancellationToken::new();
+        let meter = MediaMeter::new();
+        let handle = tokio::spawn(watch_rtp(
+            socket,
+            watch,
+            meter.clone(),
+            media_connection_id(),
+            alarm_notifier,
+            token.clone(),
+        ));
+
+        let alarm = alarm_observer.next().await.unwrap();
+        assert_eq!(
+            alarm.kind,
+            AlarmKind::Silence {
+                kind: MediaKind::Audio,
+                duration: Duration::from_millis(50)
+            }
+        );
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender
+            .send_to(&rtp(1, 0, 12345, false, &[]), addr)
+            .await
+            .unwrap();
+        let alarm = alarm_observer.next().await.unwrap();
+        assert_eq!(
+            alarm.kind,
+            AlarmKind::Resumed {
+                kind: MediaKind::Audio
+            }
+        );
+        let stats = meter.stats();
+        assert_eq!(stats[0].ssrc, 12345);
+        assert_eq!((stats[0].rtp.packets, stats[0].rtp.bytes), (1, 12));
+        token.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn closed_connection() {
+        crate::initialize(mockito::server_url());
+        let path = format!(
+            "/media/connections/{}/status",
+            media_connection_id().as_str()
+        );
+        let body = |open: bool| {
+            format!(
+                r#"{{"metadata": "", "open": {}, "remote_id": "remote", "ssrc": []}}"#,
+                open
+            )
+        };
+        // the connection is open at first, then closed
+        let open_mock = mock("GET", path.as_str())
+            .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+            .with_header("content-type", "application/json")
+            .with_body(body(true))
+            .expect(1)
+            .create();
+        let closed_mock = mock("GET", path.as_str())
+            .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+            .with_header("content-type", "application/json")
+            .with_body(body(false))
+            .expect_at_least(3)
+            .create();
+
+        let monitor = HealthMonitor::new(media_connection_id(), config());
+        let (alarm_notifier, mut alarm_observer) = mpsc::channel::<HealthAlarm>(10);
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(monitor.run(alarm_notifier, token.clone()));
+
+        let alarm = alarm_observer.next().await.unwrap();
+        assert_eq!(alarm.media_connection_id, media_connection_id());
+        assert_eq!(alarm.kind, AlarmKind::Closed);
+        // Closed is raised only once
+        polled(&closed_mock).await;
+        assert!(alarm_observer.try_recv().is_err());
+        token.cancel();
+        handle.await.unwrap().unwrap();
+        open_mock.assert();
+        closed_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn not_closed_before_open() {
+        crate::initialize(mockito::server_url());
+        let media_connection_id =
+            MediaConnectionId::try_create("mc-5d2ee3c8-0c9f-4f0b-8a6c-6d1b3b1f0a01").unwrap();
+        let status_mock = mock(
+            "GET",
+            format!("/media/connections/{}/status", media_connection_id.as_str()).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"metadata": "", "open": false, "remote_id": "remote", "ssrc": []}"#)
+        .expect_at_least(3)
+        .create();
+
+        let monitor = HealthMonitor::new(media_connection_id, config());
+        let (alarm_notifier, mut alarm_observer) = mpsc::channel::<HealthAlarm>(10);
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(monitor.run(alarm_notifier, token.clone()));
+
+        polled(&status_mock).await;
+        assert!(alarm_observer.try_recv().is_err());
+        token.cancel();
+        handle.await.unwrap().unwrap();
+        status_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn notify_status_failed_once() {
+        crate::initialize(mockito::server_url());
+        let media_connection_id =
+            MediaConnectionId::try_create("mc-8a0b3c5e-7f61-4b2a-9e0d-2c4f6a8b1d03").unwrap();
+        let status_mock = mock(
+            "GET",
+            format!("/media/connections/{}/status", media_connection_id.as_str()).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NOT_FOUND.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"command_type": "MEDIA_CONNECTION_STATUS", "params": {"errors": [{"field": "media_connection_id", "message": "not found"}]}}"#)
+        .expect_at_least(3)
+        .create();
+
+        let monitor = HealthMonitor::new(media_connection_id, config());
+        let (alarm_notifier, mut alarm_observer) = mpsc::channel::<HealthAlarm>(10);
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(monitor.run(alarm_notifier, token.clone()));
+
+        let alarm = alarm_observer.next().await.unwrap();
+        assert!(matches!(alarm.kind, AlarmKind::StatusFailed { .. }));
+        polled(&status_mock).await;
+        assert!(alarm_observer.try_recv().is_err());
+        token.cancel();
+        handle.await.unwrap().unwrap();
+        status_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn fail_when_observer_dropped() {
+        crate::initialize(mockito::server_url());
+        let media_connection_id =
+            MediaConnectionId::try_create("mc-3e9f1a7c-5b2d-4c8e-a6f0-9d1b7e3c5a02").unwrap();
+        let status_mock = mock(
+            "GET",
+            format!("/media/connections/{}/status", media_connection_id.as_str()).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NOT_FOUND.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"command_type": "MEDIA_CONNECTION_STATUS", "params": {"errors": [{"field": "media_connection_id", "message": "not found"}]}}"#)
+        .create();
+
+        // the RTCP watcher waits for reports which never come
+        let config = HealthConfig {
+            rtcp_watches: vec![RtcpWatch {
+                kind: MediaKind::Video,
+                addr: "127.0.0.1:0".parse().unwrap(),
+                forward: None,
+            }],
+            ..config()
+        };
+        let monitor = HealthMonitor::new(media_connection_id, config);
+        let (alarm_notifier, alarm_observer) = mpsc::channel::<HealthAlarm>(10);
+        drop(alarm_observer);
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            monitor.run(alarm_notifier, CancellationToken::new()),
+        )
+        .await
+        .expect("run should return when the StatusFailed alarm can't be notified");
+        assert!(result.is_err());
+        status_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn high_loss_and_recovered() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let watch = RtcpWatch {
+            kind: MediaKind::Video,
+            addr,
+            forward: None,
+        };
+        let (alarm_notifier, mut alarm_observer) = mpsc::channel::<HealthAlarm>(10);
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(watch_rtcp(
+            socket,
+            watch,
+            0.1,
+            MediaMeter::new(),
+            media_connection_id(),
+            alarm_notifier,
+            token.clone(),
+        ));
+
+        // Receiver Report with a block for ssrc 12345
+        let report = |fraction_lost: u8| {
+            let mut packet = vec![0x81, 201, 0, 7];
+            packet.extend_from_slice(&1u32.to_be_bytes());
+            packet.extend_from_slice(&12345u32.to_be_bytes());
+            packet.extend_from_slice(&[fraction_lost, 0, 0, 0]);
+            packet.extend_from_slice(&[0u8; 16]);
+            packet
+        };
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for fraction_lost in [128u8, 128, 0, 0].iter() {
+            sender.send_to(&report(*fraction_lost), addr).await.unwrap();
+        }
+        let alarm = alarm_observer.next().await.unwrap();
+        assert_eq!(
+            alarm.kind,
+            AlarmKind::HighLoss {
+                kind: MediaKind::Video,
+                ssrc: 12345,
+                loss: 0.5
+            }
+        );
+        let alarm = alarm_observer.next().await.unwrap();
+        assert_eq!(
+            alarm.kind,
+            AlarmKind::LossRecovered {
+                kind: MediaKind::Video,
+                ssrc: 12345
+            }
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(alarm_observer.try_recv().is_err());
+        token.cancel();
+        handle.await.unwrap().unwrap();
+    }
+
+    // Wait until the status is polled as many times as the mock expects.
+    // The third poll means the second one has been handled.
+    async fn polled(mock: &mockito::Mock) {
+        while !mock.matched() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn config() -> HealthConfig {
+        HealthConfig {
+            interval: Duration::from_millis(10),
+            watches: vec![],
+            rtcp_watches: vec![],
+            loss_threshold: 0.1,
+        }
+    }
+}
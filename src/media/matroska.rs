@@ -0,0 +1,472 @@
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+use super::depacketize::Codec;
+
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const BIT_DEPTH: u32 = 0x6264;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+
+// size of elements whose end is found by the next top-level element
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+// clusters are closed after this duration even without video keyframes
+const CLUSTER_DURATION_MS: u64 = 5000;
+
+/// Container written by MatroskaWriter
+///
+/// WebM is a subset of Matroska which only carries VP8 and Opus of the codecs supported here.
+/// MP4 is not supported.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Container {
+    Matroska,
+    Webm,
+}
+
+impl Container {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Matroska => "mkv",
+            Container::Webm => "webm",
+        }
+    }
+
+    fn doc_type(&self) -> &'static str {
+        match self {
+            Container::Matroska => "matroska",
+            Container::Webm => "webm",
+        }
+    }
+
+    pub fn supports(&self, codec: Codec) -> bool {
+        match self {
+            Container::Matroska => true,
+            Container::Webm => matches!(codec, Codec::Vp8 | Codec::Opus),
+        }
+    }
+}
+
+/// Track of a Matroska file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    /// Track number referred by blocks, starting from 1
+    pub number: u64,
+    pub codec: Codec,
+    /// Width and height of video
+    pub dimensions: Option<(u16, u16)>,
+    /// SPS and PPS of H264
+    pub parameter_sets: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Track {
+    fn entry(&self) -> Vec<u8> {
+        let mut body = [
+            element(TRACK_NUMBER, &uint(self.number)),
+            element(TRACK_UID, &uint(self.number)),
+            element(TRACK_TYPE, &uint(if self.codec.is_video() { 1 } else { 2 })),
+            element(FLAG_LACING, &uint(0)),
+            element(CODEC_ID, self.codec_id().as_bytes()),
+        ]
+        .concat();
+        if let Some(private) = self.codec_private() {
+            body.extend(element(CODEC_PRIVATE, &private));
+        }
+        if self.codec.is_video() {
+            if let Some((width, height)) = self.dimensions {
+                body.extend(element(
+                    VIDEO,
+                    &[
+                        element(PIXEL_WIDTH, &uint(width as u64)),
+                        element(PIXEL_HEIGHT, &uint(height as u64)),
+                    ]
+                    .concat(),
+                ));
+            }
+        } else {
+            let (channels, bit_depth) = match self.codec {
+                Codec::Opus => (2, None),
+                _ => (1, Some(8)),
+            };
+            let mut audio = [
+                element(
+                    SAMPLING_FREQUENCY,
+                    &(self.codec.clock_rate() as f64).to_be_bytes(),
+                ),
+                element(CHANNELS, &uint(channels)),
+            ]
+            .concat();
+            if let Some(bit_depth) = bit_depth {
+                audio.extend(element(BIT_DEPTH, &uint(bit_depth)));
+            }
+            body.extend(element(AUDIO, &audio));
+        }
+        element(TRACK_ENTRY, &body)
+    }
+
+    fn codec_id(&self) -> &'static str {
+        match self.codec {
+            Codec::H264 => "V_MPEG4/ISO/AVC",
+            Codec::Vp8 => "V_VP8",
+            Codec::Opus => "A_OPUS",
+            Codec::Pcmu | Codec::Pcma => "A_MS/ACM",
+        }
+    }
+
+    fn codec_private(&self) -> Option<Vec<u8>> {
+        match self.codec {
+            Codec::H264 => {
+                // AVCDecoderConfigurationRecord with 4-byte NAL unit lengths
+                let (sps, pps) = self.parameter_sets.as_ref()?;
+                if sps.len() < 4 {
+                    return None;
+                }
+                let mut record = vec![1, sps[1], sps[2], sps[3], 0xff, 0xe1];
+                record.extend((sps.len() as u16).to_be_bytes());
+                record.extend_from_slice(sps);
+                record.push(1);
+                record.extend((pps.len() as u16).to_be_bytes());
+                record.extend_from_slice(pps);
+                Some(record)
+            }
+            Codec::Vp8 => None,
+            Codec::Opus => {
+                // OpusHead of RFC 7845 for stereo without channel mapping
+                let mut head = b"OpusHead".to_vec();
+                head.extend([1, 2, 0, 0]);
+                head.extend(48000u32.to_le_bytes());
+                head.extend([0, 0, 0]);
+                Some(head)
+            }
+            Codec::Pcmu | Codec::Pcma => {
+                // WAVEFORMATEX of 8kHz mono
+                let format_tag: u16 = if self.codec == Codec::Pcmu { 7 } else { 6 };
+                let mut format = format_tag.to_le_bytes().to_vec();
+                format.extend(1u16.to_le_bytes());
+                format.extend(8000u32.to_le_bytes());
+                format.extend(8000u32.to_le_bytes());
+                format.extend(1u16.to_le_bytes());
+                format.extend(8u16.to_le_bytes());
+                format.extend(0u16.to_le_bytes());
+                Some(format)
+            }
+        }
+    }
+}
+
+fn id_bytes(id: u32) -> Vec<u8> {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+    bytes[skip..].to_vec()
+}
+
+fn size_bytes(size: u64) -> Vec<u8> {
+    // all ones is reserved for unknown size
+    let length = (1..=8)
+        .find(|length| size < (1u64 << (7 * length)) - 1)
+        .unwrap_or(8);
+    let marked = size | (1u64 << (7 * length));
+    marked.to_be_bytes()[8 - length as usize..].to_vec()
+}
+
+fn uint(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|byte| **byte == 0).count().min(7);
+    bytes[skip..].to_vec()
+}
+
+fn element(id: u32, body: &[u8]) -> Vec<u8> {
+    let mut bytes = id_bytes(id);
+    bytes.extend(size_bytes(body.len() as u64));
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+/// Writes frames to a Matroska or WebM stream.
+///
+/// The segment is written with an unknown size, so that no seeking is needed and the file is playable
+/// even if the process stops before `finish`. Clusters are buffered and written when they are complete.
+/// A cluster is started at every video keyframe. Timestamps are in milliseconds.
+pub struct MatroskaWriter<W: Write> {
+    writer: W,
+    video_tracks: Vec<u64>,
+    cluster: Vec<u8>,
+    cluster_time: Option<u64>,
+    written: u64,
+}
+
+impl<W: Write> MatroskaWriter<W> {
+    /// Write the header and tracks.
+    pub fn new(mut writer: W, container: Container, tracks: &[Track]) -> io::Result<Self> {
+        if let Some(track) = tracks.iter().find(|track| !container.supports(track.codec)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not supported in {:?}", track.codec, container),
+            ));
+        }
+        let app = concat!("skyway-webrtc-gateway-api ", env!("CARGO_PKG_VERSION"));
+        let header = [
+            element(
+                EBML,
+                &[
+                    element(EBML_VERSION, &uint(1)),
+                    element(EBML_READ_VERSION, &uint(1)),
+                    element(EBML_MAX_ID_LENGTH, &uint(4)),
+                    element(EBML_MAX_SIZE_LENGTH, &uint(8)),
+                    element(DOC_TYPE, container.doc_type().as_bytes()),
+                    element(DOC_TYPE_VERSION, &uint(4)),
+                    element(DOC_TYPE_READ_VERSION, &uint(2)),
+                ]
+                .concat(),
+            ),
+            id_bytes(SEGMENT),
+            UNKNOWN_SIZE.to_vec(),
+            element(
+                INFO,
+                &[
+                    element(TIMESTAMP_SCALE, &uint(1_000_000)),
+                    element(MUXING_APP, app.as_bytes()),
+                    element(WRITING_APP, app.as_bytes()),
+                ]
+                .concat(),
+            ),
+            element(
+                TRACKS,
+                &tracks.iter().map(Track::entry).collect::<Vec<_>>().concat(),
+            ),
+        ]
+        .concat();
+        writer.write_all(&header)?;
+        Ok(Self {
+            writer,
+            video_tracks: tracks
+                .iter()
+                .filter(|track| track.codec.is_video())
+                .map(|track| track.number)
+                .collect(),
+            cluster: vec![],
+            cluster_time: None,
+            written: header.len() as u64,
+        })
+    }
+
+    /// Write a frame of the track at `time` milliseconds.
+    pub fn write_frame(
+        &mut self,
+        track: u64,
+        time: u64,
+        keyframe: bool,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let cluster_time = match self.cluster_time {
+            Some(cluster_time)
+                if !(keyframe && self.video_tracks.contains(&track) && time > cluster_time)
+                    && time < cluster_time + CLUSTER_DURATION_MS =>
+            {
+                cluster_time
+            }
+            _ => {
+                self.flush_cluster()?;
+                self.cluster_time = Some(time);
+                time
+            }
+        };
+        // frames slightly older than the cluster are written with negative relative timestamps
+        let relative = (time as i64 - cluster_time as i64).clamp(i16::MIN as i64, 0x7fff) as i16;
+        let mut block = size_bytes(track);
+        block.extend(relative.to_be_bytes());
+        block.push(if keyframe { 0x80 } else { 0x00 });
+        block.extend_from_slice(data);
+        self.cluster.extend(element(SIMPLE_BLOCK, &block));
+        Ok(())
+    }
+
+    /// Bytes written so far, including the cluster being buffered
+    pub fn bytes_written(&self) -> u64 {
+        match self.cluster_time {
+            Some(cluster_time) => {
+                let body = element(TIMESTAMP, &uint(cluster_time)).len() + self.cluster.len();
+                let header = id_bytes(CLUSTER).len() + size_bytes(body as u64).len();
+                self.written + (header + body) as u64
+            }
+            None => self.written,
+        }
+    }
+
+    fn flush_cluster(&mut self) -> io::Result<()> {
+        if let Some(cluster_time) = self.cluster_time.take() {
+            let mut cluster = element(TIMESTAMP, &uint(cluster_time));
+            cluster.append(&mut self.cluster);
+            let cluster = element(CLUSTER, &cluster);
+            self.writer.write_all(&cluster)?;
+            self.written += cluster.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Write the last cluster and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_cluster()?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_matroska {
+    use super::*;
+
+    // id, header size and body of each element
+    pub(crate) fn read_elements(mut bytes: &[u8]) -> Vec<(u32, &[u8])> {
+        fn vint(bytes: &[u8]) -> (u64, usize) {
+            let length = bytes[0].leading_zeros() as usize + 1;
+            let value = bytes[..length]
+                .iter()
+                .fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+            (value, length)
+        }
+        let mut elements = vec![];
+        while !bytes.is_empty() {
+            let (id, id_length) = vint(bytes);
+            let (size, size_length) = vint(&bytes[id_length..]);
+            let size = size & !(1u64 << (7 * size_length));
+            let start = id_length + size_length;
+            let end = if bytes[id_length..start] == UNKNOWN_SIZE {
+                bytes.len()
+            } else {
+                start + size as usize
+            };
+            elements.push((id as u32, &bytes[start..end]));
+            bytes = &bytes[end..];
+        }
+        elements
+    }
+
+    pub(crate) fn find<'a>(elements: &[(u32, &'a [u8])], id: u32) -> Vec<&'a [u8]> {
+        elements
+            .iter()
+            .filter(|(element_id, _)| *element_id == id)
+            .map(|(_, body)| *body)
+            .collect()
+    }
+
+    // relative timestamp, flags and data of blocks in a file
+    pub(crate) fn read_blocks(file: &[u8]) -> Vec<(u64, u64, bool, Vec<u8>)> {
+        let top = read_elements(file);
+        let segment = read_elements(find(&top, SEGMENT)[0]);
+        let mut blocks = vec![];
+        for cluster in find(&segment, CLUSTER) {
+            let children = read_elements(cluster);
+            let cluster_time = find(&children, TIMESTAMP)[0]
+                .iter()
+                .fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+            for block in find(&children, SIMPLE_BLOCK) {
+                let track = (block[0] & 0x7f) as u64;
+                let relative = i16::from_be_bytes([block[1], block[2]]);
+                blocks.push((
+                    track,
+                    (cluster_time as i64 + relative as i64) as u64,
+                    block[3] & 0x80 != 0,
+                    block[4..].to_vec(),
+                ));
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn vint_and_uint() {
+        assert_eq!(size_bytes(0), vec![0x80]);
+        assert_eq!(size_bytes(126), vec![0xfe]);
+        assert_eq!(size_bytes(127), vec![0x40, 0x7f]);
+        assert_eq!(size_bytes(300), vec![0x41, 0x2c]);
+        assert_eq!(uint(0), vec![0]);
+        assert_eq!(uint(1_000_000), vec![0x0f, 0x42, 0x40]);
+        assert_eq!(id_bytes(SEGMENT), vec![0x18, 0x53, 0x80, 0x67]);
+        assert_eq!(id_bytes(SIMPLE_BLOCK), vec![0xa3]);
+    }
+
+    #[test]
+    fn write_webm() {
+        let tracks = vec![
+            Track {
+                number: 1,
+                codec: Codec::Vp8,
+                dimensions: Some((640, 480)),
+                parameter_sets: None,
+            },
+            Track {
+                number: 2,
+                codec: Codec::Opus,
+                dimensions: None,
+                parameter_sets: None,
+            },
+        ];
+        let mut writer = MatroskaWriter::new(vec![], Container::Webm, &tracks).unwrap();
+        writer.write_frame(1, 0, true, &[1]).unwrap();
+        writer.write_frame(2, 10, true, &[2]).unwrap();
+        writer.write_frame(1, 33, false, &[3]).unwrap();
+        writer.write_frame(1, 66, true, &[4]).unwrap();
+        writer.write_frame(2, 60, true, &[5]).unwrap();
+        let length = writer.bytes_written();
+        let file = writer.finish().unwrap();
+        assert_eq!(file.len() as u64, length);
+
+        let top = read_elements(&file);
+        let header = read_elements(find(&top, EBML)[0]);
+        assert_eq!(find(&header, DOC_TYPE), vec![b"webm"]);
+        let segment = read_elements(find(&top, SEGMENT)[0]);
+        let tracks = read_elements(find(&segment, TRACKS)[0]);
+        let entries = find(&tracks, TRACK_ENTRY);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(find(&read_elements(entries[1]), CODEC_ID), vec![b"A_OPUS"]);
+        // a new cluster starts at the second keyframe
+        assert_eq!(find(&segment, CLUSTER).len(), 2);
+        assert_eq!(
+            read_blocks(&file),
+            vec![
+                (1, 0, true, vec![1]),
+                (2, 10, true, vec![2]),
+                (1, 33, false, vec![3]),
+                (1, 66, true, vec![4]),
+                (2, 60, true, vec![5]),
+            ]
+        );
+
+        let h264 = Track {
+            number: 1,
+            codec: Codec::H264,
+            dimensions: None,
+            parameter_sets: None,
+        };
+        assert!(MatroskaWriter::new(vec![], Container::Webm, &[h264]).is_err());
+    }
+}
@@ -1,5 +1,27 @@
 pub(crate) mod api;
+/// Capture file formats of RTP packets
+pub mod capture;
+/// Assemble encoded frames from RTP payloads
+pub mod depacketize;
 pub(crate) mod formats;
+/// Synthetic RTP streams for tests without cameras or encoders
+pub mod generator;
+/// GStreamer pipelines for media sockets and redirect ports
+pub mod gstreamer;
+/// Watch health of MediaConnections with status and RTP arrival
+pub mod health;
+/// Matroska and WebM container writer
+pub mod matroska;
+/// Mux received audio and video into container files
+pub mod mux;
+/// Answer incoming calls according to declarative policies
+pub mod policy;
+/// Record RTP packets redirected from WebRTC Gateway
+pub mod record;
+/// Replay captured RTP packets into media sockets
+pub mod replay;
+/// RTP and RTCP headers
+pub mod rtp;
 
 use futures::channel::mpsc;
 use futures::*;
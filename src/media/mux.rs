@@ -0,0 +1,644 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+use super::depacketize::{self, Codec, Depacketizer, Frame};
+use super::matroska::{Container, MatroskaWriter, Track};
+use super::rtp::SenderReport;
+use super::MediaConnectionEventEnum;
+use crate::error;
+use crate::media::formats::MediaConnectionId;
+use crate::stats::{MediaMeter, SsrcStats};
+
+/// Redirect ports of a stream muxed by MuxRecorder
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MuxTrack {
+    pub codec: Codec,
+    /// Address WebRTC Gateway redirects RTP to. The recorder binds it.
+    pub addr: SocketAddr,
+    /// Address WebRTC Gateway redirects RTCP to.
+    /// Sender Reports received here synchronize audio and video.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtcp: Option<SocketAddr>,
+}
+
+/// Conditions to continue recording in a new file.
+///
+/// Files are switched at video keyframes, so a file may exceed the limits slightly.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Rotation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_duration_ms: Option<u64>,
+}
+
+/// Parameters for MuxRecorder
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MuxConfig {
+    pub container: Container,
+    /// Files are written in this directory as `{media_connection_id}_{index}.{extension}`.
+    pub directory: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video: Option<MuxTrack>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio: Option<MuxTrack>,
+    #[serde(default)]
+    pub rotation: Rotation,
+}
+
+fn seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+struct TrackState {
+    number: u64,
+    depacketizer: Depacketizer,
+    // RTP timestamp and the local time in seconds it corresponds to
+    clock: Option<(u32, f64)>,
+    last_time: f64,
+}
+
+impl TrackState {
+    fn codec(&self) -> Codec {
+        self.depacketizer.codec()
+    }
+
+    fn time_of(&mut self, timestamp: u32, arrival: f64) -> f64 {
+        let (rtp_timestamp, time) = *self.clock.get_or_insert((timestamp, arrival));
+        let elapsed = timestamp.wrapping_sub(rtp_timestamp) as i32 as f64;
+        // a clock updated by Sender Reports may step back a little
+        self.last_time = self
+            .last_time
+            .max(time + elapsed / self.codec().clock_rate() as f64);
+        self.last_time
+    }
+}
+
+struct Output {
+    writer: MatroskaWriter<BufWriter<File>>,
+    start: f64,
+}
+
+// Writes frames of all tracks to rotated files on the local timeline.
+struct Muxer {
+    media_connection_id: MediaConnectionId,
+    config: MuxConfig,
+    tracks: Vec<TrackState>,
+    // local time minus NTP time of the sender, shared by all tracks to keep them in sync
+    sender_offset: Option<f64>,
+    output: Option<Output>,
+    files: Vec<PathBuf>,
+}
+
+impl Muxer {
+    fn new(media_connection_id: MediaConnectionId, config: MuxConfig) -> Self {
+        let tracks = config
+            .video
+            .iter()
+            .chain(config.audio.iter())
+            .enumerate()
+            .map(|(index, track)| TrackState {
+                number: index as u64 + 1,
+                depacketizer: Depacketizer::new(track.codec),
+                clock: None,
+                last_time: 0.0,
+            })
+            .collect();
+        Self {
+            media_connection_id,
+            config,
+            tracks,
+            sender_offset: None,
+            output: None,
+            files: vec![],
+        }
+    }
+
+    fn on_rtp(&mut self, index: usize, packet: &[u8], arrival: SystemTime) -> io::Result<()> {
+        for frame in self.tracks[index].depacketizer.push(packet) {
+            let time = self.tracks[index].time_of(frame.timestamp, seconds(arrival));
+            self.write(index, &frame, time)?;
+        }
+        Ok(())
+    }
+
+    fn on_rtcp(&mut self, index: usize, packet: &[u8], arrival: SystemTime) {
+        for report in SenderReport::parse_compound(packet) {
+            let sender_time = seconds(report.time);
+            let offset = *self
+                .sender_offset
+                .get_or_insert(seconds(arrival) - sender_time);
+            self.tracks[index].clock = Some((report.rtp_timestamp, sender_time + offset));
+        }
+    }
+
+    fn write(&mut self, index: usize, frame: &Frame, time: f64) -> io::Result<()> {
+        // files start at video keyframes, so that each file is playable by itself
+        let can_start = match self.config.video {
+            Some(_) => self.tracks[index].codec().is_video() && frame.keyframe,
+            None => true,
+        };
+        let switch = match self.output {
+            None => true,
+            Some(ref output) => {
+                let rotation = &self.config.rotation;
+                rotation
+                    .max_bytes
+                    .map(|max| output.writer.bytes_written() >= max)
+                    .unwrap_or(false)
+                    || rotation
+                        .max_duration_ms
+                        .map(|max| (time - output.start) * 1000.0 >= max as f64)
+                        .unwrap_or(false)
+            }
+        };
+        if switch && can_start {
+            self.close()?;
+            self.open(index, frame, time)?;
+        }
+        let number = self.tracks[index].number;
+        if let Some(ref mut output) = self.output {
+            let millis = ((time - output.start) * 1000.0).round().max(0.0) as u64;
+            output
+                .writer
+                .write_frame(number, millis, frame.keyframe, &frame.data)?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, index: usize, frame: &Frame, time: f64) -> io::Result<()> {
+        let tracks = self
+            .tracks
+            .iter()
+            .map(|track| Track {
+                number: track.number,
+                codec: track.codec(),
+                dimensions: match track.codec() {
+                    Codec::Vp8 if track.number == self.tracks[index].number => {
+                        depacketize::vp8_dimensions(&frame.data)
+                    }
+                    _ => None,
+                },
+                parameter_sets: track
+                    .depacketizer
+                    .parameter_sets()
+                    .map(|(sps, pps)| (sps.to_vec(), pps.to_vec())),
+            })
+            .collect::<Vec<_>>();
+        if tracks
+            .iter()
+            .any(|track| track.codec == Codec::H264 && track.parameter_sets.is_none())
+        {
+            // avcC can't be written without SPS and PPS
+            return Ok(());
+        }
+        let path = self.config.directory.join(format!(
+            "{}_{:03}.{}",
+            self.media_connection_id.as_str(),
+            self.files.len(),
+            self.config.container.extension()
+        ));
+        let writer = MatroskaWriter::new(
+            BufWriter::new(File::create(&path)?),
+            self.config.container,
+            &tracks,
+        )?;
+        self.files.push(path);
+        self.output = Some(Output {
+            writer,
+            start: time,
+        });
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(output) = self.output.take() {
+            output.writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Port {
+    Rtp(usize),
+    Rtcp(usize),
+}
+
+impl Port {
+    fn index(&self) -> usize {
+        match self {
+            Port::Rtp(index) | Port::Rtcp(index) => *index,
+        }
+    }
+}
+
+fn receive(
+    port: Port,
+    socket: UdpSocket,
+) -> impl Stream<Item = (Port, Vec<u8>, SystemTime)> + Send + Unpin {
+    Box::pin(stream::unfold(socket, move |socket| async move {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            match socket.recv(&mut buf).await {
+                Ok(len) => return Some(((port, buf[..len].to_vec(), SystemTime::now()), socket)),
+                Err(e) => warn!("fail to receive a packet: {:?}", e),
+            }
+        }
+    }))
+}
+
+/// Writes video and audio of a MediaConnection to Matroska or WebM files.
+/// MP4 is out of scope, since Matroska carries all the supported codecs.
+///
+/// It depacketizes H264 or VP8 video and Opus or G.711 audio from the redirect ports.
+/// Frames are timed by RTP timestamps, and audio and video are aligned with RTCP Sender Reports if they arrive.
+/// Recording starts at a video keyframe, and files are rotated according to `Rotation`.
+/// Packets are counted per SSRC by a `MediaMeter`.
+pub struct MuxRecorder {
+    media_connection_id: MediaConnectionId,
+    config: MuxConfig,
+    meter: MediaMeter,
+}
+
+impl MuxRecorder {
+    /// # Failures
+    /// It returns error if no track is given, or codecs don't match the tracks or the container.
+    pub fn new(
+        media_connection_id: MediaConnectionId,
+        config: MuxConfig,
+    ) -> Result<Self, error::Error> {
+        if config.video.is_none() && config.audio.is_none() {
+            return Err(error::Error::create_local_error("no track to record"));
+        }
+        let tracks = [(true, &config.video), (false, &config.audio)];
+        for (is_video, track) in tracks.iter() {
+            if let Some(track) = track {
+                if track.codec.is_video() != *is_video || !config.container.supports(track.codec) {
+                    return Err(error::Error::create_local_error(&format!(
+                        "{:?} can't be recorded as this track",
+                        track.codec
+                    )));
+                }
+            }
+        }
+        Ok(Self {
+            media_connection_id,
+            config,
+            meter: MediaMeter::new(),
+        })
+    }
+
+    /// Traffic per SSRC of the redirect ports. It can be called while recording.
+    /// MediaIds are not related, since media::status is not fetched.
+    pub fn stats(&self) -> Vec<SsrcStats> {
+        self.meter.stats()
+    }
+
+    /// Bind the redirect ports and record until the token is cancelled.
+    /// It returns the files written.
+    pub async fn record(&self, token: CancellationToken) -> Result<Vec<PathBuf>, error::Error> {
+        let mut streams = vec![];
+        let tracks: Vec<&MuxTrack> = self
+            .config
+            .video
+            .iter()
+            .chain(self.config.audio.iter())
+            .collect();
+        for (index, track) in tracks.iter().enumerate() {
+            let ports = std::iter::once((Port::Rtp(index), track.addr))
+                .chain(track.rtcp.map(|addr| (Port::Rtcp(index), addr)));
+            for (port, addr) in ports {
+                let socket = UdpSocket::bind(addr).await.map_err(|e| {
+                    error::Error::create_local_error(&format!("fail to bind {}: {}", addr, e))
+                })?;
+                streams.push(receive(port, socket));
+            }
+        }
+        let mut packets = stream::select_all(streams);
+
+        let mut muxer = Muxer::new(self.media_connection_id.clone(), self.config.clone());
+        loop {
+            let (port, packet, arrival) = tokio::select! {
+                _ = token.cancelled() => break,
+                received = packets.next() => match received {
+                    Some(received) => received,
+                    None => break,
+                },
+            };
+            let codec = tracks[port.index()].codec;
+            self.meter.record(&packet, codec.clock_rate());
+            match port {
+                Port::Rtp(index) => {
+                    if let Err(e) = muxer.on_rtp(index, &packet, arrival) {
+                        warn!("fail to write a frame: {:?}", e);
+                    }
+                }
+                Port::Rtcp(index) => muxer.on_rtcp(index, &packet, arrival),
+            }
+        }
+        muxer.close()?;
+        Ok(muxer.files)
+    }
+
+    /// Record from a STREAM event to a CLOSE event of the MediaConnection.
+    ///
+    /// Events of other MediaConnections are ignored, so events from `media::listen_events`
+    /// or an EventBus subscription can be given.
+    /// It returns the files written when the MediaConnection is closed, events end, or the token is cancelled.
+    pub async fn run<S>(
+        &self,
+        mut events: S,
+        token: CancellationToken,
+    ) -> Result<Vec<PathBuf>, error::Error>
+    where
+        S: Stream<Item = MediaConnectionEventEnum> + Unpin,
+    {
+        let media_connection_id = &self.media_connection_id;
+        let is_mine = |id: &MediaConnectionId| id == media_connection_id;
+        loop {
+            let event = tokio::select! {
+                _ = token.cancelled() => return Ok(vec![]),
+                event = events.next() => event,
+            };
+            match event {
+                Some(MediaConnectionEventEnum::STREAM(wrapper))
+                    if is_mine(&wrapper.media_connection_id) =>
+                {
+                    break
+                }
+                Some(MediaConnectionEventEnum::CLOSE(wrapper))
+                    if is_mine(&wrapper.media_connection_id) =>
+                {
+                    return Ok(vec![])
+                }
+                None => return Ok(vec![]),
+                _ => {}
+            }
+        }
+
+        let record_token = token.child_token();
+        let record_fut = self.record(record_token.clone());
+        pin_mut!(record_fut);
+        let close_fut = async {
+            while let Some(event) = events.next().await {
+                if let MediaConnectionEventEnum::CLOSE(wrapper) = event {
+                    if is_mine(&wrapper.media_connection_id) {
+                        break;
+                    }
+                }
+            }
+        };
+        tokio::select! {
+            // binding ports failed
+            result = &mut record_fut => return result,
+            _ = close_fut => {}
+        }
+        record_token.cancel();
+        record_fut.await
+    }
+}
+
+#[cfg(test)]
+mod test_mux {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::channel::mpsc;
+
+    use super::*;
+    use crate::media::formats::MediaConnectionIdWrapper;
+    use crate::media::matroska::test_matroska::read_blocks;
+    use crate::media::rtp::test_rtp::rtp;
+
+    const MEDIA_CONNECTION_ID: &str = "mc-3a9c1e5d-2b7f-4d8e-a6c4-1f0e9d8c7b6a";
+
+    // VP8 keyframe of 640x480 in a packet
+    fn vp8_keyframe(sequence: u16, timestamp: u32) -> Vec<u8> {
+        rtp(
+            sequence,
+            timestamp,
+            1,
+            true,
+            &[
+                0x10, 0x00, 0x00, 0x00, 0x9d, 0x01, 0x2a, 0x80, 0x02, 0xe0, 0x01,
+            ],
+        )
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("skyway_mux_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn free_addr() -> SocketAddr {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    #[test]
+    fn sync_with_sender_reports() {
+        let directory = directory("sync");
+        let config = MuxConfig {
+            container: Container::Webm,
+            directory: directory.clone(),
+            video: Some(MuxTrack {
+                codec: Codec::Vp8,
+                addr: free_addr(),
+                rtcp: None,
+            }),
+            audio: Some(MuxTrack {
+                codec: Codec::Opus,
+                addr: free_addr(),
+                rtcp: None,
+            }),
+            rotation: Rotation::default(),
+        };
+        let mut muxer = Muxer::new(
+            MediaConnectionId::try_create(MEDIA_CONNECTION_ID).unwrap(),
+            config,
+        );
+        let local = SystemTime::now();
+        let sender = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let report = |ssrc, rtp_timestamp| {
+            SenderReport {
+                ssrc,
+                time: sender,
+                rtp_timestamp,
+                packets: 0,
+                octets: 0,
+            }
+            .to_bytes()
+        };
+        muxer.on_rtcp(0, &report(1, 90000), local);
+        muxer.on_rtcp(1, &report(2, 48000), local);
+        // video is delayed more than audio in the network
+        muxer
+            .on_rtp(
+                0,
+                &vp8_keyframe(0, 90000),
+                local + Duration::from_millis(200),
+            )
+            .unwrap();
+        muxer
+            .on_rtp(
+                1,
+                &rtp(0, 48000 + 4800, 2, true, &[0xfc]),
+                local + Duration::from_millis(50),
+            )
+            .unwrap();
+        muxer.close().unwrap();
+
+        assert_eq!(muxer.files.len(), 1);
+        let blocks = read_blocks(&std::fs::read(&muxer.files[0]).unwrap());
+        // audio sampled 100ms after the video frame is placed 100ms later
+        assert_eq!(
+            blocks
+                .iter()
+                .map(|(track, time, _, _)| (*track, *time))
+                .collect::<Vec<_>>(),
+            vec![(1, 0), (2, 100)]
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn record_between_stream_and_close() {
+        let directory = directory("events");
+        let video = free_addr();
+        let video_rtcp = free_addr();
+        let audio = free_addr();
+        let config = MuxConfig {
+            container: Container::Webm,
+            directory: directory.clone(),
+            video: Some(MuxTrack {
+                codec: Codec::Vp8,
+                addr: video,
+                rtcp: Some(video_rtcp),
+            }),
+            audio: Some(MuxTrack {
+                codec: Codec::Opus,
+                addr: audio,
+                rtcp: None,
+            }),
+            rotation: Rotation {
+                max_bytes: None,
+                max_duration_ms: Some(150),
+            },
+        };
+        let media_connection_id = MediaConnectionId::try_create(MEDIA_CONNECTION_ID).unwrap();
+        let recorder = Arc::new(MuxRecorder::new(media_connection_id.clone(), config).unwrap());
+        let (mut event_notifier, event_observer) = mpsc::channel(10);
+        let token = CancellationToken::new();
+        let handle = tokio::spawn({
+            let recorder = recorder.clone();
+            async move { recorder.run(event_observer, token).await }
+        });
+
+        let wrapper = MediaConnectionIdWrapper {
+            media_connection_id,
+        };
+        event_notifier
+            .send(MediaConnectionEventEnum::STREAM(wrapper.clone()))
+            .await
+            .unwrap();
+
+        // Sender Reports are sent until one is recorded, since the ports are bound after STREAM.
+        // All the ports are bound by then.
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let report = SenderReport {
+            ssrc: 1,
+            time: SystemTime::now(),
+            rtp_timestamp: 0,
+            packets: 0,
+            octets: 0,
+        };
+        while recorder.stats().is_empty() {
+            gateway
+                .send_to(&report.to_bytes(), video_rtcp)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for i in 0..10u16 {
+            gateway
+                .send_to(&vp8_keyframe(i, i as u32 * 3000), video)
+                .await
+                .unwrap();
+            gateway
+                .send_to(&rtp(i, i as u32 * 1600, 2, true, &[0xfc, i as u8]), audio)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let recorded = |stats: Vec<SsrcStats>| stats.iter().map(|s| s.rtp.packets).sum::<u64>();
+        while recorded(recorder.stats()) < 20 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        event_notifier
+            .send(MediaConnectionEventEnum::CLOSE(wrapper))
+            .await
+            .unwrap();
+        let files = handle.await.unwrap().unwrap();
+
+        assert_eq!(
+            files,
+            vec![
+                directory.join(format!("{}_000.webm", MEDIA_CONNECTION_ID)),
+                directory.join(format!("{}_001.webm", MEDIA_CONNECTION_ID)),
+            ]
+        );
+        for file in files.iter() {
+            let blocks = read_blocks(&std::fs::read(file).unwrap());
+            let video_blocks = blocks.iter().filter(|block| block.0 == 1).count();
+            assert_eq!(video_blocks, 5);
+            assert!(blocks[0].2);
+            assert_eq!(blocks[0].1, 0);
+            assert!(blocks.iter().any(|block| block.0 == 2));
+        }
+        let stats = recorder.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!((stats[0].ssrc, stats[0].rtp.packets), (1, 10));
+        assert!(stats[0].rtcp.packets >= 1);
+        assert_eq!((stats[1].ssrc, stats[1].rtp.packets), (2, 10));
+        assert_eq!(stats[1].rtp.gaps, 0);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn reject_unsupported_codecs() {
+        let media_connection_id = MediaConnectionId::try_create(MEDIA_CONNECTION_ID).unwrap();
+        let config = MuxConfig {
+            container: Container::Webm,
+            directory: std::env::temp_dir(),
+            video: Some(MuxTrack {
+                codec: Codec::H264,
+                addr: free_addr(),
+                rtcp: None,
+            }),
+            audio: None,
+            rotation: Rotation::default(),
+        };
+        assert!(MuxRecorder::new(media_connection_id.clone(), config.clone()).is_err());
+        let config = MuxConfig {
+            container: Container::Matroska,
+            ..config
+        };
+        assert!(MuxRecorder::new(media_connection_id, config).is_ok());
+    }
+}
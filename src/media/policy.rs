@@ -0,0 +1,460 @@
+use serde::{Deserialize, Serialize};
+
+use crate::common::formats::{PhantomId, SerializableSocket, SocketInfo};
+use crate::common::pattern::Pattern;
+use crate::error;
+use crate::media::formats::{
+    AnswerQuery, AnswerResponse, Constraints, MediaConnectionId, MediaConnectionStatus, MediaId,
+    MediaParams, RedirectParameters, RtcpId,
+};
+use crate::peer::formats::PeerCallEvent;
+use crate::registry::Registry;
+
+/// Declarative rule to answer incoming calls.
+///
+/// A policy matches a call with the remote peer_id and metadata of the MediaConnection.
+/// It also shows media to send and sockets to which received media should be redirected.
+///
+/// It can be deserialized from a TOML or JSON like below.
+/// ```toml
+/// peer_id = "operator-*"
+/// video = true
+/// audio = false
+/// video_redirect = { media_ip = "127.0.0.1", media_port = 10000, rtcp_ip = "127.0.0.1", rtcp_port = 10001 }
+/// video_params = { band_width = 1500, codec = "H264", payload_type = 100, sampling_rate = 90000 }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AnswerPolicy {
+    /// Pattern for remote peer_id. Matches any peer if not set.
+    #[serde(default)]
+    pub peer_id: Pattern,
+    /// Pattern for metadata of the MediaConnection. Matches any metadata if not set.
+    #[serde(default)]
+    pub metadata: Pattern,
+    /// Whether this side sends video or not
+    pub video: bool,
+    /// Whether this side sends audio or not
+    pub audio: bool,
+    /// Received video is redirected to this socket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_redirect: Option<RedirectSocketConfig>,
+    /// Parameters for sending video
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_params: Option<MediaParamConfig>,
+    /// Received audio is redirected to this socket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_redirect: Option<RedirectSocketConfig>,
+    /// Parameters for sending audio
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_params: Option<MediaParamConfig>,
+}
+
+/// Sockets to which received media and rtcp are redirected
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RedirectSocketConfig {
+    pub media_ip: String,
+    pub media_port: u16,
+    pub rtcp_ip: String,
+    pub rtcp_port: u16,
+}
+
+/// Parameters for sending media. It's same as `MediaParams` except ids of sockets.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MediaParamConfig {
+    pub band_width: usize,
+    pub codec: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_type: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampling_rate: Option<usize>,
+}
+
+impl MediaParamConfig {
+    /// Create MediaParams with sockets opened in WebRTC Gateway.
+    pub fn to_media_params(
+        &self,
+        media_socket: &SocketInfo<MediaId>,
+        rtcp_socket: &SocketInfo<RtcpId>,
+    ) -> Result<MediaParams, error::Error> {
+        let media_id = media_socket
+            .get_id()
+            .ok_or_else(|| error::Error::create_local_error("no media_id"))?;
+        let rtcp_id = rtcp_socket
+            .get_id()
+            .ok_or_else(|| error::Error::create_local_error("no rtcp_id"))?;
+        Ok(MediaParams {
+            band_width: self.band_width,
+            codec: self.codec.clone(),
+            media_id,
+            rtcp_id: Some(rtcp_id),
+            payload_type: self.payload_type,
+            sampling_rate: self.sampling_rate,
+        })
+    }
+}
+
+impl AnswerPolicy {
+    /// Returns true if this policy should be applied to the MediaConnection.
+    pub fn matches(&self, status: &MediaConnectionStatus) -> bool {
+        self.peer_id.matches(status.remote_id.as_str()) && self.metadata.matches(&status.metadata)
+    }
+
+    /// Shows sockets to which received media is redirected.
+    pub fn redirect_params(&self) -> Result<RedirectParameters, error::Error> {
+        redirect_parameters(self.video_redirect.as_ref(), self.audio_redirect.as_ref())
+    }
+
+    /// Check that parameters are given for media to send, and redirect sockets are valid.
+    pub fn validate(&self) -> Result<(), error::Error> {
+        if self.video && self.video_params.is_none() {
+            return Err(error::Error::create_local_error(
+                "video_params is required to send video",
+            ));
+        }
+        if self.audio && self.audio_params.is_none() {
+            return Err(error::Error::create_local_error(
+                "audio_params is required to send audio",
+            ));
+        }
+        self.redirect_params().map(|_| ())
+    }
+}
+
+impl RedirectSocketConfig {
+    pub fn media_socket(&self) -> Result<SocketInfo<PhantomId>, error::Error> {
+        SocketInfo::<PhantomId>::try_create(None, &self.media_ip, self.media_port)
+    }
+
+    pub fn rtcp_socket(&self) -> Result<SocketInfo<PhantomId>, error::Error> {
+        SocketInfo::<PhantomId>::try_create(None, &self.rtcp_ip, self.rtcp_port)
+    }
+}
+
+/// Create RedirectParameters for call and answer from redirect configs of video and audio.
+pub fn redirect_parameters(
+    video: Option<&RedirectSocketConfig>,
+    audio: Option<&RedirectSocketConfig>,
+) -> Result<RedirectParameters, error::Error> {
+    let mut redirect_params = RedirectParameters {
+        video: None,
+        video_rtcp: None,
+        audio: None,
+        audio_rtcp: None,
+    };
+    if let Some(params) = video {
+        redirect_params.video = Some(params.media_socket()?);
+        redirect_params.video_rtcp = Some(params.rtcp_socket()?);
+    }
+    if let Some(params) = audio {
+        redirect_params.audio = Some(params.media_socket()?);
+        redirect_params.audio_rtcp = Some(params.rtcp_socket()?);
+    }
+    Ok(redirect_params)
+}
+
+/// Sockets opened in WebRTC Gateway to feed media
+pub type MediaSockets = (SocketInfo<MediaId>, SocketInfo<RtcpId>);
+
+/// Shows how an incoming call has been answered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnsweredCall {
+    pub media_connection_id: MediaConnectionId,
+    /// Index of the applied policy
+    pub policy_index: usize,
+    /// Sockets to feed video. Users send video to the socket.
+    pub video: Option<MediaSockets>,
+    /// Sockets to feed audio. Users send audio to the socket.
+    pub audio: Option<MediaSockets>,
+    /// Response from the answer API
+    pub response: AnswerResponse,
+}
+
+/// Result of handling an incoming call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnswerOutcome {
+    /// The call matches a policy and has been answered.
+    Answered(Box<AnsweredCall>),
+    /// The call doesn't match any policies and has been disconnected.
+    Rejected {
+        media_connection_id: MediaConnectionId,
+    },
+}
+
+/// Answers incoming calls according to `AnswerPolicy`s.
+///
+/// Policies are evaluated in order and the first matched one is applied.
+/// Calls matching no policies are rejected by disconnecting them.
+///
+/// Sockets and MediaConnections are registered to the registry, so that `shutdown` can release them.
+#[derive(Debug, Clone)]
+pub struct AnswerEngine {
+    policies: Vec<AnswerPolicy>,
+    registry: Registry,
+}
+
+impl AnswerEngine {
+    /// # Failures
+    /// It returns error if any policy fails `AnswerPolicy::validate`.
+    pub fn new(policies: Vec<AnswerPolicy>, registry: Registry) -> Result<Self, error::Error> {
+        for policy in policies.iter() {
+            policy.validate()?;
+        }
+        Ok(Self { policies, registry })
+    }
+
+    pub fn policies(&self) -> &[AnswerPolicy] {
+        &self.policies
+    }
+
+    /// Returns index and the first policy matching the MediaConnection.
+    pub fn find(&self, status: &MediaConnectionStatus) -> Option<(usize, &AnswerPolicy)> {
+        self.policies
+            .iter()
+            .enumerate()
+            .find(|(_, policy)| policy.matches(status))
+    }
+
+    /// Handle a CALL event of a PeerObject.
+    ///
+    /// It fetches status of the MediaConnection, and answers or rejects it.
+    pub async fn handle_call(&self, call: &PeerCallEvent) -> Result<AnswerOutcome, error::Error> {
+        let media_connection_id = call.call_params.media_connection_id.clone();
+        let status = super::status(&media_connection_id).await?;
+        let (policy_index, policy) = match self.find(&status) {
+            Some(found) => found,
+            None => {
+                super::disconnect(&media_connection_id).await?;
+                return Ok(AnswerOutcome::Rejected {
+                    media_connection_id,
+                });
+            }
+        };
+
+        let redirect_params = policy.redirect_params()?;
+        let video = match (policy.video, &policy.video_params) {
+            (true, Some(params)) => Some((open_media_sockets(true, &self.registry).await?, params)),
+            _ => None,
+        };
+        let audio = match (policy.audio, &policy.audio_params) {
+            (true, Some(params)) => {
+                Some((open_media_sockets(false, &self.registry).await?, params))
+            }
+            _ => None,
+        };
+        let constraints = Constraints {
+            video: video.is_some(),
+            videoReceiveEnabled: Some(policy.video_redirect.is_some()),
+            audio: audio.is_some(),
+            audioReceiveEnabled: Some(policy.audio_redirect.is_some()),
+            video_params: match video {
+                Some(((ref media, ref rtcp), params)) => Some(params.to_media_params(media, rtcp)?),
+                None => None,
+            },
+            audio_params: match audio {
+                Some(((ref media, ref rtcp), params)) => Some(params.to_media_params(media, rtcp)?),
+                None => None,
+            },
+            metadata: None,
+        };
+        let query = AnswerQuery {
+            constraints,
+            redirect_params: Some(redirect_params),
+        };
+
+        let response = super::answer(&media_connection_id, &query).await?;
+        self.registry
+            .register_media_connection(media_connection_id.clone());
+        Ok(AnswerOutcome::Answered(Box::new(AnsweredCall {
+            media_connection_id,
+            policy_index,
+            video: video.map(|(sockets, _)| sockets),
+            audio: audio.map(|(sockets, _)| sockets),
+            response,
+        })))
+    }
+}
+
+/// Open a media socket and an RTCP socket, and register them to the registry.
+pub(crate) async fn open_media_sockets(
+    is_video: bool,
+    registry: &Registry,
+) -> Result<MediaSockets, error::Error> {
+    let media_socket = super::open_media_socket(is_video).await?;
+    if let Some(media_id) = media_socket.get_id() {
+        registry.register_media_socket(media_id);
+    }
+    let rtcp_socket = super::open_rtcp_socket().await?;
+    if let Some(rtcp_id) = rtcp_socket.get_id() {
+        registry.register_rtcp_socket(rtcp_id);
+    }
+    Ok((media_socket, rtcp_socket))
+}
+
+#[cfg(test)]
+mod test_answer_engine {
+    use mockito::mock;
+
+    use super::*;
+    use crate::media::formats::MediaConnectionIdWrapper;
+    use crate::peer::formats::PeerInfo;
+    use crate::prelude::PeerId;
+
+    fn policies() -> Vec<AnswerPolicy> {
+        toml::from_str::<toml::Value>(
+            r#"
+            [[policy]]
+            peer_id = "operator-*"
+            metadata = "camera"
+            video = false
+            audio = false
+            video_redirect = { media_ip = "127.0.0.1", media_port = 10000, rtcp_ip = "127.0.0.1", rtcp_port = 10001 }
+
+            [[policy]]
+            peer_id = "operator-*"
+            video = false
+            audio = false
+            "#,
+        )
+        .unwrap()
+        .get("policy")
+        .cloned()
+        .unwrap()
+        .try_into()
+        .unwrap()
+    }
+
+    fn status(remote_id: &str, metadata: &str) -> MediaConnectionStatus {
+        MediaConnectionStatus {
+            metadata: metadata.into(),
+            open: false,
+            remote_id: PeerId::new(remote_id),
+            ssrc: None,
+        }
+    }
+
+    fn call_event(media_connection_id: &str) -> PeerCallEvent {
+        PeerCallEvent {
+            params: PeerInfo::try_create("peer_id", "pt-9749250e-d157-4f80-9ee2-359ce8524308")
+                .unwrap(),
+            call_params: MediaConnectionIdWrapper {
+                media_connection_id: MediaConnectionId::try_create(media_connection_id).unwrap(),
+            },
+        }
+    }
+
+    #[test]
+    fn find_first_matched_policy() {
+        let engine = AnswerEngine::new(policies(), Registry::new()).unwrap();
+        assert_eq!(
+            engine.find(&status("operator-1", "camera")).map(|(i, _)| i),
+            Some(0)
+        );
+        assert_eq!(
+            engine.find(&status("operator-1", "")).map(|(i, _)| i),
+            Some(1)
+        );
+        assert!(engine.find(&status("stranger", "camera")).is_none());
+    }
+
+    #[test]
+    fn redirect_params() {
+        let params = policies()[0].redirect_params().unwrap();
+        assert_eq!(params.video.unwrap().port(), 10000);
+        assert_eq!(params.video_rtcp.unwrap().port(), 10001);
+        assert!(params.audio.is_none());
+    }
+
+    #[tokio::test]
+    async fn answer_matched_call() {
+        crate::initialize(mockito::server_url());
+        let media_connection_id = "mc-202127d9-30de-413b-93f7-41a33e39d82b";
+        let status_mock = mock(
+            "GET",
+            format!("/media/connections/{}/status", media_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"metadata": "camera", "open": false, "remote_id": "operator-1"}"#)
+        .create();
+        let answer_mock = mock(
+            "POST",
+            format!("/media/connections/{}/answer", media_connection_id).as_str(),
+        )
+        .match_body(mockito::Matcher::PartialJsonString(
+            r#"{"redirect_params": {"video": {"ip_v4": "127.0.0.1", "port": 10000}}}"#.into(),
+        ))
+        .with_status(reqwest::StatusCode::ACCEPTED.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"command_type": "MEDIA_CONNECTION_ANSWER", "params": {}}"#)
+        .create();
+
+        let registry = Registry::new();
+        let engine = AnswerEngine::new(policies(), registry.clone()).unwrap();
+        let outcome = engine
+            .handle_call(&call_event(media_connection_id))
+            .await
+            .unwrap();
+
+        if let AnswerOutcome::Answered(answered) = outcome {
+            assert_eq!(answered.policy_index, 0);
+            assert!(answered.video.is_none());
+            assert!(answered.audio.is_none());
+        } else {
+            unreachable!();
+        }
+        assert_eq!(registry.snapshot().media_connections.len(), 1);
+        status_mock.assert();
+        answer_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn reject_unmatched_call() {
+        crate::initialize(mockito::server_url());
+        let media_connection_id = "mc-302127d9-30de-413b-93f7-41a33e39d82b";
+        let status_mock = mock(
+            "GET",
+            format!("/media/connections/{}/status", media_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"metadata": "", "open": false, "remote_id": "stranger"}"#)
+        .create();
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/media/connections/{}", media_connection_id).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+
+        let engine = AnswerEngine::new(policies(), Registry::new()).unwrap();
+        let outcome = engine
+            .handle_call(&call_event(media_connection_id))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            outcome,
+            AnswerOutcome::Rejected {
+                media_connection_id: MediaConnectionId::try_create(media_connection_id).unwrap()
+            }
+        );
+        status_mock.assert();
+        disconnect_mock.assert();
+    }
+
+    #[test]
+    fn reject_media_without_params() {
+        let mut policies = policies();
+        policies[1].video = true;
+        assert!(AnswerEngine::new(policies.clone(), Registry::new()).is_err());
+        policies[1].video_params = Some(MediaParamConfig {
+            band_width: 1500,
+            codec: "H264".into(),
+            payload_type: None,
+            sampling_rate: None,
+        });
+        assert!(AnswerEngine::new(policies.clone(), Registry::new()).is_ok());
+        policies[1].audio = true;
+        assert!(AnswerEngine::new(policies, Registry::new()).is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use futures::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+
+use super::capture::{CaptureFormat, CapturedPacket, PcapWriter, RtpdumpWriter};
+use super::rtp;
+use crate::common::formats::SerializableSocket;
+use crate::error;
+use crate::media::formats::{MediaConnectionId, RedirectParameters, SsrcPair};
+use crate::peer::formats::PeerId;
+use crate::stats::{self, MediaMeter, SsrcStats};
+
+/// Stream carried by a redirect port
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamKind {
+    Video,
+    VideoRtcp,
+    Audio,
+    AudioRtcp,
+}
+
+impl StreamKind {
+    pub fn is_rtcp(&self) -> bool {
+        matches!(self, StreamKind::VideoRtcp | StreamKind::AudioRtcp)
+    }
+
+    pub fn is_video(&self) -> bool {
+        matches!(self, StreamKind::Video | StreamKind::VideoRtcp)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            StreamKind::Video => "video",
+            StreamKind::VideoRtcp => "video_rtcp",
+            StreamKind::Audio => "audio",
+            StreamKind::AudioRtcp => "audio_rtcp",
+        }
+    }
+}
+
+/// Redirect port recorded by a `Recorder`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordTarget {
+    pub kind: StreamKind,
+    /// Address WebRTC Gateway redirects packets to. The recorder binds it.
+    pub addr: SocketAddr,
+    /// Received packets are forwarded to this address, so that the actual consumer keeps working.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forward: Option<SocketAddr>,
+}
+
+impl RecordTarget {
+    /// Targets of all ports in RedirectParameters without forwarding.
+    pub fn from_redirect_params(params: &RedirectParameters) -> Vec<Self> {
+        vec![
+            (StreamKind::Video, &params.video),
+            (StreamKind::VideoRtcp, &params.video_rtcp),
+            (StreamKind::Audio, &params.audio),
+            (StreamKind::AudioRtcp, &params.audio_rtcp),
+        ]
+        .into_iter()
+        .filter_map(|(kind, socket)| {
+            socket.as_ref().map(|socket| RecordTarget {
+                kind,
+                addr: *socket.addr(),
+                forward: None,
+            })
+        })
+        .collect()
+    }
+}
+
+/// Parameters for Recorder
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecorderConfig {
+    pub format: CaptureFormat,
+    /// Capture files and the annotation are written in this directory.
+    pub directory: PathBuf,
+    pub targets: Vec<RecordTarget>,
+}
+
+/// Recorded stream in a `CaptureAnnotation`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StreamAnnotation {
+    pub kind: StreamKind,
+    pub file: PathBuf,
+    /// Address the stream is redirected to
+    pub addr: SocketAddr,
+    pub packets: u64,
+    /// SSRCs found in the recorded packets
+    pub ssrc: BTreeSet<u32>,
+}
+
+/// Describes a recording. It's written next to capture files as `{media_connection_id}.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CaptureAnnotation {
+    pub media_connection_id: MediaConnectionId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_id: Option<PeerId>,
+    /// SSRCs reported by media::status
+    #[serde(default)]
+    pub ssrc: Vec<SsrcPair>,
+    pub format: CaptureFormat,
+    pub started_at: SystemTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stopped_at: Option<SystemTime>,
+    pub streams: Vec<StreamAnnotation>,
+}
+
+enum CaptureWriter {
+    Pcap(PcapWriter<BufWriter<File>>),
+    Rtpdump(RtpdumpWriter<BufWriter<File>>),
+}
+
+impl CaptureWriter {
+    fn write_packet(&mut self, packet: &CapturedPacket) -> io::Result<()> {
+        match self {
+            CaptureWriter::Pcap(writer) => writer.write_packet(packet),
+            CaptureWriter::Rtpdump(writer) => writer.write_packet(packet),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CaptureWriter::Pcap(writer) => writer.flush(),
+            CaptureWriter::Rtpdump(writer) => writer.flush(),
+        }
+    }
+}
+
+struct RecordStream {
+    target: RecordTarget,
+    socket: UdpSocket,
+    file: PathBuf,
+    // shared by all streams in pcap format
+    writer: Arc<Mutex<CaptureWriter>>,
+    meter: MediaMeter,
+}
+
+/// Records packets redirected from WebRTC Gateway to capture files.
+///
+/// In pcap format, all streams are written to `{media_connection_id}.pcap`.
+/// In rtpdump format, each stream is written to `{media_connection_id}_{kind}.rtpdump`.
+/// The recording is annotated with SSRCs from media::status in `{media_connection_id}.json`.
+/// Packets are counted per SSRC by a `MediaMeter`.
+pub struct Recorder {
+    media_connection_id: MediaConnectionId,
+    config: RecorderConfig,
+    started_at: SystemTime,
+    streams: Vec<RecordStream>,
+    meter: MediaMeter,
+}
+
+fn bind_error(addr: SocketAddr, e: io::Error) -> error::Error {
+    error::Error::create_local_error(&format!("fail to bind {}: {}", addr, e))
+}
+
+impl Recorder {
+    /// Bind redirect ports and create capture files.
+    pub async fn bind(
+        media_connection_id: MediaConnectionId,
+        config: RecorderConfig,
+    ) -> Result<Self, error::Error> {
+        let started_at = SystemTime::now();
+        let id = media_connection_id.as_str();
+        let mut streams = vec![];
+        let mut pcap: Option<(PathBuf, Arc<Mutex<CaptureWriter>>)> = None;
+        let meter = MediaMeter::new();
+        for target in config.targets.iter() {
+            let socket = UdpSocket::bind(target.addr)
+                .await
+                .map_err(|e| bind_error(target.addr, e))?;
+            let addr = socket.local_addr()?;
+            let (file, writer) = match config.format {
+                CaptureFormat::Pcap => match pcap {
+                    Some(ref shared) => shared.clone(),
+                    None => {
+                        let file =
+                            config
+                                .directory
+                                .join(format!("{}.{}", id, config.format.extension()));
+                        let writer = PcapWriter::new(BufWriter::new(File::create(&file)?))?;
+                        let shared = (file, Arc::new(Mutex::new(CaptureWriter::Pcap(writer))));
+                        pcap = Some(shared.clone());
+                        shared
+                    }
+                },
+                CaptureFormat::Rtpdump => {
+                    let file = config.directory.join(format!(
+                        "{}_{}.{}",
+                        id,
+                        target.kind.name(),
+                        config.format.extension()
+                    ));
+                    let writer = RtpdumpWriter::new(
+                        BufWriter::new(File::create(&file)?),
+                        started_at,
+                        addr,
+                        target.kind.is_rtcp(),
+                    )?;
+                    (file, Arc::new(Mutex::new(CaptureWriter::Rtpdump(writer))))
+                }
+            };
+            streams.push(RecordStream {
+                target: RecordTarget {
+                    addr,
+                    ..target.clone()
+                },
+                socket,
+                file,
+                writer,
+                meter: meter.clone(),
+            });
+        }
+        Ok(Self {
+            media_connection_id,
+            config,
+            started_at,
+            streams,
+            meter,
+        })
+    }
+
+    /// Address bound for the stream. It differs from the target if port 0 is given.
+    pub fn local_addr(&self, kind: StreamKind) -> Option<SocketAddr> {
+        self.streams
+            .iter()
+            .find(|stream| stream.target.kind == kind)
+            .map(|stream| stream.target.addr)
+    }
+
+    /// Traffic per SSRC of the redirected streams. It can be called while recording.
+    /// SSRCs are related to MediaIds whenever the annotation is written.
+    pub fn stats(&self) -> Vec<SsrcStats> {
+        self.meter.stats()
+    }
+
+    /// Record until the token is cancelled, and return the annotation written at last.
+    ///
+    /// The annotation is written when recording starts and rewritten when it stops,
+    /// so that SSRCs which appear after STREAM events are included.
+    pub async fn run(&self, token: CancellationToken) -> Result<CaptureAnnotation, error::Error> {
+        let mut annotation = CaptureAnnotation {
+            media_connection_id: self.media_connection_id.clone(),
+            remote_id: None,
+            ssrc: vec![],
+            format: self.config.format,
+            started_at: self.started_at,
+            stopped_at: None,
+            streams: self
+                .streams
+                .iter()
+                .map(|stream| StreamAnnotation {
+                    kind: stream.target.kind,
+                    file: stream.file.clone(),
+                    addr: stream.target.addr,
+                    packets: 0,
+                    ssrc: BTreeSet::new(),
+                })
+                .collect(),
+        };
+        annotate(&mut annotation, &self.config.directory).await?;
+        self.meter.label(&annotation.ssrc);
+
+        let record_futs = self
+            .streams
+            .iter()
+            .map(|stream| record(stream, token.clone()));
+        let results = future::join_all(record_futs).await;
+        for (stream, (packets, ssrc)) in annotation.streams.iter_mut().zip(results) {
+            stream.packets = packets;
+            stream.ssrc = ssrc;
+        }
+        for stream in self.streams.iter() {
+            stream.writer.lock().unwrap().flush()?;
+        }
+        annotation.stopped_at = Some(SystemTime::now());
+        annotate(&mut annotation, &self.config.directory).await?;
+        self.meter.label(&annotation.ssrc);
+        Ok(annotation)
+    }
+}
+
+// Update SSRCs with media::status and write the annotation.
+async fn annotate(
+    annotation: &mut CaptureAnnotation,
+    directory: &Path,
+) -> Result<(), error::Error> {
+    match super::status(&annotation.media_connection_id).await {
+        Ok(status) => {
+            annotation.remote_id = Some(status.remote_id);
+            let ssrc = status.ssrc.unwrap_or_default();
+            if !ssrc.is_empty() {
+                annotation.ssrc = ssrc;
+            }
+        }
+        Err(e) => warn!("fail to get status of the MediaConnection: {:?}", e),
+    }
+    let path = directory.join(format!("{}.json", annotation.media_connection_id.as_str()));
+    let json = serde_json::to_vec_pretty(annotation)
+        .map_err(|error| error::Error::SerdeError { error })?;
+    File::create(path)?.write_all(&json)?;
+    Ok(())
+}
+
+// Record packets until the token is cancelled, and returns the number of packets and SSRCs found.
+async fn record(stream: &RecordStream, token: CancellationToken) -> (u64, BTreeSet<u32>) {
+    let mut buf = vec![0u8; 65536];
+    let mut packets = 0;
+    let mut ssrc = BTreeSet::new();
+    loop {
+        let received = tokio::select! {
+            _ = token.cancelled() => return (packets, ssrc),
+            received = stream.socket.recv_from(&mut buf) => received,
+        };
+        let (len, source) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                warn!(
+                    "fail to receive a packet on {}: {:?}",
+                    stream.target.addr, e
+                );
+                continue;
+            }
+        };
+        let packet = CapturedPacket {
+            time: SystemTime::now(),
+            source,
+            destination: stream.target.addr,
+            payload: buf[..len].to_vec(),
+        };
+        if let Some(forward) = stream.target.forward {
+            let _ = stream.socket.send_to(&packet.payload, forward).await;
+        }
+        let clock_rate = stats::clock_rate(
+            stream.target.kind.is_video(),
+            stats::payload_type(&packet.payload),
+        );
+        stream.meter.record(&packet.payload, clock_rate);
+        packets += 1;
+        ssrc.extend(rtp::ssrc(&packet.payload));
+        if let Err(e) = stream.writer.lock().unwrap().write_packet(&packet) {
+            warn!("fail to write a packet to {:?}: {:?}", stream.file, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_recorder {
+    use mockito::mock;
+
+    use super::*;
+    use crate::common::formats::SerializableId;
+    use crate::media::rtp::test_rtp::rtp;
+
+    const MEDIA_CONNECTION_ID: &str = "mc-3a9c1e5d-2b7f-4d8e-a6c4-1f0e9d8c7b6a";
+    const MEDIA_ID: &str = "vi-4d053831-5dc2-461b-a358-d062d6115216";
+
+    #[tokio::test]
+    async fn record_and_forward() {
+        crate::initialize(mockito::server_url());
+        let status_mock = mock(
+            "GET",
+            format!("/media/connections/{}/status", MEDIA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(format!(
+            r#"{{"metadata": "", "open": true, "remote_id": "robot", "ssrc": [{{"media_id": "{}", "ssrc": 12345}}]}}"#,
+            MEDIA_ID
+        ))
+        .expect(2)
+        .create();
+
+        let directory = std::env::temp_dir().join(format!("skyway_record_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let consumer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = RecorderConfig {
+            format: CaptureFormat::Pcap,
+            directory: directory.clone(),
+            targets: vec![
+                RecordTarget {
+                    kind: StreamKind::Video,
+                    addr: "127.0.0.1:0".parse().unwrap(),
+                    forward: Some(consumer.local_addr().unwrap()),
+                },
+                RecordTarget {
+                    kind: StreamKind::VideoRtcp,
+                    addr: "127.0.0.1:0".parse().unwrap(),
+                    forward: None,
+                },
+            ],
+        };
+        let media_connection_id = MediaConnectionId::try_create(MEDIA_CONNECTION_ID).unwrap();
+        let recorder = Arc::new(
+            Recorder::bind(media_connection_id.clone(), config)
+                .await
+                .unwrap(),
+        );
+        let video = recorder.local_addr(StreamKind::Video).unwrap();
+        let token = CancellationToken::new();
+        let handle = tokio::spawn({
+            let recorder = recorder.clone();
+            let token = token.clone();
+            async move { recorder.run(token).await }
+        });
+
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        for seq in [0, 1, 3].iter() {
+            gateway
+                .send_to(&rtp(*seq, 0, 12345, false, &[]), video)
+                .await
+                .unwrap();
+            let len = consumer.recv(&mut buf).await.unwrap();
+            assert_eq!(buf[..len], rtp(*seq, 0, 12345, false, &[]));
+        }
+        token.cancel();
+        let annotation = handle.await.unwrap().unwrap();
+
+        assert_eq!(annotation.media_connection_id, media_connection_id);
+        assert_eq!(annotation.remote_id, Some(PeerId::new("robot")));
+        assert_eq!(annotation.ssrc[0].ssrc, 12345);
+        assert_eq!(annotation.streams[0].packets, 3);
+        assert_eq!(
+            annotation.streams[0].ssrc,
+            vec![12345].into_iter().collect()
+        );
+        assert_eq!(annotation.streams[1].packets, 0);
+        let stats = recorder.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].ssrc, 12345);
+        assert_eq!(
+            stats[0].media_id.as_ref().map(|id| id.as_str()),
+            Some(MEDIA_ID)
+        );
+        assert_eq!((stats[0].rtp.packets, stats[0].rtp.bytes), (3, 36));
+        assert_eq!(stats[0].rtp.gaps, 1);
+        let file = directory.join(format!("{}.pcap", MEDIA_CONNECTION_ID));
+        assert_eq!(annotation.streams[1].file, file);
+        // global header and 3 packets with IPv4 and UDP headers
+        assert_eq!(
+            std::fs::metadata(&file).unwrap().len(),
+            24 + 3 * (16 + 28 + 12)
+        );
+        let json = std::fs::read(directory.join(format!("{}.json", MEDIA_CONNECTION_ID))).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<CaptureAnnotation>(&json).unwrap(),
+            annotation
+        );
+        status_mock.assert();
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use super::capture::{self, CapturedPacket};
+use super::rtp::{self, RtpPacket};
+use crate::common::formats::{SerializableSocket, SocketInfo};
+use crate::error;
+use crate::media::formats::MediaId;
+
+/// Parameters for Replayer
+///
+/// A capture may hold several streams, so packets are selected with `destination_port` and `source_ssrc`.
+/// RTCP packets are always skipped.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReplayConfig {
+    /// Only packets sent to this port are replayed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination_port: Option<u16>,
+    /// Only packets of this SSRC are replayed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_ssrc: Option<u32>,
+    /// SSRC of replayed packets. Original SSRCs are kept if it's None.
+    /// `source_ssrc` must be set with it, so that only one stream is sent with the SSRC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssrc: Option<u32>,
+    /// First sequence number. The original one is kept if it's None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence_start: Option<u16>,
+    /// First RTP timestamp. The original one is kept if it's None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_start: Option<u32>,
+    /// Replay from the beginning again after the last packet until cancelled.
+    /// Sequence numbers and timestamps keep increasing across loops.
+    #[serde(default)]
+    pub looping: bool,
+}
+
+/// Result of Replayer::run
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReplayReport {
+    pub packets: u64,
+    /// Number of times all packets were sent
+    pub loops: u64,
+}
+
+// Offsets applied to a stream of an original SSRC
+#[derive(Debug, Clone, Copy)]
+struct Rewrite {
+    first_sequence: u16,
+    first_timestamp: u32,
+    // timestamp increment put between the last packet and the first one of the next loop
+    timestamp_step: u32,
+    sequence_offset: u16,
+    timestamp_offset: u32,
+    last_sequence: u16,
+    last_timestamp: u32,
+}
+
+impl Rewrite {
+    fn new(packets: &[RtpPacket], config: &ReplayConfig) -> Self {
+        let first_sequence = packets[0].sequence;
+        let first_timestamp = packets[0].timestamp;
+        let last_timestamp = packets[packets.len() - 1].timestamp;
+        let steps = packets
+            .windows(2)
+            .filter(|pair| pair[0].timestamp != pair[1].timestamp)
+            .count() as u32;
+        let timestamp_step = match steps {
+            0 => 1,
+            steps => (last_timestamp.wrapping_sub(first_timestamp) / steps).max(1),
+        };
+        Self {
+            first_sequence,
+            first_timestamp,
+            timestamp_step,
+            sequence_offset: config
+                .sequence_start
+                .map(|start| start.wrapping_sub(first_sequence))
+                .unwrap_or(0),
+            timestamp_offset: config
+                .timestamp_start
+                .map(|start| start.wrapping_sub(first_timestamp))
+                .unwrap_or(0),
+            last_sequence: 0,
+            last_timestamp: 0,
+        }
+    }
+
+    // `packet` is the bytes of `rtp` to be rewritten
+    fn apply(&mut self, rtp: &RtpPacket, packet: &mut [u8], ssrc: Option<u32>) {
+        self.last_sequence = rtp.sequence.wrapping_add(self.sequence_offset);
+        self.last_timestamp = rtp.timestamp.wrapping_add(self.timestamp_offset);
+        packet[2..4].copy_from_slice(&self.last_sequence.to_be_bytes());
+        packet[4..8].copy_from_slice(&self.last_timestamp.to_be_bytes());
+        if let Some(ssrc) = ssrc {
+            packet[8..12].copy_from_slice(&ssrc.to_be_bytes());
+        }
+    }
+
+    // continue from the last packet in the next loop
+    fn next_loop(&mut self) {
+        self.sequence_offset = self
+            .last_sequence
+            .wrapping_add(1)
+            .wrapping_sub(self.first_sequence);
+        self.timestamp_offset = self
+            .last_timestamp
+            .wrapping_add(self.timestamp_step)
+            .wrapping_sub(self.first_timestamp);
+    }
+}
+
+/// Sends RTP packets of a capture to a media socket with the original timing.
+///
+/// It gives reproducible media inputs, typically with captures written by `record::Recorder`.
+#[derive(Debug, Clone)]
+pub struct Replayer {
+    packets: Vec<CapturedPacket>,
+    config: ReplayConfig,
+}
+
+impl Replayer {
+    /// Select packets to replay from a capture.
+    ///
+    /// It fails if `ssrc` is set without `source_ssrc`.
+    pub fn new(packets: Vec<CapturedPacket>, config: ReplayConfig) -> Result<Self, error::Error> {
+        if config.ssrc.is_some() && config.source_ssrc.is_none() {
+            return Err(error::Error::create_local_error(
+                "source_ssrc must be set to rewrite ssrc",
+            ));
+        }
+        let packets = packets
+            .into_iter()
+            .filter(|packet| RtpPacket::parse(&packet.payload).is_some())
+            .filter(|packet| {
+                config
+                    .destination_port
+                    .map(|port| packet.destination.port() == port)
+                    .unwrap_or(true)
+            })
+            .filter(|packet| {
+                config
+                    .source_ssrc
+                    .map(|ssrc| rtp::ssrc(&packet.payload) == Some(ssrc))
+                    .unwrap_or(true)
+            })
+            .collect();
+        Ok(Self { packets, config })
+    }
+
+    /// Read a pcap or rtpdump file.
+    pub fn open(path: impl AsRef<Path>, config: ReplayConfig) -> Result<Self, error::Error> {
+        let packets = capture::read_capture(File::open(path)?)?;
+        Self::new(packets, config)
+    }
+
+    /// Number of packets to send in a loop
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Send packets to the socket until all packets are sent, or the token is cancelled if looping.
+    pub async fn run(
+        self,
+        destination: &SocketInfo<MediaId>,
+        token: CancellationToken,
+    ) -> Result<ReplayReport, error::Error> {
+        let mut report = ReplayReport::default();
+        if self.packets.is_empty() {
+            return Ok(report);
+        }
+        let bind_addr = match destination.addr() {
+            std::net::SocketAddr::V4(_) => "0.0.0.0:0",
+            std::net::SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+
+        let mut streams: HashMap<u32, Vec<RtpPacket>> = HashMap::new();
+        for rtp in self
+            .packets
+            .iter()
+            .filter_map(|packet| RtpPacket::parse(&packet.payload))
+        {
+            streams.entry(rtp.ssrc).or_default().push(rtp);
+        }
+        let mut rewrites: HashMap<u32, Rewrite> = streams
+            .into_iter()
+            .map(|(ssrc, packets)| (ssrc, Rewrite::new(&packets, &self.config)))
+            .collect();
+
+        let first_time = self.packets[0].time;
+        let elapsed =
+            |packet: &CapturedPacket| packet.time.duration_since(first_time).unwrap_or_default();
+        let duration = elapsed(&self.packets[self.packets.len() - 1]);
+        // interval between the last packet and the first one of the next loop
+        let gap = match self.packets.len() {
+            1 => Duration::from_millis(20),
+            len => duration / (len as u32 - 1),
+        };
+
+        let mut base = Instant::now();
+        loop {
+            for packet in self.packets.iter() {
+                tokio::select! {
+                    _ = token.cancelled() => return Ok(report),
+                    _ = tokio::time::sleep_until(base + elapsed(packet)) => {}
+                }
+                let mut payload = packet.payload.clone();
+                if let Some(rtp) = RtpPacket::parse(&packet.payload) {
+                    if let Some(rewrite) = rewrites.get_mut(&rtp.ssrc) {
+                        rewrite.apply(&rtp, &mut payload, self.config.ssrc);
+                    }
+                }
+                socket.send_to(&payload, destination.addr()).await?;
+                report.packets += 1;
+            }
+            report.loops += 1;
+            if !self.config.looping {
+                return Ok(report);
+            }
+            rewrites.values_mut().for_each(Rewrite::next_loop);
+            base += duration + gap;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_replayer {
+    use std::net::SocketAddr;
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::media::rtp::test_rtp::rtp;
+
+    fn captured(millis: u64, port: u16, payload: Vec<u8>) -> CapturedPacket {
+        CapturedPacket {
+            time: SystemTime::UNIX_EPOCH + Duration::from_millis(1_600_000_000_000 + millis),
+            source: "10.0.0.1:50000".parse().unwrap(),
+            destination: SocketAddr::new("127.0.0.1".parse().unwrap(), port),
+            payload,
+        }
+    }
+
+    #[test]
+    fn reject_ssrc_without_source() {
+        let config = ReplayConfig {
+            ssrc: Some(42),
+            ..Default::default()
+        };
+        assert!(Replayer::new(
+            vec![captured(0, 20000, rtp(0, 0, 7, false, &[0xde, 0xad]))],
+            config
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_with_timing_and_rewrite() {
+        let packets = vec![
+            captured(0, 20000, rtp(65534, 1000, 7, false, &[0xde, 0xad])),
+            captured(5, 20001, vec![0x80, 200, 0, 6, 0, 0, 0, 7]),
+            captured(10, 20000, rtp(65535, 4000, 7, false, &[0xde, 0xad])),
+            captured(15, 30000, rtp(100, 1000, 8, false, &[0xde, 0xad])),
+            captured(20, 20000, rtp(0, 7000, 7, false, &[0xde, 0xad])),
+        ];
+        let config = ReplayConfig {
+            destination_port: Some(20000),
+            source_ssrc: Some(7),
+            ssrc: Some(42),
+            looping: true,
+            ..Default::default()
+        };
+        let replayer = Replayer::new(packets, config).unwrap();
+        assert_eq!(replayer.len(), 3);
+
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = gateway.local_addr().unwrap().port();
+        let destination = SocketInfo::<MediaId>::try_create(
+            Some("vi-4d053831-5dc2-461b-a358-d062d6115216".to_string()),
+            "127.0.0.1",
+            port,
+        )
+        .unwrap();
+        let token = CancellationToken::new();
+        let started = Instant::now();
+        let handle = tokio::spawn({
+            let token = token.clone();
+            async move { replayer.run(&destination, token).await }
+        });
+
+        let mut buf = vec![0u8; 1024];
+        let mut received = vec![];
+        for _ in 0..6 {
+            let len = gateway.recv(&mut buf).await.unwrap();
+            received.push(buf[..len].to_vec());
+        }
+        // 2 intervals in each loop and a gap between loops
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        token.cancel();
+        let report = handle.await.unwrap().unwrap();
+        assert!(report.packets >= 6);
+        assert!(report.loops >= 1);
+
+        let expected: Vec<Vec<u8>> = vec![
+            rtp(65534, 1000, 42, false, &[0xde, 0xad]),
+            rtp(65535, 4000, 42, false, &[0xde, 0xad]),
+            rtp(0, 7000, 42, false, &[0xde, 0xad]),
+            rtp(1, 10000, 42, false, &[0xde, 0xad]),
+            rtp(2, 13000, 42, false, &[0xde, 0xad]),
+            rtp(3, 16000, 42, false, &[0xde, 0xad]),
+        ];
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn replay_once_from_given_numbers() {
+        let packets = vec![
+            captured(0, 20000, rtp(10, 1000, 7, false, &[0xde, 0xad])),
+            captured(1, 20000, rtp(11, 1000, 7, false, &[0xde, 0xad])),
+            captured(2, 20000, rtp(12, 2000, 7, false, &[0xde, 0xad])),
+        ];
+        let config = ReplayConfig {
+            sequence_start: Some(65535),
+            timestamp_start: Some(u32::MAX),
+            ..Default::default()
+        };
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = gateway.local_addr().unwrap().port();
+        let destination = SocketInfo::<MediaId>::try_create(None, "127.0.0.1", port).unwrap();
+        let report = Replayer::new(packets, config)
+            .unwrap()
+            .run(&destination, CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            ReplayReport {
+                packets: 3,
+                loops: 1
+            }
+        );
+        let mut buf = vec![0u8; 1024];
+        let mut received = vec![];
+        for _ in 0..3 {
+            let len = gateway.recv(&mut buf).await.unwrap();
+            received.push(buf[..len].to_vec());
+        }
+        assert_eq!(
+            received,
+            vec![
+                rtp(65535, u32::MAX, 7, false, &[0xde, 0xad]),
+                rtp(0, u32::MAX, 7, false, &[0xde, 0xad]),
+                rtp(1, 999, 7, false, &[0xde, 0xad])
+            ]
+        );
+    }
+}
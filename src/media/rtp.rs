@@ -0,0 +1,300 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// seconds from 1900-01-01 (NTP epoch) to 1970-01-01
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Fixed header of an RTP packet and the range of its payload
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RtpPacket<'a> {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    /// Payload without CSRCs, the header extension and padding
+    pub payload: &'a [u8],
+}
+
+impl<'a> RtpPacket<'a> {
+    /// Parse an RTP packet. It returns None for RTCP and malformed packets.
+    pub fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() < 12 || packet[0] >> 6 != 2 || is_rtcp(packet) {
+            return None;
+        }
+        let mut start = 12 + 4 * (packet[0] & 0x0f) as usize;
+        if packet[0] & 0x10 != 0 {
+            let length = packet.get(start + 2..start + 4)?;
+            start += 4 + 4 * u16::from_be_bytes([length[0], length[1]]) as usize;
+        }
+        let mut end = packet.len();
+        if packet[0] & 0x20 != 0 {
+            end = end.checked_sub(*packet.last()? as usize)?;
+        }
+        if start > end {
+            return None;
+        }
+        Some(Self {
+            marker: packet[1] & 0x80 != 0,
+            payload_type: packet[1] & 0x7f,
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+            payload: &packet[start..end],
+        })
+    }
+}
+
+/// Returns true if the packet is RTCP rather than RTP.
+pub fn is_rtcp(packet: &[u8]) -> bool {
+    // RTCP packet types are 200-204, which overlap RTP payload types 72-76 with the marker bit
+    packet.len() >= 2 && (200..=204).contains(&packet[1])
+}
+
+/// SSRC of a RTP packet, or the SSRC of the sender of a RTCP packet
+pub fn ssrc(packet: &[u8]) -> Option<u32> {
+    if !is_rtcp(packet) {
+        return RtpPacket::parse(packet).map(|rtp| rtp.ssrc);
+    }
+    if packet.len() < 8 || packet[0] >> 6 != 2 {
+        return None;
+    }
+    Some(word(packet, 4))
+}
+
+/// Sender Report of RTCP. It relates RTP timestamps of a stream to the wall clock of the sender.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SenderReport {
+    pub ssrc: u32,
+    /// NTP timestamp of the sender
+    pub time: SystemTime,
+    /// RTP timestamp corresponding to `time`
+    pub rtp_timestamp: u32,
+    pub packets: u32,
+    pub octets: u32,
+}
+
+// Split a compound RTCP packet into packets. Malformed rest is ignored.
+fn rtcp_packets(packet: &[u8]) -> Vec<&[u8]> {
+    let mut packets = vec![];
+    let mut rest = packet;
+    while rest.len() >= 4 && rest[0] >> 6 == 2 {
+        let length = 4 * (u16::from_be_bytes([rest[2], rest[3]]) as usize + 1);
+        if length > rest.len() {
+            break;
+        }
+        packets.push(&rest[..length]);
+        rest = &rest[length..];
+    }
+    packets
+}
+
+fn word(packet: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        packet[offset],
+        packet[offset + 1],
+        packet[offset + 2],
+        packet[offset + 3],
+    ])
+}
+
+impl SenderReport {
+    /// Find Sender Reports in a compound RTCP packet.
+    pub fn parse_compound(packet: &[u8]) -> Vec<Self> {
+        rtcp_packets(packet)
+            .into_iter()
+            .filter(|packet| packet[1] == 200 && packet.len() >= 28)
+            .map(|packet| {
+                let seconds = (word(packet, 8) as u64).saturating_sub(NTP_UNIX_OFFSET);
+                let nanos = (word(packet, 12) as u64 * 1_000_000_000) >> 32;
+                Self {
+                    ssrc: word(packet, 4),
+                    time: UNIX_EPOCH + Duration::from_secs(seconds) + Duration::from_nanos(nanos),
+                    rtp_timestamp: word(packet, 16),
+                    packets: word(packet, 20),
+                    octets: word(packet, 24),
+                }
+            })
+            .collect()
+    }
+
+    /// Serialize as a single RTCP packet without report blocks.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let since_epoch = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let seconds = (since_epoch.as_secs() + NTP_UNIX_OFFSET) as u32;
+        let fraction = ((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000;
+        let mut packet = vec![0x80, 200, 0, 6];
+        packet.extend(self.ssrc.to_be_bytes());
+        packet.extend(seconds.to_be_bytes());
+        packet.extend((fraction as u32).to_be_bytes());
+        packet.extend(self.rtp_timestamp.to_be_bytes());
+        packet.extend(self.packets.to_be_bytes());
+        packet.extend(self.octets.to_be_bytes());
+        packet
+    }
+}
+
+/// Report block of a Sender Report or a Receiver Report.
+/// It shows how a stream is received by the sender of the report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReportBlock {
+    /// SSRC of the sender of the report
+    pub reporter: u32,
+    /// SSRC of the reported stream
+    pub ssrc: u32,
+    /// Fraction of packets lost since the previous report, in units of 1/256
+    pub fraction_lost: u8,
+    pub cumulative_lost: u32,
+    pub highest_sequence: u32,
+    /// Inter-arrival jitter in RTP timestamp units
+    pub jitter: u32,
+}
+
+impl ReportBlock {
+    /// Find report blocks in Sender Reports and Receiver Reports of a compound RTCP packet.
+    pub fn parse_compound(packet: &[u8]) -> Vec<Self> {
+        let mut blocks = vec![];
+        for packet in rtcp_packets(packet) {
+            let start = match packet[1] {
+                200 => 28,
+                201 => 8,
+                _ => continue,
+            };
+            if packet.len() < 8 {
+                continue;
+            }
+            let reporter = word(packet, 4);
+            let count = (packet[0] & 0x1f) as usize;
+            for index in 0..count {
+                let offset = start + 24 * index;
+                if offset + 24 > packet.len() {
+                    break;
+                }
+                blocks.push(Self {
+                    reporter,
+                    ssrc: word(packet, offset),
+                    fraction_lost: packet[offset + 4],
+                    cumulative_lost: word(packet, offset + 4) & 0x00ff_ffff,
+                    highest_sequence: word(packet, offset + 8),
+                    jitter: word(packet, offset + 12),
+                });
+            }
+        }
+        blocks
+    }
+
+    /// Fraction of packets lost in 0.0-1.0
+    pub fn loss(&self) -> f64 {
+        self.fraction_lost as f64 / 256.0
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_rtp {
+    use super::*;
+
+    // RTP packet of payload type 96 without CSRCs and the header extension
+    pub(crate) fn rtp(
+        sequence: u16,
+        timestamp: u32,
+        ssrc: u32,
+        marker: bool,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut packet = vec![0x80, if marker { 0xe0 } else { 0x60 }];
+        packet.extend(sequence.to_be_bytes());
+        packet.extend(timestamp.to_be_bytes());
+        packet.extend(ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn parse_ssrc() {
+        let rtp = [0x80, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0x30, 0x39];
+        assert_eq!(ssrc(&rtp), Some(12345));
+        let rtcp = [0x80, 200, 0, 6, 0, 0, 0x30, 0x39];
+        assert_eq!(ssrc(&rtcp), Some(12345));
+        assert_eq!(ssrc(&[0x80, 0x60]), None);
+    }
+
+    #[test]
+    fn parse_rtp_with_csrc_extension_and_padding() {
+        let mut packet = vec![0xb1, 0xe0, 0x12, 0x34, 0, 0, 0x30, 0x39, 0, 0, 0, 7];
+        // CSRC
+        packet.extend([0, 0, 0, 8]);
+        // header extension with a word
+        packet.extend([0xbe, 0xde, 0, 1, 1, 2, 3, 4]);
+        packet.extend([0xaa, 0xbb]);
+        // padding
+        packet.extend([0, 2]);
+        let rtp = RtpPacket::parse(&packet).unwrap();
+        assert!(rtp.marker);
+        assert_eq!(rtp.payload_type, 96);
+        assert_eq!(rtp.sequence, 0x1234);
+        assert_eq!(rtp.timestamp, 12345);
+        assert_eq!(rtp.ssrc, 7);
+        assert_eq!(rtp.payload, &[0xaa, 0xbb]);
+
+        assert_eq!(
+            RtpPacket::parse(&[0x80, 200, 0, 6, 0, 0, 0, 7, 0, 0, 0, 0]),
+            None
+        );
+        assert_eq!(RtpPacket::parse(&[0x80, 96, 0]), None);
+    }
+
+    #[test]
+    fn sender_report_in_compound_packet() {
+        let report = SenderReport {
+            ssrc: 42,
+            time: UNIX_EPOCH + Duration::from_millis(1_600_000_000_500),
+            rtp_timestamp: 90000,
+            packets: 10,
+            octets: 1000,
+        };
+        // receiver report without report blocks followed by the sender report
+        let mut packet = vec![0x80, 201, 0, 1, 0, 0, 0, 1];
+        packet.extend(report.to_bytes());
+        let reports = SenderReport::parse_compound(&packet);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].ssrc, 42);
+        assert_eq!(reports[0].rtp_timestamp, 90000);
+        let error = reports[0]
+            .time
+            .duration_since(report.time)
+            .unwrap_or_else(|e| e.duration());
+        assert!(error < Duration::from_micros(1));
+    }
+
+    #[test]
+    fn parse_report_blocks() {
+        let report = SenderReport {
+            ssrc: 1,
+            time: UNIX_EPOCH,
+            rtp_timestamp: 0,
+            packets: 0,
+            octets: 0,
+        };
+        // Sender Report without blocks followed by a Receiver Report with 2 blocks
+        let mut packet = report.to_bytes();
+        packet.extend([0x82, 201, 0, 13]);
+        packet.extend(2u32.to_be_bytes());
+        for (ssrc, fraction_lost) in [(10u32, 64u8), (20, 0)].iter() {
+            packet.extend(ssrc.to_be_bytes());
+            packet.extend([*fraction_lost, 0, 1, 0]);
+            packet.extend(1000u32.to_be_bytes());
+            packet.extend(90u32.to_be_bytes());
+            packet.extend([0u8; 8]);
+        }
+        let blocks = ReportBlock::parse_compound(&packet);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].reporter, 2);
+        assert_eq!(blocks[0].ssrc, 10);
+        assert_eq!(blocks[0].loss(), 0.25);
+        assert_eq!(blocks[0].cumulative_lost, 256);
+        assert_eq!(blocks[0].highest_sequence, 1000);
+        assert_eq!(blocks[0].jitter, 90);
+        assert_eq!(blocks[1].ssrc, 20);
+        assert_eq!(blocks[1].loss(), 0.0);
+        assert_eq!(SenderReport::parse_compound(&packet), vec![report]);
+    }
+}
@@ -0,0 +1,681 @@
+//! MQTT bridge of events, DataConnections and commands.
+//!
+//! - Events of an `EventBus` are published as JSON to the topics in `EventTopics`.
+//! - Payloads of a DataConnection are forwarded between a pair of topics and the data socket.
+//! - Commands published to the command topic are executed, and results are published to the result topic.
+//!   Commands are the ones of the WebSocket API, with an optional `id` echoed back in the result.
+//!
+//! ```json
+//! {"id": 1, "command": "disconnect", "params": {"data_connection_id": "dc-..."}}
+//! {"type": "result", "id": 1, "result": null}
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::command::{self, Command};
+use crate::common::formats::{PhantomId, SerializableSocket, SocketInfo};
+use crate::data::formats::{DataConnectionId, DataIdWrapper, RedirectDataParams};
+use crate::error;
+use crate::event_bus::{EventBus, GatewayEvent};
+use crate::helper::default_bind_ip;
+use crate::registry::Registry;
+use crate::shutdown;
+use crate::stats::{DataMeter, DataStats};
+
+/// Topics to which events are published.
+///
+/// `{peer_id}` and `{connection_id}` in topics are replaced with the ids the event belongs to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EventTopics {
+    #[serde(default = "default_peer_topic")]
+    pub peer: String,
+    #[serde(default = "default_data_topic")]
+    pub data: String,
+    #[serde(default = "default_media_topic")]
+    pub media: String,
+}
+
+fn default_peer_topic() -> String {
+    "skyway/{peer_id}/peer".into()
+}
+
+fn default_data_topic() -> String {
+    "skyway/{peer_id}/data/{connection_id}".into()
+}
+
+fn default_media_topic() -> String {
+    "skyway/{peer_id}/media/{connection_id}".into()
+}
+
+impl Default for EventTopics {
+    fn default() -> Self {
+        Self {
+            peer: default_peer_topic(),
+            data: default_data_topic(),
+            media: default_media_topic(),
+        }
+    }
+}
+
+impl EventTopics {
+    /// Topic to which the event is published
+    pub fn topic(&self, event: &GatewayEvent) -> String {
+        let template = match event {
+            GatewayEvent::Peer { .. } => &self.peer,
+            GatewayEvent::Data { .. } => &self.data,
+            GatewayEvent::Media { .. } => &self.media,
+        };
+        template
+            .replace("{peer_id}", event.peer_id().as_str())
+            .replace("{connection_id}", event.connection_id().unwrap_or_default())
+    }
+}
+
+/// DataConnection forwarded to a pair of topics
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DataTopicConfig {
+    pub data_connection_id: DataConnectionId,
+    /// Payloads published to this topic are sent to the neighbour.
+    pub inbound: String,
+    /// Payloads received from the neighbour are published to this topic.
+    pub outbound: String,
+}
+
+/// Config of a `MqttBridge`.
+///
+/// ```toml
+/// host = "localhost"
+/// port = 1883
+///
+/// [[data]]
+/// data_connection_id = "dc-4995f372-fb6a-4196-b30a-ce11e5c7f56c"
+/// inbound = "robot/cmd_vel"
+/// outbound = "robot/odom"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MqttConfig {
+    #[serde(default = "default_host")]
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    #[serde(default = "default_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    #[serde(default)]
+    pub events: EventTopics,
+    /// Commands are not accepted if it's None.
+    #[serde(default = "default_command_topic")]
+    pub command_topic: Option<String>,
+    #[serde(default = "default_result_topic")]
+    pub result_topic: String,
+    #[serde(default)]
+    pub data: Vec<DataTopicConfig>,
+    /// Address on which the bridge receives data redirected from WebRTC Gateway
+    #[serde(default = "default_bind_ip")]
+    pub bind_ip: IpAddr,
+}
+
+fn default_host() -> String {
+    "localhost".into()
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "skyway-gateway".into()
+}
+
+fn default_keep_alive_secs() -> u64 {
+    30
+}
+
+fn default_command_topic() -> Option<String> {
+    Some("skyway/command".into())
+}
+
+fn default_result_topic() -> String {
+    "skyway/command/result".into()
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: default_host(),
+            port: default_port(),
+            client_id: default_client_id(),
+            keep_alive_secs: default_keep_alive_secs(),
+            events: EventTopics::default(),
+            command_topic: default_command_topic(),
+            result_topic: default_result_topic(),
+            data: vec![],
+            bind_ip: default_bind_ip(),
+        }
+    }
+}
+
+/// Payload of the command topic
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommandMessage {
+    /// Echoed back in the result to match it with the command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub command: Box<Command>,
+}
+
+/// Payload of the result topic
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CommandResult {
+    Result {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        result: Value,
+    },
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        error: Value,
+    },
+}
+
+impl CommandResult {
+    fn error(id: Option<u64>, error: &error::Error) -> Self {
+        CommandResult::Error {
+            id,
+            error: serde_json::to_value(error).unwrap_or_default(),
+        }
+    }
+}
+
+fn client_error(e: rumqttc::ClientError) -> error::Error {
+    error::Error::create_local_error(&format!("fail to send a MQTT request: {:?}", e))
+}
+
+// Data is fed to the data socket and redirected to `socket`.
+struct DataLink {
+    config: DataTopicConfig,
+    socket: UdpSocket,
+    feed: SocketAddr,
+    meter: DataMeter,
+}
+
+impl DataLink {
+    // Open a data socket and redirect the DataConnection to a local socket.
+    async fn open(
+        config: DataTopicConfig,
+        bind_ip: IpAddr,
+        registry: &Registry,
+        meter: DataMeter,
+    ) -> Result<Self, error::Error> {
+        let socket = UdpSocket::bind(SocketAddr::new(bind_ip, 0)).await?;
+        let addr = socket.local_addr()?;
+        let feed = crate::data::open_data_socket().await?;
+        if let Some(data_id) = feed.get_id() {
+            registry.register_data_socket(data_id);
+        }
+        let params = RedirectDataParams {
+            feed_params: feed.get_id().map(|data_id| DataIdWrapper { data_id }),
+            redirect_params: Some(SocketInfo::<PhantomId>::try_create(
+                None,
+                &addr.ip().to_string(),
+                addr.port(),
+            )?),
+        };
+        let _ = crate::data::redirect(&config.data_connection_id, &params).await?;
+        Ok(Self {
+            config,
+            socket,
+            feed: *feed.addr(),
+            meter,
+        })
+    }
+
+    async fn send(&self, payload: &[u8]) {
+        match self.socket.send_to(payload, self.feed).await {
+            Ok(len) => self.meter.feed.record(len),
+            Err(e) => warn!("fail to send data to {}: {:?}", self.config.inbound, e),
+        }
+    }
+
+    // Publish data received from the neighbour until the socket fails.
+    async fn forward(self: Arc<Self>, client: AsyncClient) {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let len = match self.socket.recv_from(&mut buf).await {
+                Ok((len, _)) => len,
+                Err(e) => {
+                    warn!("fail to receive data of {}: {:?}", self.config.outbound, e);
+                    return;
+                }
+            };
+            self.meter.redirect.record(len);
+            let result = client
+                .publish(
+                    self.config.outbound.clone(),
+                    QoS::AtMostOnce,
+                    false,
+                    buf[..len].to_vec(),
+                )
+                .await;
+            if let Err(e) = result {
+                warn!("{:?}", client_error(e));
+            }
+        }
+    }
+}
+
+// Publish events of the bus until it's stopped.
+async fn publish_events(
+    mut events: broadcast::Receiver<GatewayEvent>,
+    client: AsyncClient,
+    topics: EventTopics,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let payload = serde_json::to_vec(&event).unwrap_or_default();
+        let result = client
+            .publish(topics.topic(&event), QoS::AtLeastOnce, false, payload)
+            .await;
+        if let Err(e) = result {
+            warn!("{:?}", client_error(e));
+        }
+    }
+}
+
+async fn execute(
+    registry: Registry,
+    bus: EventBus,
+    client: AsyncClient,
+    result_topic: String,
+    payload: Vec<u8>,
+) {
+    let result = match serde_json::from_slice::<CommandMessage>(&payload) {
+        Ok(CommandMessage { id, command }) => {
+            match command::execute(&registry, &bus, *command).await {
+                Ok(result) => CommandResult::Result { id, result },
+                Err(e) => CommandResult::error(id, &e),
+            }
+        }
+        Err(error) => CommandResult::error(None, &error::Error::SerdeError { error }),
+    };
+    let payload = serde_json::to_vec(&result).unwrap_or_default();
+    if let Err(e) = client
+        .publish(result_topic, QoS::AtLeastOnce, false, payload)
+        .await
+    {
+        warn!("{:?}", client_error(e));
+    }
+}
+
+/// Bridges a MQTT broker and WebRTC Gateway.
+///
+/// It reconnects to the broker and subscribes topics again when the connection is lost.
+/// Data sockets are registered to the registry, so that `shutdown` can release them.
+/// Traffic of each DataConnection is counted by a `DataMeter`.
+#[derive(Clone)]
+pub struct MqttBridge {
+    config: MqttConfig,
+    bus: EventBus,
+    registry: Registry,
+    meters: BTreeMap<DataConnectionId, DataMeter>,
+}
+
+impl MqttBridge {
+    pub fn new(config: MqttConfig, bus: EventBus, registry: Registry) -> Self {
+        let meters = config
+            .data
+            .iter()
+            .map(|data| (data.data_connection_id.clone(), DataMeter::new()))
+            .collect();
+        Self {
+            config,
+            bus,
+            registry,
+            meters,
+        }
+    }
+
+    pub fn config(&self) -> &MqttConfig {
+        &self.config
+    }
+
+    /// Traffic of the DataConnections in the config.
+    /// Clones share the traffic, so it can be called on a clone while running.
+    pub fn stats(&self) -> BTreeMap<DataConnectionId, DataStats> {
+        self.meters
+            .iter()
+            .map(|(id, meter)| (id.clone(), meter.stats()))
+            .collect()
+    }
+
+    /// Bridge until the token is cancelled.
+    ///
+    /// It fails only when the DataConnections in the config can't be redirected.
+    pub async fn run(self, token: CancellationToken) -> Result<(), error::Error> {
+        let mut options = MqttOptions::new(
+            self.config.client_id.clone(),
+            self.config.host.clone(),
+            self.config.port,
+        );
+        options.set_keep_alive(Duration::from_secs(self.config.keep_alive_secs));
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+        let mut links = HashMap::new();
+        for config in self.config.data.clone() {
+            let meter = self
+                .meters
+                .get(&config.data_connection_id)
+                .cloned()
+                .unwrap_or_default();
+            let link =
+                Arc::new(DataLink::open(config, self.config.bind_ip, &self.registry, meter).await?);
+            shutdown::spawn_listener(&token, link.clone().forward(client.clone()));
+            links.insert(link.config.inbound.clone(), link);
+        }
+        shutdown::spawn_listener(
+            &token,
+            publish_events(
+                self.bus.subscribe(),
+                client.clone(),
+                self.config.events.clone(),
+            ),
+        );
+
+        let mut filters: Vec<String> = self.config.command_topic.iter().cloned().collect();
+        filters.extend(links.keys().cloned());
+        loop {
+            let event = tokio::select! {
+                _ = token.cancelled() => break,
+                event = eventloop.poll() => event,
+            };
+            match event {
+                // subscriptions are lost when a clean session is started
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    for filter in &filters {
+                        if let Err(e) = client.try_subscribe(filter.clone(), QoS::AtLeastOnce) {
+                            warn!("{:?}", client_error(e));
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    if Some(&publish.topic) == self.config.command_topic.as_ref() {
+                        shutdown::spawn_listener(
+                            &token,
+                            execute(
+                                self.registry.clone(),
+                                self.bus.clone(),
+                                client.clone(),
+                                self.config.result_topic.clone(),
+                                publish.payload.to_vec(),
+                            ),
+                        );
+                    } else if let Some(link) = links.get(&publish.topic) {
+                        link.send(&publish.payload).await;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection is lost: {:?}", e);
+                    tokio::select! {
+                        _ = token.cancelled() => break,
+                        _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+                    }
+                }
+            }
+        }
+        let _ = client.try_disconnect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_mqtt {
+    use futures::*;
+    use mockito::mock;
+
+    use super::*;
+
+    const DATA_CONNECTION_ID: &str = "dc-5c1e4a2b-7d3f-4b8e-9a6c-0f1e2d3c4b5a";
+
+    // Broker given by MQTT_BROKER like "localhost:1883"
+    fn broker() -> (String, u16) {
+        let broker = std::env::var("MQTT_BROKER").unwrap_or_else(|_| "localhost:1883".into());
+        let (host, port) = broker
+            .rsplit_once(':')
+            .expect("MQTT_BROKER must be host:port");
+        (
+            host.into(),
+            port.parse().expect("invalid port of MQTT_BROKER"),
+        )
+    }
+
+    async fn next_message(
+        packets: &mut futures::channel::mpsc::UnboundedReceiver<Packet>,
+        topic: &str,
+    ) -> Vec<u8> {
+        loop {
+            if let Packet::Publish(publish) = packets.next().await.unwrap() {
+                if publish.topic == topic {
+                    return publish.payload.to_vec();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parse_config() {
+        let config: MqttConfig = toml::from_str(&format!(
+            r#"
+            host = "broker"
+
+            [events]
+            peer = "robot/{{peer_id}}"
+
+            [[data]]
+            data_connection_id = "{}"
+            inbound = "robot/cmd_vel"
+            outbound = "robot/odom"
+            "#,
+            DATA_CONNECTION_ID
+        ))
+        .unwrap();
+        assert_eq!(config.host, "broker");
+        assert_eq!(config.port, 1883);
+        assert_eq!(config.events.peer, "robot/{peer_id}");
+        assert_eq!(config.events.data, default_data_topic());
+        assert_eq!(config.command_topic, default_command_topic());
+        assert_eq!(config.data[0].outbound, "robot/odom");
+    }
+
+    #[test]
+    fn event_topics() {
+        let topics = EventTopics {
+            peer: "robot/{peer_id}".into(),
+            ..Default::default()
+        };
+        let event: GatewayEvent = serde_json::from_str(&format!(
+            r#"{{"type": "Data", "peer_id": "robot", "data_connection_id": "{0}", "event": {{"event": "OPEN", "data_connection_id": "{0}"}}}}"#,
+            DATA_CONNECTION_ID
+        ))
+        .unwrap();
+        assert_eq!(
+            topics.topic(&event),
+            format!("skyway/robot/data/{}", DATA_CONNECTION_ID)
+        );
+        let event: GatewayEvent = serde_json::from_str(
+            r#"{"type": "Peer", "peer_id": "robot", "event": {"event": "CLOSE", "params": {"peer_id": "robot", "token": "pt-9749250e-d157-4f80-9ee2-359ce8524308"}}}"#,
+        )
+        .unwrap();
+        assert_eq!(topics.topic(&event), "robot/robot");
+    }
+
+    // Requires an MQTT broker such as mosquitto, so it's run by .github/workflows/mqtt.yml.
+    // Run locally with `MQTT_BROKER=localhost:1883 cargo test --features mqtt -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn bridge_events_data_and_commands() {
+        crate::initialize(mockito::server_url());
+        let gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let open_mock = mock("POST", "/data")
+            .with_status(reqwest::StatusCode::CREATED.as_u16() as usize)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"data_id": "da-3f2e1d0c-9b8a-4c7d-8e6f-5a4b3c2d1e0f", "port": {}, "ip_v4": "127.0.0.1"}}"#,
+                gateway.local_addr().unwrap().port()
+            ))
+            .create();
+        let redirect_mock = mock(
+            "PUT",
+            format!("/data/connections/{}", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{"command_type": "DATA_CONNECTION_PUT", "data_id": "da-3f2e1d0c-9b8a-4c7d-8e6f-5a4b3c2d1e0f"}"#,
+        )
+        .create();
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/data/connections/{}", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .expect_at_least(1)
+        .create();
+
+        let (host, port) = broker();
+        let config = MqttConfig {
+            host: host.clone(),
+            port,
+            client_id: "bridge_test_gateway".into(),
+            command_topic: Some("bridge_test/command".into()),
+            result_topic: "bridge_test/result".into(),
+            data: vec![DataTopicConfig {
+                data_connection_id: DataConnectionId::try_create(DATA_CONNECTION_ID).unwrap(),
+                inbound: "bridge_test/inbound".into(),
+                outbound: "bridge_test/outbound".into(),
+            }],
+            ..Default::default()
+        };
+        let bus = EventBus::new(8);
+        let registry = Registry::new();
+        let token = CancellationToken::new();
+        let bridge = MqttBridge::new(config, bus.clone(), registry.clone());
+        let handle = tokio::spawn(bridge.clone().run(token.clone()));
+
+        // client of the IoT backend
+        let (client, mut eventloop) =
+            AsyncClient::new(MqttOptions::new("bridge_test_backend", host, port), 16);
+        client
+            .subscribe("bridge_test/#", QoS::AtMostOnce)
+            .await
+            .unwrap();
+        client
+            .subscribe("skyway/robot/#", QoS::AtMostOnce)
+            .await
+            .unwrap();
+        let (packet_notifier, mut packets) = futures::channel::mpsc::unbounded::<Packet>();
+        tokio::spawn(async move {
+            while let Ok(event) = eventloop.poll().await {
+                if let Event::Incoming(packet) = event {
+                    let _ = packet_notifier.unbounded_send(packet);
+                }
+            }
+        });
+        let mut subscribed = 0;
+        while subscribed < 2 {
+            if let Packet::SubAck(_) = packets.next().await.unwrap() {
+                subscribed += 1;
+            }
+        }
+
+        // MQTT -> data socket, retried until the bridge subscribes the topic
+        let mut buf = vec![0u8; 1024];
+        let (len, bridge_addr) = loop {
+            client
+                .publish("bridge_test/inbound", QoS::AtMostOnce, false, "cmd")
+                .await
+                .unwrap();
+            let received =
+                tokio::time::timeout(Duration::from_millis(100), gateway.recv_from(&mut buf)).await;
+            if let Ok(received) = received {
+                break received.unwrap();
+            }
+        };
+        assert_eq!(&buf[..len], b"cmd");
+
+        // data socket -> MQTT. The data is redirected to the socket which sent the data above.
+        gateway.send_to(b"odom", bridge_addr).await.unwrap();
+        assert_eq!(
+            next_message(&mut packets, "bridge_test/outbound").await,
+            b"odom"
+        );
+        let stats = bridge.stats()[&DataConnectionId::try_create(DATA_CONNECTION_ID).unwrap()];
+        assert_eq!((stats.redirect.packets, stats.redirect.bytes), (1, 4));
+        // publishes before the subscription may be retried
+        assert!(stats.feed.packets >= 1);
+        assert_eq!(stats.feed.bytes, 3 * stats.feed.packets);
+
+        // events
+        let event: GatewayEvent = serde_json::from_str(&format!(
+            r#"{{"type": "Data", "peer_id": "robot", "data_connection_id": "{0}", "event": {{"event": "OPEN", "data_connection_id": "{0}"}}}}"#,
+            DATA_CONNECTION_ID
+        ))
+        .unwrap();
+        bus.publish(event.clone());
+        let topic = format!("skyway/robot/data/{}", DATA_CONNECTION_ID);
+        assert_eq!(
+            serde_json::from_slice::<GatewayEvent>(&next_message(&mut packets, &topic).await)
+                .unwrap(),
+            event
+        );
+
+        // commands
+        client
+            .publish(
+                "bridge_test/command",
+                QoS::AtLeastOnce,
+                false,
+                format!(
+                    r#"{{"id": 1, "command": "disconnect", "params": {{"data_connection_id": "{}"}}}}"#,
+                    DATA_CONNECTION_ID
+                ),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<CommandResult>(
+                &next_message(&mut packets, "bridge_test/result").await
+            )
+            .unwrap(),
+            CommandResult::Result {
+                id: Some(1),
+                result: Value::Null
+            }
+        );
+
+        token.cancel();
+        assert!(handle.await.unwrap().is_ok());
+        assert_eq!(registry.snapshot().data_sockets.len(), 1);
+        open_mock.assert();
+        redirect_mock.assert();
+        disconnect_mock.assert();
+    }
+}
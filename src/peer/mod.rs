@@ -1,6 +1,8 @@
 /// Functions in this module are responsible for concealing the raw APIs
 pub(crate) mod api;
 pub(crate) mod formats;
+/// Watch whether PeerObjects are connected to SkyWay server and recreate them
+pub mod monitor;
 
 use futures::channel::mpsc;
 use futures::*;
@@ -0,0 +1,512 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::*;
+use log::{info, warn};
+use tokio_util::sync::CancellationToken;
+
+use crate::error;
+use crate::peer::formats::PeerInfo;
+use crate::peer::PeerEventEnum;
+
+/// Parameters to recreate a PeerObject with the same peer_id.
+///
+/// They are same as the parameters of `peer::create`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecreateParams {
+    pub api_key: String,
+    pub domain: String,
+    pub turn: bool,
+    /// How long to wait for the OPEN event of the recreated PeerObject
+    pub open_timeout: Duration,
+}
+
+/// Parameters for PeerMonitor
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitorConfig {
+    /// Interval of calling peer::status
+    pub interval: Duration,
+    /// If set, the PeerObject is recreated when it's disconnected from SkyWay server.
+    pub recreate: Option<RecreateParams>,
+}
+
+/// Events notified by PeerMonitor
+#[derive(Debug, Clone, PartialEq)]
+pub enum LivenessEvent {
+    /// The PeerObject has been disconnected from SkyWay server.
+    Disconnected(PeerInfo),
+    /// The PeerObject has been connected to SkyWay server again without recreation.
+    Reconnected(PeerInfo),
+    /// The PeerObject has been recreated with the same peer_id.
+    Recreated { old: PeerInfo, new: PeerInfo },
+    /// Failed to recreate the PeerObject. It will be retried in the next interval.
+    RecreateFailed(PeerInfo, String),
+    /// Failed to get status of the PeerObject.
+    /// It's notified once until status is fetched successfully again.
+    StatusFailed(PeerInfo, String),
+}
+
+type PeerHandler = Arc<dyn Fn(PeerInfo) + Send + Sync>;
+
+/// Watches whether a PeerObject is connected to SkyWay signaling server with peer::status.
+///
+/// Handlers registered to the monitor are called with PeerInfo when the monitor starts
+/// and whenever the PeerObject is recreated, so that users can start listening events of the new PeerObject.
+#[derive(Clone)]
+pub struct PeerMonitor {
+    peer_info: Arc<Mutex<PeerInfo>>,
+    config: MonitorConfig,
+    handlers: Vec<PeerHandler>,
+}
+
+impl PeerMonitor {
+    pub fn new(peer_info: PeerInfo, config: MonitorConfig) -> Self {
+        Self {
+            peer_info: Arc::new(Mutex::new(peer_info)),
+            config,
+            handlers: vec![],
+        }
+    }
+
+    /// Register a handler applied to the PeerObject.
+    pub fn register_handler(&mut self, handler: impl Fn(PeerInfo) + Send + Sync + 'static) {
+        self.handlers.push(Arc::new(handler));
+    }
+
+    /// Current PeerInfo. It changes when the PeerObject is recreated.
+    pub fn peer_info(&self) -> PeerInfo {
+        self.peer_info
+            .lock()
+            .expect("peer_info lock poisoned")
+            .clone()
+    }
+
+    fn apply_handlers(&self, peer_info: &PeerInfo) {
+        for handler in self.handlers.iter() {
+            handler(peer_info.clone());
+        }
+    }
+
+    // recreate the PeerObject, and apply handlers to the new one if it succeeds
+    async fn try_recreate(&self, old: PeerInfo, params: &RecreateParams) -> LivenessEvent {
+        match recreate(&old, params).await {
+            Ok(new) => {
+                info!("Peer({}) has been recreated", new.peer_id().as_str());
+                *self.peer_info.lock().expect("peer_info lock poisoned") = new.clone();
+                self.apply_handlers(&new);
+                LivenessEvent::Recreated { old, new }
+            }
+            Err(e) => {
+                warn!("fail to recreate Peer: {:?}", e);
+                LivenessEvent::RecreateFailed(old, serde_json::to_string(&e).unwrap_or_default())
+            }
+        }
+    }
+
+    /// Keep polling status of the PeerObject until the token is cancelled.
+    ///
+    /// After recreation fails, the old PeerObject is already deleted,
+    /// so recreation is retried in each interval instead of polling its status.
+    pub async fn run(
+        self,
+        mut event_notifier: mpsc::Sender<LivenessEvent>,
+        token: CancellationToken,
+    ) -> Result<(), error::Error> {
+        self.apply_handlers(&self.peer_info());
+        let mut interval = tokio::time::interval(self.config.interval);
+        let mut disconnected = false;
+        let mut status_failed = false;
+        let mut recreating = false;
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => return Ok(()),
+                _ = interval.tick() => {}
+            }
+
+            let peer_info = self.peer_info();
+            if let (true, Some(params)) = (recreating, self.config.recreate.as_ref()) {
+                let event = self.try_recreate(peer_info, params).await;
+                recreating = matches!(event, LivenessEvent::RecreateFailed(..));
+                disconnected = recreating;
+                notify(&mut event_notifier, event).await?;
+                continue;
+            }
+            let status = super::status(&peer_info).await;
+            let was_failed = std::mem::replace(&mut status_failed, status.is_err());
+            let event = match status {
+                Ok(status) if status.disconnected => match self.config.recreate {
+                    Some(ref params) => {
+                        if !disconnected {
+                            notify(
+                                &mut event_notifier,
+                                LivenessEvent::Disconnected(peer_info.clone()),
+                            )
+                            .await?;
+                        }
+                        let event = self.try_recreate(peer_info, params).await;
+                        recreating = matches!(event, LivenessEvent::RecreateFailed(..));
+                        disconnected = recreating;
+                        Some(event)
+                    }
+                    None if !disconnected => {
+                        disconnected = true;
+                        Some(LivenessEvent::Disconnected(peer_info))
+                    }
+                    None => None,
+                },
+                Ok(_) if disconnected => {
+                    disconnected = false;
+                    Some(LivenessEvent::Reconnected(peer_info))
+                }
+                Ok(_) => None,
+                Err(_) if was_failed => None,
+                Err(e) => Some(LivenessEvent::StatusFailed(
+                    peer_info,
+                    serde_json::to_string(&e).unwrap_or_default(),
+                )),
+            };
+            if let Some(event) = event {
+                notify(&mut event_notifier, event).await?;
+            }
+        }
+    }
+}
+
+async fn notify(
+    event_notifier: &mut mpsc::Sender<LivenessEvent>,
+    event: LivenessEvent,
+) -> Result<(), error::Error> {
+    event_notifier
+        .send(event)
+        .await
+        .map_err(|_| error::Error::create_local_error("fail to notify an event"))
+}
+
+// delete the old PeerObject and create a new one with the same peer_id, then wait for its OPEN event
+async fn recreate(peer_info: &PeerInfo, params: &RecreateParams) -> Result<PeerInfo, error::Error> {
+    // The old PeerObject may be already released.
+    let _ = super::delete(peer_info).await;
+    let new = super::create(
+        params.api_key.clone(),
+        params.domain.clone(),
+        peer_info.peer_id(),
+        params.turn,
+    )
+    .await?;
+    match wait_open(&new, params.open_timeout).await {
+        Ok(new) => Ok(new),
+        Err(e) => {
+            // release the new PeerObject so that the peer_id can be used in the next try
+            if let Err(e) = super::delete(&new).await {
+                warn!("fail to delete Peer not opened: {:?}", e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Wait for the OPEN event of a PeerObject just created.
+pub(crate) async fn wait_open(
+    peer_info: &PeerInfo,
+    timeout: Duration,
+) -> Result<PeerInfo, error::Error> {
+    let wait_open = async {
+        loop {
+            match super::event(peer_info.clone()).await? {
+                PeerEventEnum::OPEN(event) => return Ok(event.params),
+                PeerEventEnum::CLOSE(_) => {
+                    return Err(error::Error::create_local_error(
+                        "peer is closed before OPEN",
+                    ))
+                }
+                _ => {}
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, wait_open).await {
+        Ok(result) => result,
+        Err(_) => Err(error::Error::create_local_error(
+            "peer doesn't receive OPEN",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test_peer_monitor {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use mockito::mock;
+
+    use super::*;
+
+    const OLD_TOKEN: &str = "pt-9749250e-d157-4f80-9ee2-359ce8524308";
+    const NEW_TOKEN: &str = "pt-0749250e-d157-4f80-9ee2-359ce8524308";
+
+    #[tokio::test]
+    async fn notify_disconnected_once() {
+        crate::initialize(mockito::server_url());
+        let peer_info = PeerInfo::try_create("monitor_peer", OLD_TOKEN).unwrap();
+        let status_mock = mock(
+            "GET",
+            format!("/peers/monitor_peer/status?token={}", OLD_TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"peer_id": "monitor_peer", "disconnected": true}"#)
+        .expect_at_least(2)
+        .create();
+
+        let config = MonitorConfig {
+            interval: Duration::from_millis(10),
+            recreate: None,
+        };
+        let monitor = PeerMonitor::new(peer_info.clone(), config);
+        let (event_notifier, mut event_observer) = mpsc::channel::<LivenessEvent>(10);
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(monitor.run(event_notifier, token.clone()));
+
+        let event = event_observer.next().await.unwrap();
+        assert_eq!(event, LivenessEvent::Disconnected(peer_info));
+        // wait for some more polling
+        polled(&status_mock).await;
+        token.cancel();
+        handle.await.unwrap().unwrap();
+        // Disconnected is notified only once
+        assert!(event_observer.next().await.is_none());
+        status_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn notify_status_failed_once() {
+        crate::initialize(mockito::server_url());
+        let peer_info = PeerInfo::try_create("unreachable_peer", OLD_TOKEN).unwrap();
+        let status_mock = mock(
+            "GET",
+            format!("/peers/unreachable_peer/status?token={}", OLD_TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::INTERNAL_SERVER_ERROR.as_u16() as usize)
+        .expect_at_least(2)
+        .create();
+
+        let config = MonitorConfig {
+            interval: Duration::from_millis(10),
+            recreate: None,
+        };
+        let monitor = PeerMonitor::new(peer_info.clone(), config);
+        let (event_notifier, mut event_observer) = mpsc::channel::<LivenessEvent>(10);
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(monitor.run(event_notifier, token.clone()));
+
+        let event = event_observer.next().await.unwrap();
+        assert!(matches!(event, LivenessEvent::StatusFailed(ref info, _) if info == &peer_info));
+        polled(&status_mock).await;
+        token.cancel();
+        handle.await.unwrap().unwrap();
+        assert!(event_observer.next().await.is_none());
+        status_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn recreate_peer() {
+        crate::initialize(mockito::server_url());
+        let old = PeerInfo::try_create("recreated_peer", OLD_TOKEN).unwrap();
+        let new = PeerInfo::try_create("recreated_peer", NEW_TOKEN).unwrap();
+        let status_mock = mock(
+            "GET",
+            format!("/peers/recreated_peer/status?token={}", OLD_TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"peer_id": "recreated_peer", "disconnected": true}"#)
+        .create();
+        let delete_mock = mock(
+            "DELETE",
+            format!("/peers/recreated_peer?token={}", OLD_TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+        let create_mock = mock("POST", "/peers")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"peer_id": "recreated_peer"}"#.into(),
+            ))
+            .with_status(reqwest::StatusCode::CREATED.as_u16() as usize)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"command_type": "PEERS_CREATE", "params": {{"peer_id": "recreated_peer", "token": "{}"}}}}"#,
+                NEW_TOKEN
+            ))
+            .create();
+        let event_mock = mock(
+            "GET",
+            format!("/peers/recreated_peer/events?token={}", NEW_TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(format!(
+            r#"{{"event": "OPEN", "params": {{"peer_id": "recreated_peer", "token": "{}"}}}}"#,
+            NEW_TOKEN
+        ))
+        .create();
+        let new_status_mock = mock(
+            "GET",
+            format!("/peers/recreated_peer/status?token={}", NEW_TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"peer_id": "recreated_peer", "disconnected": false}"#)
+        .expect_at_least(0)
+        .create();
+
+        let config = MonitorConfig {
+            interval: Duration::from_millis(10),
+            recreate: Some(RecreateParams {
+                api_key: "api_key".into(),
+                domain: "localhost".into(),
+                turn: false,
+                open_timeout: Duration::from_secs(1),
+            }),
+        };
+        let mut monitor = PeerMonitor::new(old.clone(), config);
+        let applied = Arc::new(AtomicUsize::new(0));
+        let applied_in_handler = applied.clone();
+        monitor.register_handler(move |_| {
+            applied_in_handler.fetch_add(1, Ordering::SeqCst);
+        });
+        let (event_notifier, mut event_observer) = mpsc::channel::<LivenessEvent>(10);
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(monitor.clone().run(event_notifier, token.clone()));
+
+        assert_eq!(
+            event_observer.next().await.unwrap(),
+            LivenessEvent::Disconnected(old.clone())
+        );
+        assert_eq!(
+            event_observer.next().await.unwrap(),
+            LivenessEvent::Recreated {
+                old,
+                new: new.clone()
+            }
+        );
+        token.cancel();
+        handle.await.unwrap().unwrap();
+
+        assert_eq!(monitor.peer_info(), new);
+        // applied at start and after recreation
+        assert_eq!(applied.load(Ordering::SeqCst), 2);
+        status_mock.assert();
+        delete_mock.assert();
+        create_mock.assert();
+        event_mock.assert();
+        new_status_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn retry_recreate() {
+        crate::initialize(mockito::server_url());
+        let old = PeerInfo::try_create("retried_peer", OLD_TOKEN).unwrap();
+        let new = PeerInfo::try_create("retried_peer", NEW_TOKEN).unwrap();
+        // status of the old PeerObject is not polled after it's deleted
+        let status_mock = mock(
+            "GET",
+            format!("/peers/retried_peer/status?token={}", OLD_TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"peer_id": "retried_peer", "disconnected": true}"#)
+        .expect(1)
+        .create();
+        let delete_mock = mock(
+            "DELETE",
+            format!("/peers/retried_peer?token={}", OLD_TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .expect(2)
+        .create();
+        // the first creation fails, and the second one succeeds
+        let failed_create_mock = mock("POST", "/peers")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"peer_id": "retried_peer"}"#.into(),
+            ))
+            .with_status(reqwest::StatusCode::INTERNAL_SERVER_ERROR.as_u16() as usize)
+            .expect(1)
+            .create();
+        let create_mock = mock("POST", "/peers")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"peer_id": "retried_peer"}"#.into(),
+            ))
+            .with_status(reqwest::StatusCode::CREATED.as_u16() as usize)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"command_type": "PEERS_CREATE", "params": {{"peer_id": "retried_peer", "token": "{}"}}}}"#,
+                NEW_TOKEN
+            ))
+            .create();
+        let event_mock = mock(
+            "GET",
+            format!("/peers/retried_peer/events?token={}", NEW_TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(format!(
+            r#"{{"event": "OPEN", "params": {{"peer_id": "retried_peer", "token": "{}"}}}}"#,
+            NEW_TOKEN
+        ))
+        .create();
+        let new_status_mock = mock(
+            "GET",
+            format!("/peers/retried_peer/status?token={}", NEW_TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"peer_id": "retried_peer", "disconnected": false}"#)
+        .expect_at_least(0)
+        .create();
+
+        let config = MonitorConfig {
+            interval: Duration::from_millis(10),
+            recreate: Some(RecreateParams {
+                api_key: "api_key".into(),
+                domain: "localhost".into(),
+                turn: false,
+                open_timeout: Duration::from_secs(1),
+            }),
+        };
+        let monitor = PeerMonitor::new(old.clone(), config);
+        let (event_notifier, mut event_observer) = mpsc::channel::<LivenessEvent>(10);
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(monitor.clone().run(event_notifier, token.clone()));
+
+        assert_eq!(
+            event_observer.next().await.unwrap(),
+            LivenessEvent::Disconnected(old.clone())
+        );
+        assert!(matches!(
+            event_observer.next().await.unwrap(),
+            LivenessEvent::RecreateFailed(ref info, _) if info == &old
+        ));
+        assert_eq!(
+            event_observer.next().await.unwrap(),
+            LivenessEvent::Recreated {
+                old,
+                new: new.clone()
+            }
+        );
+        token.cancel();
+        handle.await.unwrap().unwrap();
+
+        assert_eq!(monitor.peer_info(), new);
+        status_mock.assert();
+        delete_mock.assert();
+        failed_create_mock.assert();
+        create_mock.assert();
+        event_mock.assert();
+        new_status_mock.assert();
+    }
+
+    async fn polled(mock: &mockito::Mock) {
+        while !mock.matched() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
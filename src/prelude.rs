@@ -1,4 +1,5 @@
 pub use crate::common::formats::{PhantomId, SerializableId, SerializableSocket, SocketInfo};
+pub use crate::common::pattern::Pattern;
 pub use crate::data::formats::{DataConnectionId, DataId};
 pub use crate::media::formats::{MediaConnectionId, MediaId, RtcpId};
 pub use crate::peer::formats::{PeerId, PeerInfo, Token};
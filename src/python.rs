@@ -0,0 +1,693 @@
+//! Python extension module `skyway_gateway`.
+//!
+//! Build it with maturin, which reads `pyproject.toml`.
+//! ```python
+//! import asyncio
+//! import skyway_gateway as sg
+//!
+//! async def main():
+//!     client = sg.GatewayClient("http://127.0.0.1:8000")
+//!     peer_info = await client.create_peer(api_key, "localhost", "robot")
+//!     async for event in client.peer_events(peer_info):
+//!         print(event)
+//!
+//! asyncio.run(main())
+//! ```
+//! Ids are instances of the id classes, which validate their values in the constructors,
+//! and queries are instances of the query classes, which take the fields of the JSON as keyword arguments.
+//! Other results and events are returned as dicts in the same form as JSON.
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::channel::mpsc;
+use futures::*;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyStopAsyncIteration, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3_async_runtimes::tokio::future_into_py;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::common::formats::{SerializableId, SerializableSocket};
+use crate::data::{DataConnectionId, DataId};
+use crate::error;
+use crate::media::{MediaConnectionId, MediaId, RtcpId};
+use crate::peer::{PeerId, Token};
+use crate::registry::Registry;
+
+create_exception!(
+    skyway_gateway,
+    GatewayError,
+    PyException,
+    "Error returned from WebRTC Gateway. Arguments are the reason and the message of the error."
+);
+
+fn reason_and_message(error: &error::Error) -> (String, String) {
+    let value = serde_json::to_value(error).unwrap_or_default();
+    let field = |key: &str| value[key].as_str().unwrap_or_default().to_string();
+    (field("reason"), field("message"))
+}
+
+fn value_error(error: error::Error) -> PyErr {
+    PyValueError::new_err(reason_and_message(&error).1)
+}
+
+fn gateway_error(error: error::Error) -> PyErr {
+    GatewayError::new_err(reason_and_message(&error))
+}
+
+// JSON converted to Python objects with the `json` module when it's returned to Python
+struct Json(String);
+
+impl Json {
+    fn new(value: &impl Serialize) -> PyResult<Self> {
+        serde_json::to_string(value)
+            .map(Json)
+            .map_err(|error| value_error(error::Error::SerdeError { error }))
+    }
+}
+
+impl<'py> IntoPyObject<'py> for Json {
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        py.import("json")?.call_method1("loads", (self.0,))
+    }
+}
+
+// Id classes in the fields are converted with `str`.
+fn from_fields<T: DeserializeOwned>(
+    py: Python<'_>,
+    fields: Option<&Bound<'_, PyDict>>,
+) -> PyResult<T> {
+    let kwargs = PyDict::new(py);
+    kwargs.set_item("default", py.get_type::<pyo3::types::PyString>())?;
+    let fields = match fields {
+        Some(fields) => fields.clone(),
+        None => PyDict::new(py),
+    };
+    let json: String = py
+        .import("json")?
+        .call_method("dumps", (fields,), Some(&kwargs))?
+        .extract()?;
+    serde_json::from_str(&json).map_err(|error| value_error(error::Error::SerdeError { error }))
+}
+
+macro_rules! id_class {
+    ($name:ident, $py_name:literal, $inner:ty, $create:path) => {
+        #[doc = concat!("`", $py_name, "` validated in the same way as JSON")]
+        #[pyclass(module = "skyway_gateway", name = $py_name, frozen, eq, hash)]
+        #[derive(Clone, PartialEq, Eq, Hash)]
+        pub struct $name(pub $inner);
+
+        #[pymethods]
+        impl $name {
+            #[new]
+            fn new(id: &str) -> PyResult<Self> {
+                $create(id).map(Self).map_err(value_error)
+            }
+
+            fn __str__(&self) -> &str {
+                self.0.as_str()
+            }
+
+            fn __repr__(&self) -> String {
+                format!("{}('{}')", $py_name, self.0.as_str())
+            }
+        }
+    };
+}
+
+// PeerId is not validated in JSON either
+fn peer_id(id: &str) -> Result<PeerId, error::Error> {
+    Ok(PeerId::new(id))
+}
+
+id_class!(PyPeerId, "PeerId", PeerId, peer_id);
+id_class!(PyToken, "Token", Token, Token::try_create);
+id_class!(PyDataId, "DataId", DataId, DataId::try_create);
+id_class!(
+    PyDataConnectionId,
+    "DataConnectionId",
+    DataConnectionId,
+    DataConnectionId::try_create
+);
+id_class!(PyMediaId, "MediaId", MediaId, MediaId::try_create);
+id_class!(PyRtcpId, "RtcpId", RtcpId, RtcpId::try_create);
+id_class!(
+    PyMediaConnectionId,
+    "MediaConnectionId",
+    MediaConnectionId,
+    MediaConnectionId::try_create
+);
+
+macro_rules! query_class {
+    ($name:ident, $py_name:literal, $inner:ty) => {
+        #[doc = concat!("`", $py_name, "` taking the fields of the JSON as keyword arguments")]
+        #[pyclass(module = "skyway_gateway", name = $py_name, frozen, eq)]
+        #[derive(Clone, PartialEq)]
+        pub struct $name(pub $inner);
+
+        #[pymethods]
+        impl $name {
+            #[new]
+            #[pyo3(signature = (**fields))]
+            fn new(py: Python<'_>, fields: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+                from_fields(py, fields).map(Self)
+            }
+
+            fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+                Json::new(&self.0)?.into_pyobject(py)
+            }
+
+            fn __repr__(&self) -> String {
+                format!(
+                    "{}({})",
+                    $py_name,
+                    serde_json::to_string(&self.0).unwrap_or_default()
+                )
+            }
+        }
+    };
+}
+
+query_class!(PyConnectQuery, "ConnectQuery", crate::data::ConnectQuery);
+query_class!(
+    PyRedirectDataParams,
+    "RedirectDataParams",
+    crate::data::RedirectDataParams
+);
+query_class!(PyCallQuery, "CallQuery", crate::media::CallQuery);
+query_class!(PyAnswerQuery, "AnswerQuery", crate::media::AnswerQuery);
+
+/// Pair of PeerId and Token returned from `GatewayClient.create_peer`
+#[pyclass(module = "skyway_gateway", name = "PeerInfo", frozen, eq, hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct PyPeerInfo(pub crate::peer::PeerInfo);
+
+#[pymethods]
+impl PyPeerInfo {
+    #[new]
+    fn new(peer_id: &str, token: &str) -> PyResult<Self> {
+        crate::peer::PeerInfo::try_create(peer_id, token)
+            .map(Self)
+            .map_err(value_error)
+    }
+
+    #[getter]
+    fn peer_id(&self) -> PyPeerId {
+        PyPeerId(self.0.peer_id())
+    }
+
+    #[getter]
+    fn token(&self) -> PyToken {
+        PyToken(self.0.token())
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PeerInfo('{}', '{}')",
+            self.0.peer_id().as_str(),
+            self.0.token().as_str()
+        )
+    }
+}
+
+type JsonStream = Pin<Box<dyn Stream<Item = Result<String, error::Error>> + Send>>;
+
+/// Events of a PeerObject or a connection iterated with `async for`.
+///
+/// The iteration finishes after a CLOSE event, and errors are raised as `GatewayError`.
+#[pyclass(module = "skyway_gateway", name = "EventIterator")]
+pub struct EventIterator {
+    events: Arc<tokio::sync::Mutex<JsonStream>>,
+}
+
+impl EventIterator {
+    // The listener is driven while Python awaits the next event.
+    fn new<E: Serialize + Send + 'static>(
+        listener: impl Future<Output = Result<(), error::Error>> + Send + 'static,
+        receiver: mpsc::Receiver<E>,
+    ) -> Self {
+        let events = receiver.map(|event| {
+            serde_json::to_string(&event).map_err(|error| error::Error::SerdeError { error })
+        });
+        let result = listener
+            .into_stream()
+            .filter_map(|result| future::ready(result.err().map(Err)));
+        let events: JsonStream = Box::pin(stream::select(events, result));
+        Self {
+            events: Arc::new(tokio::sync::Mutex::new(events)),
+        }
+    }
+}
+
+#[pymethods]
+impl EventIterator {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let events = self.events.clone();
+        future_into_py(py, async move {
+            match events.lock().await.next().await {
+                Some(Ok(json)) => Ok(Json(json)),
+                Some(Err(e)) => Err(gateway_error(e)),
+                None => Err(PyStopAsyncIteration::new_err(())),
+            }
+        })
+    }
+}
+
+/// Client of WebRTC Gateway. Methods except event iteration are coroutines.
+///
+/// PeerObjects, connections and sockets created through the client are released with `shutdown`.
+#[pyclass(module = "skyway_gateway", name = "GatewayClient")]
+pub struct GatewayClient {
+    registry: Registry,
+}
+
+#[pymethods]
+impl GatewayClient {
+    /// The base url of the first client is used by all clients in a process.
+    #[new]
+    fn new(base_url: &str) -> Self {
+        crate::initialize(base_url);
+        Self {
+            registry: Registry::new(),
+        }
+    }
+
+    #[pyo3(signature = (api_key, domain, peer_id, turn = false))]
+    fn create_peer<'py>(
+        &self,
+        py: Python<'py>,
+        api_key: String,
+        domain: String,
+        peer_id: String,
+        turn: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            let peer_info =
+                crate::peer::create(api_key, domain, crate::peer::PeerId::new(peer_id), turn)
+                    .await
+                    .map_err(gateway_error)?;
+            registry.register_peer(peer_info.clone());
+            Ok(PyPeerInfo(peer_info))
+        })
+    }
+
+    fn delete_peer<'py>(
+        &self,
+        py: Python<'py>,
+        peer_info: PyPeerInfo,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            crate::peer::delete(&peer_info.0)
+                .await
+                .map_err(gateway_error)?;
+            registry.unregister_peer(&peer_info.0);
+            Ok(())
+        })
+    }
+
+    fn peer_status<'py>(
+        &self,
+        py: Python<'py>,
+        peer_info: PyPeerInfo,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        future_into_py(py, async move {
+            let status = crate::peer::status(&peer_info.0)
+                .await
+                .map_err(gateway_error)?;
+            Json::new(&status)
+        })
+    }
+
+    fn peer_events(&self, peer_info: PyPeerInfo) -> EventIterator {
+        let (sender, receiver) = mpsc::channel(0);
+        EventIterator::new(crate::peer::listen_events(peer_info.0, sender), receiver)
+    }
+
+    fn open_data_socket<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            let socket = crate::data::open_data_socket()
+                .await
+                .map_err(gateway_error)?;
+            if let Some(data_id) = socket.get_id() {
+                registry.register_data_socket(data_id);
+            }
+            Json::new(&socket)
+        })
+    }
+
+    fn close_data_socket<'py>(
+        &self,
+        py: Python<'py>,
+        data_id: PyDataId,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            crate::data::close_data_socket(&data_id.0)
+                .await
+                .map_err(gateway_error)?;
+            registry.unregister_data_socket(&data_id.0);
+            Ok(())
+        })
+    }
+
+    fn connect<'py>(&self, py: Python<'py>, query: PyConnectQuery) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            let data_connection_id = crate::data::connect(query.0).await.map_err(gateway_error)?;
+            registry.register_data_connection(data_connection_id.clone());
+            Ok(PyDataConnectionId(data_connection_id))
+        })
+    }
+
+    fn redirect<'py>(
+        &self,
+        py: Python<'py>,
+        data_connection_id: PyDataConnectionId,
+        params: PyRedirectDataParams,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        future_into_py(py, async move {
+            let response = crate::data::redirect(&data_connection_id.0, &params.0)
+                .await
+                .map_err(gateway_error)?;
+            Json::new(&response)
+        })
+    }
+
+    fn disconnect_data<'py>(
+        &self,
+        py: Python<'py>,
+        data_connection_id: PyDataConnectionId,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            crate::data::disconnect(&data_connection_id.0)
+                .await
+                .map_err(gateway_error)?;
+            registry.unregister_data_connection(&data_connection_id.0);
+            Ok(())
+        })
+    }
+
+    fn data_status<'py>(
+        &self,
+        py: Python<'py>,
+        data_connection_id: PyDataConnectionId,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        future_into_py(py, async move {
+            let status = crate::data::status(&data_connection_id.0)
+                .await
+                .map_err(gateway_error)?;
+            Json::new(&status)
+        })
+    }
+
+    fn data_events(&self, data_connection_id: PyDataConnectionId) -> EventIterator {
+        let (sender, receiver) = mpsc::channel(0);
+        EventIterator::new(
+            crate::data::listen_events(data_connection_id.0, sender),
+            receiver,
+        )
+    }
+
+    fn open_media_socket<'py>(
+        &self,
+        py: Python<'py>,
+        is_video: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            let socket = crate::media::open_media_socket(is_video)
+                .await
+                .map_err(gateway_error)?;
+            if let Some(media_id) = socket.get_id() {
+                registry.register_media_socket(media_id);
+            }
+            Json::new(&socket)
+        })
+    }
+
+    fn delete_media<'py>(
+        &self,
+        py: Python<'py>,
+        media_id: PyMediaId,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            crate::media::delete_media(&media_id.0)
+                .await
+                .map_err(gateway_error)?;
+            registry.unregister_media_socket(&media_id.0);
+            Ok(())
+        })
+    }
+
+    fn open_rtcp_socket<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            let socket = crate::media::open_rtcp_socket()
+                .await
+                .map_err(gateway_error)?;
+            if let Some(rtcp_id) = socket.get_id() {
+                registry.register_rtcp_socket(rtcp_id);
+            }
+            Json::new(&socket)
+        })
+    }
+
+    fn delete_rtcp<'py>(&self, py: Python<'py>, rtcp_id: PyRtcpId) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            crate::media::delete_rtcp(&rtcp_id.0)
+                .await
+                .map_err(gateway_error)?;
+            registry.unregister_rtcp_socket(&rtcp_id.0);
+            Ok(())
+        })
+    }
+
+    fn call<'py>(&self, py: Python<'py>, query: PyCallQuery) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            let response = crate::media::call(&query.0).await.map_err(gateway_error)?;
+            registry.register_media_connection(response.params.media_connection_id.clone());
+            Json::new(&response)
+        })
+    }
+
+    fn answer<'py>(
+        &self,
+        py: Python<'py>,
+        media_connection_id: PyMediaConnectionId,
+        query: PyAnswerQuery,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            let response = crate::media::answer(&media_connection_id.0, &query.0)
+                .await
+                .map_err(gateway_error)?;
+            registry.register_media_connection(media_connection_id.0);
+            Json::new(&response)
+        })
+    }
+
+    fn disconnect_media<'py>(
+        &self,
+        py: Python<'py>,
+        media_connection_id: PyMediaConnectionId,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        future_into_py(py, async move {
+            crate::media::disconnect(&media_connection_id.0)
+                .await
+                .map_err(gateway_error)?;
+            registry.unregister_media_connection(&media_connection_id.0);
+            Ok(())
+        })
+    }
+
+    fn media_status<'py>(
+        &self,
+        py: Python<'py>,
+        media_connection_id: PyMediaConnectionId,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        future_into_py(py, async move {
+            let status = crate::media::status(&media_connection_id.0)
+                .await
+                .map_err(gateway_error)?;
+            Json::new(&status)
+        })
+    }
+
+    fn media_events(&self, media_connection_id: PyMediaConnectionId) -> EventIterator {
+        let (sender, receiver) = mpsc::channel(0);
+        EventIterator::new(
+            crate::media::listen_events(media_connection_id.0, sender),
+            receiver,
+        )
+    }
+
+    /// Release all resources created through the client within `timeout` seconds.
+    ///
+    /// Raises ValueError if `timeout` is negative or not a number.
+    #[pyo3(signature = (timeout = 10.0))]
+    fn shutdown<'py>(&self, py: Python<'py>, timeout: f64) -> PyResult<Bound<'py, PyAny>> {
+        let registry = self.registry.clone();
+        let deadline = std::time::Duration::try_from_secs_f64(timeout).map_err(|e| {
+            value_error(error::Error::create_local_error(&format!(
+                "invalid timeout {}: {}",
+                timeout, e
+            )))
+        })?;
+        future_into_py(py, async move {
+            let report = crate::shutdown::shutdown(&registry, deadline).await;
+            Json::new(&report)
+        })
+    }
+}
+
+/// Entry point of the extension module
+#[pymodule]
+pub fn skyway_gateway(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<GatewayClient>()?;
+    m.add_class::<EventIterator>()?;
+    m.add_class::<PyPeerInfo>()?;
+    m.add_class::<PyPeerId>()?;
+    m.add_class::<PyToken>()?;
+    m.add_class::<PyDataId>()?;
+    m.add_class::<PyDataConnectionId>()?;
+    m.add_class::<PyMediaId>()?;
+    m.add_class::<PyRtcpId>()?;
+    m.add_class::<PyMediaConnectionId>()?;
+    m.add_class::<PyConnectQuery>()?;
+    m.add_class::<PyRedirectDataParams>()?;
+    m.add_class::<PyCallQuery>()?;
+    m.add_class::<PyAnswerQuery>()?;
+    m.add("GatewayError", m.py().get_type::<GatewayError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_python {
+    use std::ffi::CString;
+
+    use mockito::mock;
+    use pyo3::types::PyDict;
+
+    use super::*;
+
+    const DATA_CONNECTION_ID: &str = "dc-4995f372-fb6a-4196-b30a-ce11e5c7f56c";
+    const MEDIA_CONNECTION_ID: &str = "mc-302127d9-30de-413b-93f7-41a33e39d82b";
+
+    const SCRIPT: &str = r#"
+import asyncio
+import skyway_gateway as sg
+
+for invalid in ["invalid", "da-4995f372-fb6a-4196-b30a-ce11e5c7f56c"]:
+    try:
+        sg.DataConnectionId(invalid)
+        raise AssertionError("not validated")
+    except ValueError:
+        pass
+
+dc = sg.DataConnectionId(data_connection_id)
+assert dc == sg.DataConnectionId(data_connection_id) and str(dc) == data_connection_id
+
+query = sg.ConnectQuery(
+    peer_id=sg.PeerId("robot"),
+    token="pt-9749250e-d157-4f80-9ee2-359ce8524308",
+    target_id="operator",
+    redirect_params={"ip_v4": "127.0.0.1", "port": 10000},
+)
+assert query.to_dict()["redirect_params"]["port"] == 10000
+try:
+    sg.ConnectQuery(peer_id="robot", token="invalid", target_id="operator")
+    raise AssertionError("not validated")
+except ValueError:
+    pass
+
+async def main():
+    client = sg.GatewayClient(base_url)
+    events = [event async for event in client.data_events(dc)]
+    assert events == [{"event": "CLOSE", "data_connection_id": data_connection_id}], events
+    await client.disconnect_data(dc)
+
+    response = await client.call(sg.CallQuery(
+        peer_id="python_peer",
+        token="pt-9749250e-d157-4f80-9ee2-359ce8524308",
+        target_id="operator",
+    ))
+    assert response == {
+        "command_type": "PEERS_CALL",
+        "params": {"media_connection_id": media_connection_id},
+    }, response
+
+    for invalid in [-1.0, float("nan")]:
+        try:
+            client.shutdown(invalid)
+            raise AssertionError("not validated")
+        except ValueError:
+            pass
+
+asyncio.run(main())
+"#;
+
+    #[test]
+    fn validate_ids_and_iterate_events() {
+        let events_mock = mock(
+            "GET",
+            format!("/data/connections/{}/events", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"event": "CLOSE"}"#)
+        .create();
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/data/connections/{}", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+        let call_mock = mock("POST", "/media/connections")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"peer_id": "python_peer"}"#.into(),
+            ))
+            .with_status(reqwest::StatusCode::ACCEPTED.as_u16() as usize)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"command_type": "PEERS_CALL", "params": {{"media_connection_id": "{}"}}}}"#,
+                MEDIA_CONNECTION_ID
+            ))
+            .create();
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| -> PyResult<()> {
+            let module = PyModule::new(py, "skyway_gateway")?;
+            skyway_gateway(&module)?;
+            py.import("sys")?
+                .getattr("modules")?
+                .set_item("skyway_gateway", module)?;
+            let globals = PyDict::new(py);
+            globals.set_item("base_url", mockito::server_url())?;
+            globals.set_item("data_connection_id", DATA_CONNECTION_ID)?;
+            globals.set_item("media_connection_id", MEDIA_CONNECTION_ID)?;
+            py.run(&CString::new(SCRIPT).unwrap(), Some(&globals), None)
+        })
+        .unwrap();
+        events_mock.assert();
+        disconnect_mock.assert();
+        call_mock.assert();
+    }
+}
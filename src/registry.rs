@@ -0,0 +1,135 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+
+use crate::data::formats::{DataConnectionId, DataId};
+use crate::media::formats::{MediaConnectionId, MediaId, RtcpId};
+use crate::peer::formats::PeerInfo;
+
+/// Keeps track of resources allocated in a WebRTC Gateway.
+///
+/// WebRTC Gateway doesn't release PeerObjects, connections and sockets by itself.
+/// Users register them here when they are created, and other modules such as `shutdown`
+/// refer to this registry to know what should be released.
+///
+/// It is cheap to clone. All clones share the same resources.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    inner: Arc<Mutex<Resources>>,
+}
+
+/// Snapshot of resources registered in a `Registry`.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Resources {
+    /// PeerObjects
+    pub peers: BTreeSet<PeerInfo>,
+    /// DataConnections
+    pub data_connections: BTreeSet<DataConnectionId>,
+    /// MediaConnections
+    pub media_connections: BTreeSet<MediaConnectionId>,
+    /// Sockets opened with POST /data
+    pub data_sockets: BTreeSet<DataId>,
+    /// Sockets opened with POST /media
+    pub media_sockets: BTreeSet<MediaId>,
+    /// Sockets opened with POST /media/rtcp
+    pub rtcp_sockets: BTreeSet<RtcpId>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of all registered resources.
+    pub fn snapshot(&self) -> Resources {
+        self.inner.lock().expect("registry lock poisoned").clone()
+    }
+
+    /// Removes all resources from this registry and returns them.
+    pub fn take(&self) -> Resources {
+        std::mem::take(&mut *self.inner.lock().expect("registry lock poisoned"))
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Resources) -> T) -> T {
+        f(&mut self.inner.lock().expect("registry lock poisoned"))
+    }
+
+    pub fn register_peer(&self, peer_info: PeerInfo) {
+        self.with(|r| r.peers.insert(peer_info));
+    }
+
+    pub fn unregister_peer(&self, peer_info: &PeerInfo) {
+        self.with(|r| r.peers.remove(peer_info));
+    }
+
+    pub fn register_data_connection(&self, data_connection_id: DataConnectionId) {
+        self.with(|r| r.data_connections.insert(data_connection_id));
+    }
+
+    pub fn unregister_data_connection(&self, data_connection_id: &DataConnectionId) {
+        self.with(|r| r.data_connections.remove(data_connection_id));
+    }
+
+    pub fn register_media_connection(&self, media_connection_id: MediaConnectionId) {
+        self.with(|r| r.media_connections.insert(media_connection_id));
+    }
+
+    pub fn unregister_media_connection(&self, media_connection_id: &MediaConnectionId) {
+        self.with(|r| r.media_connections.remove(media_connection_id));
+    }
+
+    pub fn register_data_socket(&self, data_id: DataId) {
+        self.with(|r| r.data_sockets.insert(data_id));
+    }
+
+    pub fn unregister_data_socket(&self, data_id: &DataId) {
+        self.with(|r| r.data_sockets.remove(data_id));
+    }
+
+    pub fn register_media_socket(&self, media_id: MediaId) {
+        self.with(|r| r.media_sockets.insert(media_id));
+    }
+
+    pub fn unregister_media_socket(&self, media_id: &MediaId) {
+        self.with(|r| r.media_sockets.remove(media_id));
+    }
+
+    pub fn register_rtcp_socket(&self, rtcp_id: RtcpId) {
+        self.with(|r| r.rtcp_sockets.insert(rtcp_id));
+    }
+
+    pub fn unregister_rtcp_socket(&self, rtcp_id: &RtcpId) {
+        self.with(|r| r.rtcp_sockets.remove(rtcp_id));
+    }
+}
+
+#[cfg(test)]
+mod test_registry {
+    use super::*;
+    use crate::common::formats::SerializableId;
+
+    #[test]
+    fn clones_share_resources() {
+        let registry = Registry::new();
+        let cloned = registry.clone();
+        let data_id = DataId::try_create("da-50a32bab-b3d9-4913-8e20-f79c90a6a211").unwrap();
+        cloned.register_data_socket(data_id.clone());
+        assert!(registry.snapshot().data_sockets.contains(&data_id));
+
+        registry.unregister_data_socket(&data_id);
+        assert!(cloned.snapshot().data_sockets.is_empty());
+    }
+
+    #[test]
+    fn take_clears_registry() {
+        let registry = Registry::new();
+        let media_connection_id =
+            MediaConnectionId::try_create("mc-102127d9-30de-413b-93f7-41a33e39d82b").unwrap();
+        registry.register_media_connection(media_connection_id.clone());
+
+        let resources = registry.take();
+        assert!(resources.media_connections.contains(&media_connection_id));
+        assert_eq!(registry.snapshot(), Resources::default());
+    }
+}
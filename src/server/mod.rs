@@ -0,0 +1,549 @@
+/// WebSocket push of events and commands
+#[cfg(feature = "websocket")]
+pub mod ws;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use futures::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::data::formats::DataConnectionId;
+use crate::error;
+use crate::event_bus::{EventBus, GatewayEvent};
+use crate::media::formats::MediaConnectionId;
+use crate::peer::formats::{PeerId, PeerInfo};
+use crate::peer::monitor::wait_open;
+use crate::registry::{Registry, Resources};
+use crate::session::config::{DataTargetConfig, MediaTargetConfig};
+use crate::session::{call_target, connect_target, DataLink, MediaLink};
+
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// High-level operations of WebRTC Gateway backing the control API.
+///
+/// PeerObjects, connections and sockets created with it are registered to the registry,
+/// and their events are published to the bus.
+/// It is cheap to clone. All clones share the same registry and bus.
+#[derive(Debug, Clone)]
+pub struct GatewayClient {
+    registry: Registry,
+    bus: EventBus,
+}
+
+impl GatewayClient {
+    pub fn new(registry: Registry, bus: EventBus) -> Self {
+        Self { registry, bus }
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn bus(&self) -> &EventBus {
+        &self.bus
+    }
+
+    /// Find a PeerObject created with this client.
+    /// PeerObjects are identified only with peer_id in the API, and the token is kept in the registry.
+    pub fn find_peer(&self, peer_id: &str) -> Option<PeerInfo> {
+        self.registry
+            .snapshot()
+            .peers
+            .into_iter()
+            .find(|peer_info| peer_info.peer_id().as_str() == peer_id)
+    }
+
+    /// Create a PeerObject and wait for its OPEN event.
+    ///
+    /// If it doesn't open within `timeout`, it's deleted and the error is returned.
+    pub async fn create_peer(
+        &self,
+        api_key: String,
+        domain: String,
+        peer_id: PeerId,
+        turn: bool,
+        timeout: Duration,
+    ) -> Result<PeerInfo, error::Error> {
+        let peer_info = crate::peer::create(api_key, domain, peer_id, turn).await?;
+        self.registry.register_peer(peer_info.clone());
+        match wait_open(&peer_info, timeout).await {
+            Ok(peer_info) => {
+                self.bus.watch_peer(peer_info.clone());
+                Ok(peer_info)
+            }
+            Err(e) => {
+                match crate::peer::delete(&peer_info).await {
+                    Ok(_) => self.registry.unregister_peer(&peer_info),
+                    Err(delete_error) => warn!(
+                        "fail to delete {} not opened: {:?}",
+                        peer_info.peer_id().as_str(),
+                        delete_error
+                    ),
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Delete the PeerObject and stop listening its events.
+    pub async fn delete_peer(&self, peer_info: &PeerInfo) -> Result<(), error::Error> {
+        crate::peer::delete(peer_info).await?;
+        self.registry.unregister_peer(peer_info);
+        self.bus.unwatch_peer(&peer_info.peer_id());
+        Ok(())
+    }
+
+    /// Connect to the target and listen events of the DataConnection.
+    pub async fn connect(
+        &self,
+        peer_info: &PeerInfo,
+        target: &DataTargetConfig,
+    ) -> Result<DataLink, error::Error> {
+        let link = connect_target(peer_info, &self.registry, target).await?;
+        self.bus
+            .watch_data_connection(peer_info.peer_id(), link.data_connection_id.clone());
+        Ok(link)
+    }
+
+    /// Call the target and listen events of the MediaConnection.
+    pub async fn call(
+        &self,
+        peer_info: &PeerInfo,
+        target: &MediaTargetConfig,
+    ) -> Result<MediaLink, error::Error> {
+        let link = call_target(peer_info, &self.registry, target, None, None).await?;
+        self.bus
+            .watch_media_connection(peer_info.peer_id(), link.media_connection_id.clone());
+        Ok(link)
+    }
+
+    /// Resources created with this client and not released yet
+    pub fn connections(&self) -> Resources {
+        self.registry.snapshot()
+    }
+
+    pub async fn disconnect_data(
+        &self,
+        data_connection_id: &DataConnectionId,
+    ) -> Result<(), error::Error> {
+        crate::data::disconnect(data_connection_id).await?;
+        self.registry.unregister_data_connection(data_connection_id);
+        Ok(())
+    }
+
+    pub async fn disconnect_media(
+        &self,
+        media_connection_id: &MediaConnectionId,
+    ) -> Result<(), error::Error> {
+        crate::media::disconnect(media_connection_id).await?;
+        self.registry
+            .unregister_media_connection(media_connection_id);
+        Ok(())
+    }
+
+    // PeerObjects unknown to this client are reported as 404.
+    fn peer_info(&self, peer_id: &str) -> Result<PeerInfo, ApiError> {
+        self.find_peer(peer_id).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                error::Error::create_local_error(&format!("peer {} is not found", peer_id)),
+            )
+        })
+    }
+}
+
+/// Error response of the control API. The body is the JSON serialization of `error::Error`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    error: error::Error,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: error::Error) -> Self {
+        Self { status, error }
+    }
+
+    fn bad_request(error: error::Error) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, error)
+    }
+}
+
+impl From<error::Error> for ApiError {
+    fn from(error: error::Error) -> Self {
+        let status = match error {
+            error::Error::ReqwestError(_) => StatusCode::BAD_GATEWAY,
+            error::Error::AddrParseError(_) | error::Error::SerdeError { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self::new(status, error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.error)).into_response()
+    }
+}
+
+/// Body of POST /peers
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CreatePeerRequest {
+    pub peer_id: String,
+    /// SkyWay Service API Key. If not set, `API_KEY` environment variable is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(default = "default_domain")]
+    pub domain: String,
+    #[serde(default)]
+    pub turn: bool,
+}
+
+fn default_domain() -> String {
+    String::from("localhost")
+}
+
+/// Query of GET /events. Events are not filtered if nothing is set.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct EventFilter {
+    /// Only events of this PeerObject and its connections are sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<String>,
+    /// Only events of this DataConnection or MediaConnection are sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_id: Option<String>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &GatewayEvent) -> bool {
+        let peer_matches = match self.peer_id {
+            Some(ref peer_id) => event.peer_id().as_str() == peer_id,
+            None => true,
+        };
+        let connection_matches = match self.connection_id {
+            Some(ref connection_id) => event.connection_id() == Some(connection_id.as_str()),
+            None => true,
+        };
+        peer_matches && connection_matches
+    }
+}
+
+/// Routes of the control API.
+///
+/// | method | path | |
+/// |---|---|---|
+/// | POST | /peers | create a PeerObject and wait for OPEN |
+/// | GET | /peers/{peer_id}/status | status of the PeerObject |
+/// | DELETE | /peers/{peer_id} | delete the PeerObject |
+/// | POST | /peers/{peer_id}/data | connect to `target_id` with `DataTargetConfig` |
+/// | POST | /peers/{peer_id}/media | call `target_id` with `MediaTargetConfig` |
+/// | GET | /connections | list active resources |
+/// | DELETE | /data/connections/{data_connection_id} | disconnect the DataConnection |
+/// | DELETE | /media/connections/{media_connection_id} | disconnect the MediaConnection |
+/// | GET | /events?peer_id=&connection_id= | Server-Sent Events of `GatewayEvent` |
+/// | GET | /ws | WebSocket of events and commands (`websocket` feature) |
+pub fn router(client: GatewayClient) -> Router {
+    let router = Router::new()
+        .route("/peers", post(create_peer))
+        .route("/peers/:peer_id", delete(delete_peer))
+        .route("/peers/:peer_id/status", get(peer_status))
+        .route("/peers/:peer_id/data", post(connect))
+        .route("/peers/:peer_id/media", post(call))
+        .route("/connections", get(connections))
+        .route(
+            "/data/connections/:data_connection_id",
+            delete(disconnect_data),
+        )
+        .route(
+            "/media/connections/:media_connection_id",
+            delete(disconnect_media),
+        )
+        .route("/events", get(events));
+    #[cfg(feature = "websocket")]
+    let router = router.route("/ws", get(ws::upgrade));
+    router.with_state(client)
+}
+
+/// Serve the control API until the token is cancelled.
+pub async fn serve(
+    addr: SocketAddr,
+    client: GatewayClient,
+    token: CancellationToken,
+) -> Result<(), error::Error> {
+    axum::Server::try_bind(&addr)
+        .map_err(|e| error::Error::create_local_error(&format!("fail to bind {}: {}", addr, e)))?
+        .serve(router(client).into_make_service())
+        .with_graceful_shutdown(async move { token.cancelled().await })
+        .await
+        .map_err(|e| error::Error::create_local_error(&format!("server error: {}", e)))
+}
+
+async fn create_peer(
+    State(client): State<GatewayClient>,
+    Json(request): Json<CreatePeerRequest>,
+) -> Result<(StatusCode, Json<PeerInfo>), ApiError> {
+    let api_key = match request.api_key {
+        Some(api_key) => api_key,
+        None => std::env::var("API_KEY").map_err(|_| {
+            ApiError::bad_request(error::Error::create_local_error("api_key is not set"))
+        })?,
+    };
+    let peer_info = client
+        .create_peer(
+            api_key,
+            request.domain,
+            PeerId::new(request.peer_id),
+            request.turn,
+            OPEN_TIMEOUT,
+        )
+        .await?;
+    Ok((StatusCode::CREATED, Json(peer_info)))
+}
+
+async fn delete_peer(
+    State(client): State<GatewayClient>,
+    Path(peer_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let peer_info = client.peer_info(&peer_id)?;
+    client.delete_peer(&peer_info).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn peer_status(
+    State(client): State<GatewayClient>,
+    Path(peer_id): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let peer_info = client.peer_info(&peer_id)?;
+    Ok(Json(crate::peer::status(&peer_info).await?))
+}
+
+async fn connect(
+    State(client): State<GatewayClient>,
+    Path(peer_id): Path<String>,
+    Json(target): Json<DataTargetConfig>,
+) -> Result<impl IntoResponse, ApiError> {
+    let peer_info = client.peer_info(&peer_id)?;
+    let link = client.connect(&peer_info, &target).await?;
+    Ok((StatusCode::CREATED, Json(link)))
+}
+
+async fn call(
+    State(client): State<GatewayClient>,
+    Path(peer_id): Path<String>,
+    Json(target): Json<MediaTargetConfig>,
+) -> Result<impl IntoResponse, ApiError> {
+    let peer_info = client.peer_info(&peer_id)?;
+    let link = client.call(&peer_info, &target).await?;
+    Ok((StatusCode::CREATED, Json(link)))
+}
+
+async fn connections(State(client): State<GatewayClient>) -> impl IntoResponse {
+    Json(client.connections())
+}
+
+async fn disconnect_data(
+    State(client): State<GatewayClient>,
+    Path(data_connection_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let data_connection_id =
+        DataConnectionId::try_create(data_connection_id).map_err(ApiError::bad_request)?;
+    client.disconnect_data(&data_connection_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn disconnect_media(
+    State(client): State<GatewayClient>,
+    Path(media_connection_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let media_connection_id =
+        MediaConnectionId::try_create(media_connection_id).map_err(ApiError::bad_request)?;
+    client.disconnect_media(&media_connection_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Stream of events from the bus. Events dropped because the subscriber lags are skipped.
+pub(crate) fn event_stream(
+    bus: &EventBus,
+    filter: EventFilter,
+) -> impl Stream<Item = GatewayEvent> + Send + 'static {
+    stream::unfold(bus.subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .filter(move |event| future::ready(filter.matches(event)))
+}
+
+async fn events(
+    State(client): State<GatewayClient>,
+    Query(filter): Query<EventFilter>,
+) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
+    let stream = event_stream(&client.bus, filter).map(|event| Event::default().json_data(event));
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod test_server {
+    use mockito::mock;
+    use serde_json::Value;
+
+    use super::*;
+
+    const DATA_CONNECTION_ID: &str = "dc-6995f372-fb6a-4196-b30a-ce11e5c7f56c";
+    const TOKEN: &str = "pt-9749250e-d157-4f80-9ee2-359ce8524308";
+
+    // start the server on a random port and return its url
+    fn start(client: GatewayClient) -> String {
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(router(client).into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn list_and_disconnect() {
+        crate::initialize(mockito::server_url());
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/data/connections/{}", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+
+        let registry = Registry::new();
+        registry
+            .register_data_connection(DataConnectionId::try_create(DATA_CONNECTION_ID).unwrap());
+        let url = start(GatewayClient::new(registry.clone(), EventBus::new(8)));
+
+        let body: Value = reqwest::get(format!("{}/connections", url))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["data_connections"][0], DATA_CONNECTION_ID);
+
+        let response = reqwest::Client::new()
+            .delete(format!("{}/data/connections/{}", url, DATA_CONNECTION_ID))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(registry.snapshot().data_connections.is_empty());
+        disconnect_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn delete_peer_not_opened() {
+        crate::initialize(mockito::server_url());
+        let create_mock = mock("POST", "/peers")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"peer_id": "server_peer"}"#.into(),
+            ))
+            .with_status(reqwest::StatusCode::CREATED.as_u16() as usize)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"command_type": "PEERS_CREATE", "params": {{"peer_id": "server_peer", "token": "{}"}}}}"#,
+                TOKEN
+            ))
+            .create();
+        let event_mock = mock(
+            "GET",
+            format!("/peers/server_peer/events?token={}", TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(format!(
+            r#"{{"event": "CLOSE", "params": {{"peer_id": "server_peer", "token": "{}"}}}}"#,
+            TOKEN
+        ))
+        .create();
+        let delete_mock = mock(
+            "DELETE",
+            format!("/peers/server_peer?token={}", TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+
+        let registry = Registry::new();
+        let url = start(GatewayClient::new(registry.clone(), EventBus::new(8)));
+        let response = reqwest::Client::new()
+            .post(format!("{}/peers", url))
+            .json(&serde_json::json!({ "peer_id": "server_peer", "api_key": "api_key" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert!(registry.snapshot().peers.is_empty());
+        create_mock.assert();
+        event_mock.assert();
+        delete_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn errors_are_serialized() {
+        let url = start(GatewayClient::new(Registry::new(), EventBus::new(8)));
+
+        let response = reqwest::Client::new()
+            .delete(format!("{}/data/connections/invalid", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["reason"], "InternalError");
+
+        let response = reqwest::Client::new()
+            .post(format!("{}/peers/unknown/data", url))
+            .json(&serde_json::json!({ "target_id": "operator" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn filter_events() {
+        let event: GatewayEvent = serde_json::from_str(&format!(
+            r#"{{
+                "type": "Data",
+                "peer_id": "robot",
+                "data_connection_id": "{0}",
+                "event": {{ "event": "OPEN", "data_connection_id": "{0}" }}
+            }}"#,
+            DATA_CONNECTION_ID
+        ))
+        .unwrap();
+        assert!(EventFilter::default().matches(&event));
+        let filter = EventFilter {
+            peer_id: Some("robot".into()),
+            connection_id: Some(DATA_CONNECTION_ID.into()),
+        };
+        assert!(filter.matches(&event));
+        let filter = EventFilter {
+            peer_id: Some("operator".into()),
+            connection_id: None,
+        };
+        assert!(!filter.matches(&event));
+    }
+}
@@ -0,0 +1,253 @@
+//! WebSocket endpoint of the control API.
+//!
+//! Clients send JSON frames like below.
+//! ```json
+//! {"type": "subscribe", "peer_id": "robot"}
+//! {"type": "unsubscribe"}
+//! {"type": "command", "id": 1, "command": "disconnect", "params": {"data_connection_id": "dc-..."}}
+//! ```
+//! Events matching any of the subscriptions are pushed as `{"type": "event", "event": {...}}`,
+//! and each command is answered with `{"type": "result", ...}` or `{"type": "error", ...}`.
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use super::{EventFilter, GatewayClient};
+use crate::command;
+pub use crate::command::{Command, ConnectionIdWrapper};
+use crate::error;
+use crate::event_bus::GatewayEvent;
+
+/// Frames sent from clients
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Start receiving events matching the filter in addition to current subscriptions
+    Subscribe(EventFilter),
+    /// Stop receiving events
+    Unsubscribe,
+    Command {
+        /// Echoed back in the response to match it with the command
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        #[serde(flatten)]
+        command: Box<Command>,
+    },
+}
+
+/// Frames sent to clients
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Event {
+        event: GatewayEvent,
+    },
+    Result {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        result: Value,
+    },
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<u64>,
+        error: Value,
+    },
+}
+
+impl ServerMessage {
+    fn error(id: Option<u64>, error: &error::Error) -> Self {
+        ServerMessage::Error {
+            id,
+            error: serde_json::to_value(error).unwrap_or_default(),
+        }
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &ServerMessage) -> bool {
+    let text = serde_json::to_string(message).unwrap_or_default();
+    socket.send(Message::Text(text)).await.is_ok()
+}
+
+/// GET /ws
+pub(super) async fn upgrade(State(client): State<GatewayClient>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| handle(socket, client))
+}
+
+// Commands are executed one by one, so events may be delayed while a command is running.
+async fn handle(mut socket: WebSocket, client: GatewayClient) {
+    let mut events = client.bus.subscribe();
+    let mut filters: Vec<EventFilter> = vec![];
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if filters.iter().any(|filter| filter.matches(&event))
+                        && !send(&mut socket, &ServerMessage::Event { event }).await
+                    {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                };
+                let reply = match serde_json::from_str::<ClientMessage>(&text) {
+                    Ok(ClientMessage::Subscribe(filter)) => {
+                        filters.push(filter);
+                        continue;
+                    }
+                    Ok(ClientMessage::Unsubscribe) => {
+                        filters.clear();
+                        continue;
+                    }
+                    Ok(ClientMessage::Command { id, command }) => {
+                        match command::execute(&client.registry, &client.bus, *command).await {
+                            Ok(result) => ServerMessage::Result { id, result },
+                            Err(e) => ServerMessage::error(id, &e),
+                        }
+                    }
+                    Err(error) => ServerMessage::error(None, &error::Error::SerdeError { error }),
+                };
+                if !send(&mut socket, &reply).await {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_ws {
+    use futures::*;
+    use mockito::mock;
+    use tokio_tungstenite::tungstenite;
+
+    use super::*;
+    use crate::data::formats::{DataConnectionId, DataConnectionIdWrapper};
+    use crate::event_bus::EventBus;
+    use crate::registry::Registry;
+
+    const DATA_CONNECTION_ID: &str = "dc-7995f372-fb6a-4196-b30a-ce11e5c7f56c";
+
+    #[test]
+    fn parse_messages() {
+        let message: ClientMessage = serde_json::from_str(&format!(
+            r#"{{"type": "command", "id": 3, "command": "disconnect", "params": {{"data_connection_id": "{}"}}}}"#,
+            DATA_CONNECTION_ID
+        ))
+        .unwrap();
+        assert_eq!(
+            message,
+            ClientMessage::Command {
+                id: Some(3),
+                command: Box::new(Command::Disconnect(ConnectionIdWrapper::Data(
+                    DataConnectionIdWrapper {
+                        data_connection_id: DataConnectionId::try_create(DATA_CONNECTION_ID)
+                            .unwrap()
+                    }
+                ))),
+            }
+        );
+        let message: ClientMessage =
+            serde_json::from_str(r#"{"type": "subscribe", "peer_id": "robot"}"#).unwrap();
+        assert_eq!(
+            message,
+            ClientMessage::Subscribe(EventFilter {
+                peer_id: Some("robot".into()),
+                connection_id: None,
+            })
+        );
+    }
+
+    async fn next_message(
+        client: &mut tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+    ) -> ServerMessage {
+        loop {
+            if let tungstenite::Message::Text(text) = client.next().await.unwrap().unwrap() {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn push_events_and_execute_commands() {
+        crate::initialize(mockito::server_url());
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/data/connections/{}", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+
+        let bus = EventBus::new(8);
+        let registry = Registry::new();
+        registry
+            .register_data_connection(DataConnectionId::try_create(DATA_CONNECTION_ID).unwrap());
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(
+            super::super::router(GatewayClient::new(registry.clone(), bus.clone()))
+                .into_make_service(),
+        );
+        let url = format!("ws://{}/ws", server.local_addr());
+        tokio::spawn(server);
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        client
+            .send(tungstenite::Message::Text(
+                r#"{"type": "subscribe", "peer_id": "robot"}"#.into(),
+            ))
+            .await
+            .unwrap();
+        // invalid frames are answered with an error, and it also shows the subscription is done
+        client
+            .send(tungstenite::Message::Text("{}".into()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_message(&mut client).await,
+            ServerMessage::Error { id: None, .. }
+        ));
+
+        let event = |peer_id: &str| -> GatewayEvent {
+            serde_json::from_str(&format!(
+                r#"{{"type": "Data", "peer_id": "{}", "data_connection_id": "{1}", "event": {{"event": "OPEN", "data_connection_id": "{1}"}}}}"#,
+                peer_id, DATA_CONNECTION_ID
+            ))
+            .unwrap()
+        };
+        bus.publish(event("operator"));
+        bus.publish(event("robot"));
+        assert_eq!(
+            next_message(&mut client).await,
+            ServerMessage::Event {
+                event: event("robot")
+            }
+        );
+
+        client
+            .send(tungstenite::Message::Text(format!(
+                r#"{{"type": "command", "id": 1, "command": "disconnect", "params": {{"data_connection_id": "{}"}}}}"#,
+                DATA_CONNECTION_ID
+            )))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_message(&mut client).await,
+            ServerMessage::Result {
+                id: Some(1),
+                result: Value::Null
+            }
+        );
+        assert!(registry.snapshot().data_connections.is_empty());
+        disconnect_mock.assert();
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::formats::{PhantomId, SocketInfo};
+use crate::data::formats::ConnectQueryOption;
+use crate::data::policy::{RedirectPolicy, RedirectSocketConfig as DataRedirectConfig};
+use crate::error;
+use crate::media::formats::RedirectParameters;
+use crate::media::policy::{
+    redirect_parameters, AnswerPolicy, MediaParamConfig, RedirectSocketConfig,
+};
+
+fn default_domain() -> String {
+    String::from("localhost")
+}
+
+/// Whole topology of a session.
+///
+/// It can be written in TOML or YAML like below.
+/// ```toml
+/// base_url = "http://localhost:8000"
+///
+/// [[peers]]
+/// peer_id = "robot"
+/// domain = "localhost"
+///
+/// [[peers.data]]
+/// target_id = "operator"
+/// feed = true
+/// redirect = { ip = "127.0.0.1", port = 10000 }
+///
+/// [[peers.media]]
+/// target_id = "operator"
+/// video_params = { band_width = 1500, codec = "H264", payload_type = 100, sampling_rate = 90000 }
+/// video_redirect = { media_ip = "127.0.0.1", media_port = 20000, rtcp_ip = "127.0.0.1", rtcp_port = 20001 }
+///
+/// [[peers.answer]]
+/// peer_id = "operator-*"
+/// video = false
+/// audio = false
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SessionConfig {
+    /// URL of WebRTC Gateway. If set, the crate is initialized with it when the session starts.
+    /// It can't be changed afterwards, so a reload changing it is rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+}
+
+/// A PeerObject and connections it establishes or accepts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerConfig {
+    pub peer_id: String,
+    /// SkyWay Service API Key. If not set, `API_KEY` environment variable is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Domain registered with SkyWay
+    #[serde(default = "default_domain")]
+    pub domain: String,
+    #[serde(default)]
+    pub turn: bool,
+    /// DataConnections established from this peer
+    #[serde(default)]
+    pub data: Vec<DataTargetConfig>,
+    /// MediaConnections established from this peer
+    #[serde(default)]
+    pub media: Vec<MediaTargetConfig>,
+    /// Policies to answer incoming calls. Incoming calls are left as they are if it's empty.
+    #[serde(default)]
+    pub answer: Vec<AnswerPolicy>,
+    /// Policies to redirect incoming DataConnections.
+    #[serde(default)]
+    pub redirect: Vec<RedirectPolicy>,
+}
+
+/// DataConnection to a target peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DataTargetConfig {
+    pub target_id: String,
+    /// If true, a data socket is opened to feed data to the target.
+    #[serde(default)]
+    pub feed: bool,
+    /// Received data is redirected to this socket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect: Option<DataRedirectConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serialization: Option<String>,
+}
+
+impl DataTargetConfig {
+    pub fn options(&self) -> Option<ConnectQueryOption> {
+        if self.metadata.is_none() && self.serialization.is_none() {
+            return None;
+        }
+        Some(ConnectQueryOption {
+            metadata: self.metadata.clone(),
+            serialization: self.serialization.clone(),
+            dcInit: None,
+        })
+    }
+
+    pub fn redirect_params(&self) -> Result<Option<SocketInfo<PhantomId>>, error::Error> {
+        self.redirect
+            .as_ref()
+            .map(|config| config.socket_info())
+            .transpose()
+    }
+}
+
+/// MediaConnection to a target peer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MediaTargetConfig {
+    pub target_id: String,
+    /// If set, a video socket is opened to send video with these parameters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_params: Option<MediaParamConfig>,
+    /// If set, an audio socket is opened to send audio with these parameters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_params: Option<MediaParamConfig>,
+    /// Received video is redirected to this socket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_redirect: Option<RedirectSocketConfig>,
+    /// Received audio is redirected to this socket
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_redirect: Option<RedirectSocketConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<String>,
+}
+
+impl MediaTargetConfig {
+    pub fn redirect_params(&self) -> Result<RedirectParameters, error::Error> {
+        redirect_parameters(self.video_redirect.as_ref(), self.audio_redirect.as_ref())
+    }
+}
+
+impl SessionConfig {
+    /// Load a config file. It's parsed as YAML if the extension is `yaml` or `yml`, otherwise as TOML.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, error::Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            error::Error::create_local_error(&format!("fail to read {}: {}", path.display(), e))
+        })?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Self::from_yaml_str(&text),
+            _ => Self::from_toml_str(&text),
+        }
+    }
+
+    pub fn from_toml_str(text: &str) -> Result<Self, error::Error> {
+        let config: Self = toml::from_str(text)
+            .map_err(|e| error::Error::create_local_error(&format!("invalid config: {}", e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_yaml_str(text: &str) -> Result<Self, error::Error> {
+        let config: Self = serde_yaml::from_str(text)
+            .map_err(|e| error::Error::create_local_error(&format!("invalid config: {}", e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// PeerIds must be unique, and so must targets of each peer,
+    /// because they are used to find what has changed when the config is reloaded.
+    pub fn validate(&self) -> Result<(), error::Error> {
+        check_unique("peer_id", self.peers.iter().map(|p| p.peer_id.as_str()))?;
+        for peer in self.peers.iter() {
+            check_unique(
+                "data target_id",
+                peer.data.iter().map(|d| d.target_id.as_str()),
+            )?;
+            check_unique(
+                "media target_id",
+                peer.media.iter().map(|m| m.target_id.as_str()),
+            )?;
+            for policy in peer.answer.iter() {
+                policy.validate()?;
+            }
+            for policy in peer.redirect.iter() {
+                policy.decision()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_unique<'a>(name: &str, values: impl Iterator<Item = &'a str>) -> Result<(), error::Error> {
+    let mut seen = BTreeSet::new();
+    for value in values {
+        if !seen.insert(value) {
+            return Err(error::Error::create_local_error(&format!(
+                "{} {} is duplicated",
+                name, value
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_session_config {
+    use super::*;
+    use crate::common::formats::SerializableSocket;
+
+    const TOML: &str = r#"
+        base_url = "http://localhost:8000"
+
+        [[peers]]
+        peer_id = "robot"
+
+        [[peers.data]]
+        target_id = "operator"
+        feed = true
+        redirect = { ip = "127.0.0.1", port = 10000 }
+
+        [[peers.media]]
+        target_id = "operator"
+        video_params = { band_width = 1500, codec = "H264", payload_type = 100, sampling_rate = 90000 }
+        video_redirect = { media_ip = "127.0.0.1", media_port = 20000, rtcp_ip = "127.0.0.1", rtcp_port = 20001 }
+
+        [[peers.answer]]
+        peer_id = "operator-*"
+        video = false
+        audio = false
+    "#;
+
+    const YAML: &str = r#"
+base_url: http://localhost:8000
+peers:
+  - peer_id: robot
+    data:
+      - target_id: operator
+        feed: true
+        redirect: { ip: 127.0.0.1, port: 10000 }
+    media:
+      - target_id: operator
+        video_params: { band_width: 1500, codec: H264, payload_type: 100, sampling_rate: 90000 }
+        video_redirect: { media_ip: 127.0.0.1, media_port: 20000, rtcp_ip: 127.0.0.1, rtcp_port: 20001 }
+    answer:
+      - peer_id: operator-*
+        video: false
+        audio: false
+"#;
+
+    #[test]
+    fn toml_and_yaml_are_same() {
+        let toml = SessionConfig::from_toml_str(TOML).unwrap();
+        let yaml = SessionConfig::from_yaml_str(YAML).unwrap();
+        assert_eq!(toml, yaml);
+
+        let peer = &toml.peers[0];
+        assert_eq!(peer.domain, "localhost");
+        assert!(!peer.turn);
+        assert_eq!(
+            peer.data[0].redirect_params().unwrap().unwrap().port(),
+            10000
+        );
+        assert!(peer.data[0].options().is_none());
+        let redirect = peer.media[0].redirect_params().unwrap();
+        assert_eq!(redirect.video_rtcp.unwrap().port(), 20001);
+        assert!(redirect.audio.is_none());
+    }
+
+    #[test]
+    fn reject_duplicated_targets() {
+        let result = SessionConfig::from_toml_str(
+            r#"
+            [[peers]]
+            peer_id = "robot"
+            data = [{ target_id = "operator" }, { target_id = "operator" }]
+            "#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn reject_video_without_params() {
+        let result = SessionConfig::from_toml_str(
+            r#"
+            [[peers]]
+            peer_id = "robot"
+            answer = [{ video = true, audio = false }]
+            "#,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,488 @@
+/// Schema of session config files
+pub mod config;
+/// Apply changes of session configs to running sessions
+pub mod reload;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::*;
+use log::warn;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::common::formats::{SerializableSocket, SocketInfo};
+use crate::data::formats::{ConnectQuery, DataConnectionId, DataId, DataIdWrapper};
+use crate::data::policy::DataRedirector;
+use crate::error;
+use crate::media::formats::{CallQuery, Constraints, MediaConnectionId};
+use crate::media::policy::{open_media_sockets, AnswerEngine, MediaSockets};
+use crate::peer::formats::{PeerId, PeerInfo};
+use crate::peer::monitor::wait_open;
+use crate::peer::PeerEventEnum;
+use crate::registry::Registry;
+use crate::shutdown::{self, ShutdownConfig, ShutdownReport};
+pub use config::{DataTargetConfig, MediaTargetConfig, PeerConfig, SessionConfig};
+pub use reload::{ReloadReport, SessionChange};
+
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Topology described in a `SessionConfig` brought up in WebRTC Gateway.
+///
+/// Each peer has its own `Registry`, so that a peer can be torn down without touching the others.
+pub struct Session {
+    path: Option<PathBuf>,
+    config: SessionConfig,
+    peers: BTreeMap<String, PeerSession>,
+}
+
+impl Session {
+    /// Load a config file and bring up the topology.
+    pub async fn from_config(path: impl AsRef<Path>) -> Result<Self, error::Error> {
+        let path = path.as_ref();
+        let config = SessionConfig::load(path)?;
+        let mut session = Self::start(config).await?;
+        session.path = Some(path.to_path_buf());
+        Ok(session)
+    }
+
+    /// Bring up the topology. If a peer fails to start, peers already started are closed.
+    pub async fn start(config: SessionConfig) -> Result<Self, error::Error> {
+        if let Some(ref base_url) = config.base_url {
+            crate::initialize(base_url.clone());
+        }
+        let mut session = Self {
+            path: None,
+            config: SessionConfig {
+                base_url: config.base_url.clone(),
+                peers: vec![],
+            },
+            peers: BTreeMap::new(),
+        };
+        for peer_config in config.peers.iter() {
+            match PeerSession::start(peer_config.clone()).await {
+                Ok(peer) => {
+                    session.peers.insert(peer_config.peer_id.clone(), peer);
+                }
+                Err(e) => {
+                    let _ = session.close().await;
+                    return Err(e);
+                }
+            }
+        }
+        session.config = config;
+        Ok(session)
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    pub fn peer(&self, peer_id: &str) -> Option<&PeerSession> {
+        self.peers.get(peer_id)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerSession> {
+        self.peers.values()
+    }
+
+    /// Release all resources of the session.
+    pub async fn close(self) -> ShutdownReport {
+        let mut report = ShutdownReport::default();
+        for (_, peer) in self.peers.into_iter() {
+            let peer_report = peer.close().await;
+            let released = peer_report.released;
+            report.released.peers.extend(released.peers);
+            report
+                .released
+                .data_connections
+                .extend(released.data_connections);
+            report
+                .released
+                .media_connections
+                .extend(released.media_connections);
+            report.released.data_sockets.extend(released.data_sockets);
+            report.released.media_sockets.extend(released.media_sockets);
+            report.released.rtcp_sockets.extend(released.rtcp_sockets);
+            report.errors.extend(peer_report.errors);
+            report.timed_out |= peer_report.timed_out;
+        }
+        report
+    }
+}
+
+/// DataConnection established from a peer in a session
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DataLink {
+    /// Config applied to the DataConnection
+    pub config: DataTargetConfig,
+    pub data_connection_id: DataConnectionId,
+    /// Socket to feed data. Users send data to this socket.
+    pub feed: Option<SocketInfo<DataId>>,
+}
+
+/// MediaConnection established from a peer in a session
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MediaLink {
+    /// Config applied to the MediaConnection
+    pub config: MediaTargetConfig,
+    pub media_connection_id: MediaConnectionId,
+    /// Sockets to feed video. Users send video to the socket.
+    pub video: Option<MediaSockets>,
+    /// Sockets to feed audio. Users send audio to the socket.
+    pub audio: Option<MediaSockets>,
+}
+
+/// A PeerObject in a session and connections it has established.
+pub struct PeerSession {
+    config: PeerConfig,
+    peer_info: PeerInfo,
+    registry: Registry,
+    data: BTreeMap<String, DataLink>,
+    media: BTreeMap<String, MediaLink>,
+    token: CancellationToken,
+    // stops handling incoming connections when the policies are replaced
+    listener: CancellationToken,
+}
+
+impl PeerSession {
+    /// Create a PeerObject, start answering incoming connections and connect to the targets.
+    ///
+    /// If any step fails, resources allocated so far are released.
+    pub async fn start(config: PeerConfig) -> Result<Self, error::Error> {
+        let api_key = match config.api_key {
+            Some(ref api_key) => api_key.clone(),
+            None => std::env::var("API_KEY")
+                .map_err(|_| error::Error::create_local_error("api_key is not set"))?,
+        };
+        let peer_info = crate::peer::create(
+            api_key,
+            config.domain.clone(),
+            PeerId::new(config.peer_id.clone()),
+            config.turn,
+        )
+        .await?;
+        let registry = Registry::new();
+        registry.register_peer(peer_info.clone());
+
+        let token = CancellationToken::new();
+        let mut session = Self {
+            config,
+            peer_info,
+            registry,
+            data: BTreeMap::new(),
+            media: BTreeMap::new(),
+            listener: token.child_token(),
+            token,
+        };
+        if let Err(e) = session.connect_all().await {
+            let _ = session.close().await;
+            return Err(e);
+        }
+        Ok(session)
+    }
+
+    async fn connect_all(&mut self) -> Result<(), error::Error> {
+        self.peer_info = wait_open(&self.peer_info, OPEN_TIMEOUT).await?;
+        if !self.config.answer.is_empty() || !self.config.redirect.is_empty() {
+            self.listen_incoming()?;
+        }
+        for target in self.config.data.clone() {
+            let link = self.connect(&target).await?;
+            self.data.insert(target.target_id, link);
+        }
+        for target in self.config.media.clone() {
+            let link = self.call(&target, None, None).await?;
+            self.media.insert(target.target_id, link);
+        }
+        Ok(())
+    }
+
+    // Handle CONNECTION and CALL events with the policies until the listener is cancelled.
+    fn listen_incoming(&self) -> Result<(), error::Error> {
+        let engine = AnswerEngine::new(self.config.answer.clone(), self.registry.clone())?;
+        let (event_notifier, mut event_observer) = mpsc::channel::<PeerEventEnum>(10);
+        shutdown::spawn_listener(
+            &self.listener,
+            crate::peer::listen_events(self.peer_info.clone(), event_notifier),
+        );
+        let redirector = DataRedirector::new(self.config.redirect.clone(), self.registry.clone());
+        let token = self.token.clone();
+        let registry = self.registry.clone();
+        shutdown::spawn_listener(&self.listener, async move {
+            while let Some(event) = event_observer.next().await {
+                match event {
+                    PeerEventEnum::CONNECTION(event) => {
+                        let redirector = redirector.clone();
+                        let registry = registry.clone();
+                        shutdown::spawn_listener(&token, async move {
+                            match redirector.handle_connection(&event).await {
+                                Ok(Some(mut channel)) => {
+                                    while channel.events.next().await.is_some() {}
+                                    registry
+                                        .unregister_data_connection(&channel.data_connection_id);
+                                }
+                                Ok(None) => {}
+                                Err(e) => warn!("fail to redirect a DataConnection: {:?}", e),
+                            }
+                        });
+                    }
+                    PeerEventEnum::CALL(event) if !engine.policies().is_empty() => {
+                        if let Err(e) = engine.handle_call(&event).await {
+                            warn!("fail to answer a call: {:?}", e);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn connect(&self, target: &DataTargetConfig) -> Result<DataLink, error::Error> {
+        connect_target(&self.peer_info, &self.registry, target).await
+    }
+
+    async fn call(
+        &self,
+        target: &MediaTargetConfig,
+        video_sockets: Option<MediaSockets>,
+        audio_sockets: Option<MediaSockets>,
+    ) -> Result<MediaLink, error::Error> {
+        call_target(
+            &self.peer_info,
+            &self.registry,
+            target,
+            video_sockets,
+            audio_sockets,
+        )
+        .await
+    }
+
+    pub fn config(&self) -> &PeerConfig {
+        &self.config
+    }
+
+    pub fn peer_info(&self) -> &PeerInfo {
+        &self.peer_info
+    }
+
+    /// Resources allocated for this peer, including connections accepted with the policies
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// DataConnection established to the target
+    pub fn data(&self, target_id: &str) -> Option<&DataLink> {
+        self.data.get(target_id)
+    }
+
+    /// MediaConnection established to the target
+    pub fn media(&self, target_id: &str) -> Option<&MediaLink> {
+        self.media.get(target_id)
+    }
+
+    /// Stop handling incoming connections and release all resources of the peer.
+    pub async fn close(self) -> ShutdownReport {
+        self.token.cancel();
+        shutdown::shutdown(&self.registry, ShutdownConfig::default().deadline).await
+    }
+}
+
+/// Open a data socket if needed and establish a DataConnection to the target.
+///
+/// The socket and the DataConnection are registered to the registry.
+pub(crate) async fn connect_target(
+    peer_info: &PeerInfo,
+    registry: &Registry,
+    target: &DataTargetConfig,
+) -> Result<DataLink, error::Error> {
+    let feed = if target.feed {
+        let socket = crate::data::open_data_socket().await?;
+        if let Some(data_id) = socket.get_id() {
+            registry.register_data_socket(data_id);
+        }
+        Some(socket)
+    } else {
+        None
+    };
+    let query = ConnectQuery {
+        peer_id: peer_info.peer_id(),
+        token: peer_info.token(),
+        options: target.options(),
+        target_id: PeerId::new(target.target_id.clone()),
+        params: feed
+            .as_ref()
+            .and_then(|socket| socket.get_id())
+            .map(|data_id| DataIdWrapper { data_id }),
+        redirect_params: target.redirect_params()?,
+    };
+    let data_connection_id = crate::data::connect(query).await?;
+    registry.register_data_connection(data_connection_id.clone());
+    Ok(DataLink {
+        config: target.clone(),
+        data_connection_id,
+        feed,
+    })
+}
+
+/// Open media sockets if needed and call the target.
+///
+/// Sockets given are reused instead of opening new ones.
+/// The sockets and the MediaConnection are registered to the registry.
+pub(crate) async fn call_target(
+    peer_info: &PeerInfo,
+    registry: &Registry,
+    target: &MediaTargetConfig,
+    video_sockets: Option<MediaSockets>,
+    audio_sockets: Option<MediaSockets>,
+) -> Result<MediaLink, error::Error> {
+    let redirect_params = target.redirect_params()?;
+    let video = match target.video_params {
+        Some(ref params) => {
+            let sockets = match video_sockets {
+                Some(sockets) => sockets,
+                None => open_media_sockets(true, registry).await?,
+            };
+            let media_params = params.to_media_params(&sockets.0, &sockets.1)?;
+            Some((sockets, media_params))
+        }
+        None => None,
+    };
+    let audio = match target.audio_params {
+        Some(ref params) => {
+            let sockets = match audio_sockets {
+                Some(sockets) => sockets,
+                None => open_media_sockets(false, registry).await?,
+            };
+            let media_params = params.to_media_params(&sockets.0, &sockets.1)?;
+            Some((sockets, media_params))
+        }
+        None => None,
+    };
+    let constraints = Constraints {
+        video: video.is_some(),
+        videoReceiveEnabled: Some(target.video_redirect.is_some()),
+        audio: audio.is_some(),
+        audioReceiveEnabled: Some(target.audio_redirect.is_some()),
+        video_params: video.as_ref().map(|(_, params)| params.clone()),
+        audio_params: audio.as_ref().map(|(_, params)| params.clone()),
+        metadata: target.metadata.clone(),
+    };
+    let query = CallQuery {
+        peer_id: peer_info.peer_id(),
+        token: peer_info.token(),
+        target_id: PeerId::new(target.target_id.clone()),
+        constraints: Some(constraints),
+        redirect_params: Some(redirect_params),
+    };
+    let media_connection_id = crate::media::call(&query).await?.params.media_connection_id;
+    registry.register_media_connection(media_connection_id.clone());
+    Ok(MediaLink {
+        config: target.clone(),
+        media_connection_id,
+        video: video.map(|(sockets, _)| sockets),
+        audio: audio.map(|(sockets, _)| sockets),
+    })
+}
+
+#[cfg(test)]
+mod test_session {
+    use mockito::mock;
+
+    use super::*;
+
+    const TOKEN: &str = "pt-9749250e-d157-4f80-9ee2-359ce8524308";
+    const DATA_CONNECTION_ID: &str = "dc-4995f372-fb6a-4196-b30a-ce11e5c7f56c";
+
+    #[tokio::test]
+    async fn start_and_remove_peer() {
+        crate::initialize(mockito::server_url());
+        let create_mock = mock("POST", "/peers")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"peer_id": "session_peer"}"#.into(),
+            ))
+            .with_status(reqwest::StatusCode::CREATED.as_u16() as usize)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"command_type": "PEERS_CREATE", "params": {{"peer_id": "session_peer", "token": "{}"}}}}"#,
+                TOKEN
+            ))
+            .create();
+        let event_mock = mock(
+            "GET",
+            format!("/peers/session_peer/events?token={}", TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(format!(
+            r#"{{"event": "OPEN", "params": {{"peer_id": "session_peer", "token": "{}"}}}}"#,
+            TOKEN
+        ))
+        .expect_at_least(1)
+        .create();
+        let connect_mock = mock("POST", "/data/connections")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"peer_id": "session_peer", "target_id": "operator", "redirect_params": {"ip_v4": "127.0.0.1", "port": 10000}}"#.into(),
+            ))
+            .with_status(reqwest::StatusCode::ACCEPTED.as_u16() as usize)
+            .with_header("content-type", "application/json")
+            .with_body(format!(
+                r#"{{"command_type": "PEERS_CONNECT", "params": {{"data_connection_id": "{}"}}}}"#,
+                DATA_CONNECTION_ID
+            ))
+            .create();
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/data/connections/{}", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+        let delete_mock = mock(
+            "DELETE",
+            format!("/peers/session_peer?token={}", TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+
+        let config = SessionConfig::from_toml_str(
+            r#"
+            [[peers]]
+            peer_id = "session_peer"
+            api_key = "api_key"
+
+            [[peers.data]]
+            target_id = "operator"
+            redirect = { ip = "127.0.0.1", port = 10000 }
+            "#,
+        )
+        .unwrap();
+        let mut session = Session::start(config).await.unwrap();
+        let peer = session.peer("session_peer").unwrap();
+        let link = peer.data("operator").unwrap();
+        assert_eq!(link.data_connection_id.as_str(), DATA_CONNECTION_ID);
+        assert!(link.feed.is_none());
+        assert_eq!(peer.registry().snapshot().data_connections.len(), 1);
+
+        // the OPEN event is not returned anymore, so that waiting for CLOSE ends soon
+        event_mock.assert();
+        drop(event_mock);
+
+        // the peer is closed when it's removed from the config
+        let report = session.apply(SessionConfig::default()).await;
+        assert!(report.errors.is_empty());
+        assert_eq!(
+            report.changes,
+            vec![SessionChange::PeerClosed {
+                peer_id: "session_peer".into()
+            }]
+        );
+        assert!(session.peer("session_peer").is_none());
+        create_mock.assert();
+        connect_mock.assert();
+        disconnect_mock.assert();
+        delete_mock.assert();
+    }
+}
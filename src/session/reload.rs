@@ -0,0 +1,686 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use futures::channel::mpsc;
+use futures::*;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use super::{DataLink, MediaLink, PeerConfig, PeerSession, Session, SessionConfig};
+use crate::common::formats::{SerializableId, SerializableSocket};
+use crate::data::formats::{DataConnectionId, DataIdWrapper, RedirectDataParams};
+use crate::error;
+use crate::media::formats::MediaConnectionId;
+use crate::media::policy::MediaSockets;
+use crate::registry::Resources;
+use crate::shutdown::ShutdownReport;
+
+/// A change applied to a running session.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "change")]
+pub enum SessionChange {
+    /// A PeerObject has been added to the session
+    PeerStarted { peer_id: String },
+    /// A PeerObject has been removed from the session
+    PeerClosed { peer_id: String },
+    /// A PeerObject has been recreated because its api_key, domain or turn has changed
+    PeerRestarted { peer_id: String },
+    /// Policies for incoming connections have been replaced
+    PoliciesUpdated { peer_id: String },
+    DataConnected {
+        peer_id: String,
+        target_id: String,
+        data_connection_id: DataConnectionId,
+    },
+    DataDisconnected {
+        peer_id: String,
+        target_id: String,
+        data_connection_id: DataConnectionId,
+    },
+    /// Feed or redirect of a DataConnection has been changed
+    DataRedirected {
+        peer_id: String,
+        target_id: String,
+        data_connection_id: DataConnectionId,
+    },
+    MediaCalled {
+        peer_id: String,
+        target_id: String,
+        media_connection_id: MediaConnectionId,
+    },
+    MediaDisconnected {
+        peer_id: String,
+        target_id: String,
+        media_connection_id: MediaConnectionId,
+    },
+    /// A data, media or rtcp socket has been opened
+    SocketOpened { peer_id: String, socket_id: String },
+    /// A data, media or rtcp socket has been closed
+    SocketClosed { peer_id: String, socket_id: String },
+}
+
+/// Result of applying a new config to a running session.
+#[derive(Debug, Default)]
+pub struct ReloadReport {
+    /// Changes applied successfully in order
+    pub changes: Vec<SessionChange>,
+    /// Errors returned from WebRTC Gateway. Failed changes are retried in the next reload.
+    pub errors: Vec<error::Error>,
+}
+
+impl ReloadReport {
+    /// Returns true if nothing has changed and nothing has failed.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.errors.is_empty()
+    }
+
+    fn shutdown(&mut self, report: ShutdownReport) {
+        self.errors.extend(report.errors);
+        if report.timed_out {
+            self.errors.push(error::Error::create_local_error(
+                "shutdown sequence didn't finish",
+            ));
+        }
+    }
+}
+
+// A PeerObject needs to be recreated if parameters for POST /peers have changed.
+fn needs_restart(old: &PeerConfig, new: &PeerConfig) -> bool {
+    old.api_key != new.api_key || old.domain != new.domain || old.turn != new.turn
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl Session {
+    /// Read the config file again and apply it.
+    pub async fn reload(&mut self) -> Result<ReloadReport, error::Error> {
+        let path = self.path.clone().ok_or_else(|| {
+            error::Error::create_local_error("session is not started from a config file")
+        })?;
+        let config = SessionConfig::load(path)?;
+        Ok(self.apply(config).await)
+    }
+
+    /// Apply a new config with as few operations as possible.
+    ///
+    /// - peers added or removed are created or closed
+    /// - peers whose api_key, domain or turn has changed are recreated
+    /// - data targets whose feed or redirect has changed are redirected with `data::redirect`
+    /// - other changed targets are disconnected and connected again. Media sockets are reused.
+    ///
+    /// A config changing `base_url` is rejected as a whole, because the crate can't be initialized twice.
+    pub async fn apply(&mut self, config: SessionConfig) -> ReloadReport {
+        let mut report = ReloadReport::default();
+        if config.base_url != self.config.base_url {
+            report.errors.push(error::Error::create_local_error(
+                "base_url can't be changed while the session is running",
+            ));
+            return report;
+        }
+
+        let removed: Vec<String> = self
+            .peers
+            .keys()
+            .filter(|peer_id| !config.peers.iter().any(|p| &p.peer_id == *peer_id))
+            .cloned()
+            .collect();
+        for peer_id in removed {
+            if let Some(peer) = self.peers.remove(&peer_id) {
+                report.shutdown(peer.close().await);
+                report.changes.push(SessionChange::PeerClosed { peer_id });
+            }
+        }
+
+        for peer_config in config.peers.iter() {
+            let peer_id = peer_config.peer_id.clone();
+            let change = match self.peers.get_mut(&peer_id) {
+                Some(peer) if !needs_restart(peer.config(), peer_config) => {
+                    peer.update(peer_config.clone(), &mut report).await;
+                    continue;
+                }
+                Some(_) => {
+                    if let Some(peer) = self.peers.remove(&peer_id) {
+                        report.shutdown(peer.close().await);
+                    }
+                    SessionChange::PeerRestarted {
+                        peer_id: peer_id.clone(),
+                    }
+                }
+                None => SessionChange::PeerStarted {
+                    peer_id: peer_id.clone(),
+                },
+            };
+            match PeerSession::start(peer_config.clone()).await {
+                Ok(peer) => {
+                    self.peers.insert(peer_id, peer);
+                    report.changes.push(change);
+                }
+                Err(e) => report.errors.push(e),
+            }
+        }
+        self.config = config;
+        report
+    }
+
+    /// Apply the config file whenever it's modified, until the token is cancelled.
+    ///
+    /// The file is checked every `interval`. Results of reloads are sent to the notifier.
+    pub async fn watch(
+        &mut self,
+        interval: Duration,
+        token: CancellationToken,
+        mut report_notifier: mpsc::Sender<Result<ReloadReport, error::Error>>,
+    ) -> Result<(), error::Error> {
+        let path = self.path.clone().ok_or_else(|| {
+            error::Error::create_local_error("session is not started from a config file")
+        })?;
+        let mut last_modified = modified(&path);
+        loop {
+            tokio::select! {
+                _ = token.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(interval) => {}
+            }
+            let current = modified(&path);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+            let result = self.reload().await;
+            if report_notifier.send(result).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl PeerSession {
+    // Apply a new config of this peer. The PeerObject itself is kept as it is.
+    pub(super) async fn update(&mut self, config: PeerConfig, report: &mut ReloadReport) {
+        let before = self.registry.snapshot();
+        let peer_id = config.peer_id.clone();
+
+        if self.config.answer != config.answer || self.config.redirect != config.redirect {
+            self.listener.cancel();
+            self.listener = self.token.child_token();
+            self.config.answer = config.answer.clone();
+            self.config.redirect = config.redirect.clone();
+            let listened = if !config.answer.is_empty() || !config.redirect.is_empty() {
+                self.listen_incoming()
+            } else {
+                Ok(())
+            };
+            match listened {
+                Ok(_) => report.changes.push(SessionChange::PoliciesUpdated {
+                    peer_id: peer_id.clone(),
+                }),
+                Err(e) => report.errors.push(e),
+            }
+        }
+
+        let removed: Vec<String> = self
+            .data
+            .keys()
+            .filter(|target_id| !config.data.iter().any(|t| &t.target_id == *target_id))
+            .cloned()
+            .collect();
+        for target_id in removed {
+            if let Some(link) = self.data.remove(&target_id) {
+                self.disconnect_data(&peer_id, link, report).await;
+            }
+        }
+        for target in config.data.iter() {
+            let link = match self.data.remove(&target.target_id) {
+                Some(link) if &link.config == target => {
+                    self.data.insert(target.target_id.clone(), link);
+                    continue;
+                }
+                Some(link)
+                    if link.config.metadata == target.metadata
+                        && link.config.serialization == target.serialization =>
+                {
+                    let data_connection_id = link.data_connection_id.clone();
+                    match self.redirect_data(link, target.clone()).await {
+                        Ok(link) => {
+                            self.data.insert(target.target_id.clone(), link);
+                            report.changes.push(SessionChange::DataRedirected {
+                                peer_id: peer_id.clone(),
+                                target_id: target.target_id.clone(),
+                                data_connection_id,
+                            });
+                        }
+                        Err((link, e)) => {
+                            self.data.insert(target.target_id.clone(), link);
+                            report.errors.push(e);
+                        }
+                    }
+                    continue;
+                }
+                // options of DataChannel can't be changed without connecting again
+                Some(link) => {
+                    if !self.disconnect_data(&peer_id, link, report).await {
+                        continue;
+                    }
+                    self.connect(target).await
+                }
+                None => self.connect(target).await,
+            };
+            match link {
+                Ok(link) => {
+                    report.changes.push(SessionChange::DataConnected {
+                        peer_id: peer_id.clone(),
+                        target_id: target.target_id.clone(),
+                        data_connection_id: link.data_connection_id.clone(),
+                    });
+                    self.data.insert(target.target_id.clone(), link);
+                }
+                Err(e) => report.errors.push(e),
+            }
+        }
+
+        let removed: Vec<String> = self
+            .media
+            .keys()
+            .filter(|target_id| !config.media.iter().any(|t| &t.target_id == *target_id))
+            .cloned()
+            .collect();
+        for target_id in removed {
+            if let Some(link) = self.media.remove(&target_id) {
+                if let Some((video, audio)) = self.disconnect_media(&peer_id, link, report).await {
+                    self.close_media_sockets(video, report).await;
+                    self.close_media_sockets(audio, report).await;
+                }
+            }
+        }
+        for target in config.media.iter() {
+            let link = match self.media.remove(&target.target_id) {
+                Some(link) if &link.config == target => {
+                    self.media.insert(target.target_id.clone(), link);
+                    continue;
+                }
+                // parameters of MediaConnection can't be changed without calling again
+                Some(link) => {
+                    let (video, audio) = match self.disconnect_media(&peer_id, link, report).await {
+                        Some(sockets) => sockets,
+                        None => continue,
+                    };
+                    let (video, closed) = match target.video_params {
+                        Some(_) => (video, None),
+                        None => (None, video),
+                    };
+                    self.close_media_sockets(closed, report).await;
+                    let (audio, closed) = match target.audio_params {
+                        Some(_) => (audio, None),
+                        None => (None, audio),
+                    };
+                    self.close_media_sockets(closed, report).await;
+                    self.call(target, video, audio).await
+                }
+                None => self.call(target, None, None).await,
+            };
+            match link {
+                Ok(link) => {
+                    report.changes.push(SessionChange::MediaCalled {
+                        peer_id: peer_id.clone(),
+                        target_id: target.target_id.clone(),
+                        media_connection_id: link.media_connection_id.clone(),
+                    });
+                    self.media.insert(target.target_id.clone(), link);
+                }
+                Err(e) => report.errors.push(e),
+            }
+        }
+
+        self.config = config;
+        let after = self.registry.snapshot();
+        socket_changes(&peer_id, &before, &after, report);
+    }
+
+    // Returns false if the DataConnection is still alive.
+    async fn disconnect_data(
+        &mut self,
+        peer_id: &str,
+        link: DataLink,
+        report: &mut ReloadReport,
+    ) -> bool {
+        if let Err(e) = crate::data::disconnect(&link.data_connection_id).await {
+            report.errors.push(e);
+            self.data.insert(link.config.target_id.clone(), link);
+            return false;
+        }
+        self.registry
+            .unregister_data_connection(&link.data_connection_id);
+        if let Some(data_id) = link.feed.and_then(|socket| socket.get_id()) {
+            match crate::data::close_data_socket(&data_id).await {
+                Ok(_) => self.registry.unregister_data_socket(&data_id),
+                Err(e) => report.errors.push(e),
+            }
+        }
+        report.changes.push(SessionChange::DataDisconnected {
+            peer_id: peer_id.to_string(),
+            target_id: link.config.target_id,
+            data_connection_id: link.data_connection_id,
+        });
+        true
+    }
+
+    // Change feed and redirect of a DataConnection. The link is given back on failure.
+    async fn redirect_data(
+        &self,
+        mut link: DataLink,
+        target: super::DataTargetConfig,
+    ) -> Result<DataLink, (DataLink, error::Error)> {
+        let redirect_params = match target.redirect_params() {
+            Ok(params) => params,
+            Err(e) => return Err((link, e)),
+        };
+        let (feed, closed) = match (link.feed.take(), target.feed) {
+            (Some(socket), true) => (Some(socket), None),
+            (Some(socket), false) => (None, Some(socket)),
+            (None, true) => match crate::data::open_data_socket().await {
+                Ok(socket) => {
+                    if let Some(data_id) = socket.get_id() {
+                        self.registry.register_data_socket(data_id);
+                    }
+                    (Some(socket), None)
+                }
+                Err(e) => return Err((link, e)),
+            },
+            (None, false) => (None, None),
+        };
+        let params = RedirectDataParams {
+            feed_params: feed
+                .as_ref()
+                .and_then(|socket| socket.get_id())
+                .map(|data_id| DataIdWrapper { data_id }),
+            redirect_params,
+        };
+        if let Err(e) = crate::data::redirect(&link.data_connection_id, &params).await {
+            // the socket opened above is left in the registry, and released on close
+            link.feed = closed.or(feed);
+            return Err((link, e));
+        }
+        if let Some(data_id) = closed.and_then(|socket| socket.get_id()) {
+            // the DataConnection doesn't use the socket anymore, so the error is ignored
+            if crate::data::close_data_socket(&data_id).await.is_ok() {
+                self.registry.unregister_data_socket(&data_id);
+            }
+        }
+        link.feed = feed;
+        link.config = target;
+        Ok(link)
+    }
+
+    // Returns sockets of the MediaConnection, or None if it's still alive.
+    async fn disconnect_media(
+        &mut self,
+        peer_id: &str,
+        link: MediaLink,
+        report: &mut ReloadReport,
+    ) -> Option<(Option<MediaSockets>, Option<MediaSockets>)> {
+        if let Err(e) = crate::media::disconnect(&link.media_connection_id).await {
+            report.errors.push(e);
+            self.media.insert(link.config.target_id.clone(), link);
+            return None;
+        }
+        self.registry
+            .unregister_media_connection(&link.media_connection_id);
+        report.changes.push(SessionChange::MediaDisconnected {
+            peer_id: peer_id.to_string(),
+            target_id: link.config.target_id,
+            media_connection_id: link.media_connection_id,
+        });
+        Some((link.video, link.audio))
+    }
+
+    async fn close_media_sockets(&self, sockets: Option<MediaSockets>, report: &mut ReloadReport) {
+        let (media_socket, rtcp_socket) = match sockets {
+            Some(sockets) => sockets,
+            None => return,
+        };
+        if let Some(media_id) = media_socket.get_id() {
+            match crate::media::delete_media(&media_id).await {
+                Ok(_) => self.registry.unregister_media_socket(&media_id),
+                Err(e) => report.errors.push(e),
+            }
+        }
+        if let Some(rtcp_id) = rtcp_socket.get_id() {
+            match crate::media::delete_rtcp(&rtcp_id).await {
+                Ok(_) => self.registry.unregister_rtcp_socket(&rtcp_id),
+                Err(e) => report.errors.push(e),
+            }
+        }
+    }
+}
+
+fn socket_ids(resources: &Resources) -> BTreeSet<String> {
+    let data_sockets = resources.data_sockets.iter().map(|id| id.as_str());
+    let media_sockets = resources.media_sockets.iter().map(|id| id.as_str());
+    let rtcp_sockets = resources.rtcp_sockets.iter().map(|id| id.as_str());
+    data_sockets
+        .chain(media_sockets)
+        .chain(rtcp_sockets)
+        .map(String::from)
+        .collect()
+}
+
+fn socket_changes(peer_id: &str, before: &Resources, after: &Resources, report: &mut ReloadReport) {
+    let before = socket_ids(before);
+    let after = socket_ids(after);
+    for socket_id in before.difference(&after) {
+        report.changes.push(SessionChange::SocketClosed {
+            peer_id: peer_id.to_string(),
+            socket_id: socket_id.clone(),
+        });
+    }
+    for socket_id in after.difference(&before) {
+        report.changes.push(SessionChange::SocketOpened {
+            peer_id: peer_id.to_string(),
+            socket_id: socket_id.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_reload {
+    use std::collections::BTreeMap;
+
+    use mockito::mock;
+
+    use super::*;
+    use crate::common::formats::SocketInfo;
+    use crate::media::formats::{MediaId, RtcpId};
+    use crate::peer::formats::PeerInfo;
+    use crate::registry::Registry;
+
+    const DATA_CONNECTION_ID: &str = "dc-5995f372-fb6a-4196-b30a-ce11e5c7f56c";
+    const MEDIA_CONNECTION_ID: &str = "mc-202127d9-30de-413b-93f7-41a33e39d82b";
+    const MEDIA_ID: &str = "vi-5d053831-5dc2-461b-a358-d062d6115216";
+    const RTCP_ID: &str = "rc-a70df60c-1ae4-4b74-8bf1-aa4d5ef2bb9b";
+
+    const OLD: &str = r#"
+        [[peers]]
+        peer_id = "reload_peer"
+        api_key = "api_key"
+
+        [[peers.data]]
+        target_id = "operator"
+        redirect = { ip = "127.0.0.1", port = 10000 }
+
+        [[peers.media]]
+        target_id = "camera"
+        video_params = { band_width = 1500, codec = "H264" }
+        "#;
+
+    const NEW: &str = r#"
+        [[peers]]
+        peer_id = "reload_peer"
+        api_key = "api_key"
+
+        [[peers.data]]
+        target_id = "operator"
+        redirect = { ip = "127.0.0.1", port = 10001 }
+        "#;
+
+    // PeerSession as if it has been started with OLD
+    fn peer_session() -> PeerSession {
+        let config = SessionConfig::from_toml_str(OLD).unwrap().peers.remove(0);
+        let peer_info =
+            PeerInfo::try_create("reload_peer", "pt-9749250e-d157-4f80-9ee2-359ce8524308").unwrap();
+        let registry = Registry::new();
+        registry.register_peer(peer_info.clone());
+        let data_connection_id = DataConnectionId::try_create(DATA_CONNECTION_ID).unwrap();
+        registry.register_data_connection(data_connection_id.clone());
+        let media_connection_id = MediaConnectionId::try_create(MEDIA_CONNECTION_ID).unwrap();
+        registry.register_media_connection(media_connection_id.clone());
+        registry.register_media_socket(MediaId::try_create(MEDIA_ID).unwrap());
+        registry.register_rtcp_socket(RtcpId::try_create(RTCP_ID).unwrap());
+
+        let mut data = BTreeMap::new();
+        data.insert(
+            "operator".to_string(),
+            DataLink {
+                config: config.data[0].clone(),
+                data_connection_id,
+                feed: None,
+            },
+        );
+        let mut media = BTreeMap::new();
+        media.insert(
+            "camera".to_string(),
+            MediaLink {
+                config: config.media[0].clone(),
+                media_connection_id,
+                video: Some((
+                    SocketInfo::try_create(Some(MEDIA_ID.into()), "127.0.0.1", 20000).unwrap(),
+                    SocketInfo::try_create(Some(RTCP_ID.into()), "127.0.0.1", 20001).unwrap(),
+                )),
+                audio: None,
+            },
+        );
+        let token = CancellationToken::new();
+        PeerSession {
+            config,
+            peer_info,
+            registry,
+            data,
+            media,
+            listener: token.child_token(),
+            token,
+        }
+    }
+
+    #[tokio::test]
+    async fn apply_minimal_changes() {
+        crate::initialize(mockito::server_url());
+        let redirect_mock = mock(
+            "PUT",
+            format!("/data/connections/{}", DATA_CONNECTION_ID).as_str(),
+        )
+        .match_body(mockito::Matcher::JsonString(
+            r#"{"redirect_params": {"ip_v4": "127.0.0.1", "port": 10001}}"#.into(),
+        ))
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(
+            r#"{"command_type": "DATA_CONNECTION_PUT", "data_id": "da-50a32bab-b3d9-4913-8e20-f79c90a6a211"}"#,
+        )
+        .create();
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/media/connections/{}", MEDIA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+        let media_mock = mock("DELETE", format!("/media/{}", MEDIA_ID).as_str())
+            .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+            .create();
+        let rtcp_mock = mock("DELETE", format!("/media/rtcp/{}", RTCP_ID).as_str())
+            .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+            .create();
+
+        let mut session = Session {
+            path: None,
+            config: SessionConfig::from_toml_str(OLD).unwrap(),
+            peers: BTreeMap::new(),
+        };
+        session
+            .peers
+            .insert("reload_peer".to_string(), peer_session());
+        let report = session
+            .apply(SessionConfig::from_toml_str(NEW).unwrap())
+            .await;
+        assert!(report.errors.is_empty());
+        let peer_id = String::from("reload_peer");
+        assert_eq!(
+            report.changes,
+            vec![
+                SessionChange::DataRedirected {
+                    peer_id: peer_id.clone(),
+                    target_id: "operator".into(),
+                    data_connection_id: DataConnectionId::try_create(DATA_CONNECTION_ID).unwrap(),
+                },
+                SessionChange::MediaDisconnected {
+                    peer_id: peer_id.clone(),
+                    target_id: "camera".into(),
+                    media_connection_id: MediaConnectionId::try_create(MEDIA_CONNECTION_ID)
+                        .unwrap(),
+                },
+                SessionChange::SocketClosed {
+                    peer_id: peer_id.clone(),
+                    socket_id: RTCP_ID.into(),
+                },
+                SessionChange::SocketClosed {
+                    peer_id,
+                    socket_id: MEDIA_ID.into(),
+                },
+            ]
+        );
+        let peer = session.peer("reload_peer").unwrap();
+        assert_eq!(
+            peer.data("operator")
+                .unwrap()
+                .config
+                .redirect
+                .as_ref()
+                .unwrap()
+                .port,
+            10001
+        );
+        assert!(peer.media("camera").is_none());
+        assert_eq!(
+            session.config(),
+            &SessionConfig::from_toml_str(NEW).unwrap()
+        );
+
+        // nothing changes if the same config is applied again
+        let report = session
+            .apply(SessionConfig::from_toml_str(NEW).unwrap())
+            .await;
+        assert!(report.is_empty());
+        redirect_mock.assert();
+        disconnect_mock.assert();
+        media_mock.assert();
+        rtcp_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn reject_base_url_change() {
+        let mut session = Session {
+            path: None,
+            config: SessionConfig::from_toml_str(OLD).unwrap(),
+            peers: BTreeMap::new(),
+        };
+        let mut config = SessionConfig::from_toml_str(OLD).unwrap();
+        config.base_url = Some("http://127.0.0.1:8001".into());
+        let report = session.apply(config).await;
+        assert!(report.changes.is_empty());
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(
+            session.config(),
+            &SessionConfig::from_toml_str(OLD).unwrap()
+        );
+    }
+}
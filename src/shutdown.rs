@@ -0,0 +1,478 @@
+use std::time::Duration;
+
+use futures::future::{join_all, BoxFuture};
+use futures::*;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::data::formats::{DataConnectionId, DataId};
+use crate::data::DataConnectionEventEnum;
+use crate::error;
+use crate::media::formats::{MediaConnectionId, MediaId, RtcpId};
+use crate::media::MediaConnectionEventEnum;
+use crate::peer::formats::PeerInfo;
+use crate::peer::PeerEventEnum;
+use crate::registry::{Registry, Resources};
+
+/// Parameters for a graceful shutdown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownConfig {
+    /// Whole shutdown sequence must finish within this duration.
+    pub deadline: Duration,
+    /// If true, SIGINT and SIGTERM trigger the shutdown sequence.
+    pub handle_signals: bool,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            deadline: Duration::from_secs(10),
+            handle_signals: false,
+        }
+    }
+}
+
+/// Result of a shutdown sequence.
+#[derive(Serialize, Debug, Default)]
+pub struct ShutdownReport {
+    /// Resources which have been released successfully.
+    pub released: Resources,
+    /// Errors returned from WebRTC Gateway while releasing resources.
+    pub errors: Vec<error::Error>,
+    /// True if the sequence didn't finish within `ShutdownConfig::deadline`.
+    pub timed_out: bool,
+}
+
+/// Spawn a future, typically `listen_events` of peer, data or media, which is stopped by the token.
+///
+/// It returns None if the future is stopped by the token before it finishes.
+pub fn spawn_listener<F>(token: &CancellationToken, fut: F) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let token = token.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = token.cancelled() => None,
+            result = fut => Some(result),
+        }
+    })
+}
+
+/// Wait for the token, and then release all resources registered in the registry.
+///
+/// Event listeners spawned with `spawn_listener` and the same token stop when the token is cancelled.
+/// After that, this function
+/// 1. disconnects DataConnections and MediaConnections
+/// 2. closes data, media and rtcp sockets
+/// 3. deletes PeerObjects
+/// 4. waits for CLOSE events of the connections and the PeerObjects
+///
+/// If `handle_signals` is set, SIGINT and SIGTERM cancel the token.
+pub async fn run(
+    registry: Registry,
+    token: CancellationToken,
+    config: ShutdownConfig,
+) -> ShutdownReport {
+    if config.handle_signals {
+        let signal_token = token.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = signal_token.cancelled() => {},
+                _ = wait_signal() => {
+                    info!("signal received. start shutting down");
+                    signal_token.cancel();
+                }
+            }
+        });
+    }
+    token.cancelled().await;
+    shutdown(&registry, config.deadline).await
+}
+
+/// Release all resources registered in the registry within the deadline.
+///
+/// Resources are unregistered one by one as they are released.
+/// Resources failed to be released, or left when the deadline passes, stay in the registry, so that users can retry.
+pub async fn shutdown(registry: &Registry, deadline: Duration) -> ShutdownReport {
+    shutdown_with(&Gateway, registry, deadline).await
+}
+
+async fn shutdown_with(
+    gateway: &impl Release,
+    registry: &Registry,
+    deadline: Duration,
+) -> ShutdownReport {
+    let mut report = ShutdownReport::default();
+    let resources = registry.snapshot();
+    if tokio::time::timeout(deadline, release(gateway, registry, resources, &mut report))
+        .await
+        .is_err()
+    {
+        warn!("shutdown sequence didn't finish within {:?}", deadline);
+        report.timed_out = true;
+    }
+    report
+}
+
+// Requests to WebRTC Gateway to release resources.
+// Tests replace it with a stub to simulate a gateway which doesn't respond.
+trait Release: Sync {
+    fn disconnect_data<'a>(
+        &'a self,
+        id: &'a DataConnectionId,
+    ) -> BoxFuture<'a, Result<(), error::Error>>;
+    fn disconnect_media<'a>(
+        &'a self,
+        id: &'a MediaConnectionId,
+    ) -> BoxFuture<'a, Result<(), error::Error>>;
+    fn close_data_socket<'a>(&'a self, id: &'a DataId) -> BoxFuture<'a, Result<(), error::Error>>;
+    fn delete_media<'a>(&'a self, id: &'a MediaId) -> BoxFuture<'a, Result<(), error::Error>>;
+    fn delete_rtcp<'a>(&'a self, id: &'a RtcpId) -> BoxFuture<'a, Result<(), error::Error>>;
+    fn delete_peer<'a>(
+        &'a self,
+        peer_info: &'a PeerInfo,
+    ) -> BoxFuture<'a, Result<(), error::Error>>;
+}
+
+struct Gateway;
+
+impl Release for Gateway {
+    fn disconnect_data<'a>(
+        &'a self,
+        id: &'a DataConnectionId,
+    ) -> BoxFuture<'a, Result<(), error::Error>> {
+        crate::data::disconnect(id).boxed()
+    }
+
+    fn disconnect_media<'a>(
+        &'a self,
+        id: &'a MediaConnectionId,
+    ) -> BoxFuture<'a, Result<(), error::Error>> {
+        crate::media::disconnect(id).boxed()
+    }
+
+    fn close_data_socket<'a>(&'a self, id: &'a DataId) -> BoxFuture<'a, Result<(), error::Error>> {
+        crate::data::close_data_socket(id).boxed()
+    }
+
+    fn delete_media<'a>(&'a self, id: &'a MediaId) -> BoxFuture<'a, Result<(), error::Error>> {
+        crate::media::delete_media(id).boxed()
+    }
+
+    fn delete_rtcp<'a>(&'a self, id: &'a RtcpId) -> BoxFuture<'a, Result<(), error::Error>> {
+        crate::media::delete_rtcp(id).boxed()
+    }
+
+    fn delete_peer<'a>(
+        &'a self,
+        peer_info: &'a PeerInfo,
+    ) -> BoxFuture<'a, Result<(), error::Error>> {
+        crate::peer::delete(peer_info).boxed()
+    }
+}
+
+async fn release(
+    gateway: &impl Release,
+    registry: &Registry,
+    resources: Resources,
+    report: &mut ShutdownReport,
+) {
+    let data_connections = join_all(resources.data_connections.into_iter().map(|id| async {
+        let result = gateway.disconnect_data(&id).await;
+        (id, result)
+    }))
+    .await;
+    for (id, result) in data_connections {
+        match result {
+            Ok(_) => {
+                registry.unregister_data_connection(&id);
+                report.released.data_connections.insert(id);
+            }
+            Err(e) => report.errors.push(e),
+        }
+    }
+
+    let media_connections = join_all(resources.media_connections.into_iter().map(|id| async {
+        let result = gateway.disconnect_media(&id).await;
+        (id, result)
+    }))
+    .await;
+    for (id, result) in media_connections {
+        match result {
+            Ok(_) => {
+                registry.unregister_media_connection(&id);
+                report.released.media_connections.insert(id);
+            }
+            Err(e) => report.errors.push(e),
+        }
+    }
+
+    for id in resources.data_sockets {
+        match gateway.close_data_socket(&id).await {
+            Ok(_) => {
+                registry.unregister_data_socket(&id);
+                report.released.data_sockets.insert(id);
+            }
+            Err(e) => report.errors.push(e),
+        }
+    }
+
+    for id in resources.media_sockets {
+        match gateway.delete_media(&id).await {
+            Ok(_) => {
+                registry.unregister_media_socket(&id);
+                report.released.media_sockets.insert(id);
+            }
+            Err(e) => report.errors.push(e),
+        }
+    }
+
+    for id in resources.rtcp_sockets {
+        match gateway.delete_rtcp(&id).await {
+            Ok(_) => {
+                registry.unregister_rtcp_socket(&id);
+                report.released.rtcp_sockets.insert(id);
+            }
+            Err(e) => report.errors.push(e),
+        }
+    }
+
+    for peer_info in resources.peers {
+        match gateway.delete_peer(&peer_info).await {
+            Ok(_) => {
+                registry.unregister_peer(&peer_info);
+                report.released.peers.insert(peer_info);
+            }
+            Err(e) => report.errors.push(e),
+        }
+    }
+
+    let data_closes = join_all(
+        report
+            .released
+            .data_connections
+            .iter()
+            .map(wait_data_connection_close),
+    );
+    let media_closes = join_all(
+        report
+            .released
+            .media_connections
+            .iter()
+            .map(wait_media_connection_close),
+    );
+    let peer_closes = join_all(report.released.peers.iter().map(wait_peer_close));
+    join!(data_closes, media_closes, peer_closes);
+}
+
+// Errors are ignored here, because WebRTC Gateway may have already forgotten the connection.
+async fn wait_data_connection_close(data_connection_id: &DataConnectionId) {
+    while let Ok(event) = crate::data::event(data_connection_id).await {
+        if let DataConnectionEventEnum::CLOSE(_) = event {
+            break;
+        }
+    }
+}
+
+async fn wait_media_connection_close(media_connection_id: &MediaConnectionId) {
+    while let Ok(event) = crate::media::event(media_connection_id).await {
+        if let MediaConnectionEventEnum::CLOSE(_) = event {
+            break;
+        }
+    }
+}
+
+async fn wait_peer_close(peer_info: &PeerInfo) {
+    while let Ok(event) = crate::peer::event(peer_info.clone()).await {
+        if let PeerEventEnum::CLOSE(_) = event {
+            break;
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("fail to listen SIGTERM: {:?}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod test_shutdown {
+    use std::time::Duration;
+
+    use mockito::mock;
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+    use crate::common::formats::SerializableId;
+
+    const DATA_CONNECTION_ID: &str = "dc-4995f372-fb6a-4196-b30a-ce11e5c7f56c";
+    const DATA_ID: &str = "da-50a32bab-b3d9-4913-8e20-f79c90a6a211";
+    const TOKEN: &str = "pt-9749250e-d157-4f80-9ee2-359ce8524308";
+
+    #[tokio::test]
+    async fn release_all_resources() {
+        crate::initialize(mockito::server_url());
+        let registry = Registry::new();
+        let peer_info = PeerInfo::try_create("shutdown_peer", TOKEN).unwrap();
+        registry.register_peer(peer_info.clone());
+        registry
+            .register_data_connection(DataConnectionId::try_create(DATA_CONNECTION_ID).unwrap());
+        registry.register_data_socket(DataId::try_create(DATA_ID).unwrap());
+
+        let disconnect_mock = mock(
+            "DELETE",
+            format!("/data/connections/{}", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+        let data_event_mock = mock(
+            "GET",
+            format!("/data/connections/{}/events", DATA_CONNECTION_ID).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"event": "CLOSE"}"#)
+        .create();
+        let socket_mock = mock("DELETE", format!("/data/{}", DATA_ID).as_str())
+            .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+            .create();
+        let peer_mock = mock(
+            "DELETE",
+            format!("/peers/shutdown_peer?token={}", TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::NO_CONTENT.as_u16() as usize)
+        .create();
+        let peer_event_mock = mock(
+            "GET",
+            format!("/peers/shutdown_peer/events?token={}", TOKEN).as_str(),
+        )
+        .with_status(reqwest::StatusCode::OK.as_u16() as usize)
+        .with_header("content-type", "application/json")
+        .with_body(format!(
+            r#"{{"event": "CLOSE", "params": {{"peer_id": "shutdown_peer", "token": "{}"}}}}"#,
+            TOKEN
+        ))
+        .create();
+
+        let token = CancellationToken::new();
+        let listener = spawn_listener(&token, future::pending::<()>());
+        token.cancel();
+        let config = ShutdownConfig {
+            deadline: Duration::from_secs(5),
+            handle_signals: false,
+        };
+        let report = run(registry.clone(), token, config).await;
+
+        assert_eq!(listener.await.unwrap(), None);
+        assert!(!report.timed_out);
+        assert!(report.errors.is_empty());
+        assert!(report.released.peers.contains(&peer_info));
+        assert_eq!(report.released.data_connections.len(), 1);
+        assert_eq!(report.released.data_sockets.len(), 1);
+        assert_eq!(registry.snapshot(), Resources::default());
+
+        disconnect_mock.assert();
+        data_event_mock.assert();
+        socket_mock.assert();
+        peer_mock.assert();
+        peer_event_mock.assert();
+    }
+
+    #[tokio::test]
+    async fn keep_resources_failed_to_release() {
+        crate::initialize(mockito::server_url());
+        let registry = Registry::new();
+        let data_id = DataId::try_create("da-60a32bab-b3d9-4913-8e20-f79c90a6a211").unwrap();
+        registry.register_data_socket(data_id.clone());
+
+        let socket_mock = mock("DELETE", format!("/data/{}", data_id.as_str()).as_str())
+            .with_status(reqwest::StatusCode::FORBIDDEN.as_u16() as usize)
+            .create();
+
+        let report = shutdown(&registry, Duration::from_secs(5)).await;
+
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.released.data_sockets.is_empty());
+        assert!(registry.snapshot().data_sockets.contains(&data_id));
+        socket_mock.assert();
+    }
+
+    // Releases data sockets, and never responds to other requests.
+    struct StalledGateway;
+
+    impl Release for StalledGateway {
+        fn disconnect_data<'a>(
+            &'a self,
+            _: &'a DataConnectionId,
+        ) -> BoxFuture<'a, Result<(), error::Error>> {
+            future::pending().boxed()
+        }
+
+        fn disconnect_media<'a>(
+            &'a self,
+            _: &'a MediaConnectionId,
+        ) -> BoxFuture<'a, Result<(), error::Error>> {
+            future::pending().boxed()
+        }
+
+        fn close_data_socket<'a>(
+            &'a self,
+            _: &'a DataId,
+        ) -> BoxFuture<'a, Result<(), error::Error>> {
+            future::ok(()).boxed()
+        }
+
+        fn delete_media<'a>(&'a self, _: &'a MediaId) -> BoxFuture<'a, Result<(), error::Error>> {
+            future::pending().boxed()
+        }
+
+        fn delete_rtcp<'a>(&'a self, _: &'a RtcpId) -> BoxFuture<'a, Result<(), error::Error>> {
+            future::pending().boxed()
+        }
+
+        fn delete_peer<'a>(&'a self, _: &'a PeerInfo) -> BoxFuture<'a, Result<(), error::Error>> {
+            future::pending().boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_resources_left_at_deadline() {
+        let registry = Registry::new();
+        let peer_info = PeerInfo::try_create("stalled_peer", TOKEN).unwrap();
+        let data_id = DataId::try_create("da-70a32bab-b3d9-4913-8e20-f79c90a6a211").unwrap();
+        registry.register_peer(peer_info.clone());
+        registry.register_data_socket(data_id.clone());
+
+        let report = shutdown_with(&StalledGateway, &registry, Duration::from_millis(50)).await;
+
+        assert!(report.timed_out);
+        assert!(report.errors.is_empty());
+        assert!(report.released.data_sockets.contains(&data_id));
+        assert!(report.released.peers.is_empty());
+        let left = registry.snapshot();
+        assert!(left.data_sockets.is_empty());
+        assert!(left.peers.contains(&peer_info));
+    }
+}
@@ -0,0 +1,423 @@
+use std::fmt;
+
+use futures::channel::mpsc;
+use futures::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::data::DataConnectionEventEnum;
+use crate::error;
+use crate::media::MediaConnectionEventEnum;
+use crate::peer::PeerEventEnum;
+
+/// Shows an event which is impossible in the current state.
+///
+/// WebRTC Gateway should never send such events, so it's regarded as a protocol anomaly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidTransition {
+    /// State when the event is received
+    pub state: String,
+    /// Received event
+    pub event: String,
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid transition: {} in {}", self.event, self.state)
+    }
+}
+
+impl From<InvalidTransition> for error::Error {
+    fn from(transition: InvalidTransition) -> Self {
+        error::Error::create_local_error(&format!("{}", transition))
+    }
+}
+
+/// State which changes with events from WebRTC Gateway.
+pub trait ConnectionState: Copy + PartialEq + fmt::Debug + Send + Sync + 'static {
+    type Event: fmt::Debug;
+
+    /// State before receiving any events
+    fn initial() -> Self;
+
+    /// Returns the next state, or InvalidTransition if the event is impossible in this state.
+    fn next(self, event: &Self::Event) -> Result<Self, InvalidTransition>;
+
+    /// Returns true if no more events come.
+    fn is_closed(&self) -> bool;
+
+    fn invalid(self, event: &Self::Event) -> InvalidTransition {
+        InvalidTransition {
+            state: format!("{:?}", self),
+            event: format!("{:?}", event),
+        }
+    }
+}
+
+/// State of a MediaConnection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaConnectionState {
+    /// Created with call or CALL event, but not ready yet
+    Created,
+    /// Received READY event
+    Ready,
+    /// Received STREAM event
+    Streaming,
+    /// Received CLOSE event
+    Closed,
+}
+
+impl ConnectionState for MediaConnectionState {
+    type Event = MediaConnectionEventEnum;
+
+    fn initial() -> Self {
+        MediaConnectionState::Created
+    }
+
+    fn next(self, event: &MediaConnectionEventEnum) -> Result<Self, InvalidTransition> {
+        use MediaConnectionState::*;
+        match (self, event) {
+            (state, MediaConnectionEventEnum::TIMEOUT) => Ok(state),
+            (Closed, _) => Err(self.invalid(event)),
+            (_, MediaConnectionEventEnum::CLOSE(_)) => Ok(Closed),
+            (state, MediaConnectionEventEnum::ERROR(_)) => Ok(state),
+            (Created, MediaConnectionEventEnum::READY(_)) => Ok(Ready),
+            // a neighbour may restart its stream
+            (Ready, MediaConnectionEventEnum::STREAM(_))
+            | (Streaming, MediaConnectionEventEnum::STREAM(_)) => Ok(Streaming),
+            _ => Err(self.invalid(event)),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        *self == MediaConnectionState::Closed
+    }
+}
+
+/// State of a DataConnection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataConnectionState {
+    /// Created with connect or CONNECTION event, but not open yet
+    Created,
+    /// Received OPEN event
+    Open,
+    /// Received CLOSE event
+    Closed,
+}
+
+impl ConnectionState for DataConnectionState {
+    type Event = DataConnectionEventEnum;
+
+    fn initial() -> Self {
+        DataConnectionState::Created
+    }
+
+    fn next(self, event: &DataConnectionEventEnum) -> Result<Self, InvalidTransition> {
+        use DataConnectionState::*;
+        match (self, event) {
+            (state, DataConnectionEventEnum::TIMEOUT) => Ok(state),
+            (Closed, _) => Err(self.invalid(event)),
+            (_, DataConnectionEventEnum::CLOSE(_)) => Ok(Closed),
+            (state, DataConnectionEventEnum::ERROR(_)) => Ok(state),
+            (Created, DataConnectionEventEnum::OPEN(_)) => Ok(Open),
+            _ => Err(self.invalid(event)),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        *self == DataConnectionState::Closed
+    }
+}
+
+/// State of a PeerObject
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    /// Created with peer::create, but not registered to SkyWay server yet
+    Created,
+    /// Received OPEN event
+    Open,
+    /// Received CLOSE event
+    Closed,
+}
+
+impl ConnectionState for PeerState {
+    type Event = PeerEventEnum;
+
+    fn initial() -> Self {
+        PeerState::Created
+    }
+
+    fn next(self, event: &PeerEventEnum) -> Result<Self, InvalidTransition> {
+        use PeerState::*;
+        match (self, event) {
+            (state, PeerEventEnum::TIMEOUT) => Ok(state),
+            (Closed, _) => Err(self.invalid(event)),
+            (_, PeerEventEnum::CLOSE(_)) => Ok(Closed),
+            (state, PeerEventEnum::ERROR(_)) => Ok(state),
+            (Created, PeerEventEnum::OPEN(_)) => Ok(Open),
+            (Open, PeerEventEnum::CONNECTION(_)) | (Open, PeerEventEnum::CALL(_)) => Ok(Open),
+            _ => Err(self.invalid(event)),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        *self == PeerState::Closed
+    }
+}
+
+/// Holds a state and publishes its changes through a `watch` channel.
+///
+/// The channel carries all states the machine has been in, so that watchers don't miss
+/// short-lived states such as Streaming followed by Closed immediately.
+#[derive(Debug)]
+pub struct StateMachine<S: ConnectionState> {
+    sender: watch::Sender<Vec<S>>,
+    anomalies: usize,
+}
+
+impl<S: ConnectionState> Default for StateMachine<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: ConnectionState> StateMachine<S> {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(vec![S::initial()]);
+        Self {
+            sender,
+            anomalies: 0,
+        }
+    }
+
+    pub fn current(&self) -> S {
+        *self.sender.borrow().last().expect("no state")
+    }
+
+    /// Number of events rejected as protocol anomalies
+    pub fn anomalies(&self) -> usize {
+        self.anomalies
+    }
+
+    pub fn subscribe(&self) -> StateWatcher<S> {
+        StateWatcher(self.sender.subscribe())
+    }
+
+    /// Apply an event. Invalid events are logged and don't change the state.
+    pub fn apply(&mut self, event: &S::Event) -> Result<S, InvalidTransition> {
+        match self.current().next(event) {
+            Ok(state) => {
+                self.sender.send_if_modified(|history| {
+                    let modified = history.last() != Some(&state);
+                    if modified {
+                        history.push(state);
+                    }
+                    modified
+                });
+                Ok(state)
+            }
+            Err(transition) => {
+                warn!("protocol anomaly: {}", transition);
+                self.anomalies += 1;
+                Err(transition)
+            }
+        }
+    }
+
+    /// Apply events from `listen_events` until the stream ends.
+    ///
+    /// Events are also passed to `event_notifier` if it's set.
+    pub async fn drive(
+        &mut self,
+        mut event_observer: mpsc::Receiver<S::Event>,
+        mut event_notifier: Option<mpsc::Sender<S::Event>>,
+    ) -> S {
+        while let Some(event) = event_observer.next().await {
+            let _ = self.apply(&event);
+            if let Some(ref mut notifier) = event_notifier {
+                if notifier.send(event).await.is_err() {
+                    event_notifier = None;
+                }
+            }
+        }
+        self.current()
+    }
+}
+
+/// Receives changes of a state.
+#[derive(Debug, Clone)]
+pub struct StateWatcher<S: ConnectionState>(watch::Receiver<Vec<S>>);
+
+impl<S: ConnectionState> StateWatcher<S> {
+    pub fn current(&self) -> S {
+        *self.0.borrow().last().expect("no state")
+    }
+
+    /// Wait until the state satisfies the condition.
+    ///
+    /// It returns immediately if one of the past states satisfies the condition.
+    /// It returns error if the state is closed, or the StateMachine is dropped, without satisfying it.
+    pub async fn wait_for(&mut self, condition: impl Fn(&S) -> bool) -> Result<S, error::Error> {
+        loop {
+            let (found, state) = {
+                let history = self.0.borrow();
+                (
+                    history.iter().find(|s| condition(s)).copied(),
+                    *history.last().expect("no state"),
+                )
+            };
+            if let Some(found) = found {
+                return Ok(found);
+            }
+            if state.is_closed() {
+                return Err(error::Error::create_local_error(
+                    "closed before reaching the expected state",
+                ));
+            }
+            if self.0.changed().await.is_err() {
+                return Err(error::Error::create_local_error(
+                    "state machine has been dropped",
+                ));
+            }
+        }
+    }
+
+    /// Wait until the state is closed.
+    pub async fn closed(&mut self) -> Result<S, error::Error> {
+        self.wait_for(|s| s.is_closed()).await
+    }
+}
+
+impl StateWatcher<MediaConnectionState> {
+    /// Wait until the MediaConnection receives READY.
+    pub async fn ready(&mut self) -> Result<MediaConnectionState, error::Error> {
+        self.wait_for(|s| {
+            *s == MediaConnectionState::Ready || *s == MediaConnectionState::Streaming
+        })
+        .await
+    }
+
+    /// Wait until the MediaConnection receives STREAM.
+    pub async fn streaming(&mut self) -> Result<MediaConnectionState, error::Error> {
+        self.wait_for(|s| *s == MediaConnectionState::Streaming)
+            .await
+    }
+}
+
+impl StateWatcher<DataConnectionState> {
+    /// Wait until the DataConnection receives OPEN.
+    pub async fn open(&mut self) -> Result<DataConnectionState, error::Error> {
+        self.wait_for(|s| *s == DataConnectionState::Open).await
+    }
+}
+
+impl StateWatcher<PeerState> {
+    /// Wait until the PeerObject receives OPEN.
+    pub async fn open(&mut self) -> Result<PeerState, error::Error> {
+        self.wait_for(|s| *s == PeerState::Open).await
+    }
+}
+
+#[cfg(test)]
+mod test_state {
+    use super::*;
+    use crate::data::formats::{DataConnectionId, DataConnectionIdWrapper};
+    use crate::media::formats::{MediaConnectionId, MediaConnectionIdWrapper};
+
+    fn media_wrapper() -> MediaConnectionIdWrapper {
+        MediaConnectionIdWrapper {
+            media_connection_id: MediaConnectionId::try_create(
+                "mc-102127d9-30de-413b-93f7-41a33e39d82b",
+            )
+            .unwrap(),
+        }
+    }
+
+    fn data_wrapper() -> DataConnectionIdWrapper {
+        DataConnectionIdWrapper {
+            data_connection_id: DataConnectionId::try_create(
+                "dc-4995f372-fb6a-4196-b30a-ce11e5c7f56c",
+            )
+            .unwrap(),
+        }
+    }
+
+    #[test]
+    fn media_transitions() {
+        use MediaConnectionState::*;
+        let ready = MediaConnectionEventEnum::READY(media_wrapper());
+        let stream = MediaConnectionEventEnum::STREAM(media_wrapper());
+        let close = MediaConnectionEventEnum::CLOSE(media_wrapper());
+
+        assert_eq!(Created.next(&ready), Ok(Ready));
+        assert_eq!(Ready.next(&stream), Ok(Streaming));
+        assert_eq!(Streaming.next(&stream), Ok(Streaming));
+        assert_eq!(Streaming.next(&close), Ok(Closed));
+        assert_eq!(Created.next(&close), Ok(Closed));
+        assert_eq!(Ready.next(&MediaConnectionEventEnum::TIMEOUT), Ok(Ready));
+
+        assert!(Created.next(&stream).is_err());
+        assert!(Ready.next(&ready).is_err());
+        assert!(Closed.next(&ready).is_err());
+        assert!(Closed.next(&close).is_err());
+    }
+
+    #[test]
+    fn data_transitions() {
+        use DataConnectionState::*;
+        let open = DataConnectionEventEnum::OPEN(data_wrapper());
+        let close = DataConnectionEventEnum::CLOSE(data_wrapper());
+
+        assert_eq!(Created.next(&open), Ok(Open));
+        assert_eq!(Open.next(&close), Ok(Closed));
+        assert!(Open.next(&open).is_err());
+        assert!(Closed.next(&open).is_err());
+    }
+
+    #[test]
+    fn reject_invalid_event() {
+        let mut machine = StateMachine::<MediaConnectionState>::new();
+        let result = machine.apply(&MediaConnectionEventEnum::STREAM(media_wrapper()));
+        assert!(result.is_err());
+        assert_eq!(machine.current(), MediaConnectionState::Created);
+        assert_eq!(machine.anomalies(), 1);
+    }
+
+    #[tokio::test]
+    async fn wait_streaming() {
+        let mut machine = StateMachine::<MediaConnectionState>::new();
+        let mut watcher = machine.subscribe();
+        let (mut event_notifier, event_observer) = mpsc::channel(10);
+        let events = vec![
+            MediaConnectionEventEnum::READY(media_wrapper()),
+            MediaConnectionEventEnum::STREAM(media_wrapper()),
+            MediaConnectionEventEnum::CLOSE(media_wrapper()),
+        ];
+        let send_fut = async move {
+            for event in events {
+                event_notifier.send(event).await.unwrap();
+            }
+        };
+        let wait_fut = async {
+            let state = watcher.streaming().await;
+            (state, watcher)
+        };
+        let drive_fut = machine.drive(event_observer, None);
+
+        let (_, (state, mut watcher), last) = join!(send_fut, wait_fut, drive_fut);
+        assert!(state.is_ok());
+        assert_eq!(last, MediaConnectionState::Closed);
+        assert_eq!(watcher.closed().await, Ok(MediaConnectionState::Closed));
+    }
+
+    #[tokio::test]
+    async fn wait_fails_after_close() {
+        let mut machine = StateMachine::<DataConnectionState>::new();
+        let mut watcher = machine.subscribe();
+        machine
+            .apply(&DataConnectionEventEnum::CLOSE(data_wrapper()))
+            .unwrap();
+        assert!(watcher.open().await.is_err());
+    }
+}
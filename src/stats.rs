@@ -0,0 +1,461 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+use crate::media::formats::{MediaConnectionId, MediaId, SsrcPair};
+use crate::media::rtp::{self, is_rtcp, RtpPacket};
+
+/// Period over which `packet_rate` and `bit_rate` are calculated
+pub const WINDOW: Duration = Duration::from_secs(5);
+
+// A jump of sequence numbers larger than these is regarded as a restart of the stream (RFC 3550 A.1)
+const MAX_DROPOUT: i32 = 3000;
+const MAX_MISORDER: i32 = 100;
+
+/// Traffic of a stream through a UDP bridge.
+///
+/// `gaps`, `reorders` and `jitter_ms` are counted only for RTP, since data has no sequence numbers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct TrafficStats {
+    pub packets: u64,
+    pub bytes: u64,
+    /// Packets missing in sequence numbers. Packets arriving late are not counted.
+    pub gaps: u64,
+    /// Packets arriving after one with a later sequence number
+    pub reorders: u64,
+    /// Inter-arrival jitter of RFC 3550 in milliseconds
+    pub jitter_ms: f64,
+    /// Packets per second in the last `WINDOW`
+    pub packet_rate: f64,
+    /// Bits per second in the last `WINDOW`
+    pub bit_rate: f64,
+}
+
+#[derive(Debug)]
+struct Counter {
+    started: Instant,
+    packets: u64,
+    bytes: u64,
+    gaps: u64,
+    reorders: u64,
+    max_sequence: Option<u16>,
+    // arrival and RTP timestamp of the previous packet
+    last: Option<(Instant, u32)>,
+    // in seconds
+    jitter: f64,
+    recent: VecDeque<(Instant, usize)>,
+}
+
+impl Counter {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            packets: 0,
+            bytes: 0,
+            gaps: 0,
+            reorders: 0,
+            max_sequence: None,
+            last: None,
+            jitter: 0.0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, now: Instant, len: usize) {
+        self.packets += 1;
+        self.bytes += len as u64;
+        self.recent.push_back((now, len));
+        self.expire(now);
+    }
+
+    fn record_rtp(&mut self, now: Instant, len: usize, packet: &RtpPacket, clock_rate: u32) {
+        self.record(now, len);
+        let max_sequence = match self.max_sequence {
+            Some(max_sequence) => max_sequence,
+            None => {
+                self.max_sequence = Some(packet.sequence);
+                self.last = Some((now, packet.timestamp));
+                return;
+            }
+        };
+        let delta = packet.sequence.wrapping_sub(max_sequence) as i16 as i32;
+        if delta == 0 {
+            // duplicated
+            return;
+        } else if delta > 0 && delta < MAX_DROPOUT {
+            self.gaps += (delta - 1) as u64;
+            self.max_sequence = Some(packet.sequence);
+        } else if delta < 0 && -delta < MAX_MISORDER {
+            // it fills a gap counted before
+            self.reorders += 1;
+            self.gaps = self.gaps.saturating_sub(1);
+        } else {
+            self.max_sequence = Some(packet.sequence);
+        }
+
+        if let Some((arrival, timestamp)) = self.last {
+            let elapsed = now.saturating_duration_since(arrival).as_secs_f64();
+            let advanced = packet.timestamp.wrapping_sub(timestamp) as i32 as f64;
+            let d = (elapsed - advanced / clock_rate as f64).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.last = Some((now, packet.timestamp));
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((time, _)) = self.recent.front() {
+            if now.saturating_duration_since(*time) < WINDOW {
+                break;
+            }
+            self.recent.pop_front();
+        }
+    }
+
+    fn stats(&mut self, now: Instant) -> TrafficStats {
+        self.expire(now);
+        let period = now.saturating_duration_since(self.started).min(WINDOW);
+        let (packet_rate, bit_rate) = if period.is_zero() {
+            (0.0, 0.0)
+        } else {
+            let bytes: usize = self.recent.iter().map(|(_, len)| len).sum();
+            (
+                self.recent.len() as f64 / period.as_secs_f64(),
+                8.0 * bytes as f64 / period.as_secs_f64(),
+            )
+        };
+        TrafficStats {
+            packets: self.packets,
+            bytes: self.bytes,
+            gaps: self.gaps,
+            reorders: self.reorders,
+            jitter_ms: self.jitter * 1000.0,
+            packet_rate,
+            bit_rate,
+        }
+    }
+}
+
+/// Counts packets of a stream. Clones share the counts.
+#[derive(Debug, Clone)]
+pub struct TrafficMeter {
+    counter: Arc<Mutex<Counter>>,
+}
+
+impl Default for TrafficMeter {
+    fn default() -> Self {
+        Self {
+            counter: Arc::new(Mutex::new(Counter::new(Instant::now()))),
+        }
+    }
+}
+
+impl TrafficMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a packet of `len` bytes.
+    pub fn record(&self, len: usize) {
+        self.counter.lock().unwrap().record(Instant::now(), len);
+    }
+
+    pub fn stats(&self) -> TrafficStats {
+        self.counter.lock().unwrap().stats(Instant::now())
+    }
+}
+
+/// Traffic of both directions of a DataConnection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct DataStats {
+    /// Data sent to the data socket
+    pub feed: TrafficStats,
+    /// Data redirected from WebRTC Gateway
+    pub redirect: TrafficStats,
+}
+
+/// Meters of both directions of a DataConnection. Clones share the counts.
+#[derive(Debug, Clone, Default)]
+pub struct DataMeter {
+    pub feed: TrafficMeter,
+    pub redirect: TrafficMeter,
+}
+
+impl DataMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> DataStats {
+        DataStats {
+            feed: self.feed.stats(),
+            redirect: self.redirect.stats(),
+        }
+    }
+}
+
+/// Traffic of a media stream identified by its SSRC
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SsrcStats {
+    pub ssrc: u32,
+    /// Media which has the SSRC in media::status
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_id: Option<MediaId>,
+    pub rtp: TrafficStats,
+    /// RTCP packets sent from the SSRC
+    pub rtcp: TrafficStats,
+}
+
+#[derive(Debug)]
+struct MediaCounters {
+    started: Instant,
+    streams: BTreeMap<u32, (Counter, Counter)>,
+    media_ids: BTreeMap<u32, MediaId>,
+}
+
+impl MediaCounters {
+    fn record(&mut self, now: Instant, packet: &[u8], clock_rate: u32) {
+        let started = self.started;
+        let ssrc = match rtp::ssrc(packet) {
+            Some(ssrc) => ssrc,
+            None => return,
+        };
+        let (rtp, rtcp) = self
+            .streams
+            .entry(ssrc)
+            .or_insert_with(|| (Counter::new(started), Counter::new(started)));
+        if is_rtcp(packet) {
+            rtcp.record(now, packet.len());
+        } else if let Some(parsed) = RtpPacket::parse(packet) {
+            rtp.record_rtp(now, packet.len(), &parsed, clock_rate);
+        }
+    }
+
+    fn stats(&mut self, now: Instant) -> Vec<SsrcStats> {
+        let media_ids = &self.media_ids;
+        self.streams
+            .iter_mut()
+            .map(|(ssrc, (rtp, rtcp))| SsrcStats {
+                ssrc: *ssrc,
+                media_id: media_ids.get(ssrc).cloned(),
+                rtp: rtp.stats(now),
+                rtcp: rtcp.stats(now),
+            })
+            .collect()
+    }
+}
+
+/// Counts RTP and RTCP packets of a MediaConnection per SSRC. Clones share the counts.
+#[derive(Debug, Clone)]
+pub struct MediaMeter {
+    counters: Arc<Mutex<MediaCounters>>,
+}
+
+impl Default for MediaMeter {
+    fn default() -> Self {
+        Self {
+            counters: Arc::new(Mutex::new(MediaCounters {
+                started: Instant::now(),
+                streams: BTreeMap::new(),
+                media_ids: BTreeMap::new(),
+            })),
+        }
+    }
+}
+
+impl MediaMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a RTP or RTCP packet. `clock_rate` of the RTP timestamp is used for jitter.
+    /// Packets which are neither RTP nor RTCP are ignored.
+    pub fn record(&self, packet: &[u8], clock_rate: u32) {
+        self.counters
+            .lock()
+            .unwrap()
+            .record(Instant::now(), packet, clock_rate);
+    }
+
+    /// Relate SSRCs to MediaIds with SsrcPairs from media::status.
+    pub fn label(&self, pairs: &[SsrcPair]) {
+        let mut counters = self.counters.lock().unwrap();
+        for pair in pairs {
+            counters
+                .media_ids
+                .insert(pair.ssrc as u32, pair.media_id.clone());
+        }
+    }
+
+    /// Fetch media::status of the MediaConnection and relate SSRCs to MediaIds with it.
+    pub async fn resolve(
+        &self,
+        media_connection_id: &MediaConnectionId,
+    ) -> Result<(), error::Error> {
+        let status = crate::media::status(media_connection_id).await?;
+        self.label(&status.ssrc.unwrap_or_default());
+        Ok(())
+    }
+
+    /// Stats of SSRCs found so far in ascending order
+    pub fn stats(&self) -> Vec<SsrcStats> {
+        self.counters.lock().unwrap().stats(Instant::now())
+    }
+}
+
+/// Clock rate of RTP timestamps of a redirected stream.
+///
+/// Video is always 90kHz. Audio is 8kHz for G.711 and G.722, and 48kHz for Opus,
+/// which is the only audio codec WebRTC Gateway negotiates with a dynamic payload type.
+pub fn clock_rate(is_video: bool, payload_type: u8) -> u32 {
+    match (is_video, payload_type) {
+        (true, _) => 90000,
+        (false, 0) | (false, 8) | (false, 9) => 8000,
+        (false, _) => 48000,
+    }
+}
+
+/// Payload type of a RTP packet, which is used to find the clock rate.
+pub(crate) fn payload_type(packet: &[u8]) -> u8 {
+    RtpPacket::parse(packet)
+        .map(|rtp| rtp.payload_type)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test_stats {
+    use super::*;
+    use crate::common::formats::SerializableId;
+    use crate::media::rtp::test_rtp::rtp;
+
+    fn record(counter: &mut Counter, now: Instant, sequence: u16, timestamp: u32) {
+        let packet = rtp(sequence, timestamp, 1, false, &[0; 88]);
+        let parsed = RtpPacket::parse(&packet).unwrap();
+        counter.record_rtp(now, packet.len(), &parsed, 90000);
+    }
+
+    #[test]
+    fn count_gaps_and_reorders() {
+        let start = Instant::now();
+        let mut counter = Counter::new(start);
+        for sequence in [65534u16, 65535, 2, 1, 3, 3, 7].iter() {
+            record(&mut counter, start, *sequence, 0);
+        }
+        let stats = counter.stats(start);
+        assert_eq!(stats.packets, 7);
+        assert_eq!(stats.bytes, 700);
+        // 0 and 4-6 are missing, and 1 arrived late
+        assert_eq!(stats.gaps, 4);
+        assert_eq!(stats.reorders, 1);
+    }
+
+    #[test]
+    fn restart_is_not_a_gap() {
+        let start = Instant::now();
+        let mut counter = Counter::new(start);
+        record(&mut counter, start, 100, 0);
+        record(&mut counter, start, 20000, 0);
+        record(&mut counter, start, 20001, 0);
+        let stats = counter.stats(start);
+        assert_eq!(stats.gaps, 0);
+        assert_eq!(stats.reorders, 0);
+    }
+
+    #[test]
+    fn jitter() {
+        let start = Instant::now();
+        let mut counter = Counter::new(start);
+        // 30fps at a constant pace
+        for i in 0..10u32 {
+            let now = start + Duration::from_micros(33_333 * i as u64);
+            record(&mut counter, now, i as u16, 3000 * i);
+        }
+        assert!(counter.stats(start).jitter_ms < 0.01);
+
+        // a packet is 16ms late
+        let now = start + Duration::from_micros(33_333 * 10 + 16_000);
+        record(&mut counter, now, 10, 30000);
+        let jitter_ms = counter.stats(now).jitter_ms;
+        assert!((jitter_ms - 1.0).abs() < 0.01, "{}", jitter_ms);
+    }
+
+    #[test]
+    fn windowed_rates() {
+        let start = Instant::now();
+        let mut counter = Counter::new(start);
+        // 100 packets of 125 bytes per second for 10 seconds
+        for i in 0..1000u64 {
+            counter.record(start + Duration::from_millis(10 * i), 125);
+        }
+        let now = start + Duration::from_secs(10);
+        let stats = counter.stats(now);
+        assert_eq!(stats.packets, 1000);
+        assert_eq!(stats.bytes, 125_000);
+        assert!((stats.packet_rate - 100.0).abs() < 1.0);
+        assert!((stats.bit_rate - 100_000.0).abs() < 1000.0);
+        assert_eq!(stats.gaps, 0);
+        assert_eq!(stats.jitter_ms, 0.0);
+
+        // the rate falls after the traffic stops
+        let stats = counter.stats(now + WINDOW);
+        assert_eq!(stats.packets, 1000);
+        assert_eq!(stats.packet_rate, 0.0);
+        assert_eq!(stats.bit_rate, 0.0);
+    }
+
+    #[test]
+    fn rates_of_a_young_meter() {
+        let start = Instant::now();
+        let mut counter = Counter::new(start);
+        counter.record(start, 100);
+        assert_eq!(counter.stats(start).packet_rate, 0.0);
+        let stats = counter.stats(start + Duration::from_secs(1));
+        assert_eq!(stats.packet_rate, 1.0);
+        assert_eq!(stats.bit_rate, 800.0);
+    }
+
+    #[test]
+    fn media_meter_keyed_by_ssrc() {
+        let meter = MediaMeter::new();
+        meter.record(&rtp(1, 0, 0x1111, false, &[0; 88]), 90000);
+        meter.record(&rtp(3, 0, 0x1111, false, &[0; 88]), 90000);
+        meter.record(&rtp(1, 0, 0x2222, false, &[0; 88]), 48000);
+        // RTCP Sender Report from 0x2222
+        let mut sr = vec![0x80, 200, 0x00, 0x06];
+        sr.extend(0x2222u32.to_be_bytes());
+        sr.extend_from_slice(&[0u8; 20]);
+        meter.record(&sr, 48000);
+        // neither RTP nor RTCP
+        meter.record(&[0u8; 4], 90000);
+
+        meter.label(&[SsrcPair {
+            media_id: MediaId::try_create("vi-4d053831-5dc2-461b-a358-d062d6115216").unwrap(),
+            ssrc: 0x1111,
+        }]);
+        let stats = meter.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].ssrc, 0x1111);
+        assert_eq!(
+            stats[0].media_id.as_ref().map(|id| id.as_str()),
+            Some("vi-4d053831-5dc2-461b-a358-d062d6115216")
+        );
+        assert_eq!(stats[0].rtp.packets, 2);
+        assert_eq!(stats[0].rtp.gaps, 1);
+        assert_eq!(stats[0].rtcp.packets, 0);
+        assert_eq!(stats[1].ssrc, 0x2222);
+        assert_eq!(stats[1].media_id, None);
+        assert_eq!(stats[1].rtp.packets, 1);
+        assert_eq!(stats[1].rtcp.packets, 1);
+        assert_eq!(stats[1].rtcp.bytes, 28);
+    }
+
+    #[test]
+    fn clock_rates() {
+        assert_eq!(clock_rate(true, 96), 90000);
+        assert_eq!(clock_rate(false, 0), 8000);
+        assert_eq!(clock_rate(false, 8), 8000);
+        assert_eq!(clock_rate(false, 111), 48000);
+    }
+}